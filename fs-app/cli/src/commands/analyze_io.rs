@@ -0,0 +1,84 @@
+//! Analyze-io command for summarizing a block I/O trace
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::path::PathBuf;
+
+use aegisfs::blockdev::{read_trace, TraceSummary};
+
+/// Summarize a trace recorded with `aegisfs mount --record-io`
+#[derive(Parser, Debug)]
+#[command(about = "Report the access pattern of a block I/O trace")]
+pub struct AnalyzeIoArgs {
+    /// Trace file written by `mount --record-io`
+    pub trace: PathBuf,
+
+    /// Number of block ranges in the access histogram
+    #[arg(long, default_value_t = 16)]
+    pub buckets: usize,
+
+    /// Number of hot blocks to list
+    #[arg(long, default_value_t = 10)]
+    pub top: usize,
+}
+
+/// Average of `total` over `count`, or 0 with nothing to average
+fn average(total: u64, count: u64) -> u64 {
+    total.checked_div(count).unwrap_or(0)
+}
+
+pub async fn run(args: AnalyzeIoArgs) -> Result<()> {
+    let records = read_trace(&args.trace)
+        .map_err(|e| anyhow!("Failed to read trace {}: {}", args.trace.display(), e))?;
+    if records.is_empty() {
+        println!("Trace {} has no records", args.trace.display());
+        return Ok(());
+    }
+
+    let summary = TraceSummary::from_records(&records, args.buckets, args.top);
+    let span_us = records.last().unwrap().timestamp_us - records[0].timestamp_us;
+    println!(
+        "{} accesses over {:.3}s: {} reads (avg {}us), {} writes (avg {}us)",
+        records.len(),
+        span_us as f64 / 1_000_000.0,
+        summary.reads,
+        average(summary.read_time_us, summary.reads),
+        summary.writes,
+        average(summary.write_time_us, summary.writes)
+    );
+    println!(
+        "Sequential: {} ({:.1}%), random: {}",
+        summary.sequential,
+        summary.sequential_ratio() * 100.0,
+        summary.random
+    );
+
+    println!();
+    println!("Access histogram:");
+    println!("{:>24} {:>10} {:>10}", "BLOCKS", "READS", "WRITES");
+    let busiest = summary
+        .histogram
+        .iter()
+        .map(|range| range.reads + range.writes)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    for range in &summary.histogram {
+        let bar = "#".repeat(((range.reads + range.writes) * 40 / busiest) as usize);
+        println!(
+            "{:>24} {:>10} {:>10} {}",
+            format!("{}-{}", range.start, range.end - 1),
+            range.reads,
+            range.writes,
+            bar
+        );
+    }
+
+    println!();
+    println!("Hot blocks:");
+    println!("{:>12} {:>10}", "BLOCK", "ACCESSES");
+    for (block, count) in &summary.hot_blocks {
+        println!("{:>12} {:>10}", block, count);
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Audit command for querying the security audit log
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aegisfs::blockdev::{BlockDevice, FileBackedBlockDevice};
+use aegisfs::format::Superblock;
+use aegisfs::layout::Layout;
+use aegisfs::modules::AuditLog;
+
+/// Query the security audit log
+#[derive(Parser)]
+#[command(about = "Query the AegisFS security audit log")]
+pub struct AuditArgs {
+    #[command(subcommand)]
+    pub command: AuditCommands,
+}
+
+#[derive(Subcommand)]
+pub enum AuditCommands {
+    /// Print audit records, oldest first
+    Dump {
+        /// Device or image file to read
+        device: PathBuf,
+
+        /// Only show events at or after this Unix timestamp
+        #[arg(long)]
+        since: Option<u64>,
+
+        /// Only show events issued by this user
+        #[arg(long)]
+        uid: Option<u32>,
+    },
+}
+
+pub async fn run(args: AuditArgs) -> Result<()> {
+    match args.command {
+        AuditCommands::Dump { device, since, uid } => dump(device, since, uid).await,
+    }
+}
+
+async fn dump(path: PathBuf, since: Option<u64>, uid: Option<u32>) -> Result<()> {
+    let device: Arc<dyn BlockDevice> = Arc::new(
+        FileBackedBlockDevice::open(&path, true)
+            .await
+            .map_err(|e| anyhow!("Failed to open device: {}", e))?,
+    );
+    let sb = Superblock::read_from_disk(&*device)
+        .await
+        .map_err(|e| anyhow!("Failed to read superblock: {}", e))?;
+    if sb.audit_log_blocks == 0 {
+        return Err(anyhow!(
+            "{} has no audit log; format with --audit to enable auditing",
+            path.display()
+        ));
+    }
+
+    let layout = Layout::for_superblock(&sb);
+    let log = AuditLog::open(device, layout.audit_log, layout.audit_log_blocks)
+        .await
+        .map_err(|e| anyhow!("Failed to open audit log: {}", e))?;
+    let events = log
+        .events()
+        .await
+        .map_err(|e| anyhow!("Failed to read audit log: {}", e))?;
+
+    println!(
+        "{:<12} {:>6} {:>6} {:>7} {:<7} {:>10} {:>6}  NAME",
+        "TIMESTAMP", "UID", "GID", "PID", "OP", "INODE", "RESULT"
+    );
+    let mut shown = 0;
+    for event in events
+        .iter()
+        .filter(|e| since.is_none_or(|t| e.timestamp >= t))
+        .filter(|e| uid.is_none_or(|u| e.uid == u))
+    {
+        println!(
+            "{:<12} {:>6} {:>6} {:>7} {:<7} {:>10} {:>6}  {}",
+            event.timestamp,
+            event.uid,
+            event.gid,
+            event.pid,
+            event.operation,
+            event.ino,
+            event.result,
+            event.name.as_deref().unwrap_or("-")
+        );
+        shown += 1;
+    }
+    println!(
+        "{} of {} records shown ({} slots free)",
+        shown,
+        events.len(),
+        log.capacity() - log.record_count().await
+    );
+    Ok(())
+}
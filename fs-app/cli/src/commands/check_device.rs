@@ -0,0 +1,351 @@
+//! Check-device command for vetting hardware before formatting
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use aegisfs::blockdev::FileBackedBlockDevice;
+use aegisfs::format::Superblock;
+use aegisfs::BLOCK_SIZE;
+
+use super::mount::is_device_mounted;
+
+/// Bytes written by the bandwidth test
+const BANDWIDTH_TEST_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Size of each write in the bandwidth test
+const BANDWIDTH_CHUNK: usize = 1024 * 1024;
+
+/// Report whether a device suits AegisFS
+#[derive(Parser, Debug)]
+#[command(about = "Check that a device meets AegisFS's requirements before formatting")]
+pub struct CheckDeviceArgs {
+    /// Device or image file to check
+    pub path: PathBuf,
+
+    /// Skip the write barrier and bandwidth tests, which overwrite data
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Run the write tests even if the device holds an AegisFS filesystem
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// Result of a single check
+enum Finding {
+    /// Requirement met, with what was found
+    Ok(String),
+    /// Usable, but with a recommendation
+    Warn(String, String),
+    /// Requirement not met
+    Fail(String, String),
+    /// Not measured on this device
+    Unknown(String),
+}
+
+/// Print a finding and return its recommendation, if any
+fn report(name: &str, finding: Finding) -> Option<String> {
+    match finding {
+        Finding::Ok(detail) => {
+            println!("  ✓ {:<24} {}", name, detail);
+            None
+        }
+        Finding::Warn(detail, advice) => {
+            println!("  ! {:<24} {}", name, detail);
+            Some(advice)
+        }
+        Finding::Fail(detail, advice) => {
+            println!("  ✗ {:<24} {}", name, detail);
+            Some(advice)
+        }
+        Finding::Unknown(reason) => {
+            println!("  - {:<24} unknown ({})", name, reason);
+            None
+        }
+    }
+}
+
+/// Issue an ioctl that fills in an integer
+#[cfg(target_os = "linux")]
+fn ioctl_int(file: &File, request: libc::c_ulong) -> std::io::Result<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut value: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), request, &mut value as *mut libc::c_int) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(value as u32)
+}
+
+/// Check a sector size reported by `request` against the filesystem block size
+#[cfg(target_os = "linux")]
+fn check_sector_size(file: &File, is_block_device: bool, request: libc::c_ulong) -> Finding {
+    if !is_block_device {
+        return Finding::Unknown("not a block device".to_string());
+    }
+    match ioctl_int(file, request) {
+        Ok(size) if size as usize <= BLOCK_SIZE && BLOCK_SIZE.is_multiple_of(size as usize) => {
+            Finding::Ok(format!("{} bytes", size))
+        }
+        Ok(size) => Finding::Fail(
+            format!("{} bytes", size),
+            format!(
+                "sectors of {} bytes do not divide the {}-byte block size; AegisFS cannot write whole sectors on this device",
+                size, BLOCK_SIZE
+            ),
+        ),
+        Err(e) => Finding::Unknown(e.to_string()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_sector_size(_file: &File, _is_block_device: bool, _request: u64) -> Finding {
+    Finding::Unknown("only detected on Linux".to_string())
+}
+
+/// Logical sector size (BLKSSZGET)
+#[cfg(target_os = "linux")]
+const BLKSSZGET: libc::c_ulong = 0x1268;
+#[cfg(not(target_os = "linux"))]
+const BLKSSZGET: u64 = 0;
+
+/// Physical sector size (BLKPBSZGET)
+#[cfg(target_os = "linux")]
+const BLKPBSZGET: libc::c_ulong = 0x127B;
+#[cfg(not(target_os = "linux"))]
+const BLKPBSZGET: u64 = 0;
+
+/// The sysfs queue directory of a block device, falling back to the whole
+/// disk's for a partition
+#[cfg(unix)]
+fn sysfs_queue(metadata: &fs::Metadata) -> Option<PathBuf> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    if !metadata.file_type().is_block_device() {
+        return None;
+    }
+    let rdev = metadata.rdev();
+    let dev = PathBuf::from(format!("/sys/dev/block/{}:{}", libc::major(rdev), libc::minor(rdev)));
+    [dev.join("queue"), dev.join("../queue")]
+        .into_iter()
+        .find(|queue| queue.is_dir())
+}
+
+#[cfg(not(unix))]
+fn sysfs_queue(_metadata: &fs::Metadata) -> Option<PathBuf> {
+    None
+}
+
+/// Read a sysfs attribute as a trimmed string
+fn read_attr(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+/// Tell SSDs from spinning disks by the queue's rotational flag
+fn check_rotation(queue: Option<&Path>) -> Finding {
+    let queue = match queue {
+        Some(q) => q,
+        None => return Finding::Unknown("no sysfs queue for this path".to_string()),
+    };
+    match read_attr(&queue.join("rotational")).as_deref() {
+        Some("0") => Finding::Ok("solid state".to_string()),
+        Some("1") => Finding::Warn(
+            "rotational (HDD)".to_string(),
+            "random metadata I/O is slow on spinning disks; mount with --noatime and a larger --cache-blocks".to_string(),
+        ),
+        Some(other) => Finding::Unknown(format!("unexpected rotational value '{}'", other)),
+        None => Finding::Unknown("rotational flag not exposed".to_string()),
+    }
+}
+
+/// Check whether the drive's write cache survives power loss
+///
+/// A write-through cache means the drive reports no volatile cache, which is
+/// how drives with power loss protection present themselves.
+fn check_power_loss(queue: Option<&Path>) -> Finding {
+    let queue = match queue {
+        Some(q) => q,
+        None => return Finding::Unknown("no sysfs queue for this path".to_string()),
+    };
+    match read_attr(&queue.join("write_cache")).as_deref() {
+        Some("write through") => Finding::Ok("no volatile write cache".to_string()),
+        Some("write back") => {
+            let fua = read_attr(&queue.join("fua")).as_deref() == Some("1");
+            Finding::Warn(
+                format!("volatile write cache{}", if fua { ", FUA supported" } else { "" }),
+                "the drive can lose acknowledged writes on power loss; keep the journal enabled (format --enable-journal)".to_string(),
+            )
+        }
+        Some(other) => Finding::Unknown(format!("unexpected write_cache value '{}'", other)),
+        None => Finding::Unknown("write cache mode not exposed".to_string()),
+    }
+}
+
+/// Drop any cached pages of `file` so the next read reaches the device
+fn drop_cached_pages(file: &File) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = file;
+}
+
+/// Write a block, fsync it and read it back from the device
+fn check_write_barrier(file: &mut File, size: u64) -> Finding {
+    if size < BLOCK_SIZE as u64 {
+        return Finding::Fail("device too small".to_string(), "use a larger device".to_string());
+    }
+    // The last block, away from where a filesystem keeps its superblock
+    let offset = (size / BLOCK_SIZE as u64 - 1) * BLOCK_SIZE as u64;
+    let pattern: Vec<u8> = (0..BLOCK_SIZE).map(|i| (i as u8) ^ 0xA5).collect();
+
+    let result = (|| -> std::io::Result<bool> {
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&pattern)?;
+        file.sync_all()?;
+        drop_cached_pages(file);
+        let mut read = vec![0u8; BLOCK_SIZE];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut read)?;
+        Ok(read == pattern)
+    })();
+    match result {
+        Ok(true) => Finding::Ok("fsync'd block read back intact".to_string()),
+        Ok(false) => Finding::Fail(
+            "block read back differs after fsync".to_string(),
+            "the device does not honour flushes; do not store data on it".to_string(),
+        ),
+        Err(e) => Finding::Fail(
+            format!("fsync test failed ({})", e),
+            "check the device for I/O errors".to_string(),
+        ),
+    }
+}
+
+/// Sequentially write up to 256 MB from the start of the device, timing
+/// until the data is flushed
+fn check_bandwidth(file: &mut File, size: u64) -> Finding {
+    let total = BANDWIDTH_TEST_BYTES.min(size - size % BANDWIDTH_CHUNK as u64);
+    if total == 0 {
+        return Finding::Unknown("device smaller than one test chunk".to_string());
+    }
+    let chunk: Vec<u8> = (0..BANDWIDTH_CHUNK).map(|i| (i % 251) as u8).collect();
+
+    let started = Instant::now();
+    let result = (|| -> std::io::Result<()> {
+        file.seek(SeekFrom::Start(0))?;
+        for _ in 0..total / BANDWIDTH_CHUNK as u64 {
+            file.write_all(&chunk)?;
+        }
+        file.sync_all()
+    })();
+    if let Err(e) = result {
+        return Finding::Fail(
+            format!("sequential write failed ({})", e),
+            "check the device for I/O errors".to_string(),
+        );
+    }
+
+    let elapsed = started.elapsed().as_secs_f64().max(f64::EPSILON);
+    let mb_per_sec = total as f64 / (1024.0 * 1024.0) / elapsed;
+    let detail = format!("{:.1} MB/s over {} MB", mb_per_sec, total / (1024 * 1024));
+    if mb_per_sec < 20.0 {
+        Finding::Warn(detail, "writes are slow; expect long journal commits and snapshot times".to_string())
+    } else {
+        Finding::Ok(detail)
+    }
+}
+
+pub async fn run(args: CheckDeviceArgs) -> Result<()> {
+    let path = &args.path;
+    let metadata = fs::metadata(path).map_err(|e| anyhow!("Cannot access {}: {}", path.display(), e))?;
+    #[cfg(unix)]
+    let is_block_device = std::os::unix::fs::FileTypeExt::is_block_device(&metadata.file_type());
+    #[cfg(not(unix))]
+    let is_block_device = false;
+
+    let queue = sysfs_queue(&metadata);
+
+    if !args.read_only {
+        if let Some(mount_point) = is_device_mounted(path)? {
+            return Err(anyhow!(
+                "Device {} is mounted at {}. Unmount it or pass --read-only.",
+                path.display(),
+                mount_point
+            ));
+        }
+        if !args.force {
+            if let Ok(device) = FileBackedBlockDevice::open(path, true).await {
+                if Superblock::read_from_disk(&device).await.is_ok() {
+                    return Err(anyhow!(
+                        "{} holds an AegisFS filesystem that the write tests would damage. Pass --read-only, or --force to overwrite it.",
+                        path.display()
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut file = File::open(path).map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+    // Seeking to the end measures block devices and image files alike
+    let size = file
+        .seek(SeekFrom::End(0))
+        .map_err(|e| anyhow!("Failed to get device size: {}", e))?;
+    println!(
+        "Checking {} ({}, {} MB)",
+        path.display(),
+        if is_block_device { "block device" } else { "image file" },
+        size / (1024 * 1024)
+    );
+
+    let mut findings = vec![
+        ("Logical sector size", check_sector_size(&file, is_block_device, BLKSSZGET)),
+        ("Physical sector size", check_sector_size(&file, is_block_device, BLKPBSZGET)),
+        ("Rotation", check_rotation(queue.as_deref())),
+        ("Power loss protection", check_power_loss(queue.as_deref())),
+    ];
+    if args.read_only {
+        println!("Skipping write tests (--read-only)");
+    } else {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| anyhow!("Failed to open {} for writing: {}", path.display(), e))?;
+        println!("Running write tests; this overwrites up to {} MB", BANDWIDTH_TEST_BYTES / (1024 * 1024));
+        findings.push(("Write barrier", check_write_barrier(&mut file, size)));
+        findings.push(("Write bandwidth", check_bandwidth(&mut file, size)));
+    }
+
+    println!();
+    let mut failed = 0;
+    let mut recommendations = Vec::new();
+    for (name, finding) in findings {
+        if matches!(finding, Finding::Fail(..)) {
+            failed += 1;
+        }
+        if let Some(advice) = report(name, finding) {
+            recommendations.push(advice);
+        }
+    }
+
+    if !recommendations.is_empty() {
+        println!();
+        println!("Recommendations:");
+        for advice in &recommendations {
+            println!("  - {}", advice);
+        }
+    }
+    println!();
+    if failed > 0 {
+        return Err(anyhow!("{} is not compatible with AegisFS ({} checks failed)", path.display(), failed));
+    }
+    println!("{} is compatible with AegisFS", path.display());
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! Checkpoint command for applying and compacting the journal
+
+use anyhow::Result;
+use clap::Parser;
+use log::info;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aegisfs::blockdev::FileBackedBlockDevice;
+use aegisfs::format::Superblock;
+use aegisfs::layout::Layout;
+use aegisfs::modules::{JournalConfig, JournalManager};
+
+/// Apply committed journal transactions and reclaim journal space
+#[derive(Parser)]
+#[command(about = "Apply committed journal transactions and reclaim journal space")]
+pub struct CheckpointArgs {
+    /// Device path to checkpoint
+    pub device: PathBuf,
+}
+
+pub async fn run(args: CheckpointArgs) -> Result<()> {
+    let device = Arc::new(
+        FileBackedBlockDevice::open(&args.device, false)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to open device: {}", e))?,
+    );
+
+    let superblock = Superblock::read_from_disk(&*device)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read superblock: {}", e))?;
+    let layout = Layout::for_superblock(&superblock);
+    if layout.journal_blocks == 0 {
+        return Err(anyhow::anyhow!(
+            "{} has no journal; format it with --enable-journal to create one",
+            args.device.display()
+        ));
+    }
+
+    let config = JournalConfig {
+        journal_start: layout.journal,
+        journal_size: layout.journal_blocks,
+        ..JournalConfig::default()
+    };
+    let mut journal = JournalManager::new(device, config);
+    journal
+        .init()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to open journal: {}", e))?;
+
+    info!("Checkpointing journal on {}", args.device.display());
+    let stats = journal
+        .checkpoint()
+        .await
+        .map_err(|e| anyhow::anyhow!("Checkpoint failed: {}", e))?;
+    journal
+        .shutdown()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to close journal: {}", e))?;
+
+    println!("Checkpoint complete:");
+    println!("  Transactions applied: {}", stats.transactions);
+    println!("  Blocks written:       {}", stats.blocks_applied);
+    println!("  Journal blocks freed: {}", stats.blocks_reclaimed);
+
+    Ok(())
+}
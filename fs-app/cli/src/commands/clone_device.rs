@@ -0,0 +1,234 @@
+//! Clone-device command for copying a filesystem to another device or image
+//!
+//! `cp` of an image that is mounted copies whatever happens to be on disk
+//! at each moment. With `--consistent` the source is frozen so nothing can
+//! mount it or write to it during the copy, and only the blocks the
+//! filesystem uses are copied. Free blocks are left as holes. Either way
+//! the superblock is copied last: a copy that was interrupted has none and
+//! won't mount.
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use aegisfs::blockdev::{BlockDevice, BlockDeviceError, FileBackedBlockDevice};
+use aegisfs::{DiskFs, DiskFsTrait, BLOCK_SIZE};
+
+use super::mount::is_device_mounted;
+
+/// Blocks copied at once
+const COPY_CONCURRENCY: usize = 16;
+
+/// Copy a filesystem to another device or image file
+#[derive(Parser)]
+#[command(about = "Copy a filesystem to another device or image file")]
+pub struct CloneDeviceArgs {
+    /// Device or image file to copy
+    pub source: PathBuf,
+
+    /// Device or image file to copy to. An image file is created the size
+    /// of the source
+    pub dest: PathBuf,
+
+    /// Freeze the source so it can't be mounted or written during the
+    /// copy, and copy only the blocks in use. Without it every block is
+    /// copied as it is
+    #[arg(long)]
+    pub consistent: bool,
+
+    /// Overwrite an existing destination image file
+    #[arg(long)]
+    pub force: bool,
+}
+
+pub async fn run(args: CloneDeviceArgs) -> Result<()> {
+    if args.dest.exists() && args.source.canonicalize().ok() == args.dest.canonicalize().ok() {
+        return Err(anyhow!("Source and destination are both {}", args.source.display()));
+    }
+
+    let source = Arc::new(
+        FileBackedBlockDevice::open(&args.source, true)
+            .await
+            .map_err(|e| anyhow!("Failed to open {}: {}", args.source.display(), e))?,
+    );
+
+    if !args.consistent {
+        if let Some(mount_point) = is_device_mounted(&args.source)? {
+            warn!(
+                "{} is mounted at {}; the copy may be inconsistent. Unmount it and pass --consistent for a consistent copy",
+                args.source.display(),
+                mount_point
+            );
+        }
+        return clone_blocks(&source, &args).await;
+    }
+
+    source.freeze().await.map_err(|e| match e {
+        BlockDeviceError::Locked(_) => anyhow!(
+            "{} is mounted or open read-write elsewhere. Unmount it before a consistent copy.",
+            args.source.display()
+        ),
+        e => anyhow!("Failed to freeze {}: {}", args.source.display(), e),
+    })?;
+    info!("Froze {}", args.source.display());
+
+    let result = clone_blocks(&source, &args).await;
+    if let Err(e) = source.unfreeze().await {
+        warn!("Failed to unfreeze {}: {}", args.source.display(), e);
+    }
+    result
+}
+
+/// Copy the source's blocks, the superblock last, leaving the free ones
+/// as holes when only blocks in use are copied
+async fn clone_blocks(source: &Arc<FileBackedBlockDevice>, args: &CloneDeviceArgs) -> Result<()> {
+    let block_count = source.block_count();
+    let free = if args.consistent {
+        free_blocks(source, &args.source).await?
+    } else {
+        Vec::new()
+    };
+    let dest = open_dest(&args.dest, block_count, args.force).await?;
+
+    let skip: HashSet<u64> = free.iter().copied().collect();
+    let total = block_count.saturating_sub(1 + skip.len() as u64);
+    info!(
+        "Copying {} of {} blocks from {} to {}",
+        total + 1,
+        block_count,
+        args.source.display(),
+        args.dest.display()
+    );
+
+    let mut pending = (1..block_count).filter(|block_num| !skip.contains(block_num));
+    let mut copies = FuturesUnordered::new();
+    let mut copied = 0u64;
+    let mut shown = u64::MAX;
+    loop {
+        while copies.len() < COPY_CONCURRENCY {
+            let Some(block_num) = pending.next() else {
+                break;
+            };
+            copies.push(copy_block(&**source, &dest, block_num));
+        }
+        let Some(result) = copies.next().await else {
+            break;
+        };
+        result?;
+        copied += 1;
+
+        // Print whole percentages only, so a large device doesn't flood the terminal
+        let percent = copied * 100 / total.max(1);
+        if percent != shown {
+            shown = percent;
+            eprint!("\rCopying: {:>3}% ({} of {} blocks)", percent, copied, total);
+            let _ = std::io::stderr().flush();
+        }
+    }
+    eprintln!();
+
+    // Free blocks read back as zeros without taking up space
+    let mut punched = 0u64;
+    for (start, len) in runs(&free) {
+        punched += dest
+            .punch_hole(start, len)
+            .await
+            .map_err(|e| anyhow!("Failed to punch free blocks {}..{}: {}", start, start + len, e))?;
+    }
+
+    // Everything else is on disk before the superblock that makes the copy mountable
+    dest.sync().await.map_err(|e| anyhow!("Failed to sync {}: {}", args.dest.display(), e))?;
+    copy_block(&**source, &dest, 0).await?;
+    dest.sync().await.map_err(|e| anyhow!("Failed to sync {}: {}", args.dest.display(), e))?;
+
+    println!(
+        "Copied {} blocks ({:.1} MiB) from {} to {}",
+        copied + 1,
+        ((copied + 1) * BLOCK_SIZE as u64) as f64 / (1024.0 * 1024.0),
+        args.source.display(),
+        args.dest.display()
+    );
+    if !free.is_empty() {
+        println!(
+            "Left {} free blocks as holes ({:.1} MiB released)",
+            free.len(),
+            punched as f64 / (1024.0 * 1024.0)
+        );
+    }
+    Ok(())
+}
+
+/// Free data blocks of the filesystem on `source`, or none if its bitmap
+/// can't be trusted
+async fn free_blocks(source: &Arc<FileBackedBlockDevice>, path: &Path) -> Result<Vec<u64>> {
+    let disk_fs = DiskFs::open(source.clone())
+        .await
+        .map_err(|e| anyhow!("Failed to open filesystem on {}: {:?}", path.display(), e))?;
+    if disk_fs.was_uncleanly_unmounted() {
+        // The block bitmap is only written back periodically
+        warn!(
+            "{} was not cleanly unmounted, so its block bitmap may be stale; copying every block",
+            path.display()
+        );
+        return Ok(Vec::new());
+    }
+    Ok(disk_fs.free_block_nums())
+}
+
+/// Open an existing block device to copy onto, or create an image file of
+/// `block_count` blocks
+async fn open_dest(path: &Path, block_count: u64, force: bool) -> Result<FileBackedBlockDevice> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_block_device() => {
+            if let Some(mount_point) = is_device_mounted(&path.to_path_buf())? {
+                return Err(anyhow!("{} is mounted at {}", path.display(), mount_point));
+            }
+            let dest = FileBackedBlockDevice::open(path, false)
+                .await
+                .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+            if dest.block_count() < block_count {
+                return Err(anyhow!(
+                    "{} has {} blocks, too few for the {} of the source",
+                    path.display(),
+                    dest.block_count(),
+                    block_count
+                ));
+            }
+            Ok(dest)
+        }
+        Ok(_) if !force => Err(anyhow!("{} already exists; pass --force to overwrite it", path.display())),
+        _ => FileBackedBlockDevice::create(path, block_count * BLOCK_SIZE as u64)
+            .await
+            .map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e)),
+    }
+}
+
+/// Copy one block from `source` to `dest`
+async fn copy_block(source: &dyn BlockDevice, dest: &dyn BlockDevice, block_num: u64) -> Result<()> {
+    let mut block = vec![0u8; BLOCK_SIZE];
+    source
+        .read_block(block_num, &mut block)
+        .await
+        .map_err(|e| anyhow!("Failed to read block {}: {}", block_num, e))?;
+    dest.write_block(block_num, &block)
+        .await
+        .map_err(|e| anyhow!("Failed to write block {}: {}", block_num, e))
+}
+
+/// Runs of consecutive block numbers in a sorted list, as (start, length)
+fn runs(blocks: &[u64]) -> Vec<(u64, u64)> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for &block_num in blocks {
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == block_num => *len += 1,
+            _ => runs.push((block_num, 1)),
+        }
+    }
+    runs
+}
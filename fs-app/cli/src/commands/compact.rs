@@ -0,0 +1,78 @@
+//! Compact command for giving unused inode table space back to the device
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use log::info;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aegisfs::blockdev::FileBackedBlockDevice;
+use aegisfs::{DiskFs, DiskFsTrait};
+
+use super::mount::is_device_mounted;
+
+/// Punch holes in inode table blocks that no longer hold any inodes,
+/// optionally renumbering inodes first to empty more of them
+#[derive(Parser)]
+#[command(about = "Reclaim inode table space left behind by deleted files")]
+pub struct CompactArgs {
+    /// Device or image file to compact
+    pub device: PathBuf,
+
+    /// First renumber live inodes into the lowest free inode numbers, so
+    /// the blocks at the end of the inode table empty out. Needs a journal
+    #[arg(long)]
+    pub renumber: bool,
+}
+
+pub async fn run(args: CompactArgs) -> Result<()> {
+    if let Some(mount_point) = is_device_mounted(&args.device)? {
+        return Err(anyhow!(
+            "Device {} is mounted at {}. Unmount it before compacting.",
+            args.device.display(),
+            mount_point
+        ));
+    }
+
+    let device = Arc::new(
+        FileBackedBlockDevice::open(&args.device, false)
+            .await
+            .map_err(|e| anyhow!("Failed to open device: {}", e))?,
+    );
+    let mut disk_fs = DiskFs::open(device)
+        .await
+        .map_err(|e| anyhow!("Failed to open filesystem: {:?}", e))?;
+
+    if args.renumber {
+        info!("Renumbering the inodes of {}", args.device.display());
+        let report = disk_fs
+            .compact_inodes()
+            .await
+            .map_err(|e| anyhow!("Renumbering failed: {:?}", e))?;
+        println!(
+            "Renumbered {} of {} inodes; highest inode in use {} -> {}",
+            report.moved.len(),
+            report.live,
+            report.highest_before,
+            report.highest_after
+        );
+    }
+
+    info!("Compacting the inode table of {}", args.device.display());
+    let reclaimed = disk_fs
+        .punch_metadata_holes()
+        .await
+        .map_err(|e| anyhow!("Compaction failed: {:?}", e))?;
+
+    if reclaimed == 0 {
+        println!("Nothing to reclaim (or the device can't deallocate blocks)");
+    } else {
+        println!(
+            "Reclaimed {} bytes ({:.1} MiB) from the inode table",
+            reclaimed,
+            reclaimed as f64 / (1024.0 * 1024.0)
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! Compress command for managing the compression dictionary
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use futures::TryStreamExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aegisfs::blockdev::FileBackedBlockDevice;
+use aegisfs::modules::compression::{MAX_DICTIONARY_SIZE, SMALL_FILE_THRESHOLD};
+use aegisfs::modules::CompressionDictionary;
+use aegisfs::{DiskFs, DiskFsTrait};
+
+use super::mount::is_device_mounted;
+
+/// Manage compression of an unmounted filesystem
+#[derive(Parser)]
+#[command(about = "Manage AegisFS compression")]
+pub struct CompressArgs {
+    #[command(subcommand)]
+    pub command: CompressCommands,
+}
+
+#[derive(Subcommand)]
+pub enum CompressCommands {
+    /// Train the Zstd dictionary from files on the filesystem
+    TrainDict {
+        /// Device or image file to train on
+        device: PathBuf,
+
+        /// Regular files to sample
+        #[arg(long, default_value_t = 1000)]
+        samples: usize,
+
+        /// Largest dictionary to train, in bytes
+        #[arg(long, default_value_t = MAX_DICTIONARY_SIZE)]
+        dict_size: usize,
+    },
+}
+
+pub async fn run(args: CompressArgs) -> Result<()> {
+    match args.command {
+        CompressCommands::TrainDict { device, samples, dict_size } => {
+            train_dict(device, samples, dict_size).await
+        }
+    }
+}
+
+/// Total size of the samples before and after compressing them with the
+/// dictionary as it stands
+async fn compressed_size(
+    disk_fs: &DiskFs,
+    dict: &CompressionDictionary,
+    inos: &[u64],
+) -> Result<(usize, usize)> {
+    let mut raw = 0;
+    let mut compressed = 0;
+    for &ino in inos {
+        let inode = disk_fs
+            .read_inode(ino)
+            .await
+            .map_err(|e| anyhow!("Failed to read inode {}: {:?}", ino, e))?;
+        let sample = disk_fs
+            .read_file_data(&inode, 0, SMALL_FILE_THRESHOLD as u32)
+            .await
+            .map_err(|e| anyhow!("Failed to read inode {}: {:?}", ino, e))?;
+        raw += sample.len();
+        compressed += dict
+            .compress(&sample)
+            .map_err(|e| anyhow!("Failed to compress inode {}: {}", ino, e))?
+            .len();
+    }
+    Ok((raw, compressed))
+}
+
+async fn train_dict(device: PathBuf, samples: usize, dict_size: usize) -> Result<()> {
+    if let Some(mount_point) = is_device_mounted(&device)? {
+        return Err(anyhow!(
+            "Device {} is mounted at {}. Unmount it before training a dictionary.",
+            device.display(),
+            mount_point
+        ));
+    }
+    let block_device = Arc::new(
+        FileBackedBlockDevice::open(&device, false)
+            .await
+            .map_err(|e| anyhow!("Failed to open device: {}", e))?,
+    );
+    let mut disk_fs = DiskFs::open(block_device)
+        .await
+        .map_err(|e| anyhow!("Failed to open filesystem: {:?}", e))?;
+
+    let mut sample_inos = Vec::new();
+    {
+        let mut inodes = Box::pin(disk_fs.iter_inodes());
+        while let Some((ino, inode)) = inodes
+            .try_next()
+            .await
+            .map_err(|e| anyhow!("Failed to read the inode table: {:?}", e))?
+        {
+            if inode.mode & 0o170000 == 0o100000 && inode.size > 0 {
+                sample_inos.push(ino);
+                if sample_inos.len() == samples {
+                    break;
+                }
+            }
+        }
+    }
+
+    let block = disk_fs
+        .compression_dict_block()
+        .await
+        .map_err(|e| anyhow!("Failed to reserve the dictionary block: {:?}", e))?;
+    let dict = CompressionDictionary::open(disk_fs.device().clone(), block)
+        .await
+        .map_err(|e| anyhow!("Failed to open the dictionary: {}", e))?;
+
+    let (raw, before) = compressed_size(&disk_fs, &dict, &sample_inos).await?;
+    let bytes = dict
+        .train_dictionary(&disk_fs, &sample_inos, dict_size)
+        .await
+        .map_err(|e| anyhow!("Failed to train dictionary: {}", e))?;
+    let (_, after) = compressed_size(&disk_fs, &dict, &sample_inos).await?;
+
+    println!(
+        "Trained a {}-byte dictionary (generation {}) from {} files into block {}",
+        bytes.len(),
+        dict.generation().unwrap_or(0),
+        sample_inos.len(),
+        block
+    );
+    let ratio = |compressed: usize| raw as f64 / compressed.max(1) as f64;
+    println!(
+        "Sampled {} bytes: {} compressed before ({:.2}x), {} with the new dictionary ({:.2}x)",
+        raw,
+        before,
+        ratio(before),
+        after,
+        ratio(after)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aegisfs::blockdev::BlockDevice;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_train_dict_stores_a_dictionary() {
+        let path = std::env::temp_dir().join(format!("aegisfs_compress_{}.img", std::process::id()));
+        let size = 16 * 1024 * 1024;
+        let device: Arc<dyn BlockDevice> =
+            Arc::new(FileBackedBlockDevice::create(&path, size).await.unwrap());
+        DiskFs::format(device.clone(), size, None).await.unwrap();
+        let mut disk_fs = DiskFs::open(device.clone()).await.unwrap();
+
+        // Small, similar regular files, marked allocated so the walk finds them
+        let mut template = disk_fs.read_inode(disk_fs.superblock().root_inode).await.unwrap();
+        template.mode = 0o100644;
+        template.links = 1;
+        let inos: Vec<u64> = (2..66).collect();
+        let mut bitmap = vec![0u8; 16];
+        for ino in [0, 1].into_iter().chain(inos.iter().copied()) {
+            bitmap[ino as usize / 8] |= 1 << (ino % 8);
+        }
+        for &ino in &inos {
+            let mut inode = template.clone();
+            inode.block = [0; 15];
+            let data = format!("{{\"id\":{},\"owner\":\"user{}\",\"ok\":true}}", ino, ino % 7).into_bytes();
+            disk_fs.write_file_data(&mut inode, 0, &data).await.unwrap();
+            inode.size = data.len() as u64;
+            disk_fs.write_inode(ino, &inode).await.unwrap();
+        }
+        disk_fs.writeback_bitmaps(&bitmap).await.unwrap();
+        drop(disk_fs);
+        drop(device);
+
+        train_dict(path.clone(), 1000, 1024).await.unwrap();
+
+        let device: Arc<dyn BlockDevice> = Arc::new(FileBackedBlockDevice::open(&path, false).await.unwrap());
+        let disk_fs = DiskFs::open(device.clone()).await.unwrap();
+        let block = disk_fs.superblock().compression_dict_block;
+        assert_ne!(block, 0);
+        let dict = CompressionDictionary::open(device, block).await.unwrap();
+        assert_eq!(dict.generation(), Some(1));
+        assert!(dict.dictionary_size() > 0 && dict.dictionary_size() <= 1024);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,136 @@
+//! Diagnose command for checking the in-memory state of a filesystem
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use aegisfs::blockdev::{BlockDevice, FileBackedBlockDevice};
+use aegisfs::{AegisFS, DiagnosticReport, MountOptions, DIAGNOSTICS_XATTR};
+use fuser::Filesystem;
+
+use super::mount::is_device_mounted;
+
+/// Check the inode cache of a filesystem against its bitmap and directories
+#[derive(Parser, Debug)]
+#[command(about = "Report inode cache and bitmap inconsistencies")]
+pub struct DiagnoseArgs {
+    /// Mount point of a mounted filesystem, or an unmounted device or image
+    pub target: PathBuf,
+}
+
+/// Fetch the report of a mounted filesystem from its root directory
+fn read_mounted_report(mount_point: &Path) -> Result<DiagnosticReport> {
+    let path = CString::new(mount_point.as_os_str().as_bytes())?;
+    let name = CString::new(DIAGNOSTICS_XATTR)?;
+
+    // Ask for the size first; the report can grow between the two calls
+    let mut buf = Vec::new();
+    loop {
+        let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let err = std::io::Error::last_os_error();
+            return Err(anyhow!(
+                "Failed to read diagnostics from {} ({}); is it an AegisFS mount point?",
+                mount_point.display(),
+                err
+            ));
+        }
+        buf.resize(size as usize, 0);
+        let read = unsafe {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+        };
+        if read >= 0 {
+            buf.truncate(read as usize);
+            break;
+        }
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(anyhow!("Failed to read diagnostics from {}: {}", mount_point.display(), err));
+        }
+    }
+
+    serde_json::from_slice(&buf).map_err(|e| anyhow!("Malformed diagnostics report: {}", e))
+}
+
+/// Mount an unmounted device in-process, loading the whole tree into the
+/// inode cache, and diagnose it
+async fn diagnose_device(device_path: &PathBuf) -> Result<DiagnosticReport> {
+    if let Some(mount_point) = is_device_mounted(device_path)? {
+        return Err(anyhow!(
+            "Device {} is mounted at {}; diagnose the mount point instead",
+            device_path.display(),
+            mount_point
+        ));
+    }
+
+    let device: Arc<dyn BlockDevice> = Arc::new(
+        FileBackedBlockDevice::open(device_path, false)
+            .await
+            .map_err(|e| anyhow!("Failed to open device: {}", e))?,
+    );
+    let options = MountOptions {
+        preload_depth: u8::MAX,
+        ..MountOptions::default()
+    };
+    let mut fs = AegisFS::from_block_device(device, options)
+        .await
+        .map_err(|e| anyhow!("Failed to open filesystem: {}", e))?;
+    let report = fs.diagnose();
+
+    // Unmount as FUSE would, so the filesystem is left clean
+    tokio::task::spawn_blocking(move || fs.destroy()).await?;
+    Ok(report)
+}
+
+/// Print the report and return whether it found problems
+fn print_report(report: &DiagnosticReport) -> bool {
+    println!(
+        "Cached inodes: {}, free inodes: {} of {}",
+        report.cached_inodes, report.free_inodes, report.total_inodes
+    );
+    if report.bitmap_mismatches.is_empty() {
+        println!("  ✓ All cached inodes are marked allocated");
+    } else {
+        println!(
+            "  ✗ {} cached inodes marked free: {:?}",
+            report.bitmap_mismatches.len(),
+            report.bitmap_mismatches
+        );
+    }
+    if report.orphaned_cached_inodes.is_empty() {
+        println!("  ✓ Every cached inode is named by a directory");
+    } else {
+        println!(
+            "  ✗ {} cached inodes no directory names: {:?}",
+            report.orphaned_cached_inodes.len(),
+            report.orphaned_cached_inodes
+        );
+    }
+    if report.collisions.is_empty() {
+        println!("  ✓ No inode has more names than links");
+    }
+    for collision in &report.collisions {
+        println!(
+            "  ✗ Inode {} is named {:?} in directory {}",
+            collision.ino, collision.names, collision.dir
+        );
+    }
+    !report.is_clean()
+}
+
+pub async fn run(args: DiagnoseArgs) -> Result<()> {
+    let report = if args.target.is_dir() {
+        read_mounted_report(&args.target)?
+    } else {
+        diagnose_device(&args.target).await?
+    };
+
+    if print_report(&report) {
+        std::process::exit(1);
+    }
+    println!("No inconsistencies found");
+    Ok(())
+}
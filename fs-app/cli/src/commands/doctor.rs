@@ -0,0 +1,440 @@
+//! Doctor command for diagnosing and fixing common AegisFS setup problems
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use log::info;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use aegisfs::blockdev::FileBackedBlockDevice;
+use aegisfs::format::Superblock;
+use aegisfs::modules::JournalConfig;
+use aegisfs::BLOCK_SIZE;
+
+use super::mount::is_device_mounted;
+use super::umount::fusermount_unmount;
+
+/// Diagnose common configuration problems, and fix those that can be fixed
+/// safely when asked
+///
+/// The run passes if no check failed; checks of a device or mount point that
+/// wasn't given are skipped and reported separately.
+#[derive(Parser, Debug)]
+#[command(about = "Diagnose and fix common AegisFS configuration problems")]
+pub struct DoctorArgs {
+    /// Device or image file to check
+    #[arg(long)]
+    pub device: Option<PathBuf>,
+
+    /// Mount point to check
+    #[arg(long)]
+    pub mountpoint: Option<PathBuf>,
+
+    /// Load the fuse module, create a missing mount point and detach stale
+    /// mounts, then check again
+    #[arg(long)]
+    pub fix: bool,
+}
+
+/// Remedy `--fix` can apply for a failed check
+#[derive(Debug, PartialEq)]
+enum Fix {
+    /// Load the fuse kernel module
+    LoadFuseModule,
+    /// Create the missing mount point
+    CreateMountpoint(PathBuf),
+    /// Unmount a mount whose FUSE server has died
+    UnmountStale(PathBuf),
+}
+
+impl Fix {
+    fn describe(&self) -> String {
+        match self {
+            Fix::LoadFuseModule => "load the fuse module".to_string(),
+            Fix::CreateMountpoint(path) => format!("create {}", path.display()),
+            Fix::UnmountStale(path) => format!("unmount the stale mount at {}", path.display()),
+        }
+    }
+
+    fn apply(&self) -> Result<()> {
+        match self {
+            Fix::LoadFuseModule => {
+                let status = Command::new("modprobe")
+                    .arg("fuse")
+                    .status()
+                    .context("Failed to run modprobe")?;
+                if !status.success() {
+                    return Err(anyhow!("modprobe fuse failed ({})", status));
+                }
+                Ok(())
+            }
+            Fix::CreateMountpoint(path) => fs::create_dir_all(path)
+                .with_context(|| format!("Failed to create {}", path.display())),
+            Fix::UnmountStale(path) => fusermount_unmount(path, false),
+        }
+    }
+}
+
+/// Outcome of a single check
+enum Outcome {
+    /// Check passed, with optional detail
+    Pass(String),
+    /// Check failed, with a remediation hint
+    Fail(String),
+    /// Check failed, with a remediation hint and a fix `--fix` applies
+    Fixable(String, Fix),
+    /// Check could not run because an input was not given
+    Skip(String),
+}
+
+/// Print the outcome of a check
+fn report(name: &str, outcome: &Outcome) {
+    match outcome {
+        Outcome::Pass(detail) if detail.is_empty() => println!("  ✓ {}", name),
+        Outcome::Pass(detail) => println!("  ✓ {} ({})", name, detail),
+        Outcome::Fail(hint) => {
+            println!("  ✗ {}", name);
+            println!("      hint: {}", hint);
+        }
+        Outcome::Fixable(hint, _) => {
+            println!("  ✗ {}", name);
+            println!("      hint: {} (or pass --fix)", hint);
+        }
+        Outcome::Skip(reason) => println!("  - {} (skipped: {})", name, reason),
+    }
+}
+
+/// Result of a run: it succeeds if no check failed, and returns how many
+/// were skipped
+fn verdict(outcomes: &[Outcome]) -> Result<usize> {
+    let failed = outcomes
+        .iter()
+        .filter(|o| matches!(o, Outcome::Fail(_) | Outcome::Fixable(..)))
+        .count();
+    if failed > 0 {
+        return Err(anyhow!("{} of {} checks failed", failed, outcomes.len()));
+    }
+    Ok(outcomes.iter().filter(|o| matches!(o, Outcome::Skip(_))).count())
+}
+
+/// Check that the FUSE kernel module is loaded
+fn check_fuse_module() -> Outcome {
+    let in_filesystems = fs::read_to_string("/proc/filesystems")
+        .map(|s| s.lines().any(|l| l.split_whitespace().last() == Some("fuse")))
+        .unwrap_or(false);
+
+    if in_filesystems || Path::new("/sys/module/fuse").exists() {
+        Outcome::Pass(String::new())
+    } else {
+        Outcome::Fixable("load the module with `sudo modprobe fuse`".to_string(), Fix::LoadFuseModule)
+    }
+}
+
+/// Check that /dev/fuse exists and can be opened read-write
+fn check_dev_fuse() -> Outcome {
+    #[cfg(unix)]
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let metadata = match fs::metadata("/dev/fuse") {
+        Ok(m) => m,
+        Err(_) => {
+            return Outcome::Fail(
+                "/dev/fuse is missing; load the fuse module or create it with `sudo mknod -m 666 /dev/fuse c 10 229`"
+                    .to_string(),
+            )
+        }
+    };
+
+    #[cfg(unix)]
+    if !metadata.file_type().is_char_device() {
+        return Outcome::Fail("/dev/fuse is not a character device; recreate it with `sudo mknod -m 666 /dev/fuse c 10 229`".to_string());
+    }
+
+    if OpenOptions::new().read(true).write(true).open("/dev/fuse").is_err() {
+        #[cfg(unix)]
+        let mode = format!("{:o}", metadata.permissions().mode() & 0o777);
+        #[cfg(not(unix))]
+        let mode = String::from("unknown");
+        return Outcome::Fail(format!(
+            "/dev/fuse is not accessible (mode {}); run `sudo chmod 666 /dev/fuse` or add your user to the `fuse` group",
+            mode
+        ));
+    }
+
+    Outcome::Pass(String::new())
+}
+
+/// Check that fusermount3 can be found in $PATH
+fn check_fusermount() -> Outcome {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    match std::env::split_paths(&path)
+        .map(|dir| dir.join("fusermount3"))
+        .find(|candidate| candidate.is_file())
+    {
+        Some(found) => Outcome::Pass(found.display().to_string()),
+        None => Outcome::Fail(
+            "install the fuse3 package (e.g. `sudo apt install fuse3`) or add fusermount3 to $PATH"
+                .to_string(),
+        ),
+    }
+}
+
+/// Check that the mount point exists, is a directory and is empty
+fn check_mountpoint(mountpoint: Option<&PathBuf>) -> Outcome {
+    let mountpoint = match mountpoint {
+        Some(m) => m,
+        None => return Outcome::Skip("no --mountpoint given".to_string()),
+    };
+
+    if !mountpoint.exists() {
+        return Outcome::Fixable(
+            format!("create it with `mkdir -p {}`", mountpoint.display()),
+            Fix::CreateMountpoint(mountpoint.clone()),
+        );
+    }
+    if !mountpoint.is_dir() {
+        return Outcome::Fail(format!("{} is not a directory", mountpoint.display()));
+    }
+
+    match fs::read_dir(mountpoint) {
+        Ok(mut entries) => {
+            if entries.next().is_some() {
+                Outcome::Fail(format!(
+                    "{} is not empty; choose an empty directory or move its contents",
+                    mountpoint.display()
+                ))
+            } else {
+                Outcome::Pass(String::new())
+            }
+        }
+        Err(e) => Outcome::Fail(format!(
+            "cannot read {} ({}); a stale FUSE mount may need `fusermount3 -u {}`",
+            mountpoint.display(),
+            e,
+            mountpoint.display()
+        )),
+    }
+}
+
+/// Check that the device exists and carries a valid AegisFS superblock
+async fn check_device(device: Option<&PathBuf>) -> Outcome {
+    let device = match device {
+        Some(d) => d,
+        None => return Outcome::Skip("no --device given".to_string()),
+    };
+
+    if !device.exists() {
+        return Outcome::Fail(format!("{} does not exist; check the device path", device.display()));
+    }
+
+    let block_device = match FileBackedBlockDevice::open(device, true).await {
+        Ok(d) => d,
+        Err(e) => {
+            return Outcome::Fail(format!(
+                "cannot open {} ({}); check permissions or run as root",
+                device.display(),
+                e
+            ))
+        }
+    };
+
+    match Superblock::read_from_disk(&block_device).await {
+        Ok(sb) => {
+            let name = String::from_utf8_lossy(&sb.volume_name)
+                .trim_end_matches('\0')
+                .to_string();
+            Outcome::Pass(format!("volume '{}', {} blocks", name, sb.block_count))
+        }
+        Err(e) => Outcome::Fail(format!(
+            "no valid AegisFS superblock ({}); format it with `aegisfs format {} <size_in_gb>`",
+            e,
+            device.display()
+        )),
+    }
+}
+
+/// Check that the device has no stale (disconnected) mount in /proc/mounts
+fn check_stale_mount(device: Option<&PathBuf>) -> Outcome {
+    let device = match device {
+        Some(d) => d,
+        None => return Outcome::Skip("no --device given".to_string()),
+    };
+
+    match is_device_mounted(device) {
+        Ok(None) => Outcome::Pass(String::new()),
+        Ok(Some(mount_point)) => {
+            // A dead FUSE server leaves the mount point returning ENOTCONN
+            if fs::metadata(&mount_point).is_err() {
+                Outcome::Fixable(
+                    format!(
+                        "stale mount at {}; clean it up with `fusermount3 -u {}`",
+                        mount_point, mount_point
+                    ),
+                    Fix::UnmountStale(PathBuf::from(mount_point)),
+                )
+            } else {
+                Outcome::Pass(format!("currently mounted at {}", mount_point))
+            }
+        }
+        Err(e) => Outcome::Fail(format!("could not read /proc/mounts ({})", e)),
+    }
+}
+
+/// Check that the filesystem holding the device has room for the journal
+fn check_journal_space(device: Option<&PathBuf>) -> Outcome {
+    let device = match device {
+        Some(d) => d,
+        None => return Outcome::Skip("no --device given".to_string()),
+    };
+
+    let journal_bytes = JournalConfig::default().journal_size * BLOCK_SIZE as u64;
+
+    // Block devices carry the journal inside the device itself
+    let metadata = match fs::metadata(device) {
+        Ok(m) => m,
+        Err(_) => return Outcome::Skip("device does not exist".to_string()),
+    };
+    if !metadata.is_file() {
+        return Outcome::Pass("journal is stored on the device".to_string());
+    }
+
+    // Image files may be sparse and need room on the host filesystem to grow
+    let parent = device
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    match available_space(parent) {
+        Some(available) if available >= journal_bytes => Outcome::Pass(format!(
+            "{} MiB available, {} MiB needed",
+            available / (1024 * 1024),
+            journal_bytes / (1024 * 1024)
+        )),
+        Some(available) => Outcome::Fail(format!(
+            "only {} MiB free on the filesystem holding {}, the journal needs {} MiB; free up space or move the image",
+            available / (1024 * 1024),
+            device.display(),
+            journal_bytes / (1024 * 1024)
+        )),
+        None => Outcome::Fail(format!("could not determine free space for {}", parent.display())),
+    }
+}
+
+/// Free space available to unprivileged users on the filesystem containing `path`
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Run every check in order
+async fn run_checks(args: &DoctorArgs) -> Vec<(&'static str, Outcome)> {
+    vec![
+        ("FUSE kernel module loaded", check_fuse_module()),
+        ("/dev/fuse accessible", check_dev_fuse()),
+        ("fusermount3 in $PATH", check_fusermount()),
+        ("Mount point exists and is empty", check_mountpoint(args.mountpoint.as_ref())),
+        ("Device is a valid AegisFS image", check_device(args.device.as_ref()).await),
+        ("No stale mount for device", check_stale_mount(args.device.as_ref())),
+        ("Sufficient space for journal", check_journal_space(args.device.as_ref())),
+    ]
+}
+
+pub async fn run(args: DoctorArgs) -> Result<()> {
+    info!("Running AegisFS doctor checks");
+    println!("AegisFS doctor");
+
+    let mut results = run_checks(&args).await;
+    for (name, outcome) in &results {
+        report(name, outcome);
+    }
+
+    let fixes: Vec<&Fix> = results
+        .iter()
+        .filter_map(|(_, outcome)| match outcome {
+            Outcome::Fixable(_, fix) => Some(fix),
+            _ => None,
+        })
+        .collect();
+    if args.fix && !fixes.is_empty() {
+        println!("Fixing");
+        for fix in fixes {
+            match fix.apply() {
+                Ok(()) => println!("  ✓ {}", fix.describe()),
+                Err(e) => println!("  ✗ {}: {}", fix.describe(), e),
+            }
+        }
+        println!("Checking again");
+        results = run_checks(&args).await;
+        for (name, outcome) in &results {
+            report(name, outcome);
+        }
+    }
+
+    let outcomes: Vec<Outcome> = results.into_iter().map(|(_, outcome)| outcome).collect();
+    match verdict(&outcomes)? {
+        0 => println!("All checks passed"),
+        skipped => println!(
+            "All checks that ran passed; {} were skipped, pass --device and --mountpoint to run them",
+            skipped
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pass() -> Outcome {
+        Outcome::Pass(String::new())
+    }
+
+    #[test]
+    fn test_only_failed_checks_fail_the_run() {
+        assert_eq!(verdict(&[pass(), Outcome::Pass("detail".to_string())]).unwrap(), 0);
+
+        let failed = verdict(&[pass(), Outcome::Fail("hint".to_string())]).unwrap_err();
+        assert_eq!(failed.to_string(), "1 of 2 checks failed");
+
+        // A bare run skips the device and mount point checks, and still passes
+        let skipped = verdict(&[pass(), Outcome::Skip("no --device given".to_string())]).unwrap();
+        assert_eq!(skipped, 1);
+
+        let both = verdict(&[
+            Outcome::Fixable("hint".to_string(), Fix::LoadFuseModule),
+            Outcome::Skip("no --mountpoint given".to_string()),
+            pass(),
+        ])
+        .unwrap_err();
+        assert_eq!(both.to_string(), "1 of 3 checks failed");
+    }
+
+    #[test]
+    fn test_fix_creates_missing_mountpoint() {
+        let mountpoint = std::env::temp_dir().join(format!("aegisfs_doctor_{}", std::process::id()));
+        fs::remove_dir_all(&mountpoint).ok();
+
+        let fix = match check_mountpoint(Some(&mountpoint)) {
+            Outcome::Fixable(_, fix) => fix,
+            _ => panic!("a missing mount point should be fixable"),
+        };
+        assert_eq!(fix, Fix::CreateMountpoint(mountpoint.clone()));
+        fix.apply().unwrap();
+        assert!(matches!(check_mountpoint(Some(&mountpoint)), Outcome::Pass(_)));
+
+        fs::remove_dir_all(&mountpoint).ok();
+    }
+}
@@ -0,0 +1,115 @@
+//! Du command for summarising space usage per top-level directory
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use futures::TryStreamExt;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aegisfs::blockdev::FileBackedBlockDevice;
+use aegisfs::format::Inode as DiskInode;
+use aegisfs::{DiskFs, DiskFsTrait};
+
+/// Summarise space usage of an unmounted filesystem
+#[derive(Parser, Debug)]
+#[command(about = "Report file sizes per top-level directory")]
+pub struct DuArgs {
+    /// Device or image file to inspect
+    pub device: PathBuf,
+}
+
+/// Sum the sizes of everything reachable from `ino`, counting each inode once
+async fn subtree_size(
+    disk_fs: &DiskFs,
+    inodes: &HashMap<u64, DiskInode>,
+    counted: &mut HashSet<u64>,
+    ino: u64,
+) -> Result<u64> {
+    let mut total = 0;
+    let mut stack = vec![ino];
+    while let Some(ino) = stack.pop() {
+        if !counted.insert(ino) {
+            continue;
+        }
+        // Entries pointing at free inodes are left for fsck to report
+        let Some(inode) = inodes.get(&ino) else {
+            continue;
+        };
+        total += inode.size;
+        if inode.mode & 0o40000 == 0 {
+            continue;
+        }
+        let entries = disk_fs
+            .read_directory_entries(inode)
+            .await
+            .map_err(|e| anyhow!("Failed to read directory {}: {:?}", ino, e))?;
+        for entry in entries {
+            if entry.inode != 0 && entry.name != "." && entry.name != ".." {
+                stack.push(entry.inode);
+            }
+        }
+    }
+    Ok(total)
+}
+
+pub async fn run(args: DuArgs) -> Result<()> {
+    let device = Arc::new(
+        FileBackedBlockDevice::open(&args.device, true)
+            .await
+            .map_err(|e| anyhow!("Failed to open device: {}", e))?,
+    );
+    let disk_fs = DiskFs::open(device)
+        .await
+        .map_err(|e| anyhow!("Failed to open filesystem: {:?}", e))?;
+
+    let inodes: HashMap<u64, DiskInode> = disk_fs
+        .iter_inodes()
+        .try_collect()
+        .await
+        .map_err(|e| anyhow!("Failed to read inode table: {:?}", e))?;
+
+    let root_ino = disk_fs.superblock().root_inode;
+    let root = inodes
+        .get(&root_ino)
+        .ok_or_else(|| anyhow!("Root inode {} is not allocated", root_ino))?;
+    let mut counted = HashSet::from([root_ino]);
+    let mut total = root.size;
+
+    let mut entries = disk_fs
+        .read_directory_entries(root)
+        .await
+        .map_err(|e| anyhow!("Failed to read root directory: {:?}", e))?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut root_files = 0;
+    for entry in entries {
+        if entry.inode == 0 || entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        let is_dir = inodes
+            .get(&entry.inode)
+            .is_some_and(|inode| inode.mode & 0o40000 != 0);
+        let size = subtree_size(&disk_fs, &inodes, &mut counted, entry.inode).await?;
+        total += size;
+        if is_dir {
+            println!("{:>14}  /{}", size, entry.name);
+        } else {
+            root_files += size;
+        }
+    }
+    println!("{:>14}  (files in /)", root_files);
+
+    // Allocated inodes the tree no longer reaches, e.g. open-but-unlinked files
+    let unreachable: u64 = inodes
+        .iter()
+        .filter(|(ino, _)| !counted.contains(ino))
+        .map(|(_, inode)| inode.size)
+        .sum();
+    if unreachable > 0 {
+        println!("{:>14}  (unreachable inodes)", unreachable);
+    }
+
+    println!("{:>14}  total across {} inodes", total + unreachable, inodes.len());
+    Ok(())
+}
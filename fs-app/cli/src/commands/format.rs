@@ -5,8 +5,13 @@ use clap::Parser;
 use log::info;
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use aegisfs::format;
+use aegisfs::blockdev::{FileBackedBlockDevice, BLOCK_SIZE};
+use aegisfs::format::{self, FormatOptions, FormatWipe};
+use aegisfs::DiskFs;
+use aegisfs::modules::audit::DEFAULT_AUDIT_LOG_BLOCKS;
+use aegisfs::modules::journaling::DEFAULT_JOURNAL_BLOCKS;
 
 /// Format a device with AegisFS
 #[derive(Parser, Debug)]
@@ -19,53 +24,69 @@ pub struct FormatArgs {
     #[arg(short, long, default_value_t = 3)]
     pub size: u64,
 
+    /// Byte offset to put the filesystem at, such as the start of a
+    /// partition in a whole-disk image; a multiple of 4096. Everything in
+    /// front of it is left alone
+    #[arg(long, default_value_t = 0, value_name = "BYTES")]
+    pub offset: u64,
+
     /// Force formatting without confirmation
     #[arg(short, long)]
     pub force: bool,
+
+    /// Reserve a region for the security audit log
+    #[arg(long)]
+    pub audit: bool,
+
+    /// Reserve a region for the journal and enable journaling
+    #[arg(long)]
+    pub enable_journal: bool,
+
+    /// Journal size in blocks
+    #[arg(long, default_value_t = DEFAULT_JOURNAL_BLOCKS, requires = "enable_journal")]
+    pub journal_size: u64,
+
+    /// Discard the whole device first so nothing of an earlier filesystem
+    /// can be read back; falls back to --zero if the device can't discard
+    #[arg(long, conflicts_with = "zero")]
+    pub discard: bool,
+
+    /// Zero the inode bitmap and inode tables first, so stale inodes from
+    /// an earlier filesystem are never taken for live ones
+    #[arg(long)]
+    pub zero: bool,
+
+    /// Keep the data of files of up to 120 bytes in their inode instead
+    /// of in a data block of their own
+    #[arg(long)]
+    pub inline_data: bool,
 }
 
 pub async fn run(args: FormatArgs) -> Result<()> {
     info!("AegisFS Format Tool v{}", env!("CARGO_PKG_VERSION"));
 
+    if args.enable_journal && args.journal_size == 0 {
+        return Err(anyhow!("Journal size must be at least one block"));
+    }
+
     // Check if device exists and is writable
     if !args.device.exists() {
         return Err(anyhow!("Device {:?} does not exist", args.device));
     }
 
-    // Get device size - different methods for files vs block devices
-    let device_size = if args.device.is_file() {
-        // For regular files, use filesystem metadata
-        let metadata = std::fs::metadata(&args.device)
-            .with_context(|| format!("Failed to get file metadata: {:?}", args.device))?;
-        
+    // Get device size - block devices report their size through the kernel
+    if args.device.is_file() {
         info!("Detected regular file: {}", args.device.display());
-        metadata.len()
     } else {
-        // For block devices, use blockdev command
         info!("Detected block device: {}", args.device.display());
-        let device_size_output = std::process::Command::new("blockdev")
-            .args(["--getsize64", args.device.to_str().unwrap()])
-            .output()
-            .with_context(|| {
-                format!(
-                    "Failed to get device size using blockdev: {:?}",
-                    args.device
-                )
-            })?;
-
-        if !device_size_output.status.success() {
-            return Err(anyhow!(
-                "Failed to get device size: {}",
-                String::from_utf8_lossy(&device_size_output.stderr)
-            ));
-        }
-
-        String::from_utf8(device_size_output.stdout)
-            .with_context(|| "Failed to parse blockdev output")?
-            .trim()
-            .parse::<u64>()
-            .with_context(|| "Failed to parse device size as number")?
-    };
+    }
+    if !args.offset.is_multiple_of(BLOCK_SIZE as u64) {
+        return Err(anyhow!("Offset {} is not a multiple of {} bytes", args.offset, BLOCK_SIZE));
+    }
+    let device_size = format::device_size(&args.device)
+        .with_context(|| format!("Failed to get device size: {:?}", args.device))?
+        .checked_sub(args.offset)
+        .ok_or_else(|| anyhow!("Offset {} is past the end of {}", args.offset, args.device.display()))?;
 
     // Convert device size to GiB for display (using floating point for precision)
     let gibibyte = 1024u64 * 1024 * 1024; // 1 GiB in bytes (2^30)
@@ -163,9 +184,31 @@ pub async fn run(args: FormatArgs) -> Result<()> {
     );
 
     // Format the device with our filesystem
-    format::format_device(&args.device, args.size, Some("AegisFS Volume"))
-        .await
-        .with_context(|| format!("Failed to format device: {}", args.device.display()))?;
+    let options = FormatOptions {
+        audit_log_blocks: if args.audit { DEFAULT_AUDIT_LOG_BLOCKS } else { 0 },
+        journal_blocks: if args.enable_journal { args.journal_size } else { 0 },
+        wipe: if args.discard {
+            FormatWipe::Discard
+        } else if args.zero {
+            FormatWipe::ZeroMetadata
+        } else {
+            FormatWipe::None
+        },
+        inline_data: args.inline_data,
+    };
+    if args.offset == 0 {
+        format::format_device_with_options(&args.device, args.size, Some("AegisFS Volume"), &options)
+            .await
+            .with_context(|| format!("Failed to format device: {}", args.device.display()))?;
+    } else {
+        info!("Placing the filesystem at byte offset {}", args.offset);
+        let device = FileBackedBlockDevice::open_at(&args.device, false, args.offset, Some(partition_size))
+            .await
+            .with_context(|| format!("Failed to open device: {}", args.device.display()))?;
+        DiskFs::format_with_options(Arc::new(device), partition_size, Some("AegisFS Volume"), &options)
+            .await
+            .map_err(|e| anyhow!("Failed to format device {}: {:?}", args.device.display(), e))?;
+    }
 
     info!(
         "Successfully formatted {} as AegisFS",
@@ -0,0 +1,160 @@
+//! Fsck command for checking and repairing filesystem metadata
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use log::info;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aegisfs::blockdev::FileBackedBlockDevice;
+use aegisfs::format::Superblock;
+use aegisfs::{DiskFs, DiskFsTrait, FsckReport, InodeBitmapReport};
+
+use super::mount::is_device_mounted;
+
+/// Check filesystem metadata consistency
+#[derive(Parser, Debug)]
+#[command(about = "Check and repair AegisFS metadata consistency")]
+pub struct FsckArgs {
+    /// Device or image file to check
+    pub device: PathBuf,
+
+    /// Rebuild the inode bitmap from the directory tree
+    #[arg(long = "rebuild-bitmap")]
+    pub rebuild_bitmap: bool,
+
+    /// Fix dangling entries, orphaned inodes, unmarked blocks and free counts
+    #[arg(long, conflicts_with = "rebuild_bitmap")]
+    pub repair: bool,
+
+    /// Once the check passes, reset the count of unclean unmounts so a
+    /// filesystem refused for crashing too often can be mounted again
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// Print the inode bitmap findings
+fn print_report(report: &InodeBitmapReport) {
+    println!("Inodes reachable from root: {}", report.reachable);
+    if report.missing.is_empty() {
+        println!("  ✓ All reachable inodes are marked allocated");
+    } else {
+        println!(
+            "  ✗ {} reachable inodes marked free: {:?}",
+            report.missing.len(),
+            report.missing
+        );
+    }
+    if report.leaked.is_empty() {
+        println!("  ✓ No unreachable inodes marked allocated");
+    } else {
+        println!(
+            "  ✗ {} unreachable inodes marked allocated: {:?}",
+            report.leaked.len(),
+            report.leaked
+        );
+    }
+}
+
+/// Print what the full check found and what it fixed
+fn print_fsck_report(report: &FsckReport) {
+    print_report(&report.inode_bitmap);
+    for repair in &report.repairs_made {
+        println!("  ✓ Repaired {}", repair);
+    }
+    for problem in &report.unrepaired {
+        println!("  ✗ {}", problem);
+    }
+    if report.repairs_made.is_empty() && report.unrepaired.is_empty() {
+        println!("  ✓ No dangling entries, orphaned inodes or unmarked blocks");
+    }
+}
+
+/// Clear a dirty flag left by an unclean unmount once the metadata checks
+/// out, and with `force` the count of past unclean unmounts
+async fn mark_clean(disk_fs: &mut DiskFs, force: bool) -> Result<()> {
+    if disk_fs.superblock().state == Superblock::STATE_DIRTY {
+        disk_fs
+            .mark_clean()
+            .await
+            .map_err(|e| anyhow!("Failed to mark filesystem clean: {:?}", e))?;
+        println!("Cleared unclean-unmount flag");
+    }
+    let unclean_mounts = disk_fs.superblock().unclean_mount_count;
+    if force && unclean_mounts > 0 {
+        disk_fs
+            .reset_unclean_mount_count()
+            .await
+            .map_err(|e| anyhow!("Failed to reset unclean mount count: {:?}", e))?;
+        println!("Reset unclean mount count (was {})", unclean_mounts);
+    } else if unclean_mounts >= Superblock::MAX_UNCLEAN_MOUNTS {
+        println!(
+            "Filesystem was not cleanly unmounted {} times and won't mount; run with --force to allow it",
+            unclean_mounts
+        );
+    }
+    Ok(())
+}
+
+pub async fn run(args: FsckArgs) -> Result<()> {
+    if let Some(mount_point) = is_device_mounted(&args.device)? {
+        return Err(anyhow!(
+            "Device {} is mounted at {}. Unmount it before running fsck.",
+            args.device.display(),
+            mount_point
+        ));
+    }
+
+    let device = Arc::new(
+        FileBackedBlockDevice::open(&args.device, false)
+            .await
+            .map_err(|e| anyhow!("Failed to open device: {}", e))?,
+    );
+    let mut disk_fs = DiskFs::open(device)
+        .await
+        .map_err(|e| anyhow!("Failed to open filesystem: {:?}", e))?;
+
+    if args.rebuild_bitmap {
+        info!("Rebuilding inode bitmap for {}", args.device.display());
+        let report = disk_fs
+            .rebuild_inode_bitmap()
+            .await
+            .map_err(|e| anyhow!("Failed to rebuild inode bitmap: {:?}", e))?;
+        print_report(&report);
+        println!(
+            "Inode bitmap rebuilt: {} restored, {} released",
+            report.missing.len(),
+            report.leaked.len()
+        );
+        mark_clean(&mut disk_fs, args.force).await?;
+        return Ok(());
+    }
+
+    if args.repair {
+        info!("Repairing {}", args.device.display());
+    }
+    let report = disk_fs
+        .fsck(args.repair)
+        .await
+        .map_err(|e| anyhow!("Failed to check filesystem: {:?}", e))?;
+    print_fsck_report(&report);
+    if args.repair {
+        println!("{} repairs made", report.repairs_made.len());
+    }
+
+    if !report.inode_bitmap.is_consistent() {
+        return Err(anyhow!(
+            "Inode bitmap is inconsistent; run with --rebuild-bitmap to repair"
+        ));
+    }
+    if !report.is_clean() {
+        return Err(anyhow!(
+            "Filesystem has {} problems; run with --repair to fix them",
+            report.unrepaired.len()
+        ));
+    }
+
+    mark_clean(&mut disk_fs, args.force).await?;
+    println!("Filesystem is clean");
+    Ok(())
+}
\ No newline at end of file
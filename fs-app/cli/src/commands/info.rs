@@ -0,0 +1,86 @@
+//! Info command for printing superblock details and mount history
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::path::PathBuf;
+
+use aegisfs::blockdev::FileBackedBlockDevice;
+use aegisfs::format::Superblock;
+
+/// Print a summary of a filesystem's superblock
+#[derive(Parser, Debug)]
+#[command(about = "Show AegisFS superblock details and mount history")]
+pub struct InfoArgs {
+    /// Device or image file to inspect
+    pub device: PathBuf,
+
+    /// Byte offset of the filesystem within the device or image; a
+    /// multiple of 4096
+    #[arg(long, default_value_t = 0, value_name = "BYTES")]
+    pub offset: u64,
+}
+
+/// Format seconds since the Unix epoch for display
+fn format_time(secs: u64) -> String {
+    chrono::DateTime::<chrono::Utc>::from_timestamp(secs as i64, 0)
+        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "Unknown".to_string())
+}
+
+pub async fn run(args: InfoArgs) -> Result<()> {
+    let device = FileBackedBlockDevice::open_at(&args.device, true, args.offset, None)
+        .await
+        .map_err(|e| anyhow!("Failed to open device: {}", e))?;
+    let sb = Superblock::read_from_disk(&device)
+        .await
+        .map_err(|e| anyhow!("Failed to read superblock: {}", e))?;
+
+    let name = String::from_utf8_lossy(&sb.volume_name)
+        .trim_end_matches('\0')
+        .to_string();
+    let uuid: String = sb.uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    let dirty = sb.state == Superblock::STATE_DIRTY;
+    println!("Volume name:             {}", name);
+    println!("UUID:                    {}", uuid);
+    println!("Size:                    {} bytes", sb.size);
+    println!("Blocks:                  {} free of {}", sb.free_blocks, sb.block_count);
+    println!("Inodes:                  {} free of {}", sb.free_inodes, sb.inode_count);
+    println!(
+        "State:                   {}",
+        if dirty { "mounted or not cleanly unmounted" } else { "clean" }
+    );
+    println!("Mount count:             {}", sb.mount_count);
+    println!(
+        "Unclean mounts:          {} (mounting is refused at {})",
+        sb.unclean_mount_count,
+        Superblock::MAX_UNCLEAN_MOUNTS
+    );
+
+    let events = sb.mount_history.events();
+    println!();
+    if events.is_empty() {
+        println!("Mount history:           none");
+        return Ok(());
+    }
+    println!("Mount history (oldest first):");
+    println!("{:<22} {:<22} RESULT", "MOUNTED", "UNMOUNTED");
+    for (i, event) in events.iter().enumerate() {
+        let current = i == events.len() - 1 && dirty;
+        let (unmounted, result) = if event.clean {
+            (format_time(event.unmount_time), "clean")
+        } else if current {
+            ("-".to_string(), "mounted or crashed")
+        } else {
+            ("-".to_string(), "crashed")
+        };
+        println!("{:<22} {:<22} {}", format_time(event.mount_time), unmounted, result);
+    }
+    if sb.unclean_mount_count >= Superblock::MAX_UNCLEAN_MOUNTS {
+        println!();
+        println!(
+            "This filesystem crashes often; check it with `aegisfs fsck --force {}` before mounting",
+            args.device.display()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,195 @@
+//! Log-replay command for inspecting the journal offline
+//!
+//! Prints every entry in the journal, one line each, so corruption can be
+//! traced back to the transactions that wrote it. The raw journal region
+//! can be written to a file for later analysis, and such a file can be
+//! inspected in place of the device. With `--apply` the transactions that
+//! were committed but not yet checkpointed are written to their home
+//! locations, as mounting would; incomplete ones are never applied.
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use log::info;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use aegisfs::blockdev::{BlockDevice, FileBackedBlockDevice, BLOCK_SIZE};
+use aegisfs::format::Superblock;
+use aegisfs::layout::Layout;
+use aegisfs::modules::{JournalConfig, JournalEntry, JournalEntryType, JournalManager};
+
+use super::mount::is_device_mounted;
+
+/// Print, dump or apply the journal of a device
+#[derive(Parser)]
+#[command(about = "Print, dump or apply the journal of a device")]
+pub struct LogReplayArgs {
+    /// Device to read the journal of, or a journal written out with
+    /// --dump-journal
+    pub device: PathBuf,
+
+    /// Write the raw journal blocks to this file
+    #[arg(long, value_name = "FILE")]
+    pub dump_journal: Option<PathBuf>,
+
+    /// Apply the committed transactions that haven't been checkpointed.
+    /// The device must not be mounted
+    #[arg(long)]
+    pub apply: bool,
+
+    /// Only print entries of this type, e.g. MetadataUpdate or BlockImage
+    #[arg(long, value_name = "TYPE")]
+    pub filter_type: Option<JournalEntryType>,
+
+    /// Only print entries of this transaction
+    #[arg(long, value_name = "ID")]
+    pub filter_tx: Option<u64>,
+}
+
+pub async fn run(args: LogReplayArgs) -> Result<()> {
+    if args.apply {
+        if let Some(mount_point) = is_device_mounted(&args.device)? {
+            return Err(anyhow!(
+                "{} is mounted at {}; unmount it before applying its journal",
+                args.device.display(),
+                mount_point
+            ));
+        }
+    }
+
+    let device = Arc::new(
+        FileBackedBlockDevice::open(&args.device, !args.apply)
+            .await
+            .map_err(|e| anyhow!("Failed to open {}: {}", args.device.display(), e))?,
+    );
+    let config = journal_config(&device, &args).await?;
+
+    let journal = JournalManager::new(device.clone(), config.clone());
+    let entries = journal
+        .read_entries()
+        .await
+        .map_err(|e| anyhow!("Failed to read journal: {}", e))?;
+    print_entries(&entries, &args);
+
+    if let Some(path) = &args.dump_journal {
+        dump_journal(&*device, &config, path).await?;
+    }
+
+    if args.apply {
+        let mut journal = JournalManager::new(device, config);
+        journal
+            .init()
+            .await
+            .map_err(|e| anyhow!("Failed to open journal: {}", e))?;
+        let stats = journal
+            .checkpoint()
+            .await
+            .map_err(|e| anyhow!("Replay failed: {}", e))?;
+        journal
+            .shutdown()
+            .await
+            .map_err(|e| anyhow!("Failed to close journal: {}", e))?;
+        println!(
+            "Applied {} transactions ({} blocks written)",
+            stats.transactions, stats.blocks_applied
+        );
+    }
+
+    Ok(())
+}
+
+/// Where the journal is: the region the superblock sets aside for it, or
+/// the whole file if it is a journal dump without one
+async fn journal_config(device: &Arc<FileBackedBlockDevice>, args: &LogReplayArgs) -> Result<JournalConfig> {
+    match Superblock::read_from_disk(&**device).await {
+        Ok(superblock) => {
+            let layout = Layout::for_superblock(&superblock);
+            if layout.journal_blocks == 0 {
+                return Err(anyhow!(
+                    "{} has no journal; format it with --enable-journal to create one",
+                    args.device.display()
+                ));
+            }
+            Ok(JournalConfig {
+                journal_start: layout.journal,
+                journal_size: layout.journal_blocks,
+                ..JournalConfig::default()
+            })
+        }
+        Err(_) if !args.apply => {
+            info!("No superblock on {}; reading it as a journal dump", args.device.display());
+            Ok(JournalConfig {
+                journal_start: 0,
+                journal_size: device.block_count(),
+                ..JournalConfig::default()
+            })
+        }
+        Err(e) => Err(anyhow!("Failed to read superblock: {}", e)),
+    }
+}
+
+/// Print the entries that pass the filters, then a summary of the journal
+fn print_entries(entries: &[(u64, JournalEntry)], args: &LogReplayArgs) {
+    let committed: BTreeSet<u64> = entries
+        .iter()
+        .filter(|(_, entry)| entry.header.entry_type == JournalEntryType::TransactionEnd)
+        .map(|(_, entry)| entry.header.transaction_id)
+        .collect();
+    let transactions: BTreeSet<u64> = entries.iter().map(|(_, entry)| entry.header.transaction_id).collect();
+
+    let mut shown = 0;
+    for (_, entry) in entries {
+        let header = &entry.header;
+        if args.filter_type.is_some_and(|entry_type| entry_type != header.entry_type)
+            || args.filter_tx.is_some_and(|tx| tx != header.transaction_id)
+        {
+            continue;
+        }
+        let incomplete = if committed.contains(&header.transaction_id) { "" } else { " (incomplete)" };
+        println!("{}{}", describe(entry), incomplete);
+        shown += 1;
+    }
+
+    let blocks: u64 = entries.iter().map(|(_, entry)| entry.block_count()).sum();
+    println!(
+        "{} of {} entries shown; {} journal blocks in use by {} committed and {} incomplete transactions",
+        shown,
+        entries.len(),
+        blocks,
+        committed.len(),
+        transactions.len() - committed.len()
+    );
+}
+
+/// One line describing a journal entry
+fn describe(entry: &JournalEntry) -> String {
+    let header = &entry.header;
+    let target = match entry.block_image() {
+        Some((home, _)) => format!(" block={}", home),
+        None => String::new(),
+    };
+    format!(
+        "[TX {}] {}{} len={} checksum={:#010X}",
+        header.transaction_id, header.entry_type, target, header.data_length, header.checksum
+    )
+}
+
+/// Write every block of the journal region to `path`
+async fn dump_journal(device: &dyn BlockDevice, config: &JournalConfig, path: &Path) -> Result<()> {
+    let file = std::fs::File::create(path).map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+    let mut out = std::io::BufWriter::new(file);
+    let mut block = vec![0u8; BLOCK_SIZE];
+    for i in 0..config.journal_size {
+        device
+            .read_block(config.journal_start + i, &mut block)
+            .await
+            .map_err(|e| anyhow!("Failed to read journal block {}: {}", i, e))?;
+        out.write_all(&block)
+            .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))?;
+    }
+    out.flush().map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))?;
+    println!("Wrote {} journal blocks to {}", config.journal_size, path.display());
+    Ok(())
+}
@@ -1,6 +1,25 @@
 //! Command implementations for the AegisFS CLI
 
+pub mod analyze_io;
+pub mod audit;
+pub mod check_device;
+pub mod checkpoint;
+pub mod clone_device;
+pub mod compact;
+pub mod compress;
+pub mod diagnose;
+pub mod doctor;
+pub mod du;
 pub mod format;
+pub mod fsck;
+pub mod info;
+pub mod log_replay;
 pub mod mount;
+pub mod quota;
+pub mod report;
 pub mod scrub;
-pub mod snapshot; 
\ No newline at end of file
+pub mod serve;
+pub mod snapshot;
+pub mod tune;
+pub mod umount;
+pub mod wipe_free;
@@ -6,17 +6,22 @@ use fuser::MountOption;
 use log::{error, info, warn};
 
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Read, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
 
+use aegisfs::blockdev::{BlockDevice, FileBackedBlockDevice, IoTracer, TracingBlockDevice};
 #[cfg(feature = "fuse")]
 use aegisfs::AegisFS;
+#[cfg(feature = "telemetry")]
+use aegisfs::modules::telemetry::{self, TelemetryBlockDevice, TelemetryConfig, TelemetryGuard};
 
 #[cfg(not(feature = "fuse"))]
 compile_error!("FUSE feature is required for the mount command. Use --features fuse");
 
 /// Check if a device is already mounted by reading /proc/mounts
-fn is_device_mounted(device_path: &PathBuf) -> Result<Option<String>> {
+pub(crate) fn is_device_mounted(device_path: &PathBuf) -> Result<Option<String>> {
     let mounts_file = File::open("/proc/mounts")
         .context("Failed to open /proc/mounts. Are you running on Linux?")?;
     
@@ -89,13 +94,13 @@ fn is_device_locked_by_another_process(device_path: &PathBuf) -> Result<bool> {
 }
 
 /// Check if a mountpoint is already in use
-fn is_mountpoint_in_use(mountpoint: &PathBuf) -> Result<bool> {
+pub(crate) fn is_mountpoint_in_use(mountpoint: &Path) -> Result<bool> {
     let mounts_file = File::open("/proc/mounts")
         .context("Failed to open /proc/mounts")?;
     
     let reader = BufReader::new(mounts_file);
     let canonical_mountpoint = mountpoint.canonicalize()
-        .unwrap_or_else(|_| mountpoint.clone());
+        .unwrap_or_else(|_| mountpoint.to_path_buf());
     
     for line in reader.lines() {
         let line = line.context("Failed to read line from /proc/mounts")?;
@@ -124,6 +129,139 @@ pub struct MountArgs {
 
     /// Mount point
     pub mountpoint: PathBuf,
+
+    /// Byte offset of the filesystem within the source, such as the start
+    /// of a partition in a whole-disk image; a multiple of 4096
+    #[arg(long, default_value_t = 0, value_name = "BYTES")]
+    pub offset: u64,
+
+    /// Mount even if the filesystem was not cleanly unmounted
+    #[arg(long)]
+    pub force: bool,
+
+    /// Directory levels below the root to pre-load into the cache at mount
+    #[arg(long, default_value_t = aegisfs::DEFAULT_PRELOAD_DEPTH)]
+    pub preload_depth: u8,
+
+    /// Reject writes that would grow a file past this many bytes
+    #[arg(long)]
+    pub max_file_size: Option<u64>,
+
+    /// Read every inode table block into the cache before serving requests
+    #[arg(long)]
+    pub warm_cache: bool,
+
+    /// Do not preload the blocks recorded in `<source>.cache_hints` at the
+    /// last unmount, nor record them at this one
+    #[arg(long)]
+    pub no_cache_hints: bool,
+
+    /// Tell the device which data blocks were freed (TRIM), so an SSD can
+    /// reclaim them
+    #[arg(long)]
+    pub discard: bool,
+
+    /// Freed blocks to collect before discarding them together
+    #[arg(long, default_value = "256", requires = "discard")]
+    pub discard_batch_size: usize,
+
+    /// Do not update file access times on read
+    #[arg(long)]
+    pub noatime: bool,
+
+    /// Keep access time updates in memory, writing them with other inode
+    /// changes or at least once a day
+    #[arg(long)]
+    pub lazytime: bool,
+
+    /// Block cache size in 4KB blocks (default: scaled to the device size)
+    #[arg(long)]
+    pub cache_blocks: Option<usize>,
+
+    /// Record every block read and write to a gzip-compressed trace file,
+    /// for `aegisfs analyze-io`
+    #[arg(long, value_name = "OUTPUT.json.gz")]
+    pub record_io: Option<PathBuf>,
+
+    /// Read every inode write back and rewrite it until it persists.
+    /// Only for NVMe drives known to lose flushed writes; it makes inode
+    /// writes hundreds of times slower
+    #[arg(long)]
+    pub verify_inode_writes: bool,
+
+    /// Expose only the directory at this path inside the filesystem at the
+    /// mount point, like `mount --bind`
+    #[arg(long, value_name = "SOURCE_PATH")]
+    pub bind: Option<String>,
+
+    /// Owner of the root directory, set at the first mount after format
+    #[arg(long, value_name = "UID")]
+    pub root_uid: Option<u32>,
+
+    /// Group of the root directory, set at the first mount after format
+    #[arg(long, value_name = "GID")]
+    pub root_gid: Option<u32>,
+
+    /// Octal permissions of the root directory (e.g. 0700), set at the
+    /// first mount after format
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    pub root_mode: Option<u32>,
+
+    /// Export traces of every operation to this OTLP collector (e.g.
+    /// http://collector:4317). Traces are also exported when
+    /// OTEL_EXPORTER_OTLP_ENDPOINT is set
+    #[cfg(feature = "telemetry")]
+    #[arg(long, value_name = "URL")]
+    pub telemetry_endpoint: Option<String>,
+
+    /// Write the PID of the mount process to this file, for `aegisfs umount`
+    #[arg(long, value_name = "PATH")]
+    pub pid_file: Option<PathBuf>,
+}
+
+/// Removes the PID file when the mount process is done with it
+struct PidFile(PathBuf);
+
+impl PidFile {
+    fn create(path: &Path) -> Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write PID file: {}", path.display()))?;
+        Ok(Self(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Failed to remove PID file {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Parse permission bits written in octal, with or without a leading 0 or 0o
+fn parse_mode(s: &str) -> std::result::Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("'{}' is not an octal mode between 0 and 7777", s)),
+    }
+}
+
+/// Start exporting traces if an endpoint was given on the command line or
+/// in the environment
+#[cfg(feature = "telemetry")]
+fn start_telemetry(args: &MountArgs) -> Result<Option<TelemetryGuard>> {
+    let from_env = std::env::var_os(telemetry::ENDPOINT_ENV).is_some();
+    if args.telemetry_endpoint.is_none() && !from_env {
+        return Ok(None);
+    }
+    let config = TelemetryConfig {
+        endpoint: args.telemetry_endpoint.clone(),
+        ..TelemetryConfig::default()
+    };
+    let guard = telemetry::init(&config).context("Failed to start trace export")?;
+    info!("Exporting traces to {}", guard.endpoint());
+    Ok(Some(guard))
 }
 
 pub async fn run(args: MountArgs) -> Result<()> {
@@ -134,22 +272,41 @@ pub async fn run(args: MountArgs) -> Result<()> {
         .read(true)
         .open(&args.source)
         .with_context(|| format!("Failed to open source device: {}", args.source.display()))?;
+    source_file
+        .seek(SeekFrom::Start(args.offset))
+        .with_context(|| format!("Failed to seek to offset {} of {}", args.offset, args.source.display()))?;
 
     // Check if the device is formatted with AegisFS
-    let mut magic = [0u8; 8];
-    if let Err(e) = source_file.read_exact(&mut magic) {
-        return Err(anyhow!(
-            "Failed to read from source device: {}. Is it a valid block device or file?",
-            e
-        ));
-    }
-
-    if &magic != b"AEGISFS\x00" {
+    let not_formatted = || {
         let source = args.source.display();
-        return Err(anyhow!(
+        anyhow!(
             "Source device is not formatted with AegisFS.\n\nPlease format it first using:\n    aegisfs format {} <size_in_gb>\n\nExample for a 3GB filesystem:\n    aegisfs format {} 3",
             source, source
-        ));
+        )
+    };
+
+    match aegisfs::format::Superblock::read_from(&mut source_file) {
+        Ok(_) => {}
+        Err(aegisfs::format::FormatError::CorruptSuperblock { stored, computed }) => {
+            warn!(
+                "Primary superblock checksum mismatch (stored {:#010x}, computed {:#010x}), checking backup superblock",
+                stored, computed
+            );
+            let device = aegisfs::blockdev::FileBackedBlockDevice::open_at(&args.source, true, args.offset, None)
+                .await
+                .with_context(|| format!("Failed to open source device: {}", args.source.display()))?;
+            aegisfs::format::Superblock::read_from_disk(&device)
+                .await
+                .map_err(|_| not_formatted())?;
+            warn!("Backup superblock is intact; mounting from backup");
+        }
+        Err(aegisfs::format::FormatError::Io(e)) => {
+            return Err(anyhow!(
+                "Failed to read from source device: {}. Is it a valid block device or file?",
+                e
+            ));
+        }
+        Err(_) => return Err(not_formatted()),
     }
 
     // Check if mountpoint exists and is a directory
@@ -188,20 +345,72 @@ pub async fn run(args: MountArgs) -> Result<()> {
     );
 
     // Create a new filesystem instance
-    let fs = AegisFS::from_device(&args.source).await.with_context(|| {
-        format!(
-            "Failed to open AegisFS on device: {}",
-            args.source.display()
-        )
-    })?;
+    let options = aegisfs::MountOptions {
+        force: args.force,
+        preload_depth: args.preload_depth,
+        max_file_size: args.max_file_size,
+        warm_cache: args.warm_cache,
+        noatime: args.noatime,
+        lazytime: args.lazytime,
+        cache: match args.cache_blocks {
+            Some(blocks) => aegisfs::CacheConfig::Blocks(blocks),
+            None => aegisfs::CacheConfig::Auto,
+        },
+        verify_inode_writes: args.verify_inode_writes,
+        bind: args.bind.clone(),
+        root_uid: args.root_uid,
+        root_gid: args.root_gid,
+        root_mode: args.root_mode,
+        cache_hints: (!args.no_cache_hints)
+            .then(|| aegisfs::cache::PersistentReadCache::for_device(&args.source)),
+        discard_batch_size: if args.discard { args.discard_batch_size.max(1) } else { 0 },
+    };
+    let device: Arc<dyn BlockDevice> = Arc::new(
+        FileBackedBlockDevice::open_at(&args.source, false, args.offset, None)
+            .await
+            .with_context(|| format!("Failed to open source device: {}", args.source.display()))?,
+    );
+    let tracer = match &args.record_io {
+        Some(path) => {
+            let tracer = IoTracer::create(path)
+                .with_context(|| format!("Failed to create I/O trace: {}", path.display()))?;
+            info!("Recording block I/O to {}", path.display());
+            Some(Arc::new(tracer))
+        }
+        None => None,
+    };
+    let device: Arc<dyn BlockDevice> = match &tracer {
+        Some(tracer) => Arc::new(TracingBlockDevice::new(device, tracer.clone())),
+        None => device,
+    };
+    #[cfg(feature = "telemetry")]
+    let (device, _telemetry) = match start_telemetry(&args)? {
+        Some(guard) => {
+            let device: Arc<dyn BlockDevice> = Arc::new(TelemetryBlockDevice::new(device));
+            (device, Some(guard))
+        }
+        None => (device, None),
+    };
+    let fs = AegisFS::from_block_device(device, options)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to open AegisFS on device: {}\n\nIf it was not cleanly unmounted, check it with:\n    aegisfs fsck {}\nor mount anyway with --force",
+                args.source.display(),
+                args.source.display()
+            )
+        })?;
 
     // Prepare mount options
-    let options = vec![
+    let mut options = vec![
         MountOption::FSName("aegisfs".to_string()),
         MountOption::AutoUnmount,
         MountOption::AllowOther,
         MountOption::NoExec,
     ];
+    if args.noatime {
+        options.push(MountOption::NoAtime);
+    }
 
     info!("Mounting AegisFS at {:?}", mountpoint);
 
@@ -213,27 +422,43 @@ pub async fn run(args: MountArgs) -> Result<()> {
 
     #[cfg(feature = "fuse")]
     {
-        // Set up signal handler for clean unmount
+        // Ctrl+C and SIGTERM unmount the session below rather than exiting,
+        // so the filesystem's destroy handler gets to write everything out
+        let (stop_tx, stop_rx) = mpsc::channel();
         ctrlc::set_handler(move || {
-            info!("Unmounting filesystem...");
-            // The AutoUnmount option should handle the actual unmounting
-            std::process::exit(0);
+            let _ = stop_tx.send(());
         })
         .context("Failed to set Ctrl+C handler")?;
 
-        info!("Filesystem mounted at {:?}", mountpoint);
-        info!("Press Ctrl+C to unmount");
+        let _pid_file = args.pid_file.as_deref().map(PidFile::create).transpose()?;
 
-        // This will block until the filesystem is unmounted
-        match fuser::mount2(fs, &mountpoint, &options) {
-            Ok(_) => {
-                info!("Filesystem unmounted successfully");
-                Ok(())
-            }
+        let session = match fuser::spawn_mount2(fs, &mountpoint, &options) {
+            Ok(session) => session,
             Err(e) => {
                 error!("Mount error: {}", e);
-                Err(anyhow!("Failed to mount: {}", e))
+                return Err(anyhow!("Failed to mount: {}", e));
             }
+        };
+        info!("Filesystem mounted at {:?}", mountpoint);
+        info!("Press Ctrl+C to unmount");
+
+        // Wait for a signal, or for the filesystem to be unmounted from outside
+        loop {
+            match stop_rx.recv_timeout(Duration::from_millis(200)) {
+                Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    info!("Unmounting filesystem...");
+                    break;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) if session.guard.is_finished() => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+            }
+        }
+        session.join();
+
+        if let Some(tracer) = &tracer {
+            tracer.finish().context("Failed to finish I/O trace")?;
         }
+        info!("Filesystem unmounted successfully");
+        Ok(())
     }
 } 
\ No newline at end of file
@@ -0,0 +1,229 @@
+//! Quota command for managing project quotas
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use aegisfs::blockdev::FileBackedBlockDevice;
+use aegisfs::modules::ProjectQuotaManager;
+use aegisfs::{DiskFs, DiskFsTrait};
+
+use super::mount::is_device_mounted;
+
+/// Manage quotas of an unmounted filesystem
+#[derive(Parser)]
+#[command(about = "Manage AegisFS quotas")]
+pub struct QuotaArgs {
+    #[command(subcommand)]
+    pub command: QuotaCommands,
+}
+
+#[derive(Subcommand)]
+pub enum QuotaCommands {
+    /// Quotas on directory subtrees
+    #[command(subcommand)]
+    Project(ProjectCommands),
+}
+
+#[derive(Subcommand)]
+pub enum ProjectCommands {
+    /// Put a directory subtree in a project and set the project's limits
+    Set {
+        /// Device or image file to change
+        device: PathBuf,
+
+        /// Directory, relative to the filesystem root
+        path: PathBuf,
+
+        /// Project ID to assign (non-zero)
+        project_id: u32,
+
+        /// Data blocks the project may use (0 for no limit)
+        #[arg(long, default_value_t = 0)]
+        blocks: u64,
+
+        /// Inodes the project may use (0 for no limit)
+        #[arg(long, default_value_t = 0)]
+        inodes: u64,
+    },
+
+    /// Print the project of a file or directory
+    Get {
+        /// Device or image file to read
+        device: PathBuf,
+
+        /// File or directory, relative to the filesystem root
+        path: PathBuf,
+    },
+
+    /// Print usage and limits of every project
+    Report {
+        /// Device or image file to read
+        device: PathBuf,
+    },
+}
+
+pub async fn run(args: QuotaArgs) -> Result<()> {
+    match args.command {
+        QuotaCommands::Project(ProjectCommands::Set { device, path, project_id, blocks, inodes }) => {
+            set(device, path, project_id, blocks, inodes).await
+        }
+        QuotaCommands::Project(ProjectCommands::Get { device, path }) => get(device, path).await,
+        QuotaCommands::Project(ProjectCommands::Report { device }) => report(device).await,
+    }
+}
+
+async fn open(device: &PathBuf, read_only: bool) -> Result<DiskFs> {
+    if !read_only {
+        if let Some(mount_point) = is_device_mounted(device)? {
+            return Err(anyhow!(
+                "Device {} is mounted at {}. Unmount it before changing quotas.",
+                device.display(),
+                mount_point
+            ));
+        }
+    }
+    let device = Arc::new(
+        FileBackedBlockDevice::open(device, read_only)
+            .await
+            .map_err(|e| anyhow!("Failed to open device: {}", e))?,
+    );
+    DiskFs::open(device)
+        .await
+        .map_err(|e| anyhow!("Failed to open filesystem: {:?}", e))
+}
+
+/// Find the inode at `path`, starting from the root directory
+async fn resolve(disk_fs: &DiskFs, path: &Path) -> Result<u64> {
+    let mut ino = disk_fs.superblock().root_inode;
+    for component in path.components() {
+        let name = match component {
+            Component::RootDir | Component::CurDir => continue,
+            Component::Normal(name) => name
+                .to_str()
+                .ok_or_else(|| anyhow!("Path {} is not valid UTF-8", path.display()))?,
+            _ => return Err(anyhow!("Path {} must not contain '..'", path.display())),
+        };
+        let dir = disk_fs
+            .read_inode(ino)
+            .await
+            .map_err(|e| anyhow!("Failed to read inode {}: {:?}", ino, e))?;
+        ino = disk_fs
+            .lookup_entry(&dir, name)
+            .await
+            .map_err(|e| anyhow!("Failed to look up {}: {:?}", name, e))?
+            .ok_or_else(|| anyhow!("{} does not exist", path.display()))?;
+    }
+    Ok(ino)
+}
+
+async fn set(device: PathBuf, path: PathBuf, project_id: u32, blocks: u64, inodes: u64) -> Result<()> {
+    if project_id == 0 {
+        return Err(anyhow!("Project ID 0 means no project; pick a non-zero ID"));
+    }
+    let mut disk_fs = open(&device, false).await?;
+    let top = resolve(&disk_fs, &path).await?;
+    let top_inode = disk_fs
+        .read_inode(top)
+        .await
+        .map_err(|e| anyhow!("Failed to read inode {}: {:?}", top, e))?;
+    if top_inode.mode & 0o40000 == 0 {
+        return Err(anyhow!("{} is not a directory", path.display()));
+    }
+
+    // Stamp the project on everything already in the subtree
+    let mut stamped = 0;
+    let mut stack = vec![top];
+    while let Some(ino) = stack.pop() {
+        let mut inode = disk_fs
+            .read_inode(ino)
+            .await
+            .map_err(|e| anyhow!("Failed to read inode {}: {:?}", ino, e))?;
+        if inode.mode & 0o40000 != 0 {
+            let entries = disk_fs
+                .read_directory_entries(&inode)
+                .await
+                .map_err(|e| anyhow!("Failed to read directory {}: {:?}", ino, e))?;
+            for entry in entries {
+                if entry.inode != 0 && entry.name != "." && entry.name != ".." {
+                    stack.push(entry.inode);
+                }
+            }
+        }
+        if inode.project_id != project_id {
+            inode.project_id = project_id;
+            disk_fs
+                .write_inode(ino, &inode)
+                .await
+                .map_err(|e| anyhow!("Failed to write inode {}: {:?}", ino, e))?;
+        }
+        stamped += 1;
+    }
+
+    let manager = ProjectQuotaManager::load(&disk_fs, true)
+        .await
+        .map_err(|e| anyhow!("Failed to load project quotas: {:?}", e))?;
+    manager
+        .set_limits(project_id, blocks, inodes)
+        .map_err(|e| anyhow!("Failed to set limits: {:?}", e))?;
+    disk_fs
+        .set_project_limits(manager.limits())
+        .await
+        .map_err(|e| anyhow!("Failed to save project limits: {:?}", e))?;
+
+    println!(
+        "Project {} now covers {} ({} inodes)",
+        project_id,
+        path.display(),
+        stamped
+    );
+    Ok(())
+}
+
+async fn get(device: PathBuf, path: PathBuf) -> Result<()> {
+    let disk_fs = open(&device, true).await?;
+    let ino = resolve(&disk_fs, &path).await?;
+    let inode = disk_fs
+        .read_inode(ino)
+        .await
+        .map_err(|e| anyhow!("Failed to read inode {}: {:?}", ino, e))?;
+    if inode.project_id == 0 {
+        println!("{}: no project", path.display());
+    } else {
+        println!("{}: project {}", path.display(), inode.project_id);
+    }
+    Ok(())
+}
+
+/// Format a limit, where 0 means unlimited
+fn limit(value: u64) -> String {
+    if value == 0 {
+        "-".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+async fn report(device: PathBuf) -> Result<()> {
+    let disk_fs = open(&device, true).await?;
+    let manager = ProjectQuotaManager::load(&disk_fs, true)
+        .await
+        .map_err(|e| anyhow!("Failed to load project quotas: {:?}", e))?;
+
+    println!(
+        "{:>10} {:>12} {:>12} {:>12} {:>12}",
+        "PROJECT", "BLOCKS", "BLOCK LIMIT", "INODES", "INODE LIMIT"
+    );
+    for (project_id, entry) in manager.report() {
+        println!(
+            "{:>10} {:>12} {:>12} {:>12} {:>12}",
+            project_id,
+            entry.blocks_used,
+            limit(entry.block_limit),
+            entry.inodes_used,
+            limit(entry.inode_limit)
+        );
+    }
+    Ok(())
+}
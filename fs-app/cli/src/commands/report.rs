@@ -0,0 +1,742 @@
+//! Report command for writing a filesystem health report
+//!
+//! Gathers everything the other commands show piecemeal (the fsck check,
+//! space and inode usage, snapshots, mount history, journal usage and
+//! optionally a verify-only scrub) and rates each against the default
+//! `[health_check]` thresholds. The result is a self-contained HTML page, or
+//! JSON for tooling.
+
+use anyhow::{anyhow, Result};
+use askama::Template;
+use clap::Parser;
+use log::info;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+
+use aegisfs::blockdev::{BlockDevice, FileBackedBlockDevice};
+use aegisfs::format::Superblock;
+use aegisfs::modules::{
+    AuditEvent, AuditLog, AuditOp, ChecksumConfig, ChecksumManager, FilesystemStats,
+    HealthCheckConfig, JournalConfig, JournalManager, SnapshotConfig, SnapshotManager,
+};
+use aegisfs::{DiskFs, DiskFsTrait, FsckReport};
+
+use super::mount::is_device_mounted;
+
+/// Most points drawn in the usage chart; longer histories are thinned out
+const MAX_USAGE_POINTS: usize = 120;
+
+/// Write a health report for a filesystem
+#[derive(Parser, Debug)]
+#[command(about = "Write an HTML or JSON health report for an AegisFS filesystem")]
+pub struct ReportArgs {
+    /// Device or image file to report on
+    pub device: PathBuf,
+
+    /// Write the report to this file instead of stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Write the report as JSON instead of HTML
+    #[arg(long)]
+    pub json: bool,
+
+    /// Verify every block's checksum for the scrub section; slow on large
+    /// devices, so the section is left out unless asked for
+    #[arg(long)]
+    pub scrub: bool,
+}
+
+/// Traffic-light rating of one check or of the whole filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Green,
+    Amber,
+    Red,
+}
+
+impl Health {
+    /// Summary shown next to the light
+    pub fn label(self) -> &'static str {
+        match self {
+            Health::Green => "Healthy",
+            Health::Amber => "Needs attention",
+            Health::Red => "Unhealthy",
+        }
+    }
+
+    /// CSS class of the light
+    pub fn css_class(self) -> &'static str {
+        match self {
+            Health::Green => "green",
+            Health::Amber => "amber",
+            Health::Red => "red",
+        }
+    }
+}
+
+/// Rating of one aspect of the filesystem
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub health: Health,
+    pub detail: String,
+}
+
+/// What the metadata check found; nothing is repaired
+#[derive(Debug, Clone, Serialize)]
+pub struct FsckSummary {
+    pub clean: bool,
+    pub reachable_inodes: u64,
+    /// Reachable inodes the bitmap has marked free
+    pub missing_inodes: Vec<u64>,
+    /// Unreachable inodes the bitmap has marked allocated
+    pub leaked_inodes: Vec<u64>,
+    /// Other inconsistencies, as `fsck --repair` would describe them
+    pub problems: Vec<String>,
+}
+
+impl From<&FsckReport> for FsckSummary {
+    fn from(report: &FsckReport) -> Self {
+        Self {
+            clean: report.is_clean(),
+            reachable_inodes: report.inode_bitmap.reachable,
+            missing_inodes: report.inode_bitmap.missing.clone(),
+            leaked_inodes: report.inode_bitmap.leaked.clone(),
+            problems: report.unrepaired.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+/// Result of a verify-only scrub
+#[derive(Debug, Clone, Serialize)]
+pub struct ScrubSummary {
+    pub blocks_scrubbed: u64,
+    pub blocks_corrupted: u64,
+    pub blocks_unrepairable: u64,
+    pub bad_blocks: u64,
+    pub duration_secs: f64,
+}
+
+/// One snapshot, placed in the snapshot tree
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotNode {
+    pub id: u64,
+    pub name: String,
+    pub parent: Option<u64>,
+    /// Distance from the root of its tree
+    pub depth: usize,
+    pub created_at: u64,
+    pub block_count: u64,
+}
+
+impl SnapshotNode {
+    /// Indentation of the node in the tree view
+    pub fn indent(&self) -> String {
+        if self.depth == 0 {
+            String::new()
+        } else {
+            format!("{}└─ ", "   ".repeat(self.depth - 1))
+        }
+    }
+
+    pub fn created(&self) -> String {
+        format_time(self.created_at)
+    }
+}
+
+/// Snapshot counts and the tree they form
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotSummary {
+    pub total: usize,
+    pub active: usize,
+    pub blocks_referenced: usize,
+    pub space_used: u64,
+    /// Every snapshot, depth first with children oldest first
+    pub tree: Vec<SnapshotNode>,
+}
+
+/// One mount from the superblock's mount history
+#[derive(Debug, Clone, Serialize)]
+pub struct MountRecord {
+    pub mounted_at: u64,
+    /// `None` while mounted or after a crash
+    pub unmounted_at: Option<u64>,
+    pub clean: bool,
+}
+
+impl MountRecord {
+    pub fn mounted(&self) -> String {
+        format_time(self.mounted_at)
+    }
+
+    pub fn unmounted(&self) -> String {
+        self.unmounted_at.map_or_else(|| "-".to_string(), format_time)
+    }
+}
+
+/// Mount counters and recent mounts, oldest first
+#[derive(Debug, Clone, Serialize)]
+pub struct MountSummary {
+    pub mount_count: u64,
+    pub unclean_mount_count: u64,
+    /// Not cleanly unmounted: mounted elsewhere, or crashed
+    pub dirty: bool,
+    pub history: Vec<MountRecord>,
+}
+
+/// Journal size and the part not yet checkpointed
+#[derive(Debug, Clone, Serialize)]
+pub struct JournalSummary {
+    pub blocks: u64,
+    pub used_blocks: u64,
+    pub used_percent: f64,
+}
+
+/// Inodes in use after the audited creates and deletes up to a moment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct UsagePoint {
+    pub timestamp: u64,
+    pub inodes_in_use: u64,
+}
+
+/// Everything the report shows
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub device: String,
+    pub generated_at: u64,
+    pub volume_name: String,
+    pub uuid: String,
+    pub size_bytes: u64,
+    /// Worst rating among the checks
+    pub health: Health,
+    pub checks: Vec<Check>,
+    pub space: FilesystemStats,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+    pub fsck: FsckSummary,
+    /// `None` unless a scrub was asked for
+    pub scrub: Option<ScrubSummary>,
+    pub snapshots: SnapshotSummary,
+    pub mounts: MountSummary,
+    /// `None` without a journal
+    pub journal: Option<JournalSummary>,
+    /// Empty without an audit log
+    pub usage_history: Vec<UsagePoint>,
+}
+
+impl HealthReport {
+    pub fn generated(&self) -> String {
+        format_time(self.generated_at)
+    }
+
+    pub fn used_percent(&self) -> f64 {
+        100.0 - self.space.free_percent()
+    }
+
+    pub fn used_percent_text(&self) -> String {
+        format!("{:.1}", self.used_percent())
+    }
+}
+
+/// The HTML page
+#[derive(Template)]
+#[template(path = "report.html")]
+struct ReportPage<'a> {
+    report: &'a HealthReport,
+    /// SVG chart of `usage_history`, empty when there is nothing to draw
+    usage_chart: String,
+}
+
+/// Format seconds since the Unix epoch for display
+fn format_time(secs: u64) -> String {
+    chrono::DateTime::<chrono::Utc>::from_timestamp(secs as i64, 0)
+        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| "Unknown".to_string())
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+/// Rate a free percentage: red below the threshold, amber below twice it
+fn rate_free(free_percent: f64, threshold: u8) -> Health {
+    let threshold = threshold as f64;
+    if free_percent < threshold {
+        Health::Red
+    } else if free_percent < threshold * 2.0 {
+        Health::Amber
+    } else {
+        Health::Green
+    }
+}
+
+/// Rate every section of the report against the health check thresholds
+fn rate(report: &HealthReport, config: &HealthCheckConfig) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let free = report.space.free_percent();
+    checks.push(Check {
+        name: "Free space",
+        health: rate_free(free, config.low_space_threshold_percent),
+        detail: format!("{:.1}% of {} data blocks free", free, report.space.total_blocks),
+    });
+
+    let free_inodes = percent(report.free_inodes, report.total_inodes);
+    checks.push(Check {
+        name: "Free inodes",
+        health: rate_free(free_inodes, config.low_space_threshold_percent),
+        detail: format!("{} of {} inodes free", report.free_inodes, report.total_inodes),
+    });
+
+    let fsck = &report.fsck;
+    checks.push(Check {
+        name: "Metadata",
+        health: if fsck.clean { Health::Green } else { Health::Red },
+        detail: if fsck.clean {
+            "fsck found no problems".to_string()
+        } else {
+            format!(
+                "{} problems, {} inodes missing from the bitmap, {} leaked; run `aegisfs fsck --repair`",
+                fsck.problems.len(),
+                fsck.missing_inodes.len(),
+                fsck.leaked_inodes.len()
+            )
+        },
+    });
+
+    let unclean = report.mounts.unclean_mount_count;
+    checks.push(Check {
+        name: "Unmounts",
+        health: if unclean >= Superblock::MAX_UNCLEAN_MOUNTS as u64 {
+            Health::Red
+        } else if unclean > 0 {
+            Health::Amber
+        } else {
+            Health::Green
+        },
+        detail: format!(
+            "{} unclean unmounts (mounting is refused at {})",
+            unclean,
+            Superblock::MAX_UNCLEAN_MOUNTS
+        ),
+    });
+
+    if let Some(journal) = &report.journal {
+        checks.push(Check {
+            name: "Journal",
+            health: if journal.used_percent >= config.journal_full_threshold_percent as f64 {
+                Health::Amber
+            } else {
+                Health::Green
+            },
+            detail: format!(
+                "{:.1}% of {} blocks awaiting checkpoint",
+                journal.used_percent, journal.blocks
+            ),
+        });
+    }
+
+    if let Some(scrub) = &report.scrub {
+        checks.push(Check {
+            name: "Checksums",
+            health: if scrub.blocks_corrupted > 0 || scrub.bad_blocks > config.max_bad_blocks {
+                Health::Red
+            } else {
+                Health::Green
+            },
+            detail: format!(
+                "{} of {} blocks corrupted, {} known bad",
+                scrub.blocks_corrupted, scrub.blocks_scrubbed, scrub.bad_blocks
+            ),
+        });
+    }
+
+    checks
+}
+
+/// Snapshots depth first from each root, children oldest first
+fn snapshot_tree(manager: &SnapshotManager) -> Vec<SnapshotNode> {
+    let chain = manager.chain();
+    let mut snapshots = manager.list_snapshots();
+    snapshots.sort_by_key(|s| s.id);
+
+    let mut tree = Vec::new();
+    let mut stack: Vec<(u64, usize)> = snapshots
+        .iter()
+        .filter(|s| chain.contains(s.id) && chain.parent(s.id).is_none())
+        .rev()
+        .map(|s| (s.id, 0))
+        .collect();
+    while let Some((id, depth)) = stack.pop() {
+        let Some(snapshot) = snapshots.iter().find(|s| s.id == id) else {
+            continue;
+        };
+        tree.push(SnapshotNode {
+            id,
+            name: snapshot.name.clone(),
+            parent: chain.parent(id),
+            depth,
+            created_at: snapshot.created_at,
+            block_count: snapshot.block_count,
+        });
+        stack.extend(chain.children(id).iter().rev().map(|&child| (child, depth + 1)));
+    }
+    tree
+}
+
+/// Replay audited creates and deletes to estimate inodes in use over time,
+/// ending at `in_use_now`. The audit log records no sizes, so inode counts
+/// stand in for space; it also wraps, so the history starts at its oldest
+/// surviving record.
+fn usage_history(events: &[AuditEvent], in_use_now: u64) -> Vec<UsagePoint> {
+    let changes: Vec<(u64, i64)> = events
+        .iter()
+        .filter(|e| e.result == 0)
+        .filter_map(|e| match e.operation {
+            AuditOp::Create => Some((e.timestamp, 1)),
+            AuditOp::Delete => Some((e.timestamp, -1)),
+            _ => None,
+        })
+        .collect();
+    let net: i64 = changes.iter().map(|&(_, delta)| delta).sum();
+
+    let mut in_use = (in_use_now as i64 - net).max(0);
+    let mut points: Vec<UsagePoint> = Vec::new();
+    for (timestamp, delta) in changes {
+        in_use = (in_use + delta).max(0);
+        match points.last_mut() {
+            Some(last) if last.timestamp == timestamp => last.inodes_in_use = in_use as u64,
+            _ => points.push(UsagePoint {
+                timestamp,
+                inodes_in_use: in_use as u64,
+            }),
+        }
+    }
+
+    if points.len() > MAX_USAGE_POINTS {
+        let step = points.len().div_ceil(MAX_USAGE_POINTS);
+        let last = points[points.len() - 1];
+        points = points.into_iter().step_by(step).collect();
+        if points.last() != Some(&last) {
+            points.push(last);
+        }
+    }
+    points
+}
+
+/// Draw the usage history as an SVG line chart
+fn usage_chart(points: &[UsagePoint]) -> String {
+    const WIDTH: f64 = 640.0;
+    const HEIGHT: f64 = 160.0;
+    const PAD: f64 = 24.0;
+
+    if points.len() < 2 {
+        return String::new();
+    }
+    let first = points[0].timestamp as f64;
+    let span = (points[points.len() - 1].timestamp as f64 - first).max(1.0);
+    let peak = points.iter().map(|p| p.inodes_in_use).max().unwrap_or(0).max(1) as f64;
+
+    let coords: Vec<String> = points
+        .iter()
+        .map(|p| {
+            let x = PAD + (p.timestamp as f64 - first) / span * (WIDTH - 2.0 * PAD);
+            let y = HEIGHT - PAD - p.inodes_in_use as f64 / peak * (HEIGHT - 2.0 * PAD);
+            format!("{:.1},{:.1}", x, y)
+        })
+        .collect();
+
+    format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {w} {h}" width="{w}" height="{h}" role="img">"#,
+            r##"<line x1="{p}" y1="{base}" x2="{right}" y2="{base}" stroke="#999"/>"##,
+            r##"<line x1="{p}" y1="{p}" x2="{p}" y2="{base}" stroke="#999"/>"##,
+            r##"<polyline fill="none" stroke="#2a6fdb" stroke-width="2" points="{coords}"/>"##,
+            r#"<text x="{p}" y="{label}" font-size="11">{peak} inodes</text>"#,
+            r#"<text x="{p}" y="{h}" font-size="11">{start}</text>"#,
+            r#"<text x="{right}" y="{h}" font-size="11" text-anchor="end">{end}</text>"#,
+            "</svg>"
+        ),
+        w = WIDTH,
+        h = HEIGHT,
+        p = PAD,
+        base = HEIGHT - PAD,
+        right = WIDTH - PAD,
+        label = PAD - 8.0,
+        coords = coords.join(" "),
+        peak = peak,
+        start = format_time(points[0].timestamp),
+        end = format_time(points[points.len() - 1].timestamp),
+    )
+}
+
+/// Render the report as a self-contained HTML page
+pub fn render_html(report: &HealthReport) -> Result<String> {
+    let page = ReportPage {
+        report,
+        usage_chart: usage_chart(&report.usage_history),
+    };
+    page.render().map_err(|e| anyhow!("Failed to render report: {}", e))
+}
+
+/// Gather the report for an unmounted device
+pub async fn collect(device_path: &Path, scrub: bool) -> Result<HealthReport> {
+    let device: Arc<dyn BlockDevice> = Arc::new(
+        FileBackedBlockDevice::open(device_path, false)
+            .await
+            .map_err(|e| anyhow!("Failed to open device: {}", e))?,
+    );
+    let mut disk_fs = DiskFs::open(device.clone())
+        .await
+        .map_err(|e| anyhow!("Failed to open filesystem: {:?}", e))?;
+
+    info!("Checking metadata of {}", device_path.display());
+    let fsck = disk_fs
+        .fsck(false)
+        .await
+        .map_err(|e| anyhow!("Failed to check filesystem: {:?}", e))?;
+
+    let sb = disk_fs.superblock();
+    let layout = *disk_fs.layout();
+
+    let journal = if layout.journal_blocks > 0 {
+        let config = JournalConfig {
+            journal_start: layout.journal,
+            journal_size: layout.journal_blocks,
+            ..JournalConfig::default()
+        };
+        let mut journal = JournalManager::new(device.clone(), config);
+        journal
+            .init()
+            .await
+            .map_err(|e| anyhow!("Failed to open journal: {}", e))?;
+        let summary = JournalSummary {
+            blocks: journal.journal_size(),
+            used_blocks: journal.used_blocks(),
+            used_percent: percent(journal.used_blocks(), journal.journal_size()),
+        };
+        journal
+            .shutdown()
+            .await
+            .map_err(|e| anyhow!("Failed to close journal: {}", e))?;
+        Some(summary)
+    } else {
+        None
+    };
+
+    let scrub = if scrub {
+        info!("Verifying checksums of {}", device_path.display());
+        let config = ChecksumConfig {
+            auto_repair: false,
+            ..ChecksumConfig::default()
+        };
+        let mut manager = ChecksumManager::new(device.clone(), config);
+        manager
+            .init()
+            .await
+            .map_err(|e| anyhow!("Failed to initialize checksum manager: {}", e))?;
+        let start = Instant::now();
+        let stats = manager
+            .scrub_all(CancellationToken::new())
+            .await
+            .map_err(|e| anyhow!("Scrub failed: {}", e))?;
+        let summary = ScrubSummary {
+            blocks_scrubbed: stats.blocks_scrubbed,
+            blocks_corrupted: stats.blocks_corrupted,
+            blocks_unrepairable: stats.blocks_unrepairable,
+            bad_blocks: manager.get_bad_blocks().len() as u64,
+            duration_secs: start.elapsed().as_secs_f64(),
+        };
+        manager.shutdown().await?;
+        Some(summary)
+    } else {
+        None
+    };
+
+    let mut snapshot_manager = SnapshotManager::new(device.clone(), SnapshotConfig::default());
+    snapshot_manager
+        .init()
+        .await
+        .map_err(|e| anyhow!("Failed to initialize snapshot manager: {}", e))?;
+    let stats = snapshot_manager.get_snapshot_stats();
+    let snapshots = SnapshotSummary {
+        total: stats.total_snapshots,
+        active: stats.active_snapshots,
+        blocks_referenced: stats.total_blocks_referenced,
+        space_used: stats.total_space_used,
+        tree: snapshot_tree(&snapshot_manager),
+    };
+
+    let in_use = sb.inode_count.saturating_sub(sb.free_inodes);
+    let usage_history = if layout.audit_log_blocks > 0 {
+        let log = AuditLog::open(device.clone(), layout.audit_log, layout.audit_log_blocks)
+            .await
+            .map_err(|e| anyhow!("Failed to open audit log: {}", e))?;
+        let events = log
+            .events()
+            .await
+            .map_err(|e| anyhow!("Failed to read audit log: {}", e))?;
+        usage_history(&events, in_use)
+    } else {
+        Vec::new()
+    };
+
+    let mounts = MountSummary {
+        mount_count: sb.mount_count as u64,
+        unclean_mount_count: sb.unclean_mount_count as u64,
+        dirty: sb.state == Superblock::STATE_DIRTY,
+        history: sb
+            .mount_history
+            .events()
+            .iter()
+            .map(|event| MountRecord {
+                mounted_at: event.mount_time,
+                unmounted_at: (event.clean && event.unmount_time != 0).then_some(event.unmount_time),
+                clean: event.clean,
+            })
+            .collect(),
+    };
+
+    let space = FilesystemStats {
+        total_blocks: layout.data_blocks_count,
+        free_blocks: disk_fs.free_blocks(),
+        bad_blocks: scrub.as_ref().map_or(0, |s| s.bad_blocks),
+        journal_blocks: journal.as_ref().map_or(0, |j| j.blocks),
+        journal_used_blocks: journal.as_ref().map_or(0, |j| j.used_blocks),
+        xattr_cache_bytes: 0,
+    };
+
+    let mut report = HealthReport {
+        device: device_path.display().to_string(),
+        generated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        volume_name: String::from_utf8_lossy(&sb.volume_name)
+            .trim_end_matches('\0')
+            .to_string(),
+        uuid: sb.uuid.iter().map(|b| format!("{:02x}", b)).collect(),
+        size_bytes: sb.size,
+        health: Health::Green,
+        checks: Vec::new(),
+        space,
+        total_inodes: sb.inode_count,
+        free_inodes: sb.free_inodes,
+        fsck: FsckSummary::from(&fsck),
+        scrub,
+        snapshots,
+        mounts,
+        journal,
+        usage_history,
+    };
+    report.checks = rate(&report, &HealthCheckConfig::default());
+    report.health = report
+        .checks
+        .iter()
+        .map(|check| check.health)
+        .max()
+        .unwrap_or(Health::Green);
+    Ok(report)
+}
+
+pub async fn run(args: ReportArgs) -> Result<()> {
+    if let Some(mount_point) = is_device_mounted(&args.device)? {
+        return Err(anyhow!(
+            "Device {} is mounted at {}. Unmount it before writing a report.",
+            args.device.display(),
+            mount_point
+        ));
+    }
+
+    let report = collect(&args.device, args.scrub).await?;
+    let contents = if args.json {
+        serde_json::to_string_pretty(&report)?
+    } else {
+        render_html(&report)?
+    };
+
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, contents)
+                .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))?;
+            println!(
+                "{}: {} — report written to {}",
+                args.device.display(),
+                report.health.label(),
+                path.display()
+            );
+        }
+        None => println!("{}", contents),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_report_has_every_section() {
+        let path = std::env::temp_dir().join(format!("aegisfs_report_{}.img", std::process::id()));
+        let size = 4 * 1024 * 1024;
+        let device: Arc<dyn BlockDevice> =
+            Arc::new(FileBackedBlockDevice::create(&path, size).await.unwrap());
+        DiskFs::format(device, size, None).await.unwrap();
+
+        let report = collect(&path, true).await.unwrap();
+        assert!(report.fsck.clean);
+        assert!(report.scrub.is_some());
+
+        let html = render_html(&report).unwrap();
+        for section in ["summary", "space", "fsck", "scrub", "snapshots", "mounts", "journal", "usage"] {
+            assert!(
+                html.contains(&format!(r#"<section id="{}">"#, section)),
+                "missing section {}",
+                section
+            );
+        }
+        assert!(html.contains(&format!(r#"class="light {}""#, report.health.css_class())));
+
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&report).unwrap()).unwrap();
+        for key in ["health", "checks", "space", "fsck", "scrub", "snapshots", "mounts", "journal", "usage_history"] {
+            assert!(json.get(key).is_some(), "missing key {}", key);
+        }
+        assert_eq!(json["health"], report.health.css_class());
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_usage_history_ends_at_current_usage() {
+        let event = |timestamp, operation| AuditEvent {
+            timestamp,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+            operation,
+            ino: 2,
+            name: None,
+            result: 0,
+        };
+        let events = [
+            event(10, AuditOp::Create),
+            event(10, AuditOp::Create),
+            event(20, AuditOp::Write),
+            event(30, AuditOp::Delete),
+            event(40, AuditOp::Create),
+        ];
+        let points = usage_history(&events, 5);
+        let in_use: Vec<(u64, u64)> = points.iter().map(|p| (p.timestamp, p.inodes_in_use)).collect();
+        assert_eq!(in_use, vec![(10, 5), (30, 4), (40, 5)]);
+        assert!(usage_chart(&points).starts_with("<svg"));
+        assert!(usage_chart(&points[..1]).is_empty());
+    }
+}
@@ -6,9 +6,11 @@ use log::{error, info, warn};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Instant;
+use tokio_util::sync::CancellationToken;
 
 use aegisfs::blockdev::FileBackedBlockDevice;
 use aegisfs::modules::{ChecksumConfig, ChecksumManager};
+use aegisfs::Error;
 
 /// Check and repair filesystem integrity
 #[derive(Parser)]
@@ -46,6 +48,34 @@ pub struct ScrubArgs {
     pub list_bad_blocks: bool,
 }
 
+/// Cancel `token` on SIGINT or SIGTERM so an interrupted scrub stops cleanly
+fn cancel_on_signal(token: CancellationToken) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut sigterm = match signal(SignalKind::terminate()) {
+                Ok(sigterm) => sigterm,
+                Err(e) => {
+                    warn!("Failed to install SIGTERM handler: {}", e);
+                    let _ = tokio::signal::ctrl_c().await;
+                    token.cancel();
+                    return;
+                }
+            };
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+
+        info!("Interrupted, cancelling scrub...");
+        token.cancel();
+    });
+}
+
 pub async fn run(args: ScrubArgs) -> Result<()> {
     // Open device
     let device = Arc::new(
@@ -113,8 +143,10 @@ pub async fn run(args: ScrubArgs) -> Result<()> {
     }
 
     let start_time = Instant::now();
+    let token = CancellationToken::new();
+    cancel_on_signal(token.clone());
 
-    match manager.scrub_all().await {
+    match manager.scrub_all(token).await {
         Ok(stats) => {
             let duration = start_time.elapsed();
 
@@ -142,6 +174,11 @@ pub async fn run(args: ScrubArgs) -> Result<()> {
                 info!("\n✓ All errors were successfully repaired");
             }
         }
+        Err(Error::Cancelled) => {
+            warn!("Scrub cancelled after {:.2} seconds", start_time.elapsed().as_secs_f64());
+            manager.shutdown().await?;
+            std::process::exit(130);
+        }
         Err(e) => {
             error!("Scrub failed: {}", e);
             std::process::exit(3);
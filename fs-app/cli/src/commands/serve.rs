@@ -0,0 +1,67 @@
+//! Serve command for exporting an AegisFS filesystem over 9P
+
+use anyhow::Result;
+use clap::Parser;
+use log::{error, info};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aegisfs::server::NinePServer;
+use aegisfs::{AegisFS, MountOptions, VfsHandle};
+
+/// Serve an AegisFS filesystem to 9P2000.L clients over TCP
+#[derive(Parser, Debug)]
+#[command(about = "Serve an AegisFS filesystem over 9P2000.L")]
+pub struct ServeArgs {
+    /// Device or image file to serve
+    pub device: PathBuf,
+
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:5640")]
+    pub listen: SocketAddr,
+
+    /// Serve even if the filesystem was not cleanly unmounted
+    #[arg(long)]
+    pub force: bool,
+}
+
+pub async fn run(args: ServeArgs) -> Result<()> {
+    let options = MountOptions {
+        force: args.force,
+        ..MountOptions::default()
+    };
+    let fs = AegisFS::from_device_with_options(&args.device, options)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to open AegisFS on {}: {}", args.device.display(), e))?;
+
+    let server = NinePServer::bind(args.listen, Arc::new(VfsHandle::new(fs)))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", args.listen, e))?;
+    let addr = server
+        .local_addr()
+        .map_err(|e| anyhow::anyhow!("Failed to read listen address: {}", e))?;
+
+    info!("Serving {} over 9P on {}", args.device.display(), addr);
+    println!("Serving {} on {}", args.device.display(), addr);
+    println!("Mount it with:");
+    println!(
+        "    mount -t 9p -o trans=tcp,port={},version=9p2000.L {} <mountpoint>",
+        addr.port(),
+        addr.ip()
+    );
+    println!("Press Ctrl+C to stop");
+
+    tokio::select! {
+        result = server.run() => {
+            if let Err(e) = &result {
+                error!("9P server stopped: {}", e);
+            }
+            result.map_err(|e| anyhow::anyhow!("9P server failed: {}", e))
+        }
+        _ = tokio::signal::ctrl_c() => {
+            info!("Stopping 9P server");
+            Ok(())
+        }
+    }
+}
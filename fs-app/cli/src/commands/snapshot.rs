@@ -2,13 +2,14 @@
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use log::{error, info};
+use log::{error, info, warn};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use aegisfs::blockdev::FileBackedBlockDevice;
-use aegisfs::modules::{SnapshotConfig, SnapshotManager};
+use aegisfs::blockdev::{BlockDevice, FileBackedBlockDevice};
+use aegisfs::modules::{ScriptHook, SnapshotConfig, SnapshotIntegrity, SnapshotManager};
+use aegisfs::{DiskFs, DiskFsTrait};
 
 /// Manage snapshots
 #[derive(Parser)]
@@ -35,6 +36,16 @@ pub enum SnapshotCommands {
         /// Additional tags in key=value format
         #[arg(short = 't', long = "tag")]
         tags: Vec<String>,
+
+        /// Shell command run before the snapshot is taken, e.g. to flush a
+        /// database. The snapshot is not taken if it fails
+        #[arg(long, value_name = "CMD")]
+        pre_hook: Option<String>,
+
+        /// Shell command run after the snapshot has been taken, with its ID
+        /// in AEGISFS_SNAPSHOT_ID
+        #[arg(long, value_name = "CMD")]
+        post_hook: Option<String>,
     },
 
     /// List all snapshots
@@ -62,17 +73,23 @@ pub enum SnapshotCommands {
 
     /// Show snapshot statistics
     Stats,
+
+    /// Check that a snapshot was taken while the filesystem was frozen
+    Verify {
+        /// Name or ID of the snapshot to check
+        snapshot: String,
+    },
 }
 
 pub async fn run(args: SnapshotArgs) -> Result<()> {
     // Initialize snapshot manager
-    let device = Arc::new(
+    let device: Arc<dyn BlockDevice> = Arc::new(
         FileBackedBlockDevice::open(&args.device, false) // Open in read-write mode
             .await
             .map_err(|e| anyhow::anyhow!("Failed to open device: {}", e))?,
     );
 
-    let mut manager = SnapshotManager::new(device, SnapshotConfig::default());
+    let mut manager = SnapshotManager::new(device.clone(), SnapshotConfig::default());
     manager
         .init()
         .await
@@ -83,9 +100,19 @@ pub async fn run(args: SnapshotArgs) -> Result<()> {
             name,
             description,
             tags,
+            pre_hook,
+            post_hook,
         } => {
             info!("Creating snapshot '{}'", name);
 
+            if pre_hook.is_some() || post_hook.is_some() {
+                manager.register_hook(Arc::new(ScriptHook {
+                    pre_snapshot: pre_hook,
+                    post_snapshot: post_hook,
+                    ..Default::default()
+                }));
+            }
+
             // Parse tags
             let mut tag_map = HashMap::new();
             for tag in tags {
@@ -102,7 +129,27 @@ pub async fn run(args: SnapshotArgs) -> Result<()> {
                 tag_map.insert("description".to_string(), desc);
             }
 
-            match manager.create_snapshot(&name, tag_map).await {
+            // Holding the device open read-write keeps it from being mounted
+            // or written elsewhere, so the snapshot is taken frozen; the
+            // freeze record lets `verify` confirm that later
+            let disk_fs = DiskFs::open(device.clone())
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to open filesystem: {:?}", e))?;
+            let recorded = match disk_fs.record_freeze().await {
+                Ok(_) => true,
+                Err(e) => {
+                    warn!("Not recording the freeze: {}", e);
+                    false
+                }
+            };
+            let created = manager.create_snapshot(&name, tag_map).await;
+            if recorded {
+                if let Err(e) = disk_fs.record_unfreeze().await {
+                    warn!("Failed to record the unfreeze: {}", e);
+                }
+            }
+
+            match created {
                 Ok(id) => {
                     info!("Successfully created snapshot '{}' with ID {}", name, id);
                 }
@@ -233,6 +280,39 @@ pub async fn run(args: SnapshotArgs) -> Result<()> {
             );
             println!("  Pending CoW ops:     {}", stats.cow_operations_pending);
         }
+
+        SnapshotCommands::Verify { snapshot } => {
+            let snap = snapshot
+                .parse::<u64>()
+                .ok()
+                .and_then(|id| manager.get_snapshot(id))
+                .or_else(|| manager.get_snapshot_by_name(&snapshot))
+                .ok_or_else(|| anyhow::anyhow!("Snapshot '{}' not found", snapshot))?;
+            let disk_fs = DiskFs::open(device.clone())
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to open filesystem: {:?}", e))?;
+            let freeze = disk_fs
+                .freeze_info()
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to read the freeze record: {}", e))?
+                .ok_or_else(|| anyhow::anyhow!("The filesystem has no journal region to hold a freeze record"))?;
+
+            match manager.verify_snapshot_integrity(snap.id, &freeze)? {
+                SnapshotIntegrity::Frozen => {
+                    println!("Snapshot {} ({}) was taken while the filesystem was frozen", snap.id, snap.name);
+                }
+                SnapshotIntegrity::PossiblyInconsistent => {
+                    return Err(anyhow::anyhow!(
+                        "Snapshot {} ({}) was taken at {}, outside the last freeze ({} to {}); it may be inconsistent",
+                        snap.id,
+                        snap.name,
+                        snap.created_at,
+                        freeze.last_freeze_time,
+                        freeze.last_unfreeze_time
+                    ));
+                }
+            }
+        }
     }
 
     Ok(())
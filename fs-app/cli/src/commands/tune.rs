@@ -0,0 +1,157 @@
+//! Tune command for adjusting superblock parameters after format
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use log::info;
+use std::path::PathBuf;
+
+use aegisfs::blockdev::{BlockDevice, FileBackedBlockDevice};
+use aegisfs::format::Superblock;
+
+use super::mount::is_device_mounted;
+
+/// Adjust filesystem parameters without reformatting
+#[derive(Parser, Debug)]
+#[command(about = "Adjust AegisFS parameters without reformatting")]
+pub struct TuneArgs {
+    /// Device or image file to tune
+    pub device: PathBuf,
+
+    /// New volume name
+    #[arg(long)]
+    pub volume_name: Option<String>,
+
+    /// Percentage of blocks kept back from ordinary file writes
+    #[arg(long)]
+    pub reserved_blocks_percent: Option<u8>,
+
+    /// Journal size in blocks; the journal region is fixed at format time
+    #[arg(long)]
+    pub journal_size: Option<u64>,
+
+    /// Mounts after which fsck is recommended (0 disables the check)
+    #[arg(long)]
+    pub max_mount_count: Option<u16>,
+
+    /// Print the current values without changing anything
+    #[arg(long)]
+    pub check_only: bool,
+}
+
+impl TuneArgs {
+    fn has_changes(&self) -> bool {
+        self.volume_name.is_some()
+            || self.reserved_blocks_percent.is_some()
+            || self.journal_size.is_some()
+            || self.max_mount_count.is_some()
+    }
+}
+
+/// Print the tunable superblock fields
+fn print_values(sb: &Superblock) {
+    let name = String::from_utf8_lossy(&sb.volume_name)
+        .trim_end_matches('\0')
+        .to_string();
+    println!("Volume name:             {}", name);
+    println!(
+        "Reserved blocks:         {}% ({} blocks)",
+        sb.reserved_blocks_percent,
+        sb.reserved_blocks()
+    );
+    println!("Journal size:            {} blocks", sb.journal_blocks);
+    println!("Mount count:             {}", sb.mount_count);
+    if sb.max_mount_count == 0 {
+        println!("Maximum mount count:     disabled");
+    } else {
+        println!("Maximum mount count:     {}", sb.max_mount_count);
+    }
+}
+
+/// Apply the requested changes to the superblock, rejecting any that can't
+/// be made in place
+fn apply_changes(sb: &mut Superblock, args: &TuneArgs) -> Result<()> {
+    if let Some(name) = &args.volume_name {
+        if name.len() > Superblock::MAX_VOLUME_NAME {
+            return Err(anyhow!(
+                "Volume name is {} bytes; the maximum is {}",
+                name.len(),
+                Superblock::MAX_VOLUME_NAME
+            ));
+        }
+        sb.set_volume_name(name);
+    }
+
+    if let Some(percent) = args.reserved_blocks_percent {
+        if percent > Superblock::MAX_RESERVED_PERCENT {
+            return Err(anyhow!(
+                "Reserved blocks percentage {} is above the maximum of {}",
+                percent,
+                Superblock::MAX_RESERVED_PERCENT
+            ));
+        }
+        sb.reserved_blocks_percent = percent;
+    }
+
+    if let Some(blocks) = args.journal_size {
+        // The block groups are laid out behind the journal region, so
+        // resizing it would mean relocating every one of them
+        if blocks != sb.journal_blocks {
+            return Err(anyhow!(
+                "Resizing the journal from {} to {} blocks would require moving data blocks; \
+                 back up and reformat with `--journal-size` instead",
+                sb.journal_blocks,
+                blocks
+            ));
+        }
+    }
+
+    if let Some(count) = args.max_mount_count {
+        sb.max_mount_count = count;
+    }
+
+    Ok(())
+}
+
+pub async fn run(args: TuneArgs) -> Result<()> {
+    let read_only = args.check_only || !args.has_changes();
+    let device = FileBackedBlockDevice::open(&args.device, read_only)
+        .await
+        .map_err(|e| anyhow!("Failed to open device: {}", e))?;
+    let mut sb = Superblock::read_from_disk(&device)
+        .await
+        .map_err(|e| anyhow!("Failed to read superblock: {}", e))?;
+
+    if read_only {
+        print_values(&sb);
+        return Ok(());
+    }
+
+    if let Some(mount_point) = is_device_mounted(&args.device)? {
+        return Err(anyhow!(
+            "Device {} is mounted at {}. Unmount it before tuning.",
+            args.device.display(),
+            mount_point
+        ));
+    }
+    if sb.state == Superblock::STATE_DIRTY {
+        return Err(anyhow!(
+            "Filesystem was not cleanly unmounted; run `aegisfs fsck {}` first",
+            args.device.display()
+        ));
+    }
+
+    apply_changes(&mut sb, &args)?;
+
+    info!("Writing tuned superblock to {}", args.device.display());
+    sb.write_to_disk(&device)
+        .await
+        .map_err(|e| anyhow!("Failed to write superblock: {}", e))?;
+    device
+        .sync()
+        .await
+        .map_err(|e| anyhow!("Failed to sync device: {}", e))?;
+
+    println!("Updated superblock:");
+    print_values(&sb);
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Umount command for stopping a filesystem mounted with `aegisfs mount`
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use super::mount::is_mountpoint_in_use;
+
+/// How often the mount process is checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Unmount an AegisFS filesystem
+#[derive(Parser, Debug)]
+#[command(about = "Unmount an AegisFS filesystem")]
+pub struct UmountArgs {
+    /// Mount point
+    pub mountpoint: PathBuf,
+
+    /// PID file written by `aegisfs mount --pid-file`. The mount process is
+    /// asked to unmount with SIGTERM so it writes everything out first;
+    /// without one the mount is detached with fusermount3 straight away
+    #[arg(long, value_name = "PATH")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Seconds to wait for the mount process to exit before detaching the
+    /// mount with fusermount3
+    #[arg(long, default_value = "30")]
+    pub timeout: u64,
+
+    /// Detach the mount even while files in it are open (fusermount3 -uz)
+    #[arg(long)]
+    pub lazy: bool,
+}
+
+pub async fn run(args: UmountArgs) -> Result<()> {
+    let mountpoint = args
+        .mountpoint
+        .canonicalize()
+        .with_context(|| format!("Failed to access mountpoint: {}", args.mountpoint.display()))?;
+    if !is_mountpoint_in_use(&mountpoint)? {
+        return Err(anyhow!("Nothing is mounted at {}", mountpoint.display()));
+    }
+
+    if let Some(pid_file) = &args.pid_file {
+        let pid = read_pid(pid_file)?;
+        if stop_mount_process(pid, Duration::from_secs(args.timeout))? {
+            println!("Unmounted {}", mountpoint.display());
+            return Ok(());
+        }
+        warn!(
+            "Mount process {} did not exit within {}s, detaching {} with fusermount3",
+            pid,
+            args.timeout,
+            mountpoint.display()
+        );
+    }
+
+    fusermount_unmount(&mountpoint, args.lazy)?;
+    println!("Unmounted {}", mountpoint.display());
+    Ok(())
+}
+
+/// Read the PID `aegisfs mount --pid-file` wrote
+fn read_pid(path: &Path) -> Result<libc::pid_t> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read PID file: {}", path.display()))?;
+    match contents.trim().parse::<libc::pid_t>() {
+        Ok(pid) if pid > 0 => Ok(pid),
+        _ => Err(anyhow!("PID file {} does not hold a process ID", path.display())),
+    }
+}
+
+/// Whether a process with this PID is still running
+fn process_alive(pid: libc::pid_t) -> bool {
+    // Signal 0 only checks that the process exists
+    unsafe { libc::kill(pid, 0) == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH) }
+}
+
+/// Send SIGTERM to the mount process, which unmounts and writes out the
+/// filesystem before exiting, and wait for it to exit.
+///
+/// Returns false if it is still running after `timeout`.
+fn stop_mount_process(pid: libc::pid_t, timeout: Duration) -> Result<bool> {
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ESRCH) {
+            warn!("Mount process {} is not running", pid);
+            return Ok(false);
+        }
+        return Err(anyhow!("Failed to signal mount process {}: {}", pid, err));
+    }
+    info!("Sent SIGTERM to mount process {}", pid);
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if !process_alive(pid) {
+            info!("Mount process {} exited", pid);
+            return Ok(true);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Ok(!process_alive(pid))
+}
+
+/// Unmount with fusermount3, lazily if asked
+pub(super) fn fusermount_unmount(mountpoint: &Path, lazy: bool) -> Result<()> {
+    let flags = if lazy { "-uz" } else { "-u" };
+    let status = Command::new("fusermount3")
+        .arg(flags)
+        .arg(mountpoint)
+        .status()
+        .context("Failed to run fusermount3; is the fuse3 package installed?")?;
+    if !status.success() {
+        return Err(anyhow!(
+            "fusermount3 {} {} failed ({}){}",
+            flags,
+            mountpoint.display(),
+            status,
+            if lazy { "" } else { "; pass --lazy to detach a busy mount" }
+        ));
+    }
+    Ok(())
+}
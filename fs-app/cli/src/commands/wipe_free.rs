@@ -0,0 +1,84 @@
+//! Wipe-free command for overwriting free space before a disk is retired
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use log::info;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use aegisfs::blockdev::FileBackedBlockDevice;
+use aegisfs::{DiskFs, DiskFsTrait, WipePattern};
+
+use super::mount::is_device_mounted;
+
+/// Overwrite every free data block so deleted files can't be recovered
+#[derive(Parser)]
+#[command(about = "Overwrite free space so deleted data can't be recovered")]
+pub struct WipeFreeArgs {
+    /// Device or image file to wipe
+    pub device: PathBuf,
+
+    /// Overwrite passes, alternating zeros, ones and random data; more
+    /// than one needs --secure
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    pub passes: u32,
+
+    /// Allow multi-pass wiping, which takes --passes times as long
+    #[arg(long)]
+    pub secure: bool,
+}
+
+pub async fn run(args: WipeFreeArgs) -> Result<()> {
+    if args.passes > 1 && !args.secure {
+        return Err(anyhow!("--passes {} needs --secure", args.passes));
+    }
+    if let Some(mount_point) = is_device_mounted(&args.device)? {
+        return Err(anyhow!(
+            "Device {} is mounted at {}. Unmount it before wiping free space.",
+            args.device.display(),
+            mount_point
+        ));
+    }
+
+    let device = Arc::new(
+        FileBackedBlockDevice::open(&args.device, false)
+            .await
+            .map_err(|e| anyhow!("Failed to open device: {}", e))?,
+    );
+    let disk_fs = DiskFs::open(device)
+        .await
+        .map_err(|e| anyhow!("Failed to open filesystem: {:?}", e))?;
+
+    let passes = WipePattern::alternating(args.passes as usize);
+    info!(
+        "Wiping {} free blocks of {} in {} passes",
+        disk_fs.free_blocks(),
+        args.device.display(),
+        passes.len()
+    );
+
+    // Print whole percentages only, so a large device doesn't flood the terminal
+    let shown = AtomicU64::new(u64::MAX);
+    let progress = |written: u64, total: u64| {
+        let percent = written * 100 / total.max(1);
+        if shown.swap(percent, Ordering::Relaxed) != percent {
+            eprint!("\rWiping free space: {:>3}% ({} of {} blocks)", percent, written, total);
+            let _ = std::io::stderr().flush();
+        }
+    };
+    disk_fs
+        .wipe_free_blocks(&passes, progress)
+        .await
+        .map_err(|e| anyhow!("Wipe failed: {:?}", e))?;
+    eprintln!();
+
+    println!(
+        "Overwrote {} free blocks ({:.1} MiB) in {} passes",
+        disk_fs.free_blocks(),
+        (disk_fs.free_blocks() * aegisfs::BLOCK_SIZE as u64) as f64 / (1024.0 * 1024.0),
+        passes.len()
+    );
+    Ok(())
+}
@@ -36,12 +36,69 @@ enum Commands {
     
     /// Mount an AegisFS filesystem
     Mount(commands::mount::MountArgs),
+
+    /// Unmount an AegisFS filesystem
+    Umount(commands::umount::UmountArgs),
     
     /// Manage snapshots
     Snapshot(commands::snapshot::SnapshotArgs),
     
     /// Check and repair filesystem integrity
     Scrub(commands::scrub::ScrubArgs),
+
+    /// Diagnose and fix common configuration problems
+    Doctor(commands::doctor::DoctorArgs),
+
+    /// Check and repair filesystem metadata
+    Fsck(commands::fsck::FsckArgs),
+
+    /// Show superblock details and mount history
+    Info(commands::info::InfoArgs),
+
+    /// Report inode cache and bitmap inconsistencies
+    Diagnose(commands::diagnose::DiagnoseArgs),
+
+    /// Report space usage per top-level directory
+    Du(commands::du::DuArgs),
+
+    /// Apply the journal to disk and reclaim its space
+    Checkpoint(commands::checkpoint::CheckpointArgs),
+
+    /// Manage compression dictionaries
+    Compress(commands::compress::CompressArgs),
+
+    /// Adjust filesystem parameters without reformatting
+    Tune(commands::tune::TuneArgs),
+
+    /// Query the security audit log
+    Audit(commands::audit::AuditArgs),
+
+    /// Manage project quotas
+    Quota(commands::quota::QuotaArgs),
+
+    /// Serve a filesystem over the network with 9P
+    Serve(commands::serve::ServeArgs),
+
+    /// Summarize a block I/O trace recorded by mount --record-io
+    AnalyzeIo(commands::analyze_io::AnalyzeIoArgs),
+
+    /// Check that a device meets AegisFS's requirements before formatting
+    CheckDevice(commands::check_device::CheckDeviceArgs),
+
+    /// Reclaim inode table space left behind by deleted files
+    Compact(commands::compact::CompactArgs),
+
+    /// Write an HTML or JSON health report
+    Report(commands::report::ReportArgs),
+
+    /// Overwrite free space so deleted data can't be recovered
+    WipeFree(commands::wipe_free::WipeFreeArgs),
+
+    /// Copy a filesystem to another device or image file
+    CloneDevice(commands::clone_device::CloneDeviceArgs),
+
+    /// Print, dump or apply the journal of a device
+    LogReplay(commands::log_replay::LogReplayArgs),
 }
 
 #[tokio::main]
@@ -67,7 +124,26 @@ async fn main() -> Result<()> {
     match cli.command {
         Commands::Format(args) => commands::format::run(args).await,
         Commands::Mount(args) => commands::mount::run(args).await,
+        Commands::Umount(args) => commands::umount::run(args).await,
         Commands::Snapshot(args) => commands::snapshot::run(args).await,
         Commands::Scrub(args) => commands::scrub::run(args).await,
+        Commands::Doctor(args) => commands::doctor::run(args).await,
+        Commands::Fsck(args) => commands::fsck::run(args).await,
+        Commands::Info(args) => commands::info::run(args).await,
+        Commands::Diagnose(args) => commands::diagnose::run(args).await,
+        Commands::Du(args) => commands::du::run(args).await,
+        Commands::Checkpoint(args) => commands::checkpoint::run(args).await,
+        Commands::Compress(args) => commands::compress::run(args).await,
+        Commands::Tune(args) => commands::tune::run(args).await,
+        Commands::Audit(args) => commands::audit::run(args).await,
+        Commands::Quota(args) => commands::quota::run(args).await,
+        Commands::Serve(args) => commands::serve::run(args).await,
+        Commands::AnalyzeIo(args) => commands::analyze_io::run(args).await,
+        Commands::CheckDevice(args) => commands::check_device::run(args).await,
+        Commands::Compact(args) => commands::compact::run(args).await,
+        Commands::Report(args) => commands::report::run(args).await,
+        Commands::WipeFree(args) => commands::wipe_free::run(args).await,
+        Commands::CloneDevice(args) => commands::clone_device::run(args).await,
+        Commands::LogReplay(args) => commands::log_replay::run(args).await,
     }
 } 
\ No newline at end of file
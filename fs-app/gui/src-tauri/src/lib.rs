@@ -6,6 +6,7 @@ use tauri::{State, Manager};
 // State management for filesystem instances
 struct AppState {
     instances: Mutex<Vec<FilesystemInstance>>,
+    operations: Mutex<Vec<serde_json::Value>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,7 +46,16 @@ async fn get_stats() -> Result<ApiResponse<serde_json::Value>, String> {
         "cache_hit_ratio": 0.85,
         "total_size": 1000000000,
         "used_size": 750000000,
-        "free_size": 250000000
+        "free_size": 250000000,
+        // Shaped like aegisfs::metrics::MetricsSnapshot (core `metrics` feature)
+        "latency": {
+            "read": { "count": 18234, "mean_us": 41.7, "p50_us": 32, "p99_us": 512, "max_us": 3104, "buckets": [] },
+            "write": { "count": 9120, "mean_us": 12.3, "p50_us": 16, "p99_us": 128, "max_us": 977, "buckets": [] },
+            "lookup": { "count": 40211, "mean_us": 3.1, "p50_us": 4, "p99_us": 32, "max_us": 420, "buckets": [] },
+            "create": { "count": 812, "mean_us": 88.0, "p50_us": 64, "p99_us": 1024, "max_us": 5120, "buckets": [] },
+            "flush": { "count": 1533, "mean_us": 1850.4, "p50_us": 1024, "p99_us": 16384, "max_us": 40213, "buckets": [] },
+            "inode_write": { "count": 2410, "mean_us": 702.9, "p50_us": 512, "p99_us": 8192, "max_us": 21870, "buckets": [] }
+        }
     });
     
     Ok(ApiResponse {
@@ -88,6 +98,33 @@ async fn create_snapshot(_name: String, _description: Option<String>) -> Result<
     })
 }
 
+// Command to list long-running operations (scrub, snapshot, flush, defrag)
+#[tauri::command]
+async fn list_operations(state: State<'_, AppState>) -> Result<ApiResponse<Vec<serde_json::Value>>, String> {
+    let operations = state.operations.lock().unwrap();
+    Ok(ApiResponse {
+        success: true,
+        data: Some(operations.clone()),
+        error: None,
+    })
+}
+
+// Command to cancel a long-running operation by ID
+#[tauri::command]
+async fn cancel_operation(state: State<'_, AppState>, id: u64) -> Result<ApiResponse<bool>, String> {
+    // Mock implementation; aegisfs::OperationRegistry::cancel keeps the
+    // operation listed until it has stopped
+    let mut operations = state.operations.lock().unwrap();
+    let before = operations.len();
+    operations.retain(|op| op["id"] != id);
+    let cancelled = operations.len() < before;
+    Ok(ApiResponse {
+        success: cancelled,
+        data: Some(cancelled),
+        error: if cancelled { None } else { Some(format!("No operation with ID {}", id)) },
+    })
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     env_logger::init();
@@ -105,6 +142,18 @@ pub fn run() {
                         status: "online".to_string(),
                     }
                 ]),
+                // Shaped like aegisfs::OperationInfo
+                operations: Mutex::new(vec![
+                    serde_json::json!({
+                        "id": 1,
+                        "kind": "scrub",
+                        "description": "Scrub of 262144 blocks",
+                        "done": 98304,
+                        "total": 262144,
+                        "started": { "secs_since_epoch": 1735572600, "nanos_since_epoch": 0 },
+                        "cancelling": false
+                    })
+                ]),
             });
             Ok(())
         })
@@ -118,7 +167,9 @@ pub fn run() {
             get_status,
             get_stats,
             list_snapshots,
-            create_snapshot
+            create_snapshot,
+            list_operations,
+            cancel_operation
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
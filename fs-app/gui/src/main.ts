@@ -28,11 +28,22 @@ interface ApiResponse<T> {
   error?: string;
 }
 
+interface LatencyHistogram {
+  count: number;
+  mean_us: number;
+  p50_us: number;
+  p99_us: number;
+  max_us: number;
+  buckets: number[];
+}
+
 interface IoStats {
   read_rate: number;
   write_rate: number;
   iops: number;
   cache_hit_ratio: number;
+  // Per-operation latencies, only reported when the core was built with metrics
+  latency?: Record<string, LatencyHistogram>;
 }
 
 interface SnapshotInfo {
@@ -504,6 +515,9 @@ function updateIoStats(stats: IoStats) {
   updateElementText('read-rate', `${stats.read_rate.toFixed(1)} MB/s`);
   updateElementText('write-rate', `${stats.write_rate.toFixed(1)} MB/s`);
   updateElementText('iops', stats.iops.toString());
+  if (stats.latency) {
+    updateLatencyStats(stats.latency);
+  }
 
   // Update chart
   if (ioChart && ioChart.data.labels) {
@@ -523,6 +537,24 @@ function updateIoStats(stats: IoStats) {
   }
 }
 
+function formatLatency(us: number): string {
+  return us >= 1000 ? `${(us / 1000).toFixed(1)} ms` : `${us} µs`;
+}
+
+function updateLatencyStats(latency: Record<string, LatencyHistogram>) {
+  const container = document.getElementById('latency-stats');
+  if (!container) return;
+
+  container.innerHTML = Object.entries(latency)
+    .filter(([, histogram]) => histogram.count > 0)
+    .map(([op, histogram]) => `
+      <div class="stat-row">
+        <span>${op.replace('_', ' ')}:</span>
+        <span>p50 ${formatLatency(histogram.p50_us)} · p99 ${formatLatency(histogram.p99_us)}</span>
+      </div>`)
+    .join('');
+}
+
 function displaySnapshots(snapshots: SnapshotInfo[]) {
   const timeline = document.getElementById('snapshot-timeline');
   if (!timeline) return;
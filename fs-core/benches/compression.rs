@@ -0,0 +1,149 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+use aegisfs::modules::{AdaptiveCompressor, CompressionDictionary};
+use aegisfs::{BlockDevice, FileBackedBlockDevice, BLOCK_SIZE};
+
+const FILE_COUNT: usize = 1000;
+const FILE_SIZE: usize = 100;
+
+/// Small files shaped like the metadata and config snippets that dominate
+/// real small-file workloads, each exactly `FILE_SIZE` bytes
+fn small_files() -> Vec<Vec<u8>> {
+    (0..FILE_COUNT)
+        .map(|i| {
+            let mut file = format!(
+                "[entry]\nid = {}\nowner = \"user{}\"\nmode = \"0{}44\"\nupdated = 17000{:05}\n",
+                i,
+                i % 23,
+                6 + i % 2,
+                i * 37
+            )
+            .into_bytes();
+            file.resize(FILE_SIZE, b'#');
+            file
+        })
+        .collect()
+}
+
+/// Open a dictionary on a fresh device, trained on `files` if given
+async fn dictionary(temp_dir: &TempDir, name: &str, files: Option<&[Vec<u8>]>) -> CompressionDictionary {
+    let path = temp_dir.path().join(name);
+    let device: Arc<dyn BlockDevice> =
+        Arc::new(FileBackedBlockDevice::create(&path, 16 * BLOCK_SIZE as u64).await.unwrap());
+    let dict = CompressionDictionary::open(device, 1).await.unwrap();
+    if let Some(files) = files {
+        dict.train(files).await.unwrap();
+    }
+    dict
+}
+
+fn benchmark_small_file_compression(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let files = small_files();
+    let plain = runtime.block_on(dictionary(&temp_dir, "plain.img", None));
+    let trained = runtime.block_on(dictionary(&temp_dir, "trained.img", Some(&files)));
+
+    let input: usize = files.iter().map(Vec::len).sum();
+    for (name, dict) in [("plain", &plain), ("dictionary", &trained)] {
+        let output: usize = files.iter().map(|f| dict.compress(f).unwrap().len()).sum();
+        println!(
+            "{}: {} bytes -> {} bytes, ratio {:.2}",
+            name,
+            input,
+            output,
+            input as f64 / output as f64
+        );
+    }
+
+    let mut group = c.benchmark_group("compress_1000_100_byte_files");
+    group.throughput(Throughput::Bytes(input as u64));
+    for (name, dict) in [("plain", &plain), ("dictionary", &trained)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for file in &files {
+                    black_box(dict.compress(black_box(file)).unwrap());
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+const MIXED_BLOCKS: usize = 256;
+
+/// Half text blocks, half blocks of pseudo-random bytes standing in for JPEG
+/// data, interleaved
+fn mixed_blocks() -> Vec<Vec<u8>> {
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    (0..MIXED_BLOCKS)
+        .map(|i| {
+            if i % 2 == 0 {
+                let mut block = Vec::with_capacity(BLOCK_SIZE);
+                let mut line = i;
+                while block.len() < BLOCK_SIZE {
+                    block.extend_from_slice(
+                        format!("{} GET /img/{}.jpg 200 user{}\n", 1_700_000_000 + line, line, line % 31).as_bytes(),
+                    );
+                    line += 1;
+                }
+                block.truncate(BLOCK_SIZE);
+                block
+            } else {
+                (0..BLOCK_SIZE)
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        (state >> 32) as u8
+                    })
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+/// Compress every block with Zstd, as a filesystem without sampling would
+fn zstd_all(blocks: &[Vec<u8>]) -> usize {
+    blocks
+        .iter()
+        .map(|block| zstd::bulk::compress(block, 3).unwrap().len())
+        .sum()
+}
+
+fn benchmark_mixed_block_compression(c: &mut Criterion) {
+    let blocks = mixed_blocks();
+    let adaptive = AdaptiveCompressor::new();
+    let input = blocks.len() * BLOCK_SIZE;
+
+    let adaptive_output: usize = blocks
+        .iter()
+        .map(|block| adaptive.compress_block(block).unwrap().1.len())
+        .sum();
+    for (name, output) in [("zstd", zstd_all(&blocks)), ("adaptive", adaptive_output)] {
+        println!(
+            "{}: {} bytes -> {} bytes, ratio {:.2}",
+            name,
+            input,
+            output,
+            input as f64 / output as f64
+        );
+    }
+
+    let mut group = c.benchmark_group("compress_mixed_text_and_jpeg_blocks");
+    group.throughput(Throughput::Bytes(input as u64));
+    group.bench_function("zstd", |b| b.iter(|| black_box(zstd_all(black_box(&blocks)))));
+    group.bench_function("adaptive", |b| {
+        b.iter(|| {
+            for block in &blocks {
+                black_box(adaptive.compress_block(black_box(block)).unwrap());
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_small_file_compression, benchmark_mixed_block_compression);
+criterion_main!(benches);
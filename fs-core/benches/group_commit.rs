@@ -0,0 +1,75 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+use aegisfs::modules::journaling::{JournalConfig, JournalEntryType, JournalManager};
+use aegisfs::{BlockDevice, FileBackedBlockDevice};
+
+const IMAGE_SIZE: u64 = 16 * 1024 * 1024;
+/// Transactions committed at once by every iteration
+const CONCURRENT_COMMITS: usize = 100;
+/// Journal big enough for one round of commits (three blocks each)
+const JOURNAL_BLOCKS: u64 = 512;
+
+/// Commit `CONCURRENT_COMMITS` single-entry transactions at once and time
+/// until all of them are durable
+async fn commit_round(device: Arc<dyn BlockDevice>, group_commit_interval: Option<Duration>) -> Duration {
+    let config = JournalConfig {
+        journal_size: JOURNAL_BLOCKS,
+        checkpoint_interval: u64::MAX,
+        group_commit_interval,
+        ..JournalConfig::default()
+    };
+    let mut journal = JournalManager::new(device, config);
+    journal.init().await.unwrap();
+    journal.checkpoint().await.unwrap();
+    let journal = Arc::new(journal);
+
+    let started = Instant::now();
+    let commits: Vec<_> = (0..CONCURRENT_COMMITS)
+        .map(|i| {
+            let journal = journal.clone();
+            tokio::spawn(async move {
+                let tx = journal.begin_transaction().unwrap();
+                journal
+                    .add_entry(tx, JournalEntryType::MetadataUpdate, format!("update {}", i).into_bytes())
+                    .unwrap();
+                journal.commit_transaction(tx).await.unwrap();
+            })
+        })
+        .collect();
+    for commit in commits {
+        commit.await.unwrap();
+    }
+    started.elapsed()
+}
+
+fn benchmark_concurrent_commits(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("journal.img");
+    let device: Arc<dyn BlockDevice> =
+        Arc::new(runtime.block_on(FileBackedBlockDevice::create(&path, IMAGE_SIZE)).unwrap());
+
+    let mut group = c.benchmark_group("100_concurrent_commits");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(CONCURRENT_COMMITS as u64));
+    for (name, interval) in [
+        ("sync_per_commit", None),
+        ("group_commit_1ms", Some(Duration::from_millis(1))),
+        ("group_commit_5ms", Some(Duration::from_millis(5))),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| runtime.block_on(commit_round(device.clone(), interval)))
+                    .sum()
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_concurrent_commits);
+criterion_main!(benches);
@@ -0,0 +1,254 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+use aegisfs::cache::PersistentReadCache;
+use aegisfs::format::{DirEntry, Inode};
+use aegisfs::modules::fscrypt::FSCRYPT_CONTEXT_SIZE;
+use aegisfs::{AegisFS, BlockDevice, DiskFs, DiskFsTrait, FileBackedBlockDevice, MountOptions};
+
+const IMAGE_SIZE: u64 = 64 * 1024 * 1024;
+const ROOT_INODE: u64 = 1;
+const FILES_PER_DIR: u64 = 50;
+const TARGET: &str = "/home/user/documents/report.txt";
+/// Children of the directory read by `benchmark_bulk_inode_read`
+const WIDE_DIR_CHILDREN: u64 = 1000;
+/// First child inode; above the inodes `DiskFs::open` pins in its cache
+const WIDE_DIR_FIRST_CHILD: u64 = 2048;
+
+fn new_inode(mode: u32) -> Inode {
+    Inode {
+        mode,
+        uid: 1000,
+        gid: 1000,
+        size: 0,
+        atime: 0,
+        mtime: 0,
+        ctime: 0,
+        atime_nsec: 0,
+        mtime_nsec: 0,
+        ctime_nsec: 0,
+        crtime: 0,
+        crtime_nsec: 0,
+        project_id: 0,
+        links: 1,
+        blocks: 0,
+        flags: 0,
+        osd1: [0; 4],
+        block: [0; 15],
+        generation: 0,
+        file_acl: 0,
+        dir_acl: 0,
+        faddr: 0,
+        osd2: [0; 12],
+        fscrypt_context: [0; FSCRYPT_CONTEXT_SIZE],
+        parent: 0,
+    }
+}
+
+/// Write a directory inode holding the given entries
+async fn write_dir(disk_fs: &mut DiskFs, ino: u64, parent: u64, entries: &[(String, u64)]) {
+    let mut data = Vec::new();
+    DirEntry::new(ino, ".").write_to(&mut data).unwrap();
+    DirEntry::new(parent, "..").write_to(&mut data).unwrap();
+    for (name, child) in entries {
+        DirEntry::new(*child, name).write_to(&mut data).unwrap();
+    }
+
+    let mut inode = new_inode(0o40755);
+    inode.links = 2;
+    disk_fs.write_file_data(&mut inode, 0, &data).await.unwrap();
+    inode.size = data.len() as u64;
+    disk_fs.write_inode(ino, &inode).await.unwrap();
+}
+
+/// Format an image holding a typical home directory:
+/// /home/user/{documents,downloads,pictures,music,videos,.config}/file-N.txt
+async fn build_home_image(path: &Path) {
+    std::fs::File::create(path).unwrap().set_len(IMAGE_SIZE).unwrap();
+    let device: Arc<dyn BlockDevice> = Arc::new(FileBackedBlockDevice::open(path, false).await.unwrap());
+    DiskFs::format(device.clone(), IMAGE_SIZE, Some("home")).await.unwrap();
+    let mut disk_fs = DiskFs::open(device).await.unwrap();
+
+    let mut next_ino = ROOT_INODE + 1;
+    let mut alloc = || {
+        next_ino += 1;
+        next_ino - 1
+    };
+    let home = alloc();
+    let user = alloc();
+
+    let mut user_entries = Vec::new();
+    for dir_name in ["documents", "downloads", "pictures", "music", "videos", ".config"] {
+        let dir = alloc();
+        let mut files = Vec::new();
+        for i in 0..FILES_PER_DIR {
+            let name = if dir_name == "documents" && i == 0 {
+                "report.txt".to_string()
+            } else {
+                format!("file-{}.txt", i)
+            };
+            let ino = alloc();
+            let mut inode = new_inode(0o100644);
+            let content = format!("contents of {}/{}\n", dir_name, name);
+            disk_fs.write_file_data(&mut inode, 0, content.as_bytes()).await.unwrap();
+            disk_fs.write_inode(ino, &inode).await.unwrap();
+            files.push((name, ino));
+        }
+        write_dir(&mut disk_fs, dir, user, &files).await;
+        user_entries.push((dir_name.to_string(), dir));
+    }
+
+    write_dir(&mut disk_fs, user, home, &user_entries).await;
+    write_dir(&mut disk_fs, home, ROOT_INODE, &[("user".to_string(), user)]).await;
+    write_dir(&mut disk_fs, ROOT_INODE, ROOT_INODE, &[("home".to_string(), home)]).await;
+
+    // Mark every inode used above as allocated
+    let used = alloc();
+    let mut inode_bitmap = vec![0u8; disk_fs.superblock().inode_count.div_ceil(8) as usize];
+    for ino in 0..used {
+        inode_bitmap[(ino / 8) as usize] |= 1 << (ino % 8);
+    }
+    disk_fs.writeback_bitmaps(&inode_bitmap).await.unwrap();
+}
+
+/// Resolve a path, loading directories deeper than the preload depth on demand
+async fn resolve(fs: &AegisFS, path: &str) -> u64 {
+    let mut ino = ROOT_INODE;
+    for name in path.split('/').filter(|c| !c.is_empty()) {
+        ino = match fs.cached_lookup(ino, name) {
+            Some(child) => child,
+            None => {
+                fs.load_directory(ino).await.unwrap();
+                fs.cached_lookup(ino, name).expect("path component missing")
+            }
+        };
+    }
+    ino
+}
+
+/// Mount the image and time how long until the target file is resolved,
+/// preloading and then recording `hints` if given
+async fn first_access(image: &Path, preload_depth: u8, hints: Option<&PersistentReadCache>) -> Duration {
+    let started = Instant::now();
+    let options = MountOptions {
+        preload_depth,
+        cache_hints: hints.cloned(),
+        ..Default::default()
+    };
+    let fs = AegisFS::from_device_with_options(image, options).await.unwrap();
+    criterion::black_box(resolve(&fs, TARGET).await);
+    let elapsed = started.elapsed();
+    fs.save_cache_hints().unwrap();
+    drop(fs);
+
+    // The filesystem is never unmounted through FUSE, so clear the dirty flag
+    let device: Arc<dyn BlockDevice> = Arc::new(FileBackedBlockDevice::open(image, false).await.unwrap());
+    let mut disk_fs = DiskFs::open(device).await.unwrap();
+    disk_fs.mark_clean().await.unwrap();
+
+    elapsed
+}
+
+fn benchmark_time_to_first_access(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let image = temp_dir.path().join("home.img");
+    runtime.block_on(build_home_image(&image));
+
+    let mut group = c.benchmark_group("time_to_first_file_access");
+    group.sample_size(20);
+    for depth in 1..=3u8 {
+        group.bench_with_input(BenchmarkId::new("preload_depth", depth), &depth, |b, &depth| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| runtime.block_on(first_access(&image, depth, None)))
+                    .sum()
+            });
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_cache_hints(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let image = temp_dir.path().join("home.img");
+    runtime.block_on(build_home_image(&image));
+
+    // One mount to record which blocks resolving the target touches
+    let hints = PersistentReadCache::for_device(&image);
+    runtime.block_on(first_access(&image, 1, Some(&hints)));
+
+    let mut group = c.benchmark_group("time_to_first_file_access_after_restart");
+    group.sample_size(20);
+    for (name, hints) in [("cold", None), ("cache_hints", Some(&hints))] {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| runtime.block_on(first_access(&image, 1, hints)))
+                    .sum()
+            });
+        });
+    }
+    group.finish();
+}
+
+/// Format an image whose root holds `WIDE_DIR_CHILDREN` files and return
+/// their inode numbers
+async fn build_wide_dir_image(path: &Path) -> Vec<u64> {
+    std::fs::File::create(path).unwrap().set_len(IMAGE_SIZE).unwrap();
+    let device: Arc<dyn BlockDevice> = Arc::new(FileBackedBlockDevice::open(path, false).await.unwrap());
+    DiskFs::format(device.clone(), IMAGE_SIZE, Some("wide")).await.unwrap();
+    let mut disk_fs = DiskFs::open(device).await.unwrap();
+
+    let mut entries = Vec::new();
+    for i in 0..WIDE_DIR_CHILDREN {
+        let ino = WIDE_DIR_FIRST_CHILD + i;
+        disk_fs.write_inode(ino, &new_inode(0o100644)).await.unwrap();
+        entries.push((format!("file-{}.txt", i), ino));
+    }
+    write_dir(&mut disk_fs, ROOT_INODE, ROOT_INODE, &entries).await;
+    entries.into_iter().map(|(_, ino)| ino).collect()
+}
+
+/// Time reading every child inode on a freshly opened filesystem
+async fn read_children(image: &Path, inos: &[u64], bulk: bool) -> Duration {
+    let device: Arc<dyn BlockDevice> = Arc::new(FileBackedBlockDevice::open(image, true).await.unwrap());
+    let disk_fs = DiskFs::open(device).await.unwrap();
+
+    let started = Instant::now();
+    if bulk {
+        criterion::black_box(disk_fs.bulk_read_inodes(inos).await.unwrap());
+    } else {
+        for &ino in inos {
+            criterion::black_box(disk_fs.read_inode(ino).await.unwrap());
+        }
+    }
+    started.elapsed()
+}
+
+fn benchmark_bulk_inode_read(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let image = temp_dir.path().join("wide.img");
+    let inos = runtime.block_on(build_wide_dir_image(&image));
+
+    let mut group = c.benchmark_group("read_1000_child_inodes");
+    group.sample_size(20);
+    for (name, bulk) in [("per_inode", false), ("bulk", true)] {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| runtime.block_on(read_children(&image, &inos, bulk)))
+                    .sum()
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_time_to_first_access, benchmark_cache_hints, benchmark_bulk_inode_read);
+criterion_main!(benches);
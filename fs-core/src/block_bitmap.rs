@@ -4,6 +4,7 @@
 //! to replace the naive static counter approach. Supports block allocation,
 //! deallocation, and persistence across mounts.
 
+use std::collections::BTreeSet;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use thiserror::Error;
@@ -28,6 +29,9 @@ pub enum BlockBitmapError {
     NoFreeBlocks,
     #[error("Bitmap is full")]
     BitmapFull,
+    /// A delta does not fit the bitmap it is applied to
+    #[error("Invalid bitmap delta: {0}")]
+    InvalidDelta(String),
 }
 
 /// Block bitmap for tracking allocated/free data blocks
@@ -42,6 +46,11 @@ pub struct BlockBitmap {
     data_blocks_start: u64,
     /// Number of data blocks
     data_blocks_count: u64,
+    /// Freed blocks not yet handed to `BlockDevice::discard`
+    pending_discards: BTreeSet<u64>,
+    /// Freed blocks to collect before discarding them together; 0 never
+    /// discards
+    discard_batch_size: usize,
 }
 
 impl BlockBitmap {
@@ -56,6 +65,8 @@ impl BlockBitmap {
             free_blocks: AtomicU64::new(data_blocks_count),
             data_blocks_start,
             data_blocks_count,
+            pending_discards: BTreeSet::new(),
+            discard_batch_size: 0,
         }
     }
 
@@ -86,19 +97,7 @@ impl BlockBitmap {
             }
         }
         
-        // Count free blocks by scanning the bitmap
-        let mut free_count = 0;
-        for (byte_idx, &byte) in bitmap.iter().enumerate() {
-            for bit in 0..8 {
-                let block_idx = (byte_idx * 8 + bit) as u64;
-                if block_idx >= layout.data_blocks_count {
-                    break;
-                }
-                if (byte & (1 << bit)) == 0 {
-                    free_count += 1;
-                }
-            }
-        }
+        let free_count = count_free(&bitmap, layout.data_blocks_count);
         
         log::info!(
             "BLOCK_BITMAP: Loaded from disk - {} free blocks out of {} total data blocks",
@@ -112,6 +111,8 @@ impl BlockBitmap {
             free_blocks: AtomicU64::new(free_count),
             data_blocks_start: layout.data_blocks,
             data_blocks_count: layout.data_blocks_count,
+            pending_discards: BTreeSet::new(),
+            discard_batch_size: 0,
         })
     }
 
@@ -178,9 +179,10 @@ impl BlockBitmap {
                             );
                             *byte |= 1 << bit;
                             self.free_blocks.fetch_sub(1, Ordering::Relaxed);
+                            self.pending_discards.remove(&block_idx);
                             
                             let actual_block_num = self.data_blocks_start + block_idx;
-                            log::info!(
+                            log::trace!(
                                 "BlockBitmap::allocate: Successfully allocated data block {} (index {}), {} free remaining",
                                 actual_block_num,
                                 block_idx,
@@ -200,6 +202,36 @@ impl BlockBitmap {
         None
     }
 
+    /// Allocate a free block, searching from `start` and wrapping around.
+    ///
+    /// Used to keep allocations within a preferred block group.
+    pub fn allocate_from(&mut self, start: u64) -> Option<u64> {
+        if self.free_blocks.load(Ordering::Relaxed) == 0 {
+            log::warn!("BlockBitmap::allocate_from: No free blocks available");
+            return None;
+        }
+
+        let start = if start < self.data_blocks_count { start } else { 0 };
+        let candidates = (start..self.data_blocks_count).chain(0..start);
+        for block_idx in candidates {
+            let byte_idx = (block_idx / 8) as usize;
+            let bit = (block_idx % 8) as u8;
+            if (self.bitmap[byte_idx] & (1 << bit)) == 0 {
+                self.bitmap[byte_idx] |= 1 << bit;
+                self.free_blocks.fetch_sub(1, Ordering::Relaxed);
+                self.pending_discards.remove(&block_idx);
+                log::debug!(
+                    "BlockBitmap::allocate_from: Allocated block index {} (searched from {})",
+                    block_idx,
+                    start
+                );
+                return Some(block_idx);
+            }
+        }
+
+        None
+    }
+
     /// Free a block
     pub fn free(&mut self, block_idx: u64) -> Result<(), BlockBitmapError> {
         if block_idx >= self.data_blocks_count {
@@ -220,9 +252,12 @@ impl BlockBitmap {
         
         self.bitmap[byte_idx] &= !(1 << bit);
         self.free_blocks.fetch_add(1, Ordering::Relaxed);
+        if self.discard_batch_size > 0 {
+            self.pending_discards.insert(block_idx);
+        }
         
         let actual_block_num = self.data_blocks_start + block_idx;
-        log::info!(
+        log::trace!(
             "BlockBitmap::free: Freed data block {} (index {}), {} free total",
             actual_block_num,
             block_idx,
@@ -268,9 +303,32 @@ impl BlockBitmap {
 
         self.bitmap[byte_idx] |= 1 << bit;
         self.free_blocks.fetch_sub(1, Ordering::Relaxed);
+        self.pending_discards.remove(&block_idx);
         Ok(())
     }
 
+    /// Collect freed blocks for discarding in batches of `size`; 0 stops
+    /// discarding and forgets the blocks collected so far
+    pub fn set_discard_batch_size(&mut self, size: usize) {
+        self.discard_batch_size = size;
+        if size == 0 {
+            self.pending_discards.clear();
+        }
+    }
+
+    /// Take the freed blocks waiting to be discarded, in ascending order,
+    /// once there are a whole batch of them or whenever `all` is set.
+    ///
+    /// A block allocated again before this is called is no longer
+    /// included, so discarding what is returned can't destroy live data as
+    /// long as nothing is allocated until the discard completes.
+    pub fn take_discards(&mut self, all: bool) -> Vec<u64> {
+        if self.pending_discards.is_empty() || (!all && self.pending_discards.len() < self.discard_batch_size) {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.pending_discards).into_iter().collect()
+    }
+
     /// Get the number of free blocks
     pub fn free_blocks(&self) -> u64 {
         self.free_blocks.load(Ordering::Relaxed)
@@ -295,6 +353,92 @@ impl BlockBitmap {
     pub fn bitmap_data(&self) -> &[u8] {
         &self.bitmap
     }
+
+    /// Encode the changes that turn `baseline` into this bitmap.
+    ///
+    /// The two bitmaps are XORed and only the runs of changed bytes are
+    /// kept: a little-endian u64 bitmap length in bytes, then for every run
+    /// a u32 count of unchanged bytes to skip, a u32 run length and the
+    /// run's XORed bytes. Unchanged bitmaps encode to the length alone.
+    pub fn serialize_delta(&self, baseline: &BlockBitmap) -> Vec<u8> {
+        let len = self.bitmap.len();
+        let changed = |i: usize| self.bitmap[i] ^ baseline.bitmap.get(i).copied().unwrap_or(0);
+
+        let mut delta = Vec::new();
+        delta.extend_from_slice(&(len as u64).to_le_bytes());
+        let mut pos = 0;
+        while pos < len {
+            let Some(start) = (pos..len).find(|&i| changed(i) != 0) else {
+                break;
+            };
+            let end = (start..len).find(|&i| changed(i) == 0).unwrap_or(len);
+            delta.extend_from_slice(&((start - pos) as u32).to_le_bytes());
+            delta.extend_from_slice(&((end - start) as u32).to_le_bytes());
+            delta.extend((start..end).map(changed));
+            pos = end;
+        }
+        delta
+    }
+
+    /// Apply a delta from [`serialize_delta`](Self::serialize_delta) made
+    /// against this bitmap's current contents.
+    ///
+    /// The delta is checked in full before anything changes, so a bad one
+    /// leaves the bitmap as it was.
+    pub fn apply_delta(&mut self, delta: &[u8]) -> Result<(), BlockBitmapError> {
+        let invalid = |msg: String| BlockBitmapError::InvalidDelta(msg);
+        let (header, mut runs) = delta
+            .split_first_chunk::<8>()
+            .ok_or_else(|| invalid(format!("{} bytes is too short", delta.len())))?;
+        let len = u64::from_le_bytes(*header);
+        if len != self.bitmap.len() as u64 {
+            return Err(invalid(format!(
+                "made for a {} byte bitmap, this one is {} bytes",
+                len,
+                self.bitmap.len()
+            )));
+        }
+
+        let mut patches = Vec::new();
+        let mut pos = 0usize;
+        while !runs.is_empty() {
+            let (run_header, rest) = runs
+                .split_first_chunk::<8>()
+                .ok_or_else(|| invalid("truncated run header".to_string()))?;
+            let skip = u32::from_le_bytes(run_header[..4].try_into().unwrap()) as usize;
+            let count = u32::from_le_bytes(run_header[4..].try_into().unwrap()) as usize;
+            let start = pos + skip;
+            if start + count > self.bitmap.len() || count > rest.len() {
+                return Err(invalid(format!("run of {} bytes at {} is out of range", count, start)));
+            }
+            patches.push((start, &rest[..count]));
+            pos = start + count;
+            runs = &rest[count..];
+        }
+
+        for (start, bytes) in patches {
+            for (byte, xor) in self.bitmap[start..start + bytes.len()].iter_mut().zip(bytes) {
+                *byte ^= xor;
+            }
+        }
+        let bitmap = &self.bitmap;
+        self.pending_discards
+            .retain(|&block_idx| bitmap[(block_idx / 8) as usize] & (1 << (block_idx % 8)) == 0);
+        self.free_blocks
+            .store(count_free(&self.bitmap, self.data_blocks_count), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Count the clear bits among the first `blocks` bits of a bitmap
+fn count_free(bitmap: &[u8], blocks: u64) -> u64 {
+    let full_bytes = (blocks / 8) as usize;
+    let mut used: u64 = bitmap[..full_bytes].iter().map(|b| b.count_ones() as u64).sum();
+    let tail_bits = blocks % 8;
+    if tail_bits > 0 {
+        used += (bitmap[full_bytes] & ((1u8 << tail_bits) - 1)).count_ones() as u64;
+    }
+    blocks - used
 }
 
 impl std::fmt::Debug for BlockBitmap {
@@ -312,6 +456,7 @@ impl std::fmt::Debug for BlockBitmap {
 mod tests {
     use super::*;
     use crate::blockdev::FileBackedBlockDevice;
+    use proptest::prelude::*;
     use tempfile::tempdir;
 
     #[test]
@@ -346,6 +491,89 @@ mod tests {
         assert!(!bitmap.is_allocated(block));
     }
 
+    #[test]
+    fn test_discards_wait_for_a_batch_and_skip_reused_blocks() {
+        let mut bitmap = BlockBitmap::new(1024, 100, 900);
+        let blocks: Vec<u64> = (0..4).map(|_| bitmap.allocate().unwrap()).collect();
+
+        // Off by default
+        bitmap.free(blocks[0]).unwrap();
+        assert!(bitmap.take_discards(true).is_empty());
+
+        bitmap.set_discard_batch_size(3);
+        bitmap.free(blocks[1]).unwrap();
+        bitmap.free(blocks[2]).unwrap();
+        assert!(bitmap.take_discards(false).is_empty());
+
+        // Reallocated blocks hold live data again and must not be discarded
+        bitmap.set_allocated(blocks[1]).unwrap();
+        bitmap.free(blocks[3]).unwrap();
+        assert!(bitmap.take_discards(false).is_empty());
+        assert_eq!(bitmap.take_discards(true), vec![blocks[2], blocks[3]]);
+        assert!(bitmap.take_discards(true).is_empty());
+    }
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let mut baseline = BlockBitmap::new(1024, 100, 900);
+        let mut target = BlockBitmap::new(1024, 100, 900);
+        for block in [0, 1, 2, 500, 899] {
+            target.set_allocated(block).unwrap();
+        }
+        baseline.set_allocated(300).unwrap();
+
+        let delta = target.serialize_delta(&baseline);
+        // The header and four one-byte runs: bytes 0, 37, 62 and 112
+        assert_eq!(delta.len(), 8 + 4 * (8 + 1));
+        baseline.apply_delta(&delta).unwrap();
+        assert_eq!(baseline.bitmap_data(), target.bitmap_data());
+        assert_eq!(baseline.free_blocks(), 895);
+
+        // Unchanged bitmaps send only the header
+        assert_eq!(target.serialize_delta(&baseline).len(), 8);
+    }
+
+    #[test]
+    fn test_bad_delta_leaves_bitmap_alone() {
+        let mut bitmap = BlockBitmap::new(1024, 100, 900);
+        let mut other = BlockBitmap::new(1024, 100, 900);
+        other.set_allocated(7).unwrap();
+        let delta = other.serialize_delta(&bitmap);
+
+        assert!(bitmap.apply_delta(&delta[..delta.len() - 1]).is_err());
+        assert!(bitmap.apply_delta(&delta[..4]).is_err());
+        let smaller = BlockBitmap::new(1024, 100, 800);
+        assert!(bitmap.apply_delta(&smaller.serialize_delta(&smaller)).is_err());
+        assert!(!bitmap.is_allocated(7));
+        assert_eq!(bitmap.free_blocks(), 900);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_apply_delta_reproduces_target(
+            (count, a, b) in (1u64..2048).prop_flat_map(|count| {
+                let len = count.div_ceil(8) as usize;
+                (Just(count), prop::collection::vec(any::<u8>(), len), prop::collection::vec(any::<u8>(), len))
+            })
+        ) {
+            let bitmap = |bytes: &[u8]| {
+                let mut bitmap = BlockBitmap::new(count, 0, count);
+                for block in 0..count {
+                    if bytes[(block / 8) as usize] & (1 << (block % 8)) != 0 {
+                        bitmap.set_allocated(block).unwrap();
+                    }
+                }
+                bitmap
+            };
+            let (mut a, b) = (bitmap(&a), bitmap(&b));
+
+            let delta = b.serialize_delta(&a);
+            a.apply_delta(&delta).unwrap();
+            prop_assert_eq!(a.bitmap_data(), b.bitmap_data());
+            prop_assert_eq!(a.free_blocks(), b.free_blocks());
+        }
+    }
+
     #[test]
     fn test_block_reuse() {
         let mut bitmap = BlockBitmap::new(1024, 100, 900);
@@ -21,6 +21,23 @@ pub enum BlockDeviceError {
     DeviceNotOpen,
     #[error("Device is already closed")]
     DeviceClosed,
+    /// Another process holds the device's lock
+    #[error("Device {0} is already opened read-write by another process")]
+    Locked(String),
+    /// The devices combined into one don't share a block size
+    #[error("Backing devices have mismatched block sizes: {expected} and {found}")]
+    BlockSizeMismatch {
+        /// Block size of the first device
+        expected: usize,
+        /// Block size of the device that differs
+        found: usize,
+    },
+    /// A striped device was given no devices or a zero stripe width
+    #[error("Invalid stripe layout: {0}")]
+    InvalidStripe(String),
+    /// A region lies outside its device or is not block aligned
+    #[error("Invalid device region: {0}")]
+    InvalidRegion(String),
 }
 
 /// Result type for block device operations
@@ -46,6 +63,33 @@ pub trait BlockDevice: Send + Sync + 'static {
     /// Sync any pending writes to the device
     async fn sync(&self) -> Result<()>;
 
+    /// Release the storage behind `count` blocks starting at `block_num`.
+    ///
+    /// The blocks read back as zeros afterwards. Returns how many bytes of
+    /// backing storage were freed; devices that can't deallocate leave the
+    /// blocks alone and return 0.
+    async fn punch_hole(&self, _block_num: u64, _count: u64) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Tell the device that `count` blocks starting at `block_num` hold
+    /// nothing anymore, so an SSD can reclaim the cells behind them (TRIM).
+    ///
+    /// Unlike `punch_hole` nothing is promised about what the blocks read
+    /// back as afterwards. Devices that can't discard do nothing.
+    async fn discard(&self, _block_num: u64, _count: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Discard every block of the device, as before formatting over an
+    /// earlier filesystem.
+    ///
+    /// Returns the bytes freed, like `punch_hole`; 0 means the device may
+    /// not have discarded anything and still hold its old contents.
+    async fn trim_all(&self) -> Result<u64> {
+        self.punch_hole(0, self.block_count()).await
+    }
+
     /// Close the device
     async fn close(&mut self) -> Result<()>;
 
@@ -0,0 +1,241 @@
+//! Block device mirrored over two backing devices (RAID1)
+
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use super::{BlockDevice, BlockDeviceError, Result};
+
+/// One side of a mirror
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorCopy {
+    /// The first backing device
+    Primary,
+    /// The second backing device
+    Secondary,
+}
+
+/// Result of checking both copies of a mirrored block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealOutcome {
+    /// Both copies are good
+    Intact,
+    /// This copy was bad and has been rewritten from the other one
+    Healed(MirrorCopy),
+    /// Neither copy is good
+    Unrepairable,
+}
+
+/// A block device that keeps every block on two devices
+///
+/// Writes go to both devices. Reads are served by the primary and fall back
+/// to the secondary when the primary returns an I/O error. Silent corruption
+/// can't be detected here; `verify_and_heal` takes a validity check from the
+/// checksum layer to decide which copy to trust.
+pub struct MirrorBlockDevice {
+    primary: Arc<dyn BlockDevice>,
+    secondary: Arc<dyn BlockDevice>,
+}
+
+impl MirrorBlockDevice {
+    /// Mirror `primary` onto `secondary`
+    pub fn new(primary: Arc<dyn BlockDevice>, secondary: Arc<dyn BlockDevice>) -> Result<Self> {
+        if primary.block_size() != secondary.block_size() {
+            return Err(BlockDeviceError::BlockSizeMismatch {
+                expected: primary.block_size(),
+                found: secondary.block_size(),
+            });
+        }
+        if primary.block_count() != secondary.block_count() {
+            log::warn!(
+                "MIRROR: Devices differ in size ({} and {} blocks), using the smaller",
+                primary.block_count(),
+                secondary.block_count()
+            );
+        }
+        Ok(Self { primary, secondary })
+    }
+
+    fn device(&self, copy: MirrorCopy) -> &Arc<dyn BlockDevice> {
+        match copy {
+            MirrorCopy::Primary => &self.primary,
+            MirrorCopy::Secondary => &self.secondary,
+        }
+    }
+
+    /// Read one copy of a block without falling back to the other
+    pub async fn read_copy(&self, copy: MirrorCopy, block_num: u64, buf: &mut [u8]) -> Result<()> {
+        if block_num >= self.block_count() {
+            return Err(BlockDeviceError::InvalidBlockNumber(block_num));
+        }
+        self.device(copy).read_block(block_num, buf).await
+    }
+
+    /// Check both copies of a block with `is_good` and repair a bad copy
+    /// from the good one.
+    ///
+    /// A copy that can't be read counts as bad.
+    pub async fn verify_and_heal<F>(&self, block_num: u64, is_good: F) -> Result<HealOutcome>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let block_size = self.block_size();
+        let mut primary = vec![0u8; block_size];
+        let mut secondary = vec![0u8; block_size];
+        let primary_good =
+            self.copy_is_good(MirrorCopy::Primary, block_num, &mut primary, &is_good).await?;
+        let secondary_good =
+            self.copy_is_good(MirrorCopy::Secondary, block_num, &mut secondary, &is_good).await?;
+
+        let (bad, good) = match (primary_good, secondary_good) {
+            (true, true) => return Ok(HealOutcome::Intact),
+            (false, false) => {
+                log::error!("MIRROR: Both copies of block {} are bad", block_num);
+                return Ok(HealOutcome::Unrepairable);
+            }
+            (true, false) => (MirrorCopy::Secondary, primary),
+            (false, true) => (MirrorCopy::Primary, secondary),
+        };
+
+        self.device(bad).write_block(block_num, &good).await?;
+        log::warn!("MIRROR: Rewrote {:?} copy of block {} from the good copy", bad, block_num);
+        Ok(HealOutcome::Healed(bad))
+    }
+
+    async fn copy_is_good<F>(
+        &self,
+        copy: MirrorCopy,
+        block_num: u64,
+        buf: &mut [u8],
+        is_good: &F,
+    ) -> Result<bool>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        match self.read_copy(copy, block_num, buf).await {
+            Ok(()) => Ok(is_good(buf)),
+            Err(BlockDeviceError::Io(e)) => {
+                log::warn!("MIRROR: Failed to read {:?} copy of block {}: {}", copy, block_num, e);
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl BlockDevice for MirrorBlockDevice {
+    async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<()> {
+        match self.read_copy(MirrorCopy::Primary, block_num, buf).await {
+            Err(BlockDeviceError::Io(e)) => {
+                log::warn!(
+                    "MIRROR: Primary read of block {} failed ({}), reading secondary",
+                    block_num,
+                    e
+                );
+                self.read_copy(MirrorCopy::Secondary, block_num, buf).await
+            }
+            result => result,
+        }
+    }
+
+    async fn write_block(&self, block_num: u64, data: &[u8]) -> Result<()> {
+        if block_num >= self.block_count() {
+            return Err(BlockDeviceError::InvalidBlockNumber(block_num));
+        }
+        let (primary, secondary) = tokio::join!(
+            self.primary.write_block(block_num, data),
+            self.secondary.write_block(block_num, data)
+        );
+        primary.and(secondary)
+    }
+
+    fn block_count(&self) -> u64 {
+        self.primary.block_count().min(self.secondary.block_count())
+    }
+
+    fn block_size(&self) -> usize {
+        self.primary.block_size()
+    }
+
+    async fn sync(&self) -> Result<()> {
+        let (primary, secondary) = tokio::join!(self.primary.sync(), self.secondary.sync());
+        primary.and(secondary)
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        // Devices shared with someone else are only synced; they close them
+        for device in [&mut self.primary, &mut self.secondary] {
+            match Arc::get_mut(device) {
+                Some(device) => device.close().await?,
+                None => device.sync().await?,
+            }
+        }
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.primary.is_read_only() || self.secondary.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::{FileBackedBlockDevice, BLOCK_SIZE};
+    use std::io;
+    use tempfile::tempdir;
+
+    /// Device whose reads always fail
+    struct Unreadable(FileBackedBlockDevice);
+
+    #[async_trait]
+    impl BlockDevice for Unreadable {
+        async fn read_block(&self, _block_num: u64, _buf: &mut [u8]) -> Result<()> {
+            Err(io::Error::other("media error").into())
+        }
+        async fn write_block(&self, block_num: u64, data: &[u8]) -> Result<()> {
+            self.0.write_block(block_num, data).await
+        }
+        fn block_count(&self) -> u64 {
+            self.0.block_count()
+        }
+        async fn sync(&self) -> Result<()> {
+            self.0.sync().await
+        }
+        async fn close(&mut self) -> Result<()> {
+            self.0.close().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mirror_reads_fall_back_to_secondary() {
+        let temp_dir = tempdir().unwrap();
+        let size = BLOCK_SIZE as u64 * 8;
+        let primary = FileBackedBlockDevice::create(temp_dir.path().join("a.bin"), size)
+            .await
+            .unwrap();
+        let secondary: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(temp_dir.path().join("b.bin"), size)
+                .await
+                .unwrap(),
+        );
+        let mirror = MirrorBlockDevice::new(Arc::new(Unreadable(primary)), secondary.clone()).unwrap();
+
+        mirror.write_block(3, &[0x5A; BLOCK_SIZE]).await.unwrap();
+        let mut buf = [0u8; BLOCK_SIZE];
+        mirror.read_block(3, &mut buf).await.unwrap();
+        assert_eq!(buf, [0x5A; BLOCK_SIZE]);
+
+        // An unreadable copy counts as bad and is rewritten from the other
+        let outcome = mirror.verify_and_heal(3, |_| true).await.unwrap();
+        assert_eq!(outcome, HealOutcome::Healed(MirrorCopy::Primary));
+
+        // Both copies failing verification can't be repaired
+        let outcome = mirror.verify_and_heal(3, |_| false).await.unwrap();
+        assert_eq!(outcome, HealOutcome::Unrepairable);
+        assert!(matches!(
+            mirror.read_block(8, &mut buf).await,
+            Err(BlockDeviceError::InvalidBlockNumber(8))
+        ));
+    }
+}
@@ -1,6 +1,10 @@
 //! Block device I/O operations for AegisFS
 
 mod blockdev_trait;
+mod mirror;
+mod striped;
+mod throttled;
+mod trace;
 
 use async_trait::async_trait;
 use std::path::{Path, PathBuf};
@@ -10,12 +14,18 @@ use tokio::sync::Mutex;
 
 // Re-export the block device trait and related types
 pub use self::blockdev_trait::{BlockDevice, BlockDeviceError, Result, BLOCK_SIZE};
+pub use self::mirror::{HealOutcome, MirrorBlockDevice, MirrorCopy};
+pub use self::striped::StripedBlockDevice;
+pub use self::throttled::ThrottledBlockDevice;
+pub use self::trace::{read_trace, BlockRange, IoOp, IoRecord, IoTracer, TraceSummary, TracingBlockDevice};
 
 /// A block device that is backed by a file on the filesystem
 #[derive(Debug)]
 pub struct FileBackedBlockDevice {
     file: Mutex<Option<File>>,
     path: PathBuf,
+    /// Byte offset of block 0 within the file
+    offset: u64,
     size: u64,
     block_count: u64,
     read_only: bool,
@@ -32,6 +42,7 @@ impl FileBackedBlockDevice {
             .truncate(true)
             .open(&path)
             .await?;
+        Self::lock_exclusive(&file, &path)?;
 
         // Set file length
         file.set_len(size).await?;
@@ -41,12 +52,220 @@ impl FileBackedBlockDevice {
         Ok(Self {
             file: Mutex::new(Some(file)),
             path,
+            offset: 0,
             size,
             block_count,
             read_only: false,
         })
     }
 
+    /// Create a zeroed device of `size` bytes starting `offset` bytes into
+    /// a file, keeping the bytes in front of it, such as the partition
+    /// table of a whole-disk image. Anything after the new region is cut
+    /// off. `offset` must be a multiple of the block size.
+    pub async fn create_at(path: impl AsRef<Path>, offset: u64, size: u64) -> Result<Self> {
+        Self::check_offset(offset)?;
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await?;
+        Self::lock_exclusive(&file, &path)?;
+
+        // Cut back to the offset first so the region reads back as zeros
+        file.set_len(offset).await?;
+        file.set_len(offset + size).await?;
+
+        Ok(Self {
+            file: Mutex::new(Some(file)),
+            path,
+            offset,
+            size,
+            block_count: size / BLOCK_SIZE as u64,
+            read_only: false,
+        })
+    }
+
+    /// Refuse an offset that would leave blocks straddling two host blocks
+    fn check_offset(offset: u64) -> Result<()> {
+        if !offset.is_multiple_of(BLOCK_SIZE as u64) {
+            return Err(BlockDeviceError::InvalidRegion(format!(
+                "offset {} is not a multiple of the {}-byte block size",
+                offset, BLOCK_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    /// Take an advisory exclusive lock on the backing file.
+    ///
+    /// The lock is released when the file is closed, including when the
+    /// process dies, so it never goes stale.
+    #[cfg(unix)]
+    fn lock_exclusive(file: &File, path: &Path) -> Result<()> {
+        Self::flock(file, path, libc::LOCK_EX | libc::LOCK_NB)
+    }
+
+    #[cfg(not(unix))]
+    fn lock_exclusive(_file: &File, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Apply a `flock` operation to the backing file, reporting a lock held
+    /// by someone else as [`BlockDeviceError::Locked`]
+    #[cfg(unix)]
+    fn flock(file: &File, path: &Path, operation: libc::c_int) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let result = unsafe { libc::flock(file.as_raw_fd(), operation) };
+        if result == -1 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Err(BlockDeviceError::Locked(path.display().to_string()));
+            }
+            return Err(BlockDeviceError::Io(err));
+        }
+        Ok(())
+    }
+
+    /// Keep anyone from opening the device read-write until it is unfrozen
+    /// or closed, so what is read from it stays consistent, e.g. while it
+    /// is copied.
+    ///
+    /// A device opened read-write already holds its exclusive lock, so this
+    /// only changes anything for a read-only one. It fails with
+    /// [`BlockDeviceError::Locked`] while a mount or another tool has the
+    /// device open read-write.
+    #[cfg(unix)]
+    pub async fn freeze(&self) -> Result<()> {
+        let file_guard = self.file.lock().await;
+        let file = file_guard.as_ref().ok_or(BlockDeviceError::DeviceClosed)?;
+        if self.read_only {
+            Self::flock(file, &self.path, libc::LOCK_SH | libc::LOCK_NB)?;
+        }
+        Ok(())
+    }
+
+    /// Let the device be opened read-write again after [`Self::freeze`]
+    #[cfg(unix)]
+    pub async fn unfreeze(&self) -> Result<()> {
+        let file_guard = self.file.lock().await;
+        let file = file_guard.as_ref().ok_or(BlockDeviceError::DeviceClosed)?;
+        if self.read_only {
+            Self::flock(file, &self.path, libc::LOCK_UN)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub async fn freeze(&self) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub async fn unfreeze(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Deallocate a byte range of the backing file, keeping its size.
+    ///
+    /// Returns the bytes freed, measured from the file's allocated blocks
+    /// for regular files. Block devices don't report that, so the whole
+    /// range is counted once the discard succeeds. Filesystems without hole
+    /// punching free nothing.
+    #[cfg(target_os = "linux")]
+    fn punch_range(file: &File, offset: u64, len: u64) -> Result<u64> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = file.as_raw_fd();
+        let allocated = |fd| -> Result<Option<u64>> {
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            if unsafe { libc::fstat(fd, &mut stat) } == -1 {
+                return Err(BlockDeviceError::Io(std::io::Error::last_os_error()));
+            }
+            if stat.st_mode & libc::S_IFMT != libc::S_IFREG {
+                return Ok(None);
+            }
+            Ok(Some(stat.st_blocks as u64 * 512))
+        };
+
+        let before = allocated(fd)?;
+        let result = unsafe {
+            libc::fallocate(
+                fd,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if result == -1 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                log::debug!("BLOCKDEV: Hole punching is not supported here");
+                return Ok(0);
+            }
+            return Err(BlockDeviceError::Io(err));
+        }
+
+        match (before, allocated(fd)?) {
+            (Some(before), Some(after)) => Ok(before.saturating_sub(after)),
+            _ => Ok(len),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn punch_range(_file: &File, _offset: u64, _len: u64) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Discard `len` bytes at `offset`: BLKDISCARD on a block device, a
+    /// punched hole in a regular file. Devices and filesystems that can't
+    /// discard are left as they are.
+    #[cfg(target_os = "linux")]
+    fn discard_range(file: &File, offset: u64, len: u64) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        // BLKDISCARD = _IO(0x12, 119) on Linux
+        const BLKDISCARD: libc::c_ulong = 0x1277;
+
+        let fd = file.as_raw_fd();
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } == -1 {
+            return Err(BlockDeviceError::Io(std::io::Error::last_os_error()));
+        }
+
+        let result = if stat.st_mode & libc::S_IFMT == libc::S_IFBLK {
+            let range: [u64; 2] = [offset, len];
+            unsafe { libc::ioctl(fd, BLKDISCARD, range.as_ptr()) }
+        } else {
+            unsafe {
+                libc::fallocate(
+                    fd,
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    offset as libc::off_t,
+                    len as libc::off_t,
+                )
+            }
+        };
+        if result == -1 {
+            let err = std::io::Error::last_os_error();
+            if matches!(err.raw_os_error(), Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY)) {
+                log::debug!("BLOCKDEV: Discard is not supported here");
+                return Ok(());
+            }
+            return Err(BlockDeviceError::Io(err));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn discard_range(_file: &File, _offset: u64, _len: u64) -> Result<()> {
+        Ok(())
+    }
+
     /// Get the size of a block device using platform-specific methods
     fn get_block_device_size(path: &Path) -> Result<u64> {
         #[cfg(unix)]
@@ -122,20 +341,60 @@ impl FileBackedBlockDevice {
 
     /// Open an existing file-backed block device
     pub async fn open(path: impl AsRef<Path>, read_only: bool) -> Result<Self> {
+        Self::open_at(path, read_only, 0, None).await
+    }
+
+    /// Open the part of a file or device starting `offset` bytes in, such
+    /// as a partition inside a whole-disk image, so block N is at
+    /// `offset + N * BLOCK_SIZE`. The region is `length` bytes long, or
+    /// runs to the end without one. `offset` must be a multiple of the
+    /// block size.
+    pub async fn open_at(
+        path: impl AsRef<Path>,
+        read_only: bool,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Self> {
+        Self::check_offset(offset)?;
         let path = path.as_ref().to_path_buf();
         let file = OpenOptions::new()
             .read(true)
             .write(!read_only)
             .open(&path)
             .await?;
+        if !read_only {
+            Self::lock_exclusive(&file, &path)?;
+        }
 
         // Get the actual size (handles both files and block devices)
-        let size = Self::get_block_device_size(&path)?;
+        let total = Self::get_block_device_size(&path)?;
+        let available = total.checked_sub(offset).ok_or_else(|| {
+            BlockDeviceError::InvalidRegion(format!(
+                "offset {} is past the end of {} ({} bytes)",
+                offset,
+                path.display(),
+                total
+            ))
+        })?;
+        let size = match length {
+            Some(length) if length > available => {
+                return Err(BlockDeviceError::InvalidRegion(format!(
+                    "{} bytes at offset {} run past the end of {} ({} bytes)",
+                    length,
+                    offset,
+                    path.display(),
+                    total
+                )));
+            }
+            Some(length) => length,
+            None => available,
+        };
         let block_count = size / BLOCK_SIZE as u64;
 
         Ok(Self {
             file: Mutex::new(Some(file)),
             path,
+            offset,
             size,
             block_count,
             read_only,
@@ -147,6 +406,11 @@ impl FileBackedBlockDevice {
         self.size
     }
 
+    /// Byte offset of the device's first block within the backing file
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
     /// Get the number of blocks in the device
     pub fn block_count(&self) -> u64 {
         self.block_count
@@ -169,7 +433,7 @@ impl BlockDevice for FileBackedBlockDevice {
             return Err(BlockDeviceError::InvalidBlockSize(buf.len()));
         }
 
-        let offset = block_num * BLOCK_SIZE as u64;
+        let offset = self.offset + block_num * BLOCK_SIZE as u64;
         let mut file_guard = self.file.lock().await;
 
         if let Some(file) = &mut *file_guard {
@@ -194,7 +458,7 @@ impl BlockDevice for FileBackedBlockDevice {
             return Err(BlockDeviceError::InvalidBlockSize(data.len()));
         }
 
-        let offset = block_num * BLOCK_SIZE as u64;
+        let offset = self.offset + block_num * BLOCK_SIZE as u64;
         let mut file_guard = self.file.lock().await;
 
         if let Some(file) = &mut *file_guard {
@@ -222,6 +486,54 @@ impl BlockDevice for FileBackedBlockDevice {
         }
     }
 
+    async fn punch_hole(&self, block_num: u64, count: u64) -> Result<u64> {
+        if self.read_only {
+            return Err(BlockDeviceError::ReadOnly);
+        }
+
+        let end = block_num.saturating_add(count);
+        if end > self.block_count {
+            return Err(BlockDeviceError::InvalidBlockNumber(end - 1));
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let file_guard = self.file.lock().await;
+        match &*file_guard {
+            Some(file) => Self::punch_range(
+                file,
+                self.offset + block_num * BLOCK_SIZE as u64,
+                count * BLOCK_SIZE as u64,
+            ),
+            None => Err(BlockDeviceError::DeviceClosed),
+        }
+    }
+
+    async fn discard(&self, block_num: u64, count: u64) -> Result<()> {
+        if self.read_only {
+            return Err(BlockDeviceError::ReadOnly);
+        }
+
+        let end = block_num.saturating_add(count);
+        if end > self.block_count {
+            return Err(BlockDeviceError::InvalidBlockNumber(end - 1));
+        }
+        if count == 0 {
+            return Ok(());
+        }
+
+        let file_guard = self.file.lock().await;
+        match &*file_guard {
+            Some(file) => Self::discard_range(
+                file,
+                self.offset + block_num * BLOCK_SIZE as u64,
+                count * BLOCK_SIZE as u64,
+            ),
+            None => Err(BlockDeviceError::DeviceClosed),
+        }
+    }
+
     async fn close(&mut self) -> Result<()> {
         let mut file_guard = self.file.lock().await;
 
@@ -303,4 +615,76 @@ mod tests {
         let write_result = read_only_device.write_block(0, &[0u8; 4096]).await;
         assert!(matches!(write_result, Err(BlockDeviceError::ReadOnly)));
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_frozen_device_cannot_be_opened_read_write() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("test_freeze.bin");
+        let writer = FileBackedBlockDevice::create(&file_path, 4096).await.unwrap();
+
+        // A device open read-write can't be frozen by someone else
+        let reader = FileBackedBlockDevice::open(&file_path, true).await.unwrap();
+        assert!(matches!(reader.freeze().await, Err(BlockDeviceError::Locked(_))));
+        drop(writer);
+
+        reader.freeze().await.unwrap();
+        let second = FileBackedBlockDevice::open(&file_path, false).await;
+        assert!(matches!(second, Err(BlockDeviceError::Locked(_))));
+        // Other readers are fine
+        FileBackedBlockDevice::open(&file_path, true).await.unwrap();
+
+        reader.unfreeze().await.unwrap();
+        FileBackedBlockDevice::open(&file_path, false).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_filesystem_at_offset_leaves_prefix_alone() {
+        use crate::layout::{DiskFs, DiskFsTrait};
+        use crate::{AegisFS, FileType, MountOptions, ROOT_INODE};
+        use std::sync::Arc;
+
+        // A whole-disk image whose first megabyte holds a partition table
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("disk.img");
+        let offset = 1024 * 1024;
+        let size = 8 * 1024 * 1024;
+        let prefix: Vec<u8> = (0..offset).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &prefix).unwrap();
+
+        let device = FileBackedBlockDevice::create_at(&path, offset as u64, size).await.unwrap();
+        assert_eq!(device.block_count(), size / BLOCK_SIZE as u64);
+        let device: Arc<dyn BlockDevice> = Arc::new(device);
+        DiskFs::format(device.clone(), size, None).await.unwrap();
+        drop(device);
+
+        let open = || async {
+            let device = FileBackedBlockDevice::open_at(&path, false, offset as u64, None).await.unwrap();
+            let device: Arc<dyn BlockDevice> = Arc::new(device);
+            AegisFS::from_block_device(device, MountOptions::default()).await.unwrap()
+        };
+        let mut fs = open().await;
+        fs.create_file(ROOT_INODE, "inside", FileType::RegularFile).unwrap();
+        fs.unmount();
+        drop(fs);
+        let fs = open().await;
+        assert!(fs.cached_lookup(ROOT_INODE, "inside").is_some());
+        drop(fs);
+
+        // The superblock starts at the offset and the prefix is untouched
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), offset + size as usize);
+        assert_eq!(&bytes[..offset], &prefix[..]);
+        assert_eq!(&bytes[offset + 4..offset + 12], b"AEGISFS\x00");
+
+        // Offsets inside a block and regions past the end are refused
+        assert!(matches!(
+            FileBackedBlockDevice::open_at(&path, true, 512, None).await,
+            Err(BlockDeviceError::InvalidRegion(_))
+        ));
+        assert!(matches!(
+            FileBackedBlockDevice::open_at(&path, true, offset as u64, Some(size + BLOCK_SIZE as u64)).await,
+            Err(BlockDeviceError::InvalidRegion(_))
+        ));
+    }
 }
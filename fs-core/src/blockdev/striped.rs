@@ -0,0 +1,209 @@
+//! Block device striped across several backing devices (RAID0)
+
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use super::{BlockDevice, BlockDeviceError, Result};
+
+/// A block device that spreads its blocks round-robin over several devices
+///
+/// Logical blocks are grouped into stripe units of `stripe_width` blocks.
+/// Unit `n` lives on device `n % devices.len()`, so sequential I/O touches
+/// every device in turn. Each device contributes the same number of whole
+/// stripe units; blocks past that on a larger device are left unused.
+pub struct StripedBlockDevice {
+    devices: Vec<Arc<dyn BlockDevice>>,
+    stripe_width: u64,
+    /// Blocks used on each backing device
+    blocks_per_device: u64,
+}
+
+impl StripedBlockDevice {
+    /// Stripe across `devices` in units of `stripe_width` blocks
+    pub fn new(devices: Vec<Arc<dyn BlockDevice>>, stripe_width: u64) -> Result<Self> {
+        if devices.is_empty() {
+            return Err(BlockDeviceError::InvalidStripe(
+                "at least one backing device is required".to_string(),
+            ));
+        }
+        if stripe_width == 0 {
+            return Err(BlockDeviceError::InvalidStripe(
+                "stripe width must be at least one block".to_string(),
+            ));
+        }
+
+        let block_size = devices[0].block_size();
+        if let Some(other) = devices.iter().find(|d| d.block_size() != block_size) {
+            return Err(BlockDeviceError::BlockSizeMismatch {
+                expected: block_size,
+                found: other.block_size(),
+            });
+        }
+
+        let smallest = devices.iter().map(|d| d.block_count()).min().unwrap_or(0);
+        let blocks_per_device = smallest - smallest % stripe_width;
+        log::info!(
+            "STRIPE: {} devices, {} block stripe units, {} blocks per device",
+            devices.len(),
+            stripe_width,
+            blocks_per_device
+        );
+
+        Ok(Self {
+            devices,
+            stripe_width,
+            blocks_per_device,
+        })
+    }
+
+    /// Number of backing devices
+    pub fn device_count(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Blocks in each stripe unit
+    pub fn stripe_width(&self) -> u64 {
+        self.stripe_width
+    }
+
+    /// Map a logical block to its (device index, device block)
+    pub fn locate(&self, block_num: u64) -> Result<(usize, u64)> {
+        if block_num >= self.block_count() {
+            return Err(BlockDeviceError::InvalidBlockNumber(block_num));
+        }
+
+        let unit = block_num / self.stripe_width;
+        let device_count = self.devices.len() as u64;
+        let device = (unit % device_count) as usize;
+        let device_block = (unit / device_count) * self.stripe_width + block_num % self.stripe_width;
+        Ok((device, device_block))
+    }
+}
+
+#[async_trait]
+impl BlockDevice for StripedBlockDevice {
+    async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<()> {
+        let (device, device_block) = self.locate(block_num)?;
+        self.devices[device].read_block(device_block, buf).await
+    }
+
+    async fn write_block(&self, block_num: u64, data: &[u8]) -> Result<()> {
+        let (device, device_block) = self.locate(block_num)?;
+        self.devices[device].write_block(device_block, data).await
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks_per_device * self.devices.len() as u64
+    }
+
+    fn block_size(&self) -> usize {
+        self.devices[0].block_size()
+    }
+
+    async fn sync(&self) -> Result<()> {
+        futures::future::try_join_all(self.devices.iter().map(|d| d.sync())).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        // Devices shared with someone else are only synced; they close them
+        for device in &mut self.devices {
+            match Arc::get_mut(device) {
+                Some(device) => device.close().await?,
+                None => device.sync().await?,
+            }
+        }
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.devices.iter().any(|d| d.is_read_only())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::{FileBackedBlockDevice, BLOCK_SIZE};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_striped_writes_land_on_expected_devices() {
+        let temp_dir = tempdir().unwrap();
+        let mut devices: Vec<Arc<dyn BlockDevice>> = Vec::new();
+        for i in 0..2 {
+            let path = temp_dir.path().join(format!("stripe{}.bin", i));
+            devices.push(Arc::new(
+                FileBackedBlockDevice::create(&path, BLOCK_SIZE as u64 * 8).await.unwrap(),
+            ));
+        }
+
+        let striped = StripedBlockDevice::new(devices.clone(), 2).unwrap();
+        assert_eq!(striped.block_count(), 16);
+
+        // Blocks 1..=4 cross two stripe unit boundaries
+        for block in 1..=4u64 {
+            striped.write_block(block, &[block as u8; BLOCK_SIZE]).await.unwrap();
+        }
+        striped.sync().await.unwrap();
+
+        let mut buf = [0u8; BLOCK_SIZE];
+        for block in 1..=4u64 {
+            striped.read_block(block, &mut buf).await.unwrap();
+            assert_eq!(buf, [block as u8; BLOCK_SIZE]);
+        }
+
+        // Units alternate between devices: 0-1 on device 0, 2-3 on device 1,
+        // 4-5 back on device 0 after its first unit
+        for (block, device, device_block) in [(1, 0, 1), (2, 1, 0), (3, 1, 1), (4, 0, 2)] {
+            assert_eq!(striped.locate(block).unwrap(), (device, device_block));
+            devices[device].read_block(device_block, &mut buf).await.unwrap();
+            assert_eq!(buf, [block as u8; BLOCK_SIZE]);
+        }
+
+        assert!(matches!(
+            striped.read_block(16, &mut buf).await,
+            Err(BlockDeviceError::InvalidBlockNumber(16))
+        ));
+    }
+
+    /// Device that only reports a non-standard block size
+    struct SmallBlocks;
+
+    #[async_trait]
+    impl BlockDevice for SmallBlocks {
+        async fn read_block(&self, _block_num: u64, _buf: &mut [u8]) -> Result<()> {
+            Ok(())
+        }
+        async fn write_block(&self, _block_num: u64, _data: &[u8]) -> Result<()> {
+            Ok(())
+        }
+        fn block_count(&self) -> u64 {
+            8
+        }
+        fn block_size(&self) -> usize {
+            512
+        }
+        async fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+        async fn close(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_striped_rejects_mixed_block_sizes() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("stripe.bin");
+        let file: Arc<dyn BlockDevice> =
+            Arc::new(FileBackedBlockDevice::create(&path, BLOCK_SIZE as u64 * 8).await.unwrap());
+
+        let result = StripedBlockDevice::new(vec![file, Arc::new(SmallBlocks)], 2);
+        assert!(matches!(
+            result,
+            Err(BlockDeviceError::BlockSizeMismatch { expected: BLOCK_SIZE, found: 512 })
+        ));
+        assert!(StripedBlockDevice::new(Vec::new(), 2).is_err());
+    }
+}
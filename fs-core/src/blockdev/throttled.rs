@@ -0,0 +1,188 @@
+//! Block device that limits how much I/O is outstanding at once
+
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use super::{BlockDevice, BlockDeviceError, Result};
+
+/// A block device that lets at most `max_depth` reads, writes and syncs
+/// reach another device at a time
+///
+/// Callers beyond the limit wait their turn. The number of operations in
+/// flight, and the most there have been at once, can be read back, so tests
+/// can reproduce contention and check how deep the I/O of a workload gets.
+pub struct ThrottledBlockDevice {
+    inner: Arc<dyn BlockDevice>,
+    permits: Semaphore,
+    max_depth: usize,
+    in_flight: AtomicUsize,
+    peak_in_flight: AtomicUsize,
+}
+
+/// An admitted operation; counts as in flight until dropped
+struct InFlight<'a> {
+    device: &'a ThrottledBlockDevice,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.device.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ThrottledBlockDevice {
+    /// Limit `inner` to `max_depth` operations in flight
+    pub fn new(inner: Arc<dyn BlockDevice>, max_depth: usize) -> Result<Self> {
+        if max_depth == 0 {
+            return Err(BlockDeviceError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "I/O depth must be at least 1",
+            )));
+        }
+        Ok(Self {
+            inner,
+            permits: Semaphore::new(max_depth),
+            max_depth,
+            in_flight: AtomicUsize::new(0),
+            peak_in_flight: AtomicUsize::new(0),
+        })
+    }
+
+    /// Most operations let through at once
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Operations currently in flight on the inner device
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Most operations that have been in flight at once
+    pub fn peak_in_flight(&self) -> usize {
+        self.peak_in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Wait for a free slot
+    async fn admit(&self) -> Result<InFlight<'_>> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|_| BlockDeviceError::DeviceClosed)?;
+        let depth = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_in_flight.fetch_max(depth, Ordering::SeqCst);
+        Ok(InFlight {
+            device: self,
+            _permit: permit,
+        })
+    }
+}
+
+#[async_trait]
+impl BlockDevice for ThrottledBlockDevice {
+    async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<()> {
+        let _slot = self.admit().await?;
+        self.inner.read_block(block_num, buf).await
+    }
+
+    async fn write_block(&self, block_num: u64, data: &[u8]) -> Result<()> {
+        let _slot = self.admit().await?;
+        self.inner.write_block(block_num, data).await
+    }
+
+    fn block_count(&self) -> u64 {
+        self.inner.block_count()
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    async fn sync(&self) -> Result<()> {
+        let _slot = self.admit().await?;
+        self.inner.sync().await
+    }
+
+    async fn punch_hole(&self, block_num: u64, count: u64) -> Result<u64> {
+        let _slot = self.admit().await?;
+        self.inner.punch_hole(block_num, count).await
+    }
+
+    async fn discard(&self, block_num: u64, count: u64) -> Result<()> {
+        let _slot = self.admit().await?;
+        self.inner.discard(block_num, count).await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.permits.close();
+        match Arc::get_mut(&mut self.inner) {
+            Some(inner) => inner.close().await,
+            None => self.inner.sync().await,
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::{FileBackedBlockDevice, BLOCK_SIZE};
+    use crate::cache::BlockCache;
+    use tempfile::tempdir;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_depth_one_serializes_concurrent_io() {
+        let dir = tempdir().unwrap();
+        let inner: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(dir.path().join("device"), 64 * BLOCK_SIZE as u64)
+                .await
+                .unwrap(),
+        );
+        let device = Arc::new(ThrottledBlockDevice::new(inner, 1).unwrap());
+        assert!(ThrottledBlockDevice::new(device.clone(), 0).is_err());
+        for block in 0..64u64 {
+            device.write_block(block, &[block as u8; BLOCK_SIZE]).await.unwrap();
+        }
+
+        let reads = (0..64u64).map(|block| {
+            let device = device.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; BLOCK_SIZE];
+                device.read_block(block, &mut buf).await.unwrap();
+                assert_eq!(buf, vec![block as u8; BLOCK_SIZE]);
+            })
+        });
+        let all = futures::future::join_all(reads);
+        for result in tokio::time::timeout(std::time::Duration::from_secs(10), all)
+            .await
+            .expect("reads deadlocked at depth 1")
+        {
+            result.unwrap();
+        }
+        assert_eq!(device.peak_in_flight(), 1);
+        assert_eq!(device.in_flight(), 0);
+
+        // The cache's write-through and flush paths only take one slot at a time
+        let cache = Arc::new(BlockCache::new(device.clone(), 8, true));
+        let writes = (0..16u64).map(|block| {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.write_block(block, &[0xAB; BLOCK_SIZE]).await.unwrap() })
+        });
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            for result in futures::future::join_all(writes).await {
+                result.unwrap();
+            }
+            cache.flush().await.unwrap();
+        })
+        .await
+        .expect("cache deadlocked at depth 1");
+        assert_eq!(device.peak_in_flight(), 1);
+    }
+}
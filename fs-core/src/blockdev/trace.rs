@@ -0,0 +1,359 @@
+//! Block device wrapper that records every block access
+//!
+//! Each `read_block` and `write_block` becomes one JSON line of a
+//! gzip-compressed trace file, so the I/O pattern of a real workload can be
+//! replayed or analyzed offline with `aegisfs analyze-io`.
+
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::{BlockDevice, Result};
+
+/// Kind of block access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IoOp {
+    /// A block read
+    Read,
+    /// A block write
+    Write,
+}
+
+/// One traced block access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IoRecord {
+    /// When the access started, in microseconds since the Unix epoch
+    pub timestamp_us: u64,
+    /// Whether the block was read or written
+    pub op: IoOp,
+    /// Block accessed
+    pub block_num: u64,
+    /// How long the backing device took, in microseconds
+    pub duration_us: u64,
+}
+
+/// Destination of a trace, shared by the devices recording into it
+///
+/// `finish` must be called for the gzip trailer to be written; a trace cut
+/// off without it can still be read up to the last complete record.
+pub struct IoTracer {
+    encoder: Mutex<Option<GzEncoder<BufWriter<File>>>>,
+}
+
+impl IoTracer {
+    /// Start a new trace at `path`, replacing any file already there
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::create(path)?;
+        let encoder = GzEncoder::new(BufWriter::new(file), Compression::fast());
+        Ok(Self {
+            encoder: Mutex::new(Some(encoder)),
+        })
+    }
+
+    /// Append a record; records after `finish` are dropped
+    pub fn record(&self, record: &IoRecord) {
+        let mut encoder = self.encoder.lock();
+        let Some(writer) = encoder.as_mut() else {
+            return;
+        };
+        let result = serde_json::to_writer(&mut *writer, record)
+            .map_err(io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"));
+        if let Err(e) = result {
+            log::error!("TRACE: Failed to record I/O, stopping trace: {}", e);
+            *encoder = None;
+        }
+    }
+
+    /// Write out buffered records and close the trace
+    pub fn finish(&self) -> io::Result<()> {
+        match self.encoder.lock().take() {
+            Some(encoder) => encoder.finish()?.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for IoTracer {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::error!("TRACE: Failed to finish I/O trace: {}", e);
+        }
+    }
+}
+
+/// A block device that records each block access of another device
+pub struct TracingBlockDevice {
+    inner: Arc<dyn BlockDevice>,
+    tracer: Arc<IoTracer>,
+}
+
+impl TracingBlockDevice {
+    /// Record the accesses to `inner` into `tracer`
+    pub fn new(inner: Arc<dyn BlockDevice>, tracer: Arc<IoTracer>) -> Self {
+        Self { inner, tracer }
+    }
+
+    fn record(&self, op: IoOp, block_num: u64, timestamp: SystemTime, started: Instant) {
+        let timestamp_us = timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        self.tracer.record(&IoRecord {
+            timestamp_us,
+            op,
+            block_num,
+            duration_us: started.elapsed().as_micros() as u64,
+        });
+    }
+}
+
+#[async_trait]
+impl BlockDevice for TracingBlockDevice {
+    async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<()> {
+        let (timestamp, started) = (SystemTime::now(), Instant::now());
+        let result = self.inner.read_block(block_num, buf).await;
+        self.record(IoOp::Read, block_num, timestamp, started);
+        result
+    }
+
+    async fn write_block(&self, block_num: u64, data: &[u8]) -> Result<()> {
+        let (timestamp, started) = (SystemTime::now(), Instant::now());
+        let result = self.inner.write_block(block_num, data).await;
+        self.record(IoOp::Write, block_num, timestamp, started);
+        result
+    }
+
+    fn block_count(&self) -> u64 {
+        self.inner.block_count()
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    async fn sync(&self) -> Result<()> {
+        self.inner.sync().await
+    }
+
+    async fn punch_hole(&self, block_num: u64, count: u64) -> Result<u64> {
+        self.inner.punch_hole(block_num, count).await
+    }
+
+    async fn discard(&self, block_num: u64, count: u64) -> Result<()> {
+        self.inner.discard(block_num, count).await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        match Arc::get_mut(&mut self.inner) {
+            Some(inner) => inner.close().await?,
+            None => self.inner.sync().await?,
+        }
+        self.tracer.finish()?;
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+/// Read every record of a trace.
+///
+/// A trace whose writer was killed before `finish` ends early; the records
+/// before the cut are returned.
+pub fn read_trace(path: impl AsRef<Path>) -> io::Result<Vec<IoRecord>> {
+    let reader = BufReader::new(GzDecoder::new(File::open(path)?));
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                log::warn!("TRACE: Trace is truncated after {} records", records.len());
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            // A record cut off mid-line is the end of a truncated trace
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(records)
+}
+
+/// Accesses to one range of block numbers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockRange {
+    /// First block of the range
+    pub start: u64,
+    /// One past the last block of the range
+    pub end: u64,
+    /// Reads in the range
+    pub reads: u64,
+    /// Writes in the range
+    pub writes: u64,
+}
+
+/// Access pattern of a trace
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceSummary {
+    /// Reads traced
+    pub reads: u64,
+    /// Writes traced
+    pub writes: u64,
+    /// Total device time of reads, in microseconds
+    pub read_time_us: u64,
+    /// Total device time of writes, in microseconds
+    pub write_time_us: u64,
+    /// Accesses to the block after the previous access of the same kind
+    pub sequential: u64,
+    /// Accesses anywhere else; the first access of each kind counts as
+    /// neither
+    pub random: u64,
+    /// Accesses by block number range, in block order
+    pub histogram: Vec<BlockRange>,
+    /// Most accessed blocks with their access count, busiest first
+    pub hot_blocks: Vec<(u64, u64)>,
+}
+
+impl TraceSummary {
+    /// Summarize `records` into at most `buckets` histogram ranges and the
+    /// `top` most accessed blocks
+    pub fn from_records(records: &[IoRecord], buckets: usize, top: usize) -> Self {
+        let mut summary = Self::default();
+        let mut previous: HashMap<IoOp, u64> = HashMap::new();
+        let mut counts: HashMap<u64, u64> = HashMap::new();
+
+        for record in records {
+            match record.op {
+                IoOp::Read => {
+                    summary.reads += 1;
+                    summary.read_time_us += record.duration_us;
+                }
+                IoOp::Write => {
+                    summary.writes += 1;
+                    summary.write_time_us += record.duration_us;
+                }
+            }
+            if let Some(last) = previous.insert(record.op, record.block_num) {
+                if record.block_num == last.wrapping_add(1) {
+                    summary.sequential += 1;
+                } else {
+                    summary.random += 1;
+                }
+            }
+            *counts.entry(record.block_num).or_default() += 1;
+        }
+
+        if let Some(max) = records.iter().map(|r| r.block_num).max() {
+            let buckets = buckets.max(1) as u64;
+            let width = (max / buckets + 1).max(1);
+            let mut histogram: Vec<BlockRange> = (0..=max / width)
+                .map(|i| BlockRange {
+                    start: i * width,
+                    end: (i + 1) * width,
+                    ..BlockRange::default()
+                })
+                .collect();
+            for record in records {
+                let range = &mut histogram[(record.block_num / width) as usize];
+                match record.op {
+                    IoOp::Read => range.reads += 1,
+                    IoOp::Write => range.writes += 1,
+                }
+            }
+            summary.histogram = histogram;
+        }
+
+        let mut hot: Vec<(u64, u64)> = counts.into_iter().collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot.truncate(top);
+        summary.hot_blocks = hot;
+        summary
+    }
+
+    /// Fraction of accesses that were sequential, or 0 with none to judge
+    pub fn sequential_ratio(&self) -> f64 {
+        let judged = self.sequential + self.random;
+        if judged == 0 {
+            0.0
+        } else {
+            self.sequential as f64 / judged as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::{FileBackedBlockDevice, BLOCK_SIZE};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_trace_records_accesses_and_summarizes_them() {
+        let dir = tempdir().unwrap();
+        let trace_path = dir.path().join("trace.json.gz");
+        let inner: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(dir.path().join("device"), 16 * BLOCK_SIZE as u64)
+                .await
+                .unwrap(),
+        );
+        let tracer = Arc::new(IoTracer::create(&trace_path).unwrap());
+        let device = TracingBlockDevice::new(inner, tracer.clone());
+
+        // Four sequential writes, then reads that jump around
+        for block in 0..4 {
+            device.write_block(block, &[1; BLOCK_SIZE]).await.unwrap();
+        }
+        let mut buf = [0u8; BLOCK_SIZE];
+        for block in [3, 3, 9, 3] {
+            device.read_block(block, &mut buf).await.unwrap();
+        }
+        tracer.finish().unwrap();
+        // Accesses after the trace is finished are not recorded
+        device.read_block(0, &mut buf).await.unwrap();
+
+        let records = read_trace(&trace_path).unwrap();
+        let ops: Vec<(IoOp, u64)> = records.iter().map(|r| (r.op, r.block_num)).collect();
+        assert_eq!(
+            ops,
+            vec![
+                (IoOp::Write, 0),
+                (IoOp::Write, 1),
+                (IoOp::Write, 2),
+                (IoOp::Write, 3),
+                (IoOp::Read, 3),
+                (IoOp::Read, 3),
+                (IoOp::Read, 9),
+                (IoOp::Read, 3),
+            ]
+        );
+        assert!(records.windows(2).all(|w| w[0].timestamp_us <= w[1].timestamp_us));
+
+        let summary = TraceSummary::from_records(&records, 2, 1);
+        assert_eq!((summary.reads, summary.writes), (4, 4));
+        assert_eq!((summary.sequential, summary.random), (3, 3));
+        assert_eq!(summary.sequential_ratio(), 0.5);
+        assert_eq!(summary.hot_blocks, vec![(3, 4)]);
+        assert_eq!(
+            summary.histogram,
+            vec![
+                BlockRange { start: 0, end: 5, reads: 3, writes: 4 },
+                BlockRange { start: 5, end: 10, reads: 1, writes: 0 },
+            ]
+        );
+    }
+}
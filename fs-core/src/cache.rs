@@ -4,11 +4,13 @@ use crate::blockdev::FileBackedBlockDevice;
 use crate::blockdev::{BlockDevice, BlockDeviceError, Result, BLOCK_SIZE};
 use arrayref::array_ref;
 use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
 use lru::LruCache;
 use parking_lot::RwLock;
 use std::collections::HashMap;
 use std::io;
 use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tempfile::tempdir;
 use thiserror::Error;
@@ -36,6 +38,31 @@ impl From<CacheError> for BlockDeviceError {
     }
 }
 
+/// Default number of concurrent device reads issued by `BlockCache::preload`
+pub const DEFAULT_PRELOAD_CONCURRENCY: usize = 16;
+
+/// Smallest cache `default_capacity` picks (256KB)
+const MIN_DEFAULT_CAPACITY: usize = 64;
+/// Largest cache `default_capacity` picks (256MB)
+const MAX_DEFAULT_CAPACITY: usize = 65536;
+/// `default_capacity` caches one block per this many device blocks
+const DEFAULT_CAPACITY_RATIO: u64 = 256;
+
+/// Cache capacity in blocks suited to a device of `device_blocks` blocks.
+///
+/// This is 1/256th of the device (1024 blocks for a 1GB device), kept
+/// between 256KB and 256MB.
+pub fn default_capacity(device_blocks: u64) -> usize {
+    let blocks = (device_blocks / DEFAULT_CAPACITY_RATIO).min(MAX_DEFAULT_CAPACITY as u64) as usize;
+    blocks.max(MIN_DEFAULT_CAPACITY)
+}
+
+/// Block numbers a [`PersistentReadCache`] hints file holds at most (4KB)
+pub const MAX_CACHE_HINTS: usize = 1024;
+
+/// Suffix of the hints file kept next to a device image
+pub const CACHE_HINTS_SUFFIX: &str = ".cache_hints";
+
 /// A cached block with metadata
 struct CachedBlock {
     data: Box<[u8; BLOCK_SIZE]>,
@@ -46,7 +73,10 @@ struct CachedBlock {
 pub struct BlockCache {
     device: Arc<dyn BlockDevice>,
     cache: RwLock<LruCache<u64, CachedBlock>>,
+    /// Preloaded blocks, kept outside the LRU so they are never evicted
+    pinned: RwLock<HashMap<u64, CachedBlock>>,
     write_through: bool,
+    preload_concurrency: usize,
 }
 
 impl BlockCache {
@@ -55,10 +85,97 @@ impl BlockCache {
         Self {
             device,
             cache: RwLock::new(LruCache::new(NonZeroUsize::new(capacity).unwrap())),
+            pinned: RwLock::new(HashMap::new()),
             write_through,
+            preload_concurrency: DEFAULT_PRELOAD_CONCURRENCY,
         }
     }
 
+    /// Set how many device reads `preload` keeps in flight at once
+    pub fn with_preload_concurrency(mut self, concurrency: usize) -> Self {
+        self.preload_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Read the given blocks into the cache and pin them there.
+    ///
+    /// Up to `preload_concurrency` reads are in flight at a time. Pinned
+    /// blocks are served from memory for the lifetime of the cache.
+    pub async fn preload(&self, block_nums: &[u64]) -> Result<()> {
+        let mut pending = block_nums
+            .iter()
+            .copied()
+            .filter(|block_num| !self.pinned.read().contains_key(block_num));
+        let mut reads = FuturesUnordered::new();
+
+        loop {
+            while reads.len() < self.preload_concurrency {
+                let Some(block_num) = pending.next() else {
+                    break;
+                };
+                if block_num >= self.device.block_count() {
+                    return Err(BlockDeviceError::InvalidBlockNumber(block_num));
+                }
+                reads.push(async move {
+                    let mut block = Box::new([0u8; BLOCK_SIZE]);
+                    self.device
+                        .read_block(block_num, &mut block[..])
+                        .await
+                        .map(|()| (block_num, block))
+                });
+            }
+
+            let Some(result) = reads.next().await else {
+                break;
+            };
+            let (block_num, data) = result?;
+
+            // A copy already in the LRU may be newer than what we read
+            let cached = self
+                .cache
+                .write()
+                .pop(&block_num)
+                .unwrap_or(CachedBlock { data, dirty: false });
+            self.pinned.write().entry(block_num).or_insert(cached);
+        }
+
+        log::debug!("CACHE: {} blocks pinned", self.pinned.read().len());
+        Ok(())
+    }
+
+    /// Up to `limit` cached block numbers, most recently cached first,
+    /// followed by pinned ones
+    pub fn recent_blocks(&self, limit: usize) -> Vec<u64> {
+        let cache = self.cache.read();
+        let pinned = self.pinned.read();
+        cache
+            .iter()
+            .map(|(&block_num, _)| block_num)
+            .chain(pinned.keys().copied())
+            .take(limit)
+            .collect()
+    }
+
+    /// Number of blocks pinned by `preload`
+    pub fn pinned_count(&self) -> usize {
+        self.pinned.read().len()
+    }
+
+    /// Maximum number of unpinned blocks held
+    pub fn capacity(&self) -> usize {
+        self.cache.read().cap().get()
+    }
+
+    /// The device this cache reads from and writes to
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    /// Whether this cache sits in front of `device`
+    pub fn caches(&self, device: &Arc<dyn BlockDevice>) -> bool {
+        std::ptr::addr_eq(Arc::as_ptr(&self.device), Arc::as_ptr(device))
+    }
+
     /// Read a block from the cache or device
     pub async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<()> {
         if block_num >= self.device.block_count() {
@@ -70,6 +187,10 @@ impl BlockCache {
         }
 
         // Check cache first with a read lock
+        if let Some(block) = self.pinned.read().get(&block_num) {
+            buf.copy_from_slice(&*block.data);
+            return Ok(());
+        }
         {
             let cache = self.cache.read();
             if let Some(block) = cache.peek(&block_num) {
@@ -135,6 +256,11 @@ impl BlockCache {
             dirty: !self.write_through,
         };
 
+        if let Some(pinned) = self.pinned.write().get_mut(&block_num) {
+            *pinned = cached_block;
+            return Ok(());
+        }
+
         // We need to handle eviction carefully to avoid holding the lock across an await point
         let maybe_evicted = {
             let mut cache = self.cache.write();
@@ -159,8 +285,10 @@ impl BlockCache {
         // First collect all dirty blocks with a read lock
         let dirty_blocks: Vec<_> = {
             let cache = self.cache.read();
+            let pinned = self.pinned.read();
             cache
                 .iter()
+                .chain(pinned.iter())
                 .filter(|(_, block)| block.dirty)
                 .map(|(block_num, block)| (*block_num, block.data.clone()))
                 .collect()
@@ -174,6 +302,9 @@ impl BlockCache {
                 write_errors.push((block_num, e));
             } else {
                 // Mark as clean with a write lock
+                if let Some(block) = self.pinned.write().get_mut(&block_num) {
+                    block.dirty = false;
+                }
                 let mut cache = self.cache.write();
                 if let Some(block) = cache.get_mut(&block_num) {
                     block.dirty = false;
@@ -192,14 +323,157 @@ impl BlockCache {
         self.device.sync().await
     }
 
+    /// Drop a block from the cache without writing it back, for blocks
+    /// whose contents no longer matter such as ones just freed
+    pub fn forget(&self, block_num: u64) {
+        self.cache.write().pop(&block_num);
+        self.pinned.write().remove(&block_num);
+    }
+
+    /// Drop the cached copy of a block so the next read goes to the device,
+    /// writing it back first if it is dirty.
+    ///
+    /// Code that writes to the device around the cache should call this
+    /// before its write, so a dirty copy can't later be written back over
+    /// the new data.
+    pub async fn invalidate(&self, block_num: u64) -> Result<()> {
+        let cached = match self.pinned.write().remove(&block_num) {
+            Some(block) => Some(block),
+            None => self.cache.write().pop(&block_num),
+        };
+        if let Some(block) = cached {
+            if block.dirty {
+                self.device.write_block(block_num, &*block.data).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `invalidate` every block in `start..end`
+    pub async fn invalidate_range(&self, start: u64, end: u64) -> Result<()> {
+        for block_num in start..end {
+            self.invalidate(block_num).await?;
+        }
+        Ok(())
+    }
+
     /// Clear the entire cache, writing back any dirty blocks
     pub async fn clear(&self) -> Result<()> {
         self.flush().await?;
         self.cache.write().clear();
+        self.pinned.write().clear();
         Ok(())
     }
 }
 
+/// Which blocks a [`BlockCache`] held, kept in a file across restarts so
+/// the next mount can start with them cached.
+///
+/// Only block numbers are stored, as little-endian `u32`s, most recently
+/// used first; their contents are read from the device again.
+#[derive(Debug, Clone)]
+pub struct PersistentReadCache {
+    path: PathBuf,
+}
+
+impl PersistentReadCache {
+    /// Hints kept in the given file
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Hints for a device, kept next to it in `<device-path>.cache_hints`
+    pub fn for_device(device_path: &Path) -> Self {
+        let mut path = device_path.as_os_str().to_owned();
+        path.push(CACHE_HINTS_SUFFIX);
+        Self::new(path)
+    }
+
+    /// The hints file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record the blocks `cache` holds, returning how many were written
+    pub fn save(&self, cache: &BlockCache) -> io::Result<usize> {
+        let blocks: Vec<u32> = cache
+            .recent_blocks(MAX_CACHE_HINTS)
+            .into_iter()
+            .filter_map(|block_num| u32::try_from(block_num).ok())
+            .collect();
+        let bytes: Vec<u8> = blocks.iter().flat_map(|block_num| block_num.to_le_bytes()).collect();
+        std::fs::write(&self.path, bytes)?;
+        Ok(blocks.len())
+    }
+
+    /// The recorded block numbers; none if nothing was recorded yet
+    pub fn load(&self) -> io::Result<Vec<u64>> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(bytes
+            .chunks_exact(4)
+            .take(MAX_CACHE_HINTS)
+            .map(|chunk| u32::from_le_bytes(*array_ref!(chunk, 0, 4)) as u64)
+            .collect())
+    }
+
+    /// Preload the recorded blocks that still exist on `cache`'s device,
+    /// returning how many
+    pub async fn warm(&self, cache: &BlockCache) -> Result<usize> {
+        let block_count = cache.device().block_count();
+        let blocks: Vec<u64> = self.load()?.into_iter().filter(|&block_num| block_num < block_count).collect();
+        cache.preload(&blocks).await?;
+        Ok(blocks.len())
+    }
+}
+
+impl std::fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity())
+            .field("cached", &self.cache.read().len())
+            .field("pinned", &self.pinned_count())
+            .field("write_through", &self.write_through)
+            .finish()
+    }
+}
+
+/// A cache can stand in for its device, so components that take a
+/// `BlockDevice` (such as `SnapshotManager`) can share it with `DiskFs`
+#[async_trait]
+impl BlockDevice for BlockCache {
+    async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<()> {
+        BlockCache::read_block(self, block_num, buf).await
+    }
+
+    async fn write_block(&self, block_num: u64, data: &[u8]) -> Result<()> {
+        BlockCache::write_block(self, block_num, data).await
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    async fn sync(&self) -> Result<()> {
+        self.flush().await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.flush().await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -340,4 +614,129 @@ mod tests {
         assert_eq!(&read_buf2, &test_data[1]);
         assert_eq!(&read_buf3, &test_data[2]);
     }
+
+    /// Counts device reads so tests can tell cache hits from misses
+    struct CountingDevice {
+        inner: FileBackedBlockDevice,
+        reads: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl BlockDevice for CountingDevice {
+        async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> Result<()> {
+            self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.read_block(block_num, buf).await
+        }
+
+        async fn write_block(&self, block_num: u64, data: &[u8]) -> Result<()> {
+            self.inner.write_block(block_num, data).await
+        }
+
+        fn block_count(&self) -> u64 {
+            self.inner.block_count()
+        }
+
+        async fn sync(&self) -> Result<()> {
+            self.inner.sync().await
+        }
+
+        async fn close(&mut self) -> Result<()> {
+            self.inner.close().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_hints_warm_the_next_cache() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test_hints");
+        let device: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(&path, 64 * BLOCK_SIZE as u64).await.unwrap(),
+        );
+        let hints = PersistentReadCache::for_device(&path);
+        assert_eq!(hints.path(), dir.path().join("test_hints.cache_hints"));
+        assert!(hints.load().unwrap().is_empty());
+
+        let cache = BlockCache::new(device.clone(), 8, true);
+        let mut buf = [0u8; BLOCK_SIZE];
+        for i in [5u64, 9, 3] {
+            cache.read_block(i, &mut buf).await.unwrap();
+        }
+        assert_eq!(hints.save(&cache).unwrap(), 3);
+        assert_eq!(std::fs::metadata(hints.path()).unwrap().len(), 12);
+        assert_eq!(hints.load().unwrap(), vec![3, 9, 5]);
+
+        // A restarted cache starts with the hinted blocks in memory
+        let cache = BlockCache::new(device, 8, true);
+        assert_eq!(hints.warm(&cache).await.unwrap(), 3);
+        assert_eq!(cache.pinned_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_preload_pins_blocks() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test_preload");
+        let inner = FileBackedBlockDevice::create(&path, 64 * BLOCK_SIZE as u64)
+            .await
+            .unwrap();
+        for i in 0..64u64 {
+            inner.write_block(i, &[i as u8; BLOCK_SIZE]).await.unwrap();
+        }
+        let device = Arc::new(CountingDevice {
+            inner,
+            reads: std::sync::atomic::AtomicUsize::new(0),
+        });
+
+        // A two-block LRU can't hold the preloaded blocks, but pinning keeps them
+        let cache = BlockCache::new(device.clone(), 2, true).with_preload_concurrency(4);
+        let preloaded: Vec<u64> = (0..32).collect();
+        cache.preload(&preloaded).await.unwrap();
+        assert_eq!(cache.pinned_count(), 32);
+        assert_eq!(device.reads.load(std::sync::atomic::Ordering::SeqCst), 32);
+
+        let mut buf = [0u8; BLOCK_SIZE];
+        for i in 40..50 {
+            cache.read_block(i, &mut buf).await.unwrap();
+        }
+        for &i in &preloaded {
+            cache.read_block(i, &mut buf).await.unwrap();
+            assert_eq!(buf, [i as u8; BLOCK_SIZE]);
+        }
+        assert_eq!(device.reads.load(std::sync::atomic::Ordering::SeqCst), 42);
+
+        // Writes to a pinned block update the pinned copy
+        cache.write_block(5, &[0xEE; BLOCK_SIZE]).await.unwrap();
+        cache.read_block(5, &mut buf).await.unwrap();
+        assert_eq!(buf, [0xEE; BLOCK_SIZE]);
+
+        assert!(cache.preload(&[64]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_picks_up_out_of_band_writes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test_invalidate");
+        let device: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(&path, 8 * BLOCK_SIZE as u64).await.unwrap(),
+        );
+        let cache = BlockCache::new(device.clone(), 4, false);
+
+        let mut buf = [0u8; BLOCK_SIZE];
+        cache.read_block(1, &mut buf).await.unwrap();
+        device.write_block(1, &[0x11; BLOCK_SIZE]).await.unwrap();
+        cache.read_block(1, &mut buf).await.unwrap();
+        assert_eq!(buf, [0u8; BLOCK_SIZE], "cache still holds the old copy");
+
+        cache.invalidate(1).await.unwrap();
+        cache.read_block(1, &mut buf).await.unwrap();
+        assert_eq!(buf, [0x11; BLOCK_SIZE]);
+
+        // Dirty copies are written back before being dropped
+        cache.write_block(2, &[0x22; BLOCK_SIZE]).await.unwrap();
+        cache.write_block(3, &[0x33; BLOCK_SIZE]).await.unwrap();
+        cache.invalidate_range(2, 4).await.unwrap();
+        device.read_block(2, &mut buf).await.unwrap();
+        assert_eq!(buf, [0x22; BLOCK_SIZE]);
+        device.read_block(3, &mut buf).await.unwrap();
+        assert_eq!(buf, [0x33; BLOCK_SIZE]);
+    }
 }
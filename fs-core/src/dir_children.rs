@@ -0,0 +1,146 @@
+//! Directory entries of a cached inode, listed through stable cursors
+//!
+//! Every name added to a [`DirChildren`] is given a cookie one higher than
+//! any handed out before in that directory, and keeps it until it is
+//! removed. Listing walks the names in cookie order and hands the cookie of
+//! the last name returned back as the cursor, so a paginated `readdir`
+//! resumes exactly after it: removing other names doesn't shift it, and
+//! names added meanwhile sort after every existing one. Resuming costs a
+//! range lookup rather than a sort of the whole directory.
+//!
+//! Cookies live only in memory. An inode evicted from the cache and loaded
+//! again gets fresh ones, like any other cached state.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Cursor that starts a listing at the first entry
+pub const CURSOR_START: u64 = 0;
+
+/// Names in a directory mapped to their inodes, in the order they were
+/// added
+#[derive(Debug, Clone, Default)]
+pub struct DirChildren {
+    /// Inode and cookie of every name
+    entries: HashMap<String, (u64, u64)>,
+    /// Names by cookie
+    order: BTreeMap<u64, String>,
+    /// Cookie of the most recently added name
+    last_cookie: u64,
+}
+
+impl DirChildren {
+    /// An empty directory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of names
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether there are no names at all
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Inode a name refers to
+    pub fn get(&self, name: &str) -> Option<&u64> {
+        self.entries.get(name).map(|(ino, _)| ino)
+    }
+
+    /// Inode a name refers to, for repointing it in place
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut u64> {
+        self.entries.get_mut(name).map(|(ino, _)| ino)
+    }
+
+    /// Whether the name exists
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    /// Point a name at an inode, returning the inode it replaced.
+    ///
+    /// A replaced name keeps its place in listings; a new one goes last.
+    pub fn insert(&mut self, name: String, ino: u64) -> Option<u64> {
+        if let Some((old, _)) = self.entries.get_mut(&name) {
+            return Some(std::mem::replace(old, ino));
+        }
+        self.last_cookie += 1;
+        self.order.insert(self.last_cookie, name.clone());
+        self.entries.insert(name, (ino, self.last_cookie));
+        None
+    }
+
+    /// Add a name unless it already exists
+    pub fn insert_if_absent(&mut self, name: &str, ino: u64) {
+        if !self.contains_key(name) {
+            self.insert(name.to_string(), ino);
+        }
+    }
+
+    /// Remove a name, returning its inode
+    pub fn remove(&mut self, name: &str) -> Option<u64> {
+        let (ino, cookie) = self.entries.remove(name)?;
+        self.order.remove(&cookie);
+        Some(ino)
+    }
+
+    /// Every name and inode, in listing order
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &u64)> + '_ {
+        self.order.values().map(|name| (name, &self.entries[name].0))
+    }
+
+    /// Every name, in listing order
+    pub fn keys(&self) -> impl Iterator<Item = &String> + '_ {
+        self.order.values()
+    }
+
+    /// Names listed after `cursor`, with the cursor to resume after each.
+    /// [`CURSOR_START`] lists from the beginning.
+    pub fn after(&self, cursor: u64) -> impl Iterator<Item = (u64, &str, u64)> + '_ {
+        self.order
+            .range(cursor.saturating_add(1)..)
+            .map(|(&cookie, name)| (cookie, name.as_str(), self.entries[name].0))
+    }
+}
+
+impl<'a> IntoIterator for &'a DirChildren {
+    type Item = (&'a String, &'a u64);
+    type IntoIter = Box<dyn Iterator<Item = (&'a String, &'a u64)> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_survives_removal_and_insertion() {
+        let mut children = DirChildren::new();
+        for (i, name) in ["a", "b", "c", "d"].iter().enumerate() {
+            assert_eq!(children.insert(name.to_string(), i as u64 + 10), None);
+        }
+
+        let first: Vec<_> = children.after(CURSOR_START).take(2).collect();
+        assert_eq!(first.iter().map(|e| e.1).collect::<Vec<_>>(), ["a", "b"]);
+        let cursor = first[1].0;
+
+        // Removing an entry already returned and adding a new one neither
+        // skips nor repeats anything
+        children.remove("a");
+        children.insert("0".to_string(), 99);
+        let rest: Vec<&str> = children.after(cursor).map(|e| e.1).collect();
+        assert_eq!(rest, ["c", "d", "0"]);
+
+        // Repointing a name keeps its place
+        assert_eq!(children.insert("c".to_string(), 7), Some(12));
+        assert_eq!(children.keys().map(String::as_str).collect::<Vec<_>>(), ["b", "c", "d", "0"]);
+        assert_eq!(children.get("c"), Some(&7));
+        assert_eq!(children.len(), 4);
+        assert_eq!(children.after(u64::MAX).count(), 0);
+    }
+}
@@ -0,0 +1,205 @@
+//! On-disk hashed directory index
+//!
+//! Small directories keep their entries as a packed list of [`DirEntry`]
+//! records. Once a directory outgrows [`LINEAR_MAX_BLOCKS`] it is converted
+//! to a hashed index. The directory's first block holds an [`IndexHeader`],
+//! the next direct blocks hold tables of bucket block pointers, and each
+//! bucket is a packed list of the entries whose name hashes to it. A lookup
+//! reads the header, one table block and one bucket, and an insert or remove
+//! rewrites only that bucket. When a bucket fills up the bucket count
+//! doubles and every entry is rehashed, which happens a handful of times
+//! over a directory's life.
+//!
+//! Buckets are reached through the index's own tables rather than the
+//! inode's indirect pointers, since only the first direct pointers of an
+//! inode survive a remount.
+
+use crate::blockdev::BLOCK_SIZE;
+use crate::format::DirEntry;
+
+/// Magic number at the start of an indexed directory's first block
+pub const INDEX_MAGIC: &[u8; 8] = b"AEGISDIX";
+
+/// Version of the index format
+pub const INDEX_VERSION: u32 = 1;
+
+/// Largest directory, in blocks, kept in the linear format
+pub const LINEAR_MAX_BLOCKS: u64 = 1;
+
+/// Bucket count of a newly converted directory
+pub const INITIAL_BUCKETS: u32 = 16;
+
+/// Bucket pointers held by one table block
+pub const TABLE_POINTERS: usize = BLOCK_SIZE / 8;
+
+/// Most buckets an index can have; the header and its four table blocks
+/// stay within the direct pointers an on-disk inode keeps
+pub const MAX_BUCKETS: u32 = 4 * TABLE_POINTERS as u32;
+
+/// Fixed part of an entry: inode, record length, name length and type
+const ENTRY_HEADER_SIZE: usize = 8 + 2 + 1 + 1;
+
+/// Bytes an entry takes on disk. `rec_len` counts the inode, name,
+/// terminator and padding but not the length and type fields.
+fn encoded_len(rec_len: u16) -> usize {
+    rec_len as usize + ENTRY_HEADER_SIZE - 8
+}
+
+/// Header in block 0 of an indexed directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexHeader {
+    /// Number of buckets, a power of two
+    pub bucket_count: u32,
+}
+
+impl IndexHeader {
+    /// Parse the header from a directory's first block, or `None` if the
+    /// directory is in the linear format
+    pub fn read_from(block: &[u8]) -> Option<Self> {
+        if block.len() < 16 || &block[..8] != INDEX_MAGIC {
+            return None;
+        }
+        let version = u32::from_le_bytes(block[8..12].try_into().unwrap());
+        let bucket_count = u32::from_le_bytes(block[12..16].try_into().unwrap());
+        if version != INDEX_VERSION
+            || !bucket_count.is_power_of_two()
+            || bucket_count > MAX_BUCKETS
+        {
+            log::warn!(
+                "DIR_INDEX: Ignoring index header with version {} and {} buckets",
+                version, bucket_count
+            );
+            return None;
+        }
+        Some(Self { bucket_count })
+    }
+
+    /// Serialize the header into a zeroed block
+    pub fn to_block(&self) -> Vec<u8> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        block[..8].copy_from_slice(INDEX_MAGIC);
+        block[8..12].copy_from_slice(&INDEX_VERSION.to_le_bytes());
+        block[12..16].copy_from_slice(&self.bucket_count.to_le_bytes());
+        block
+    }
+
+    /// Bucket holding the entry for `name`
+    pub fn bucket_of(&self, name: &str) -> u32 {
+        name_hash(name) & (self.bucket_count - 1)
+    }
+
+    /// Number of table blocks the bucket pointers take up
+    pub fn table_blocks(&self) -> usize {
+        (self.bucket_count as usize).div_ceil(TABLE_POINTERS)
+    }
+}
+
+/// FNV-1a hash of an entry name; stored on disk implicitly through bucket
+/// placement, so it must never change
+pub fn name_hash(name: &str) -> u32 {
+    name.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Parse the entries packed at the start of a block.
+///
+/// Parsing stops at the first zero record length, which marks the unused
+/// tail of the block. Entries with inode 0 are skipped.
+pub fn parse_block(block: &[u8]) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos + ENTRY_HEADER_SIZE <= block.len() {
+        let rec_len = u16::from_le_bytes([block[pos + 8], block[pos + 9]]);
+        let len = encoded_len(rec_len);
+        if rec_len == 0 || pos + len > block.len() {
+            break;
+        }
+        match DirEntry::read_from(&mut &block[pos..pos + len]) {
+            Ok(entry) if entry.inode != 0 => entries.push(entry),
+            Ok(_) => {}
+            Err(e) => {
+                log::warn!("DIR_INDEX: Stopping at unreadable entry at offset {}: {}", pos, e);
+                break;
+            }
+        }
+        pos += len;
+    }
+    entries
+}
+
+/// Bytes the entries take up when packed
+pub fn packed_len(entries: &[DirEntry]) -> usize {
+    entries.iter().map(|entry| encoded_len(entry.rec_len)).sum()
+}
+
+/// Pack entries into a zero-padded block, or `None` if they don't fit
+pub fn pack_block(entries: &[DirEntry]) -> Option<Vec<u8>> {
+    if packed_len(entries) > BLOCK_SIZE {
+        return None;
+    }
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    for entry in entries {
+        entry
+            .write_to(&mut block)
+            .expect("writing to a Vec cannot fail");
+    }
+    block.resize(BLOCK_SIZE, 0);
+    Some(block)
+}
+
+/// Spread entries over the smallest bucket count of at least
+/// `min_buckets` where every bucket fits in a block. Returns the header and
+/// the packed bucket blocks, or `None` if even [`MAX_BUCKETS`] isn't enough.
+pub fn build_index(entries: &[DirEntry], min_buckets: u32) -> Option<(IndexHeader, Vec<Vec<u8>>)> {
+    let mut bucket_count = min_buckets.max(1).next_power_of_two();
+    while bucket_count <= MAX_BUCKETS {
+        let header = IndexHeader { bucket_count };
+        let mut buckets: Vec<Vec<DirEntry>> = (0..bucket_count).map(|_| Vec::new()).collect();
+        for entry in entries {
+            buckets[header.bucket_of(&entry.name) as usize].push(DirEntry::new(entry.inode, &entry.name));
+        }
+        let packed: Option<Vec<Vec<u8>>> = buckets.iter().map(|b| pack_block(b)).collect();
+        if let Some(packed) = packed {
+            return Some((header, packed));
+        }
+        bucket_count *= 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_and_parse_round_trip() {
+        let entries = vec![DirEntry::new(256, "a"), DirEntry::new(7, "second.txt")];
+        let block = pack_block(&entries).unwrap();
+        let parsed = parse_block(&block);
+        assert_eq!(parsed.len(), 2);
+        // Inode 256 has a zero low byte, which must not end the block
+        assert_eq!((parsed[0].inode, parsed[0].name.as_str()), (256, "a"));
+        assert_eq!((parsed[1].inode, parsed[1].name.as_str()), (7, "second.txt"));
+
+        let too_many: Vec<DirEntry> = (0..300).map(|i| DirEntry::new(i + 1, &format!("name_{:05}", i))).collect();
+        assert!(pack_block(&too_many).is_none());
+    }
+
+    #[test]
+    fn test_header_and_bucket_growth() {
+        assert_eq!(IndexHeader::read_from(&[0u8; BLOCK_SIZE]), None);
+        let header = IndexHeader { bucket_count: 32 };
+        assert_eq!(IndexHeader::read_from(&header.to_block()), Some(header));
+
+        // More entries than 16 buckets hold forces a larger index
+        let entries: Vec<DirEntry> = (0..5000).map(|i| DirEntry::new(i + 2, &format!("file_{:05}", i))).collect();
+        let (header, buckets) = build_index(&entries, INITIAL_BUCKETS).unwrap();
+        assert!(header.bucket_count > INITIAL_BUCKETS);
+        assert_eq!(buckets.len(), header.bucket_count as usize);
+        assert_eq!(header.table_blocks(), 1);
+        assert_eq!(IndexHeader { bucket_count: MAX_BUCKETS }.table_blocks(), 4);
+        let total: usize = buckets.iter().map(|b| parse_block(b).len()).sum();
+        assert_eq!(total, entries.len());
+    }
+}
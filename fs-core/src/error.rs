@@ -1,45 +1,83 @@
-use std::fmt;
 use std::io;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    Io(io::Error),
+    /// A device or host filesystem operation failed
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid superblock")]
     InvalidSuperblock,
+    #[error("Invalid inode")]
     InvalidInode,
+    #[error("Invalid path")]
     InvalidPath,
+    #[error("Not a directory")]
     NotADirectory,
+    /// A file operation was given a directory
+    #[error("Is a directory")]
+    IsADirectory,
+    #[error("File or directory already exists")]
     AlreadyExists,
+    #[error("File or directory not found")]
     NotFound,
+    #[error("Directory not empty")]
     NotEmpty,
+    #[error("Invalid argument")]
     InvalidArgument,
+    /// A write would take a file past the largest size it can have
+    #[error("File too large")]
+    FileTooLarge,
+    /// A write would need more data blocks than are free
+    #[error("No space left on device")]
+    NoSpace,
+    #[error("Operation not supported")]
     Unsupported,
+    /// The caller lacks access to a file
+    #[error("Permission denied")]
+    PermissionDenied,
+    /// The caller isn't privileged enough for the operation, as opposed to
+    /// lacking access to a file
+    #[error("Operation not permitted")]
+    NotPermitted,
+    /// Every inode number is in use
+    #[error("No free inodes available")]
+    InodeExhausted,
+    /// The parent directory of a new entry is not in the inode cache
+    #[error("Parent directory {0} not found")]
+    ParentNotFound(u64),
+    /// The inode bitmap handed out an inode that is already in use
+    #[error("Inode bitmap is corrupt: {0}")]
+    InodeBitmapCorrupt(String),
+    /// A write or create would take a project over its block or inode limit
+    #[error("Quota exceeded for project {0}")]
+    QuotaExceeded(u32),
+    /// A long-running operation was stopped through its cancellation token
+    #[error("Operation cancelled")]
+    Cancelled,
+    /// The master key of an encrypted directory is not in the keyring
+    #[error("Encryption key not available")]
+    NoKey,
+    /// An extended attribute that is not set was asked for or removed
+    #[error("No such extended attribute")]
+    NoAttribute,
+    /// A file handle refers to an inode number that has since been reused
+    #[error("Stale file handle for inode {0}")]
+    Stale(u64),
+    /// Queued writes or metadata for an inode could not be written to disk
+    #[error("Failed to flush dirty data for inode {ino}")]
+    DirtyFlushFailed {
+        /// Inode whose data was not flushed
+        ino: u64,
+        /// Why the flush failed
+        #[source]
+        source: crate::FsError,
+    },
+    #[error("Error: {0}")]
     Other(String),
-}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Error::Io(e) => write!(f, "I/O error: {}", e),
-            Error::InvalidSuperblock => write!(f, "Invalid superblock"),
-            Error::InvalidInode => write!(f, "Invalid inode"),
-            Error::InvalidPath => write!(f, "Invalid path"),
-            Error::NotADirectory => write!(f, "Not a directory"),
-            Error::AlreadyExists => write!(f, "File or directory already exists"),
-            Error::NotFound => write!(f, "File or directory not found"),
-            Error::NotEmpty => write!(f, "Directory not empty"),
-            Error::InvalidArgument => write!(f, "Invalid argument"),
-            Error::Unsupported => write!(f, "Operation not supported"),
-            Error::Other(msg) => write!(f, "Error: {}", msg),
-        }
-    }
-}
-
-impl std::error::Error for Error {}
-
-impl From<io::Error> for Error {
-    fn from(err: io::Error) -> Self {
-        Error::Io(err)
-    }
+    /// A lower-layer error with context about what was being done; the
+    /// original error stays available through the chain
+    #[error("{0:#}")]
+    Context(#[from] anyhow::Error),
 }
 
 impl From<crate::FileSystemError> for Error {
@@ -57,9 +95,7 @@ impl From<crate::FileSystemError> for Error {
             crate::FileSystemError::NotADirectory => Error::NotADirectory,
             crate::FileSystemError::AlreadyExists => Error::AlreadyExists,
             crate::FileSystemError::InvalidName => Error::InvalidPath,
-            crate::FileSystemError::PermissionDenied => {
-                Error::Other("Permission denied".to_string())
-            }
+            crate::FileSystemError::PermissionDenied => Error::PermissionDenied,
             crate::FileSystemError::Io(e) => Error::Io(e),
             crate::FileSystemError::Layout(e) => Error::Other(format!("Layout error: {:?}", e)),
         }
@@ -83,6 +119,38 @@ impl From<crate::BlockDeviceError> for Error {
             crate::BlockDeviceError::DeviceClosed => {
                 Error::Other("Device is already closed".to_string())
             }
+            crate::BlockDeviceError::Locked(path) => {
+                Error::Other(format!("Device {} is already opened read-write", path))
+            }
+            crate::BlockDeviceError::BlockSizeMismatch { expected, found } => Error::Other(
+                format!("Backing devices have mismatched block sizes: {} and {}", expected, found),
+            ),
+            crate::BlockDeviceError::InvalidStripe(msg) => {
+                Error::Other(format!("Invalid stripe layout: {}", msg))
+            }
+            crate::BlockDeviceError::InvalidRegion(msg) => {
+                Error::Other(format!("Invalid device region: {}", msg))
+            }
+        }
+    }
+}
+
+impl From<crate::FsError> for Error {
+    fn from(err: crate::FsError) -> Self {
+        match err {
+            crate::FsError::Io(e) => e.into(),
+            crate::FsError::InvalidInode | crate::FsError::CorruptInode => Error::InvalidInode,
+            crate::FsError::FileNotFound => Error::NotFound,
+            crate::FsError::NotADirectory => Error::NotADirectory,
+            crate::FsError::IsADirectory => Error::IsADirectory,
+            crate::FsError::NoFreeInodes => Error::InodeExhausted,
+            crate::FsError::DirectoryNotEmpty => Error::NotEmpty,
+            crate::FsError::AlreadyExists => Error::AlreadyExists,
+            crate::FsError::InvalidArgument(_) => Error::InvalidArgument,
+            crate::FsError::FileTooLarge => Error::FileTooLarge,
+            crate::FsError::NoFreeBlocks => Error::NoSpace,
+            crate::FsError::NoKey => Error::NoKey,
+            e => Error::Other(e.to_string()),
         }
     }
 }
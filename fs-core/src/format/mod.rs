@@ -11,11 +11,15 @@ use thiserror::Error;
 
 use crate::blockdev::BlockDevice;
 use crate::layout::DiskFsTrait;
+use crate::modules::fscrypt::FSCRYPT_CONTEXT_SIZE;
 
 /// Magic number for AegisFS filesystem
 const AEGISFS_MAGIC: &[u8; 8] = b"AEGISFS\x00";
-/// Current filesystem version
-const FS_VERSION: u32 = 1;
+/// Current filesystem version; 2 widened inodes to 256 bytes
+const FS_VERSION: u32 = 2;
+
+/// Size of an on-disk inode slot in bytes
+pub const INODE_SIZE: usize = 256;
 
 /// Filesystem metadata stored at the beginning of the partition
 /// On-disk inode structure
@@ -35,6 +39,18 @@ pub struct Inode {
     pub mtime: u64,
     /// Creation time
     pub ctime: u64,
+    /// Nanoseconds part of `atime`
+    pub atime_nsec: u32,
+    /// Nanoseconds part of `mtime`
+    pub mtime_nsec: u32,
+    /// Nanoseconds part of `ctime`
+    pub ctime_nsec: u32,
+    /// Birth time, set when the inode is created and never changed
+    pub crtime: u64,
+    /// Nanoseconds part of `crtime`
+    pub crtime_nsec: u32,
+    /// Project the inode is accounted to; 0 for none
+    pub project_id: u32,
     /// Number of hard links
     pub links: u16,
     /// Number of 512-byte blocks allocated
@@ -47,7 +63,7 @@ pub struct Inode {
     pub block: [u64; 15],
     /// File version (for NFS)
     pub generation: u32,
-    /// File ACL
+    /// Data block holding the extended attribute header; 0 for none
     pub file_acl: u32,
     /// Directory ACL or high 32 bits of size
     pub dir_acl: u32,
@@ -55,6 +71,12 @@ pub struct Inode {
     pub faddr: u32,
     /// OS specific value 2
     pub osd2: [u8; 12],
+    /// Serialized `FscryptContext` of an encrypted inode; all zeros if it
+    /// isn't encrypted
+    pub fscrypt_context: [u8; FSCRYPT_CONTEXT_SIZE],
+    /// Directory the inode was created in or last renamed into; 0 if
+    /// unknown. A hard-linked file keeps the parent of its first name
+    pub parent: u64,
 }
 
 /// Directory entry structure
@@ -175,7 +197,51 @@ impl DirEntry {
 }
 
 impl Inode {
-    /// Write inode to buffer (exactly 128 bytes)
+    /// Byte offset of the timestamp nanoseconds, followed by the birth time,
+    /// project ID and extended attribute block, within the inode slot
+    pub const NSEC_OFFSET: u64 = 128;
+
+    /// Byte offset of the block pointers that don't fit in the first 128
+    /// bytes: the last direct pointers and the indirect pointers
+    pub const BLOCK_TAIL_OFFSET: u64 = 160;
+
+    /// Block pointers stored before `NSEC_OFFSET`
+    pub const HEAD_BLOCK_POINTERS: usize = 8;
+
+    /// Byte offset of the fscrypt context, after the last block pointer,
+    /// followed by the parent directory
+    pub const FSCRYPT_CONTEXT_OFFSET: u64 = 216;
+
+    /// `flags` bit of a file whose data is kept in `block` instead of in
+    /// data blocks
+    pub const INLINE_DATA_FL: u32 = 0x1000_0000;
+
+    /// Bytes of file data that fit in the block pointers
+    pub const INLINE_DATA_MAX: usize = 15 * 8;
+
+    /// Whether the file's data is stored inline
+    pub fn has_inline_data(&self) -> bool {
+        self.flags & Self::INLINE_DATA_FL != 0
+    }
+
+    /// The bytes stored in the block pointers of an inline file
+    pub fn inline_data(&self) -> [u8; Self::INLINE_DATA_MAX] {
+        let mut data = [0u8; Self::INLINE_DATA_MAX];
+        for (chunk, pointer) in data.chunks_exact_mut(8).zip(self.block) {
+            chunk.copy_from_slice(&pointer.to_le_bytes());
+        }
+        data
+    }
+
+    /// Store `data` in the block pointers and mark the file inline
+    pub fn set_inline_data(&mut self, data: &[u8; Self::INLINE_DATA_MAX]) {
+        for (pointer, chunk) in self.block.iter_mut().zip(data.chunks_exact(8)) {
+            *pointer = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        self.flags |= Self::INLINE_DATA_FL;
+    }
+
+    /// Write inode to buffer (exactly `INODE_SIZE` bytes)
     pub fn write_to<W: Write>(&self, buf: &mut W) -> io::Result<()> {
         // Calculate total size to ensure we write exactly 128 bytes
         // mode: 4, uid: 4, gid: 4, size: 8, atime: 8, mtime: 8, ctime: 8,
@@ -183,9 +249,9 @@ impl Inode {
         // generation: 4, file_acl: 4, dir_acl: 4, faddr: 4, osd2: 12
         // Total: 4*4 + 8*5 + 2 + 8 + 4 + 4 + 120 + 4*4 + 12 = 16 + 40 + 2 + 8 + 4 + 4 + 120 + 16 + 12 = 222 bytes
 
-        // But we only want to write 128 bytes, so we need to adjust the structure.
-        // Let's create a fixed-size buffer and write to that first.
-        let mut buffer = [0u8; 128];
+        // But the first 128 bytes only hold 8 block pointers; the rest follow
+        // at BLOCK_TAIL_OFFSET. Timestamp nanoseconds follow at NSEC_OFFSET.
+        let mut buffer = [0u8; INODE_SIZE];
         let mut cursor = std::io::Cursor::new(&mut buffer[..]);
 
         // Write fixed-size fields (4+4+4+8+8+8+8+2+8+4+4 = 62 bytes)
@@ -201,12 +267,29 @@ impl Inode {
         cursor.write_u32::<LittleEndian>(self.flags)?;
         cursor.write_all(&self.osd1)?;
 
-        // Write block pointers (truncate to fit in remaining space)
-        let max_blocks = (128 - 62) / 8; // (128 - 62) / 8 = 8 blocks max
-        for &block in self.block.iter().take(max_blocks) {
+        // Write the block pointers that fit in the remaining space
+        for &block in self.block.iter().take(Self::HEAD_BLOCK_POINTERS) {
             cursor.write_u64::<LittleEndian>(block)?;
         }
 
+        cursor.set_position(Self::NSEC_OFFSET);
+        cursor.write_u32::<LittleEndian>(self.atime_nsec)?;
+        cursor.write_u32::<LittleEndian>(self.mtime_nsec)?;
+        cursor.write_u32::<LittleEndian>(self.ctime_nsec)?;
+        cursor.write_u64::<LittleEndian>(self.crtime)?;
+        cursor.write_u32::<LittleEndian>(self.crtime_nsec)?;
+        cursor.write_u32::<LittleEndian>(self.project_id)?;
+        cursor.write_u32::<LittleEndian>(self.file_acl)?;
+
+        cursor.set_position(Self::BLOCK_TAIL_OFFSET);
+        for &block in &self.block[Self::HEAD_BLOCK_POINTERS..] {
+            cursor.write_u64::<LittleEndian>(block)?;
+        }
+
+        cursor.set_position(Self::FSCRYPT_CONTEXT_OFFSET);
+        cursor.write_all(&self.fscrypt_context)?;
+        cursor.write_u64::<LittleEndian>(self.parent)?;
+
         // Write the buffer to the actual writer
         buf.write_all(&buffer)?;
 
@@ -216,6 +299,8 @@ impl Inode {
 
 #[derive(Debug)]
 pub struct Superblock {
+    /// CRC32 of the serialized superblock bytes that follow this field
+    pub superblock_crc: u32,
     /// Magic number (AEGISFS\x00)
     pub magic: [u8; 8],
     /// Filesystem version
@@ -238,10 +323,211 @@ pub struct Superblock {
     pub last_mount: u64,
     /// Timestamp of last write
     pub last_write: u64,
+    /// Mount state: `STATE_CLEAN` or `STATE_DIRTY` while mounted read-write
+    pub state: u16,
     /// Filesystem UUID
     pub uuid: [u8; 16],
     /// Volume name
     pub volume_name: [u8; 64],
+    /// Blocks set aside for the journal; 0 when there is no journal
+    pub journal_blocks: u64,
+    /// Percentage of blocks kept back from ordinary file writes
+    pub reserved_blocks_percent: u8,
+    /// Read-write mounts since the count was last reset
+    pub mount_count: u16,
+    /// Mounts after which a check is recommended; 0 disables the warning
+    pub max_mount_count: u16,
+    /// Blocks set aside for the audit log; 0 when auditing is off
+    pub audit_log_blocks: u64,
+    /// Limits of the projects that have them, at most `MAX_PROJECT_LIMITS`
+    pub project_limits: Vec<ProjectLimit>,
+    /// Mounts that ended without a clean unmount since `fsck --force` last
+    /// reset the count
+    pub unclean_mount_count: u32,
+    /// The most recent mounts
+    pub mount_history: MountHistory,
+    /// Data block holding the trained compression dictionary; 0 until one
+    /// is trained
+    pub compression_dict_block: u64,
+    /// `COMPAT_*` features in use; a binary that doesn't know one can
+    /// still mount read-write
+    pub features_compat: u32,
+    /// `INCOMPAT_*` features in use; a binary that doesn't know one can't
+    /// read the filesystem and refuses to open it
+    pub features_incompat: u32,
+    /// `RO_COMPAT_*` features in use; a binary that doesn't know one could
+    /// leave them inconsistent by writing, so it refuses read-write mounts
+    pub features_ro_compat: u32,
+}
+
+/// One mount of the filesystem, kept in the superblock's [`MountHistory`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MountEvent {
+    /// When the filesystem was mounted, in seconds since the Unix epoch
+    pub mount_time: u64,
+    /// When it was unmounted; 0 while it is mounted or if it never was
+    pub unmount_time: u64,
+    /// The mount ended with a clean unmount
+    pub clean: bool,
+}
+
+impl MountEvent {
+    /// Size of one serialized event in bytes
+    const SIZE: usize = 8 + 8 + 1;
+}
+
+/// The last [`MountHistory::CAPACITY`] mounts, oldest first.
+///
+/// Recording a mount when the history is full drops the oldest one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MountHistory {
+    events: Vec<MountEvent>,
+}
+
+impl MountHistory {
+    /// Mounts the history keeps
+    pub const CAPACITY: usize = 8;
+
+    /// Size of the serialized history in bytes: the slots and a count
+    const SIZE: usize = Self::CAPACITY * MountEvent::SIZE + 1;
+
+    /// Recorded mounts, oldest first
+    pub fn events(&self) -> &[MountEvent] {
+        &self.events
+    }
+
+    /// Record a mount at `time`
+    pub fn record_mount(&mut self, time: u64) {
+        if self.events.len() == Self::CAPACITY {
+            self.events.remove(0);
+        }
+        self.events.push(MountEvent {
+            mount_time: time,
+            ..MountEvent::default()
+        });
+    }
+
+    /// Record a clean unmount at `time` of the latest mount, if it is still
+    /// open
+    pub fn record_unmount(&mut self, time: u64) {
+        if let Some(event) = self.events.last_mut().filter(|e| e.unmount_time == 0 && !e.clean) {
+            event.unmount_time = time;
+            event.clean = true;
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for slot in 0..Self::CAPACITY {
+            let event = self.events.get(slot).copied().unwrap_or_default();
+            writer.write_u64::<LittleEndian>(event.mount_time)?;
+            writer.write_u64::<LittleEndian>(event.unmount_time)?;
+            writer.write_u8(event.clean as u8)?;
+        }
+        writer.write_u8(self.events.len() as u8)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut events = Vec::with_capacity(Self::CAPACITY);
+        for _ in 0..Self::CAPACITY {
+            events.push(MountEvent {
+                mount_time: reader.read_u64::<LittleEndian>()?,
+                unmount_time: reader.read_u64::<LittleEndian>()?,
+                clean: reader.read_u8()? != 0,
+            });
+        }
+        events.truncate((reader.read_u8()? as usize).min(Self::CAPACITY));
+        Ok(Self { events })
+    }
+}
+
+/// Record of when the filesystem was last frozen and unfrozen, kept in a
+/// block of the journal region so snapshots can be checked against it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FreezeInfoBlock {
+    /// Times the filesystem has been frozen
+    pub freeze_count: u64,
+    /// Seconds since the epoch of the last freeze; 0 if never frozen
+    pub last_freeze_time: u64,
+    /// Seconds since the epoch of the last unfreeze; 0 if never unfrozen
+    pub last_unfreeze_time: u64,
+}
+
+impl FreezeInfoBlock {
+    /// Size of the serialized record in bytes
+    pub const SIZE: usize = 512;
+
+    /// Whether the filesystem is frozen now
+    pub fn is_frozen(&self) -> bool {
+        self.last_freeze_time > 0 && self.last_unfreeze_time < self.last_freeze_time
+    }
+
+    /// Whether `time` falls between the last freeze and the unfreeze that
+    /// followed it, or after the last freeze while still frozen
+    pub fn covers(&self, time: u64) -> bool {
+        self.last_freeze_time > 0
+            && time >= self.last_freeze_time
+            && (self.is_frozen() || time <= self.last_unfreeze_time)
+    }
+
+    /// Record a freeze at `time`
+    pub fn record_freeze(&mut self, time: u64) {
+        self.freeze_count = self.freeze_count.saturating_add(1);
+        self.last_freeze_time = time;
+    }
+
+    /// Record an unfreeze at `time`
+    pub fn record_unfreeze(&mut self, time: u64) {
+        self.last_unfreeze_time = time;
+    }
+
+    /// Serialize to [`Self::SIZE`] bytes, CRC first
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; Self::SIZE];
+        bytes[4..12].copy_from_slice(&self.freeze_count.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.last_freeze_time.to_le_bytes());
+        bytes[20..28].copy_from_slice(&self.last_unfreeze_time.to_le_bytes());
+        let crc = crc32fast::hash(&bytes[4..]);
+        bytes[..4].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Parse a record. An all-zero block, as format leaves it, is a
+    /// filesystem that was never frozen; a bad CRC is an error
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FormatError> {
+        let bytes = bytes.get(..Self::SIZE).ok_or_else(|| {
+            FormatError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "freeze record is truncated"))
+        })?;
+        if bytes.iter().all(|&b| b == 0) {
+            return Ok(Self::default());
+        }
+        let stored = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+        let computed = crc32fast::hash(&bytes[4..]);
+        if stored != computed {
+            return Err(FormatError::CorruptFreezeInfo { stored, computed });
+        }
+        let field = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        Ok(Self {
+            freeze_count: field(4),
+            last_freeze_time: field(12),
+            last_unfreeze_time: field(20),
+        })
+    }
+}
+
+/// Block and inode limits of one project, kept in the superblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProjectLimit {
+    /// Project ID; never 0, which means "no project"
+    pub project_id: u32,
+    /// Data blocks the project may use; 0 for no limit
+    pub block_limit: u64,
+    /// Inodes the project may use; 0 for no limit
+    pub inode_limit: u64,
+}
+
+impl ProjectLimit {
+    /// Size of one serialized entry in bytes
+    const SIZE: usize = 4 + 8 + 8;
 }
 
 impl Default for Superblock {
@@ -250,6 +536,7 @@ impl Default for Superblock {
         getrandom::getrandom(&mut uuid).expect("Failed to generate UUID");
 
         Self {
+            superblock_crc: 0,
             magic: *AEGISFS_MAGIC,
             version: FS_VERSION,
             size: 0,
@@ -261,8 +548,21 @@ impl Default for Superblock {
             root_inode: 1, // FUSE root inode number
             last_mount: 0,
             last_write: 0,
+            state: Superblock::STATE_CLEAN,
             uuid,
             volume_name: [0; 64],
+            journal_blocks: 0,
+            reserved_blocks_percent: 0,
+            mount_count: 0,
+            max_mount_count: 0,
+            audit_log_blocks: 0,
+            project_limits: Vec::new(),
+            unclean_mount_count: 0,
+            mount_history: MountHistory::default(),
+            compression_dict_block: 0,
+            features_compat: 0,
+            features_incompat: 0,
+            features_ro_compat: 0,
         }
     }
 }
@@ -278,11 +578,86 @@ pub enum FormatError {
     UnsupportedVersion(u32),
     #[error("Invalid filesystem size")]
     InvalidSize,
+    /// Neither the primary nor any backup superblock passed its checksum
+    #[error("Superblock checksum mismatch (stored {stored:#010x}, computed {computed:#010x})")]
+    CorruptSuperblock {
+        /// Checksum stored in the superblock
+        stored: u32,
+        /// Checksum of the superblock as read
+        computed: u32,
+    },
+    /// The freeze record failed its checksum
+    #[error("Freeze record checksum mismatch (stored {stored:#010x}, computed {computed:#010x})")]
+    CorruptFreezeInfo {
+        /// Checksum stored in the record
+        stored: u32,
+        /// Checksum of the record as read
+        computed: u32,
+    },
 }
 
 impl Superblock {
     /// Size of the superblock in bytes
-    pub const SIZE: usize = 8 + 4 + 8 + 4 + 8 + 8 + 8 + 8 + 8 + 8 + 16 + 64; // 140 bytes
+    pub const SIZE: usize = 4 + 8 + 4 + 8 + 4 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 2 + 16 + 64 + 8 + 1 + 2 + 2 + 8
+        + Self::MAX_PROJECT_LIMITS * ProjectLimit::SIZE
+        + 4
+        + MountHistory::SIZE
+        + 8
+        + 4 + 4 + 4; // 988 bytes
+
+    /// Projects that can have limits; the table has a fixed number of slots
+    pub const MAX_PROJECT_LIMITS: usize = 32;
+
+    /// Unclean mounts after which mounting is refused until `fsck --force`
+    pub const MAX_UNCLEAN_MOUNTS: u32 = 3;
+
+    /// Largest volume name that fits, leaving room for a NUL terminator
+    pub const MAX_VOLUME_NAME: usize = 63;
+
+    /// Highest `reserved_blocks_percent` that can be set
+    pub const MAX_RESERVED_PERCENT: u8 = 50;
+
+    /// The filesystem was cleanly unmounted
+    pub const STATE_CLEAN: u16 = 1;
+    /// The filesystem is mounted read-write, or was not cleanly unmounted
+    pub const STATE_DIRTY: u16 = 2;
+
+    /// Blocks are set aside for a journal
+    pub const COMPAT_JOURNAL: u32 = 1 << 0;
+    /// Blocks are set aside for an audit log
+    pub const COMPAT_AUDIT_LOG: u32 = 1 << 1;
+    /// A data block holds a trained compression dictionary
+    pub const COMPAT_COMPRESSION_DICT: u32 = 1 << 2;
+
+    /// Projects have block and inode limits that writes must be charged to
+    pub const RO_COMPAT_PROJECT_QUOTA: u32 = 1 << 0;
+
+    /// Some directories are encrypted, so their names and file contents
+    /// can't be read without their key
+    pub const INCOMPAT_ENCRYPTION: u32 = 1 << 0;
+    /// Reflink clones share data blocks, so freeing a block has to check
+    /// whether another file still uses it
+    pub const INCOMPAT_SHARED_BLOCKS: u32 = 1 << 1;
+    /// Small files keep their data in the inode, so their block pointers
+    /// aren't pointers
+    pub const INCOMPAT_INLINE_DATA: u32 = 1 << 2;
+
+    /// Compatible features this binary understands
+    pub const KNOWN_COMPAT: u32 =
+        Self::COMPAT_JOURNAL | Self::COMPAT_AUDIT_LOG | Self::COMPAT_COMPRESSION_DICT;
+    /// Read-only compatible features this binary understands
+    pub const KNOWN_RO_COMPAT: u32 = Self::RO_COMPAT_PROJECT_QUOTA;
+    /// Incompatible features this binary understands
+    pub const KNOWN_INCOMPAT: u32 =
+        Self::INCOMPAT_ENCRYPTION | Self::INCOMPAT_SHARED_BLOCKS | Self::INCOMPAT_INLINE_DATA;
+
+    /// Block number of the backup superblock for a filesystem of `block_count` blocks.
+    ///
+    /// The backup lives at the midpoint of the device so that damage to the
+    /// start of the device (the most common failure) leaves it intact.
+    pub fn backup_block(block_count: u64) -> u64 {
+        block_count / 2
+    }
 
     /// Create a new superblock for a filesystem of the given size
     pub fn new(size: u64, volume_name: Option<&str>) -> io::Result<Self> {
@@ -304,9 +679,7 @@ impl Superblock {
 
         // Set volume name if provided
         if let Some(name) = volume_name {
-            let name_bytes = name.as_bytes();
-            let len = name_bytes.len().min(63);
-            sb.volume_name[..len].copy_from_slice(&name_bytes[..len]);
+            sb.set_volume_name(name);
         }
 
         let now = std::time::SystemTime::now()
@@ -320,21 +693,81 @@ impl Superblock {
         Ok(sb)
     }
 
+    /// Replace the volume name, truncating it to `MAX_VOLUME_NAME` bytes
+    pub fn set_volume_name(&mut self, name: &str) {
+        let name_bytes = name.as_bytes();
+        let len = name_bytes.len().min(Self::MAX_VOLUME_NAME);
+        self.volume_name = [0; 64];
+        self.volume_name[..len].copy_from_slice(&name_bytes[..len]);
+    }
+
+    /// Incompatible features in use that this binary doesn't understand
+    pub fn unknown_incompat(&self) -> u32 {
+        self.features_incompat & !Self::KNOWN_INCOMPAT
+    }
+
+    /// Read-only compatible features in use that this binary doesn't
+    /// understand
+    pub fn unknown_ro_compat(&self) -> u32 {
+        self.features_ro_compat & !Self::KNOWN_RO_COMPAT
+    }
+
+    /// Blocks kept back from ordinary file writes
+    pub fn reserved_blocks(&self) -> u64 {
+        self.block_count * self.reserved_blocks_percent as u64 / 100
+    }
+
+    /// Serialize every field after `superblock_crc`
+    fn body_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::with_capacity(Self::SIZE - 4);
+        body.write_all(&self.magic)?;
+        body.write_u32::<LittleEndian>(self.version)?;
+        body.write_u64::<LittleEndian>(self.size)?;
+        body.write_u32::<LittleEndian>(self.block_size)?;
+        body.write_u64::<LittleEndian>(self.block_count)?;
+        body.write_u64::<LittleEndian>(self.free_blocks)?;
+        body.write_u64::<LittleEndian>(self.inode_count)?;
+        body.write_u64::<LittleEndian>(self.free_inodes)?;
+        body.write_u64::<LittleEndian>(self.root_inode)?;
+        body.write_u64::<LittleEndian>(self.last_mount)?;
+        body.write_u64::<LittleEndian>(self.last_write)?;
+        body.write_u16::<LittleEndian>(self.state)?;
+        body.write_all(&self.uuid)?;
+        body.write_all(&self.volume_name)?;
+        body.write_u64::<LittleEndian>(self.journal_blocks)?;
+        body.write_u8(self.reserved_blocks_percent)?;
+        body.write_u16::<LittleEndian>(self.mount_count)?;
+        body.write_u16::<LittleEndian>(self.max_mount_count)?;
+        body.write_u64::<LittleEndian>(self.audit_log_blocks)?;
+        if self.project_limits.len() > Self::MAX_PROJECT_LIMITS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("More than {} project limits", Self::MAX_PROJECT_LIMITS),
+            ));
+        }
+        for slot in 0..Self::MAX_PROJECT_LIMITS {
+            let limit = self.project_limits.get(slot).copied().unwrap_or_default();
+            body.write_u32::<LittleEndian>(limit.project_id)?;
+            body.write_u64::<LittleEndian>(limit.block_limit)?;
+            body.write_u64::<LittleEndian>(limit.inode_limit)?;
+        }
+        body.write_u32::<LittleEndian>(self.unclean_mount_count)?;
+        self.mount_history.write_to(&mut body)?;
+        body.write_u64::<LittleEndian>(self.compression_dict_block)?;
+        body.write_u32::<LittleEndian>(self.features_compat)?;
+        body.write_u32::<LittleEndian>(self.features_incompat)?;
+        body.write_u32::<LittleEndian>(self.features_ro_compat)?;
+        Ok(body)
+    }
+
     /// Write the superblock to a writer
+    ///
+    /// The CRC32 of the serialized fields is computed here and written first,
+    /// so the stored `superblock_crc` field is ignored.
     pub fn write_to<W: Write + Seek>(&self, writer: &mut W) -> io::Result<()> {
-        writer.write_all(&self.magic)?;
-        writer.write_u32::<LittleEndian>(self.version)?;
-        writer.write_u64::<LittleEndian>(self.size)?;
-        writer.write_u32::<LittleEndian>(self.block_size)?;
-        writer.write_u64::<LittleEndian>(self.block_count)?;
-        writer.write_u64::<LittleEndian>(self.free_blocks)?;
-        writer.write_u64::<LittleEndian>(self.inode_count)?;
-        writer.write_u64::<LittleEndian>(self.free_inodes)?;
-        writer.write_u64::<LittleEndian>(self.root_inode)?;
-        writer.write_u64::<LittleEndian>(self.last_mount)?;
-        writer.write_u64::<LittleEndian>(self.last_write)?;
-        writer.write_all(&self.uuid)?;
-        writer.write_all(&self.volume_name)?;
+        let body = self.body_bytes()?;
+        writer.write_u32::<LittleEndian>(crc32fast::hash(&body))?;
+        writer.write_all(&body)?;
 
         // Pad to block size
         let pos = writer.stream_position()?;
@@ -346,7 +779,21 @@ impl Superblock {
     }
 
     /// Read a superblock from a reader
+    ///
+    /// The CRC is verified before any field is interpreted, so a flipped bit
+    /// anywhere in the superblock is reported as `CorruptSuperblock`.
     pub fn read_from<R: Read + Seek>(reader: &mut R) -> Result<Self, FormatError> {
+        let mut raw = [0u8; Self::SIZE];
+        reader.read_exact(&mut raw)?;
+
+        let stored = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+        let computed = crc32fast::hash(&raw[4..]);
+        if stored != computed {
+            return Err(FormatError::CorruptSuperblock { stored, computed });
+        }
+
+        let mut reader = Cursor::new(&raw[4..]);
+
         let mut magic = [0u8; 8];
         reader.read_exact(&mut magic)?;
 
@@ -368,6 +815,7 @@ impl Superblock {
         let root_inode = reader.read_u64::<LittleEndian>()?;
         let last_mount = reader.read_u64::<LittleEndian>()?;
         let last_write = reader.read_u64::<LittleEndian>()?;
+        let state = reader.read_u16::<LittleEndian>()?;
 
         let mut uuid = [0u8; 16];
         reader.read_exact(&mut uuid)?;
@@ -375,7 +823,32 @@ impl Superblock {
         let mut volume_name = [0u8; 64];
         reader.read_exact(&mut volume_name)?;
 
+        let journal_blocks = reader.read_u64::<LittleEndian>()?;
+        let reserved_blocks_percent = reader.read_u8()?;
+        let mount_count = reader.read_u16::<LittleEndian>()?;
+        let max_mount_count = reader.read_u16::<LittleEndian>()?;
+        let audit_log_blocks = reader.read_u64::<LittleEndian>()?;
+
+        let mut project_limits = Vec::new();
+        for _ in 0..Self::MAX_PROJECT_LIMITS {
+            let limit = ProjectLimit {
+                project_id: reader.read_u32::<LittleEndian>()?,
+                block_limit: reader.read_u64::<LittleEndian>()?,
+                inode_limit: reader.read_u64::<LittleEndian>()?,
+            };
+            if limit.project_id != 0 {
+                project_limits.push(limit);
+            }
+        }
+        let unclean_mount_count = reader.read_u32::<LittleEndian>()?;
+        let mount_history = MountHistory::read_from(&mut reader)?;
+        let compression_dict_block = reader.read_u64::<LittleEndian>()?;
+        let features_compat = reader.read_u32::<LittleEndian>()?;
+        let features_incompat = reader.read_u32::<LittleEndian>()?;
+        let features_ro_compat = reader.read_u32::<LittleEndian>()?;
+
         Ok(Self {
+            superblock_crc: stored,
             magic,
             version,
             size,
@@ -387,10 +860,106 @@ impl Superblock {
             root_inode,
             last_mount,
             last_write,
+            state,
             uuid,
             volume_name,
+            journal_blocks,
+            reserved_blocks_percent,
+            mount_count,
+            max_mount_count,
+            audit_log_blocks,
+            project_limits,
+            unclean_mount_count,
+            mount_history,
+            compression_dict_block,
+            features_compat,
+            features_incompat,
+            features_ro_compat,
         })
     }
+
+    /// Block count recorded in a superblock image whose checksum doesn't
+    /// match, if its magic is still intact
+    fn damaged_block_count(raw: &[u8]) -> Option<u64> {
+        if raw.len() < 36 || &raw[4..12] != AEGISFS_MAGIC {
+            return None;
+        }
+        Some(u64::from_le_bytes(raw[28..36].try_into().ok()?))
+    }
+
+    /// Blocks that may hold the backup copy, most likely first.
+    ///
+    /// The backup sits at the midpoint of the filesystem, which can be
+    /// smaller than the device. The block count of a damaged primary is
+    /// tried first, then a filesystem filling the device, then every whole
+    /// GiB size that fits, as `format --size` makes.
+    fn backup_candidates(primary: &[u8], device_blocks: u64, block_size: u64) -> Vec<u64> {
+        let blocks_per_gib = (1024 * 1024 * 1024) / block_size;
+        let mut candidates = Vec::new();
+        let counts = Self::damaged_block_count(primary)
+            .into_iter()
+            .chain(std::iter::once(device_blocks))
+            .chain((1..=device_blocks / blocks_per_gib).rev().map(|gib| gib * blocks_per_gib));
+        for count in counts {
+            let backup = Self::backup_block(count);
+            if backup > 0 && count <= device_blocks && !candidates.contains(&backup) {
+                candidates.push(backup);
+            }
+        }
+        candidates
+    }
+
+    /// Read the superblock from a block device, falling back to the backup copy
+    /// at the filesystem midpoint if the primary copy is corrupt.
+    pub async fn read_from_disk(device: &dyn BlockDevice) -> Result<Self, FormatError> {
+        let to_format_err = |e: crate::blockdev::BlockDeviceError| FormatError::Io(io::Error::other(e.to_string()));
+
+        let mut primary = vec![0u8; device.block_size()];
+        device.read_block(0, &mut primary).await.map_err(to_format_err)?;
+
+        let primary_err = match Self::read_from(&mut Cursor::new(&primary[..])) {
+            Ok(sb) => return Ok(sb),
+            Err(e @ FormatError::CorruptSuperblock { .. }) | Err(e @ FormatError::InvalidMagic) => e,
+            Err(e) => return Err(e),
+        };
+        log::warn!("SUPERBLOCK: Primary superblock unreadable ({}), trying the backup", primary_err);
+
+        let mut buf = vec![0u8; device.block_size()];
+        for backup in Self::backup_candidates(&primary, device.block_count(), device.block_size() as u64) {
+            if device.read_block(backup, &mut buf).await.is_err() {
+                continue;
+            }
+            // A copy only counts where its own filesystem would have put it
+            match Self::read_from(&mut Cursor::new(&buf[..])) {
+                Ok(sb) if Self::backup_block(sb.block_count) == backup => {
+                    log::warn!("SUPERBLOCK: Recovered superblock from backup at block {}", backup);
+                    return Ok(sb);
+                }
+                _ => {}
+            }
+        }
+
+        log::error!("SUPERBLOCK: No readable backup superblock found");
+        Err(primary_err)
+    }
+
+    /// Write the superblock to block 0 and to the backup location
+    pub async fn write_to_disk(&self, device: &dyn BlockDevice) -> Result<(), FormatError> {
+        let mut buf = Cursor::new(Vec::with_capacity(self.block_size as usize));
+        self.write_to(&mut buf)?;
+        let buf = buf.into_inner();
+
+        let to_format_err = |e: crate::blockdev::BlockDeviceError| FormatError::Io(io::Error::other(e.to_string()));
+
+        device.write_block(0, &buf).await.map_err(to_format_err)?;
+
+        let backup = Self::backup_block(self.block_count);
+        if backup > 0 && backup < device.block_count() {
+            device.write_block(backup, &buf).await.map_err(to_format_err)?;
+        }
+
+        Ok(())
+    }
 }
 
 /// Get the size of a block device using platform-specific methods
@@ -405,6 +974,28 @@ fn get_block_device_size<P: AsRef<Path>>(device_path: P) -> io::Result<u64> {
     }
 }
 
+/// Size in bytes of a device or image file.
+///
+/// Block devices report their full size through the kernel; for regular
+/// files this is the file length.
+pub fn device_size<P: AsRef<Path>>(device_path: P) -> io::Result<u64> {
+    let metadata = std::fs::metadata(device_path.as_ref())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if metadata.file_type().is_block_device() {
+            return get_block_device_size(device_path);
+        }
+    }
+    #[cfg(windows)]
+    {
+        if !metadata.is_file() {
+            return get_block_device_size(device_path);
+        }
+    }
+    Ok(metadata.len())
+}
+
 /// Unix-specific block device size detection
 #[cfg(unix)]
 fn get_block_device_size_unix<P: AsRef<Path>>(device_path: P) -> io::Result<u64> {
@@ -460,11 +1051,49 @@ fn get_block_device_size_windows<P: AsRef<Path>>(device_path: P) -> io::Result<u
     }
 }
 
+/// What format clears of an earlier filesystem on the device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FormatWipe {
+    /// Overwrite only the blocks the new filesystem writes
+    #[default]
+    None,
+    /// Zero the inode bitmap and inode tables, so no stale inode is ever
+    /// taken for a live one
+    ZeroMetadata,
+    /// Discard the whole device so every block reads back as zeros,
+    /// zeroing the metadata instead on devices that can't discard
+    Discard,
+}
+
+/// Optional features chosen when formatting
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// Blocks to set aside for the audit log; 0 disables auditing
+    pub audit_log_blocks: u64,
+    /// Blocks to set aside for the journal; 0 disables journaling
+    pub journal_blocks: u64,
+    /// What to clear of an earlier filesystem first
+    pub wipe: FormatWipe,
+    /// Store the data of files small enough to fit in the inode there
+    /// instead of in a data block
+    pub inline_data: bool,
+}
+
 /// Format a block device with the AegisFS filesystem
 pub async fn format_device<P: AsRef<Path>>(
     device_path: P,
     size_gb: u64,
     volume_name: Option<&str>,
+) -> Result<(), FormatError> {
+    format_device_with_options(device_path, size_gb, volume_name, &FormatOptions::default()).await
+}
+
+/// Format a block device with the AegisFS filesystem and the given options
+pub async fn format_device_with_options<P: AsRef<Path>>(
+    device_path: P,
+    size_gb: u64,
+    volume_name: Option<&str>,
+    options: &FormatOptions,
 ) -> Result<(), FormatError> {
     use crate::blockdev::BlockDevice;
     use crate::layout::DiskFs;
@@ -529,7 +1158,7 @@ pub async fn format_device<P: AsRef<Path>>(
 
     // Create or open the device/file
     use crate::blockdev::FileBackedBlockDevice;
-    let mut device = if path.exists() {
+    let device = if path.exists() {
         FileBackedBlockDevice::open(device_path, false).await
     } else {
         FileBackedBlockDevice::create(device_path, size).await
@@ -542,7 +1171,7 @@ pub async fn format_device<P: AsRef<Path>>(
     })?;
 
     // Format the device using DiskFs implementation
-    let format_result = DiskFs::format(&mut device, size, volume_name).await;
+    let format_result = DiskFs::format_with_options(Arc::new(device), size, volume_name, options).await;
 
     // Convert FsError to FormatError
     format_result.map_err(|e| {
@@ -571,6 +1200,23 @@ mod tests {
         // Create a test superblock with a volume name
         let volume_name = "testvol";
         let mut sb = Superblock::new(1024 * 1024 * 1024, Some(volume_name)).unwrap();
+        sb.reserved_blocks_percent = 5;
+        sb.mount_count = 3;
+        sb.max_mount_count = 20;
+        sb.audit_log_blocks = 256;
+        sb.project_limits = vec![ProjectLimit { project_id: 42, block_limit: 1000, inode_limit: 10 }];
+        sb.unclean_mount_count = 2;
+        sb.compression_dict_block = 1234;
+        sb.features_compat = Superblock::COMPAT_AUDIT_LOG;
+        sb.features_incompat = Superblock::INCOMPAT_ENCRYPTION | 1 << 31;
+        sb.features_ro_compat = Superblock::RO_COMPAT_PROJECT_QUOTA;
+        // One more mount than fits, so the first is dropped
+        for i in 0..=MountHistory::CAPACITY as u64 {
+            sb.mount_history.record_mount(100 * i);
+            if i % 2 == 0 {
+                sb.mount_history.record_unmount(100 * i + 50);
+            }
+        }
 
         // Write superblock to buffer
         sb.write_to(&mut buffer).unwrap();
@@ -586,6 +1232,24 @@ mod tests {
         assert_eq!(sb.version, sb2.version, "Versions should match");
         assert_eq!(sb.size, sb2.size, "Sizes should match");
         assert_eq!(sb.block_size, sb2.block_size, "Block sizes should match");
+        assert_eq!(sb2.reserved_blocks_percent, 5);
+        assert_eq!(sb2.reserved_blocks(), sb.block_count / 20);
+        assert_eq!((sb2.mount_count, sb2.max_mount_count), (3, 20));
+        assert_eq!(sb2.audit_log_blocks, 256);
+        assert_eq!(sb2.project_limits, sb.project_limits);
+        assert_eq!(sb2.unclean_mount_count, 2);
+        assert_eq!(sb2.compression_dict_block, 1234);
+        assert_eq!(
+            (sb2.features_compat, sb2.features_incompat, sb2.features_ro_compat),
+            (sb.features_compat, sb.features_incompat, sb.features_ro_compat)
+        );
+        assert_eq!(sb2.unknown_incompat(), 1 << 31);
+        assert_eq!(sb2.unknown_ro_compat(), 0);
+        assert_eq!(sb2.mount_history, sb.mount_history);
+        let events = sb2.mount_history.events();
+        assert_eq!(events.len(), MountHistory::CAPACITY);
+        assert_eq!(events[0], MountEvent { mount_time: 100, unmount_time: 0, clean: false });
+        assert_eq!(events[7], MountEvent { mount_time: 800, unmount_time: 850, clean: true });
 
         // Compare volume names as strings, handling null termination
         let vol1 = std::str::from_utf8(&sb.volume_name)
@@ -598,4 +1262,67 @@ mod tests {
         assert_eq!(vol1, volume_name, "Original volume name should match");
         assert_eq!(vol2, volume_name, "Round-tripped volume name should match");
     }
+
+    #[test]
+    fn test_superblock_crc_detects_corruption() {
+        let sb = Superblock::new(1024 * 1024 * 1024, Some("crcvol")).unwrap();
+        let mut buffer = Cursor::new(Vec::new());
+        sb.write_to(&mut buffer).unwrap();
+
+        // Flip a single bit in the magic number
+        let mut bytes = buffer.into_inner();
+        bytes[4] ^= 0x01;
+
+        match Superblock::read_from(&mut Cursor::new(bytes)) {
+            Err(FormatError::CorruptSuperblock { .. }) => {}
+            other => panic!("Expected CorruptSuperblock, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_superblock_backup_fallback() {
+        use crate::blockdev::FileBackedBlockDevice;
+
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let device = FileBackedBlockDevice::create(temp.path(), 16 * 1024 * 1024)
+            .await
+            .unwrap();
+
+        let mut sb = Superblock::new(16 * 1024 * 1024, Some("backup")).unwrap();
+        sb.block_count = device.block_count();
+        sb.write_to_disk(&device).await.unwrap();
+
+        // Smash the primary copy
+        device.write_block(0, &vec![0xFFu8; 4096]).await.unwrap();
+
+        let recovered = Superblock::read_from_disk(&device).await.unwrap();
+        assert_eq!(recovered.uuid, sb.uuid);
+        assert_eq!(&recovered.volume_name[..6], b"backup");
+    }
+
+    #[tokio::test]
+    async fn test_superblock_backup_of_filesystem_smaller_than_device() {
+        use crate::blockdev::FileBackedBlockDevice;
+
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let device = FileBackedBlockDevice::create(temp.path(), 3 * 1024 * 1024 * 1024 / 2)
+            .await
+            .unwrap();
+
+        // A 1GiB filesystem on a 1.5GiB device keeps its backup at 512MiB
+        let sb = Superblock::new(1024 * 1024 * 1024, Some("small")).unwrap();
+        sb.write_to_disk(&device).await.unwrap();
+        assert_ne!(Superblock::backup_block(sb.block_count), Superblock::backup_block(device.block_count()));
+
+        // Found through the damaged primary's block count
+        let mut damaged = vec![0u8; 4096];
+        device.read_block(0, &mut damaged).await.unwrap();
+        damaged[100] ^= 0x01;
+        device.write_block(0, &damaged).await.unwrap();
+        assert_eq!(Superblock::read_from_disk(&device).await.unwrap().uuid, sb.uuid);
+
+        // and by probing when nothing of the primary is left
+        device.write_block(0, &vec![0xFFu8; 4096]).await.unwrap();
+        assert_eq!(Superblock::read_from_disk(&device).await.unwrap().uuid, sb.uuid);
+    }
 }
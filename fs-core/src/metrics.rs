@@ -0,0 +1,250 @@
+//! Per-operation latency histograms
+//!
+//! Built with the `metrics` feature. Every instrumented operation starts an
+//! [`OpTimer`] that adds its latency to the operation's histogram when it
+//! is dropped, so early returns and errors are counted too. Histograms use
+//! fixed power-of-two buckets of atomic counters: recording takes no locks
+//! and allocates nothing.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Number of buckets per histogram. Bucket `i` counts latencies under
+/// `2^i` microseconds, the last one everything slower (over ~4.2s).
+pub const BUCKETS: usize = 24;
+
+/// Operations that have a latency histogram
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Op {
+    /// Reading file data
+    Read,
+    /// Writing file data into the write cache
+    Write,
+    /// Looking a name up in a directory
+    Lookup,
+    /// Creating a file, directory or other inode
+    Create,
+    /// Flushing one inode's queued writes and metadata to disk
+    Flush,
+    /// Writing an inode to the inode table, including any verification
+    InodeWrite,
+}
+
+impl Op {
+    /// Number of operations
+    pub const COUNT: usize = 6;
+
+    /// Every operation, in histogram order
+    pub const ALL: [Op; Op::COUNT] = [Op::Read, Op::Write, Op::Lookup, Op::Create, Op::Flush, Op::InodeWrite];
+
+    /// Name the operation is reported under
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Lookup => "lookup",
+            Op::Create => "create",
+            Op::Flush => "flush",
+            Op::InodeWrite => "inode_write",
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Latency histogram with power-of-two microsecond buckets
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+impl LatencyHistogram {
+    /// Add one sample
+    pub fn record(&self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = ((u64::BITS - us.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    /// Copy the counters out. Samples recorded meanwhile may be only
+    /// partly included.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let buckets: Vec<u64> = self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        let count = self.count.load(Ordering::Relaxed);
+        let total_us = self.total_us.load(Ordering::Relaxed);
+        HistogramSnapshot {
+            count,
+            mean_us: if count == 0 { 0.0 } else { total_us as f64 / count as f64 },
+            p50_us: percentile(&buckets, 0.50),
+            p99_us: percentile(&buckets, 0.99),
+            max_us: self.max_us.load(Ordering::Relaxed),
+            buckets,
+        }
+    }
+}
+
+/// Upper bound in microseconds of the bucket holding the given fraction
+/// of samples, 0 when there are none
+fn percentile(buckets: &[u64], fraction: f64) -> u64 {
+    let count: u64 = buckets.iter().sum();
+    if count == 0 {
+        return 0;
+    }
+    let target = ((count as f64 * fraction).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (i, &n) in buckets.iter().enumerate() {
+        seen += n;
+        if seen >= target {
+            return bucket_upper_bound_us(i);
+        }
+    }
+    bucket_upper_bound_us(buckets.len() - 1)
+}
+
+/// Exclusive upper bound of bucket `i` in microseconds; the last bucket is
+/// unbounded
+pub fn bucket_upper_bound_us(i: usize) -> u64 {
+    if i >= BUCKETS - 1 {
+        u64::MAX
+    } else {
+        1 << i
+    }
+}
+
+/// Point-in-time copy of one histogram
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramSnapshot {
+    /// Samples recorded
+    pub count: u64,
+    /// Mean latency
+    pub mean_us: f64,
+    /// Median latency, rounded up to a bucket boundary
+    pub p50_us: u64,
+    /// 99th percentile latency, rounded up to a bucket boundary
+    pub p99_us: u64,
+    /// Slowest sample
+    pub max_us: u64,
+    /// Samples per bucket; see [`bucket_upper_bound_us`]
+    pub buckets: Vec<u64>,
+}
+
+/// Latency histograms of every operation
+#[derive(Debug, Default)]
+pub struct Metrics {
+    histograms: [LatencyHistogram; Op::COUNT],
+}
+
+impl Metrics {
+    /// Create empty histograms
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Histogram of one operation
+    pub fn histogram(&self, op: Op) -> &LatencyHistogram {
+        &self.histograms[op as usize]
+    }
+
+    /// Add a sample to an operation's histogram
+    pub fn record(&self, op: Op, latency: Duration) {
+        self.histogram(op).record(latency);
+    }
+
+    /// Start timing an operation; the latency is recorded when the timer
+    /// is dropped
+    pub fn start(self: &Arc<Self>, op: Op) -> OpTimer {
+        OpTimer {
+            metrics: self.clone(),
+            op,
+            start: Instant::now(),
+        }
+    }
+
+    /// Copy every histogram out
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            operations: Op::ALL.iter().map(|&op| (op.name(), self.histogram(op).snapshot())).collect(),
+        }
+    }
+}
+
+/// Times one operation, recording it on drop
+#[must_use = "the operation is timed until the timer is dropped"]
+pub struct OpTimer {
+    metrics: Arc<Metrics>,
+    op: Op,
+    start: Instant,
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        self.metrics.record(self.op, self.start.elapsed());
+    }
+}
+
+/// Point-in-time copy of every histogram, keyed by [`Op::name`]
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    /// Histogram of each operation
+    #[serde(flatten)]
+    pub operations: BTreeMap<&'static str, HistogramSnapshot>,
+}
+
+impl MetricsSnapshot {
+    /// Histogram of one operation
+    pub fn get(&self, op: Op) -> &HistogramSnapshot {
+        &self.operations[op.name()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples_land_in_power_of_two_buckets() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_nanos(500));
+        histogram.record(Duration::from_micros(1));
+        histogram.record(Duration::from_micros(3));
+        histogram.record(Duration::from_micros(1000));
+        histogram.record(Duration::from_secs(3600));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 5);
+        assert_eq!(snapshot.buckets[0], 1);
+        assert_eq!(snapshot.buckets[1], 1);
+        assert_eq!(snapshot.buckets[2], 1);
+        assert_eq!(snapshot.buckets[10], 1);
+        assert_eq!(snapshot.buckets[BUCKETS - 1], 1);
+        assert_eq!(snapshot.p50_us, 4);
+        assert_eq!(snapshot.p99_us, u64::MAX);
+        assert_eq!(snapshot.max_us, 3_600_000_000);
+    }
+
+    #[test]
+    fn test_timer_records_on_drop() {
+        let metrics = Arc::new(Metrics::new());
+        {
+            let _timer = metrics.start(Op::Flush);
+        }
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.get(Op::Flush).count, 1);
+        assert_eq!(snapshot.get(Op::Read).count, 0);
+        assert_eq!(snapshot.get(Op::Read).p99_us, 0);
+    }
+}
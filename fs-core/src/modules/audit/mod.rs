@@ -0,0 +1,403 @@
+//! Security audit log for AegisFS
+//!
+//! `AuditLog` appends a fixed-size record for every security-relevant
+//! operation (opens, creates, writes, deletes, renames and ownership or
+//! permission changes) to a dedicated region of the device that is reserved
+//! when the filesystem is formatted with an audit log. The region is
+//! append-only: records are never rewritten, and once it is full further
+//! events are dropped rather than overwriting the oldest ones.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::blockdev::{BlockDevice, BlockDeviceError, BLOCK_SIZE};
+
+/// Audit log size used when formatting with `--audit` (8192 records)
+pub const DEFAULT_AUDIT_LOG_BLOCKS: u64 = 256;
+
+/// Size of one serialized audit record
+pub const RECORD_SIZE: usize = 128;
+
+/// Records stored in each block of the audit log
+const RECORDS_PER_BLOCK: u64 = (BLOCK_SIZE / RECORD_SIZE) as u64;
+
+/// Bytes of the entry name kept in a record; longer names are truncated
+const MAX_NAME_LEN: usize = 88;
+
+/// Offset of the name within a record
+const NAME_OFFSET: usize = 36;
+
+/// Offset of the CRC32 that covers the rest of the record
+const CRC_OFFSET: usize = RECORD_SIZE - 4;
+
+/// Record flag: the event carries an entry name
+const FLAG_HAS_NAME: u8 = 0x01;
+
+/// Audited operation types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AuditOp {
+    /// A file was opened
+    Open = 1,
+    /// A file or directory was created
+    Create = 2,
+    /// File data was written
+    Write = 3,
+    /// A file or directory was removed
+    Delete = 4,
+    /// An entry was renamed or moved
+    Rename = 5,
+    /// Permission bits were changed
+    ChMod = 6,
+    /// Owner or group was changed
+    ChOwn = 7,
+}
+
+impl AuditOp {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(AuditOp::Open),
+            2 => Some(AuditOp::Create),
+            3 => Some(AuditOp::Write),
+            4 => Some(AuditOp::Delete),
+            5 => Some(AuditOp::Rename),
+            6 => Some(AuditOp::ChMod),
+            7 => Some(AuditOp::ChOwn),
+            _ => None,
+        }
+    }
+
+    /// Lowercase name used in log dumps
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOp::Open => "open",
+            AuditOp::Create => "create",
+            AuditOp::Write => "write",
+            AuditOp::Delete => "delete",
+            AuditOp::Rename => "rename",
+            AuditOp::ChMod => "chmod",
+            AuditOp::ChOwn => "chown",
+        }
+    }
+}
+
+impl std::fmt::Display for AuditOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// One audited operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    /// User that issued the request
+    pub uid: u32,
+    /// Group that issued the request
+    pub gid: u32,
+    /// Process that issued the request
+    pub pid: u32,
+    /// Operation performed
+    pub operation: AuditOp,
+    /// Inode operated on (the parent directory when the entry wasn't found)
+    pub ino: u64,
+    /// Entry name for operations on directory entries
+    pub name: Option<String>,
+    /// 0 on success, otherwise the errno returned to the caller
+    pub result: i32,
+}
+
+impl AuditEvent {
+    /// Serialize the event into a fixed-size record
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0u8; RECORD_SIZE];
+        buf[0..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.uid.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.gid.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.pid.to_le_bytes());
+        buf[20] = self.operation as u8;
+        buf[24..28].copy_from_slice(&self.result.to_le_bytes());
+        buf[28..36].copy_from_slice(&self.ino.to_le_bytes());
+
+        if let Some(name) = &self.name {
+            // Cut long names on a character boundary so they stay valid UTF-8
+            let mut len = name.len().min(MAX_NAME_LEN);
+            while !name.is_char_boundary(len) {
+                len -= 1;
+            }
+            buf[21] = FLAG_HAS_NAME;
+            buf[22] = len as u8;
+            buf[NAME_OFFSET..NAME_OFFSET + len].copy_from_slice(&name.as_bytes()[..len]);
+        }
+
+        let crc = crc32fast::hash(&buf[..CRC_OFFSET]);
+        buf[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Parse a record, returning `None` for an empty or damaged slot
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < RECORD_SIZE {
+            return None;
+        }
+        let stored = u32::from_le_bytes(buf[CRC_OFFSET..RECORD_SIZE].try_into().ok()?);
+        if stored != crc32fast::hash(&buf[..CRC_OFFSET]) {
+            return None;
+        }
+
+        let u32_at = |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().unwrap());
+
+        let name = if buf[21] & FLAG_HAS_NAME != 0 {
+            let len = (buf[22] as usize).min(MAX_NAME_LEN);
+            Some(String::from_utf8_lossy(&buf[NAME_OFFSET..NAME_OFFSET + len]).into_owned())
+        } else {
+            None
+        };
+
+        Some(Self {
+            timestamp: u64_at(0),
+            uid: u32_at(8),
+            gid: u32_at(12),
+            pid: u32_at(16),
+            operation: AuditOp::from_u8(buf[20])?,
+            ino: u64_at(28),
+            name,
+            result: u32_at(24) as i32,
+        })
+    }
+}
+
+/// Audit log error types
+#[derive(Error, Debug)]
+pub enum AuditError {
+    /// Reading or writing the log region failed
+    #[error("Block device error: {0}")]
+    BlockDevice(#[from] BlockDeviceError),
+    /// Every record slot of the region is used
+    #[error("Audit log region is full ({0} records)")]
+    Full(u64),
+    /// The superblock points the region past the end of the device
+    #[error("Audit log region {start}+{blocks} lies outside the device")]
+    OutOfRange {
+        /// First block of the region
+        start: u64,
+        /// Blocks in the region
+        blocks: u64,
+    },
+}
+
+/// Result type for audit log operations
+pub type Result<T> = std::result::Result<T, AuditError>;
+
+/// Append position and a copy of the block it falls in
+struct Tail {
+    /// Index of the next free record slot
+    next_slot: u64,
+    /// Contents of the block holding `next_slot`
+    block: Vec<u8>,
+}
+
+/// Append-only audit log stored in a fixed region of a block device
+pub struct AuditLog {
+    device: Arc<dyn BlockDevice>,
+    /// First block of the region
+    start: u64,
+    /// Number of blocks in the region
+    blocks: u64,
+    tail: Mutex<Tail>,
+    /// A full log has already been reported
+    full_reported: AtomicBool,
+}
+
+impl AuditLog {
+    /// Open the audit log in `blocks` blocks starting at `start`.
+    ///
+    /// Records are written in order from the start of the region, so the
+    /// append point is found by binary search for the first empty slot.
+    pub async fn open(device: Arc<dyn BlockDevice>, start: u64, blocks: u64) -> Result<Self> {
+        if start.checked_add(blocks).is_none_or(|end| end > device.block_count()) {
+            return Err(AuditError::OutOfRange { start, blocks });
+        }
+
+        let mut log = Self {
+            device,
+            start,
+            blocks,
+            tail: Mutex::new(Tail {
+                next_slot: 0,
+                block: vec![0u8; BLOCK_SIZE],
+            }),
+            full_reported: AtomicBool::new(false),
+        };
+
+        let (mut low, mut high) = (0, log.capacity());
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        while low < high {
+            let mid = low + (high - low) / 2;
+            log.read_log_block(mid / RECORDS_PER_BLOCK, &mut buf).await?;
+            if AuditEvent::from_bytes(Self::slot(&buf, mid)).is_some() {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        if low < log.capacity() {
+            log.read_log_block(low / RECORDS_PER_BLOCK, &mut buf).await?;
+        }
+        let tail = log.tail.get_mut();
+        tail.next_slot = low;
+        tail.block = buf;
+        log::info!(
+            "AUDIT: Opened audit log at block {} with {} of {} records used",
+            start,
+            low,
+            log.capacity()
+        );
+        Ok(log)
+    }
+
+    /// Total number of records the region can hold
+    pub fn capacity(&self) -> u64 {
+        self.blocks * RECORDS_PER_BLOCK
+    }
+
+    /// Number of records written so far
+    pub async fn record_count(&self) -> u64 {
+        self.tail.lock().await.next_slot
+    }
+
+    /// Append an event to the log
+    pub async fn append(&self, event: &AuditEvent) -> Result<()> {
+        let mut tail = self.tail.lock().await;
+        if tail.next_slot >= self.capacity() {
+            if !self.full_reported.swap(true, Ordering::Relaxed) {
+                log::warn!(
+                    "AUDIT: Audit log is full ({} records), further events are dropped",
+                    self.capacity()
+                );
+            }
+            return Err(AuditError::Full(self.capacity()));
+        }
+
+        let slot = tail.next_slot;
+        let offset = (slot % RECORDS_PER_BLOCK) as usize * RECORD_SIZE;
+        tail.block[offset..offset + RECORD_SIZE].copy_from_slice(&event.to_bytes());
+        self.device
+            .write_block(self.start + slot / RECORDS_PER_BLOCK, &tail.block)
+            .await?;
+
+        tail.next_slot += 1;
+        if tail.next_slot % RECORDS_PER_BLOCK == 0 {
+            tail.block.fill(0);
+        }
+        Ok(())
+    }
+
+    /// Read every record in the log, oldest first
+    pub async fn events(&self) -> Result<Vec<AuditEvent>> {
+        let used = self.record_count().await;
+        let mut events = Vec::with_capacity(used as usize);
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        for slot in 0..used {
+            if slot % RECORDS_PER_BLOCK == 0 {
+                self.read_log_block(slot / RECORDS_PER_BLOCK, &mut buf).await?;
+            }
+            match AuditEvent::from_bytes(Self::slot(&buf, slot)) {
+                Some(event) => events.push(event),
+                None => log::warn!("AUDIT: Skipping damaged record {}", slot),
+            }
+        }
+        Ok(events)
+    }
+
+    async fn read_log_block(&self, index: u64, buf: &mut [u8]) -> Result<()> {
+        self.device.read_block(self.start + index, buf).await?;
+        Ok(())
+    }
+
+    /// The bytes of record `slot` within its block
+    fn slot(block: &[u8], slot: u64) -> &[u8] {
+        let offset = (slot % RECORDS_PER_BLOCK) as usize * RECORD_SIZE;
+        &block[offset..offset + RECORD_SIZE]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::FileBackedBlockDevice;
+    use tempfile::tempdir;
+
+    fn event(n: u64) -> AuditEvent {
+        AuditEvent {
+            timestamp: 1_700_000_000 + n,
+            uid: 1000,
+            gid: 100,
+            pid: 42,
+            operation: AuditOp::Create,
+            ino: n,
+            name: Some(format!("file{}", n)),
+            result: 0,
+        }
+    }
+
+    #[test]
+    fn test_audit_record_roundtrip() {
+        let mut ev = event(7);
+        ev.operation = AuditOp::ChOwn;
+        ev.result = -1;
+        assert_eq!(AuditEvent::from_bytes(&ev.to_bytes()), Some(ev.clone()));
+
+        ev.name = None;
+        assert_eq!(AuditEvent::from_bytes(&ev.to_bytes()), Some(ev.clone()));
+
+        // Long names are cut at a character boundary
+        ev.name = Some("é".repeat(60));
+        let parsed = AuditEvent::from_bytes(&ev.to_bytes()).unwrap();
+        assert_eq!(parsed.name.unwrap(), "é".repeat(44));
+
+        // An empty slot and a damaged record are both rejected
+        assert!(AuditEvent::from_bytes(&[0u8; RECORD_SIZE]).is_none());
+        let mut bytes = ev.to_bytes();
+        bytes[0] ^= 0x01;
+        assert!(AuditEvent::from_bytes(&bytes).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_append_and_reopen() {
+        let temp_dir = tempdir().unwrap();
+        let device: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(temp_dir.path().join("audit.img"), BLOCK_SIZE as u64 * 8)
+                .await
+                .unwrap(),
+        );
+
+        // Crossing into a second block
+        let count = RECORDS_PER_BLOCK + 3;
+        {
+            let log = AuditLog::open(device.clone(), 2, 2).await.unwrap();
+            for n in 0..count {
+                log.append(&event(n)).await.unwrap();
+            }
+        }
+
+        let log = AuditLog::open(device.clone(), 2, 2).await.unwrap();
+        assert_eq!(log.record_count().await, count);
+        log.append(&event(count)).await.unwrap();
+        let events = log.events().await.unwrap();
+        assert_eq!(events.len() as u64, count + 1);
+        assert!(events.iter().enumerate().all(|(n, ev)| *ev == event(n as u64)));
+
+        // Fill the region; the log refuses to wrap
+        while log.record_count().await < log.capacity() {
+            log.append(&event(0)).await.unwrap();
+        }
+        assert!(matches!(log.append(&event(0)).await, Err(AuditError::Full(64))));
+        assert!(AuditLog::open(device, 7, 2).await.is_err());
+    }
+}
@@ -12,9 +12,12 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tokio::time::{interval, sleep};
+use tokio_util::sync::CancellationToken;
 
-use crate::blockdev::{BlockDevice, BlockDeviceError};
-use crate::error::Result;
+use crate::blockdev::{BlockDevice, BlockDeviceError, HealOutcome, MirrorBlockDevice};
+use crate::cache::BlockCache;
+use crate::error::{Error, Result};
+use crate::operations::{OperationHandle, OperationKind, OperationRegistry};
 
 /// Default scrub interval (24 hours)
 const DEFAULT_SCRUB_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
@@ -22,6 +25,9 @@ const DEFAULT_SCRUB_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 /// Maximum number of bad blocks to track
 const MAX_BAD_BLOCKS: usize = 10000;
 
+/// How many blocks a scrub reads between checks for cancellation
+const CANCEL_CHECK_INTERVAL: u64 = 100;
+
 /// Checksum algorithm types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChecksumAlgorithm {
@@ -135,6 +141,13 @@ pub enum ChecksumTask {
 pub struct ChecksumManager {
     /// Block device
     device: Arc<dyn BlockDevice>,
+    /// Mirror behind `device`, used to repair corrupted blocks
+    mirror: Option<Arc<MirrorBlockDevice>>,
+    /// Filesystem cache in front of `device`, invalidated before blocks are
+    /// written around it
+    cache: Option<Arc<BlockCache>>,
+    /// Registry scrubs are listed in while they run
+    operations: Option<Arc<OperationRegistry>>,
     /// Configuration
     config: ChecksumConfig,
     /// Block metadata storage
@@ -145,8 +158,8 @@ pub struct ChecksumManager {
     scrub_stats: RwLock<ScrubStats>,
     /// Flag indicating if scrub is running
     scrub_running: AtomicBool,
-    /// Flag to cancel scrub
-    scrub_cancel: AtomicBool,
+    /// Token of the scrub in progress, if any
+    scrub_token: RwLock<Option<CancellationToken>>,
     /// Next block to scrub
     next_scrub_block: AtomicU64,
     /// Task sender for background operations
@@ -158,17 +171,50 @@ impl ChecksumManager {
     pub fn new(device: Arc<dyn BlockDevice>, config: ChecksumConfig) -> Self {
         Self {
             device,
+            mirror: None,
+            cache: None,
+            operations: None,
             config,
             metadata: RwLock::new(HashMap::new()),
             bad_blocks: RwLock::new(HashSet::new()),
             scrub_stats: RwLock::new(ScrubStats::default()),
             scrub_running: AtomicBool::new(false),
-            scrub_cancel: AtomicBool::new(false),
+            scrub_token: RwLock::new(None),
             next_scrub_block: AtomicU64::new(0),
             task_sender: None,
         }
     }
 
+    /// Create a checksum manager over a mirror; corrupted blocks are
+    /// repaired from the good copy
+    pub fn with_mirror(mirror: Arc<MirrorBlockDevice>, config: ChecksumConfig) -> Self {
+        let mut manager = Self::new(mirror.clone(), config);
+        manager.mirror = Some(mirror);
+        manager
+    }
+
+    /// Keep `cache`, which sits in front of the same device, coherent with
+    /// the blocks this manager writes and repairs
+    pub fn with_cache(mut self, cache: Arc<BlockCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// List scrubs in `registry` while they run, so they can be followed
+    /// and cancelled from there
+    pub fn with_operations(mut self, registry: Arc<OperationRegistry>) -> Self {
+        self.operations = Some(registry);
+        self
+    }
+
+    /// Drop a block from the cache before writing it directly to the device
+    async fn invalidate_cached(&self, block_num: u64) -> Result<()> {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(block_num).await?;
+        }
+        Ok(())
+    }
+
     /// Initialize the checksum manager
     pub async fn init(&mut self) -> Result<()> {
         // Start background task handler
@@ -248,6 +294,7 @@ impl ChecksumManager {
         let checksum = self.calculate_checksum(data);
 
         // Write the block
+        self.invalidate_cached(block_num).await?;
         self.device.write_block(block_num, data).await?;
 
         // Update metadata
@@ -319,15 +366,29 @@ impl ChecksumManager {
     }
 
     /// Attempt to repair a corrupted block
-    async fn repair_block(&self, block_num: u64, _buf: &mut [u8]) -> Result<()> {
+    ///
+    /// With a mirror, the copy that fails its checksum is rewritten from the
+    /// other and the repaired data is read into `buf`.
+    async fn repair_block(&self, block_num: u64, buf: &mut [u8]) -> Result<()> {
         log::info!("Attempting to repair block {}", block_num);
 
-        // In a real implementation, this would:
-        // 1. Try to read from mirror/parity blocks
-        // 2. Use forward error correction if available
-        // 3. Restore from recent backup/snapshot
+        let expected = self.metadata.read().get(&block_num).map(|m| m.checksum);
+        if let (Some(mirror), Some(expected)) = (&self.mirror, expected) {
+            self.invalidate_cached(block_num).await?;
+            let outcome = mirror
+                .verify_and_heal(block_num, |data| self.calculate_checksum(data) == expected)
+                .await?;
+            if outcome != HealOutcome::Unrepairable {
+                mirror.read_block(block_num, buf).await?;
+                if let Some(metadata) = self.metadata.write().get_mut(&block_num) {
+                    metadata.correction_count += 1;
+                }
+                self.bad_blocks.write().remove(&block_num);
+                log::info!("Repaired block {} from its mirror ({:?})", block_num, outcome);
+                return Ok(());
+            }
+        }
 
-        // For now, we'll just mark it as unrepairable
         Err(crate::error::Error::Other(format!(
             "Block {} is corrupted and cannot be repaired",
             block_num
@@ -335,15 +396,57 @@ impl ChecksumManager {
     }
 
     /// Perform a full scrub of all blocks
-    pub async fn scrub_all(&self) -> Result<ScrubStats> {
+    ///
+    /// `token` is checked every [`CANCEL_CHECK_INTERVAL`] blocks; once it is
+    /// cancelled the scrub stops with [`Error::Cancelled`] and the stats of
+    /// the previous scrub are left in place. With an operation registry the
+    /// scrub is listed there until it ends and cancelling it there cancels
+    /// `token`.
+    pub async fn scrub_all(&self, token: CancellationToken) -> Result<ScrubStats> {
         if self.scrub_running.swap(true, Ordering::Acquire) {
             return Err(crate::error::Error::Other(
                 "Scrub already in progress".to_string(),
             ));
         }
 
-        self.scrub_cancel.store(false, Ordering::Relaxed);
+        let operation = self.operations.as_ref().map(|registry| {
+            let total = self.device.block_count();
+            registry.register(OperationKind::Scrub, format!("Scrub of {} blocks", total), total, token.clone())
+        });
+        *self.scrub_token.write() = Some(token.clone());
+        let result = self.scrub_blocks(&token, operation.as_ref()).await;
+        drop(operation);
+        *self.scrub_token.write() = None;
+        self.next_scrub_block.store(0, Ordering::Relaxed);
+        self.scrub_running.store(false, Ordering::Release);
+
+        let stats = result?;
+        *self.scrub_stats.write() = stats.clone();
+
+        log::info!(
+            "Scrub completed: {} blocks scrubbed, {} corrupted, {} repaired, {} unrepairable",
+            stats.blocks_scrubbed,
+            stats.blocks_corrupted,
+            stats.blocks_repaired,
+            stats.blocks_unrepairable
+        );
 
+        Ok(stats)
+    }
+
+    /// Cancel the scrub in progress, if any
+    pub fn cancel(&self) {
+        if let Some(token) = self.scrub_token.read().as_ref() {
+            token.cancel();
+        }
+    }
+
+    /// Read and verify every block, stopping early if `token` is cancelled
+    async fn scrub_blocks(
+        &self,
+        token: &CancellationToken,
+        operation: Option<&OperationHandle>,
+    ) -> Result<ScrubStats> {
         let mut stats = ScrubStats {
             start_time: Some(SystemTime::now()),
             ..Default::default()
@@ -353,14 +456,16 @@ impl ChecksumManager {
         let mut buf = vec![0u8; 4096]; // Assuming 4KB blocks
 
         for block_num in 0..total_blocks {
-            // Check if scrub was cancelled
-            if self.scrub_cancel.load(Ordering::Relaxed) {
+            if block_num % CANCEL_CHECK_INTERVAL == 0 && token.is_cancelled() {
                 log::info!("Scrub cancelled at block {}", block_num);
-                break;
+                return Err(Error::Cancelled);
             }
 
             // Update progress
             self.next_scrub_block.store(block_num, Ordering::Relaxed);
+            if let Some(operation) = operation {
+                operation.set_progress(block_num);
+            }
 
             // Read and verify the block
             match self.read_block_with_verification(block_num, &mut buf).await {
@@ -392,18 +497,6 @@ impl ChecksumManager {
         }
 
         stats.end_time = Some(SystemTime::now());
-        *self.scrub_stats.write() = stats.clone();
-
-        self.scrub_running.store(false, Ordering::Release);
-
-        log::info!(
-            "Scrub completed: {} blocks scrubbed, {} corrupted, {} repaired, {} unrepairable",
-            stats.blocks_scrubbed,
-            stats.blocks_corrupted,
-            stats.blocks_repaired,
-            stats.blocks_unrepairable
-        );
-
         Ok(stats)
     }
 
@@ -443,7 +536,7 @@ impl ChecksumManager {
     /// Shutdown the checksum manager
     pub async fn shutdown(&mut self) -> Result<()> {
         // Stop any ongoing scrub
-        self.scrub_cancel.store(true, Ordering::Relaxed);
+        self.cancel();
 
         // Send shutdown signal
         if let Some(sender) = &self.task_sender {
@@ -466,6 +559,35 @@ mod tests {
     use crate::blockdev::FileBackedBlockDevice;
     use tempfile::NamedTempFile;
 
+    /// Device that cancels a token once a given block is read
+    struct CancelAt {
+        inner: FileBackedBlockDevice,
+        block: u64,
+        token: CancellationToken,
+    }
+
+    #[async_trait::async_trait]
+    impl BlockDevice for CancelAt {
+        async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> crate::blockdev::Result<()> {
+            if block_num == self.block {
+                self.token.cancel();
+            }
+            self.inner.read_block(block_num, buf).await
+        }
+        async fn write_block(&self, block_num: u64, data: &[u8]) -> crate::blockdev::Result<()> {
+            self.inner.write_block(block_num, data).await
+        }
+        fn block_count(&self) -> u64 {
+            self.inner.block_count()
+        }
+        async fn sync(&self) -> crate::blockdev::Result<()> {
+            self.inner.sync().await
+        }
+        async fn close(&mut self) -> crate::blockdev::Result<()> {
+            self.inner.close().await
+        }
+    }
+
     #[tokio::test]
     async fn test_checksum_calculation() {
         let config = ChecksumConfig::default();
@@ -550,4 +672,141 @@ mod tests {
         assert_eq!(bad_blocks.len(), 2);
         assert!(!bad_blocks.contains(&20));
     }
+
+    #[tokio::test]
+    async fn test_cancelled_scrub_leaves_no_partial_state() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let inner = FileBackedBlockDevice::create(temp_file.path(), 1024 * 1024)
+            .await
+            .unwrap();
+        let total_blocks = inner.block_count();
+        let token = CancellationToken::new();
+        let device = Arc::new(CancelAt {
+            inner,
+            block: 150,
+            token: token.clone(),
+        });
+        let manager = ChecksumManager::new(device, ChecksumConfig::default());
+
+        // Cancelled at block 150, noticed at the next check on block 200
+        assert!(matches!(manager.scrub_all(token).await, Err(Error::Cancelled)));
+        let stats = manager.get_scrub_stats();
+        assert_eq!(stats.blocks_scrubbed, 0);
+        assert!(stats.start_time.is_none());
+        assert!(!manager.scrub_running.load(Ordering::Acquire));
+        assert_eq!(manager.next_scrub_block.load(Ordering::Relaxed), 0);
+        assert!(manager.scrub_token.read().is_none());
+
+        // A fresh token runs a full scrub
+        let stats = manager.scrub_all(CancellationToken::new()).await.unwrap();
+        assert_eq!(stats.blocks_scrubbed, total_blocks);
+        assert_eq!(manager.get_scrub_stats().blocks_scrubbed, total_blocks);
+    }
+
+    /// Device that stops reading at a given block until `resume` is notified
+    struct PauseAt {
+        inner: FileBackedBlockDevice,
+        block: u64,
+        reached: tokio::sync::Notify,
+        resume: tokio::sync::Notify,
+    }
+
+    #[async_trait::async_trait]
+    impl BlockDevice for PauseAt {
+        async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> crate::blockdev::Result<()> {
+            if block_num == self.block {
+                self.reached.notify_one();
+                self.resume.notified().await;
+            }
+            self.inner.read_block(block_num, buf).await
+        }
+        async fn write_block(&self, block_num: u64, data: &[u8]) -> crate::blockdev::Result<()> {
+            self.inner.write_block(block_num, data).await
+        }
+        fn block_count(&self) -> u64 {
+            self.inner.block_count()
+        }
+        async fn sync(&self) -> crate::blockdev::Result<()> {
+            self.inner.sync().await
+        }
+        async fn close(&mut self) -> crate::blockdev::Result<()> {
+            self.inner.close().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scrub_is_listed_and_cancellable_by_id() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let device = Arc::new(PauseAt {
+            inner: FileBackedBlockDevice::create(temp_file.path(), 1024 * 1024)
+                .await
+                .unwrap(),
+            block: 150,
+            reached: tokio::sync::Notify::new(),
+            resume: tokio::sync::Notify::new(),
+        });
+        let registry = Arc::new(OperationRegistry::new());
+        let manager = Arc::new(
+            ChecksumManager::new(device.clone(), ChecksumConfig::default()).with_operations(registry.clone()),
+        );
+
+        let scrub = tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.scrub_all(CancellationToken::new()).await }
+        });
+        device.reached.notified().await;
+
+        let running = registry.list();
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].kind, OperationKind::Scrub);
+        assert_eq!((running[0].done, running[0].total), (150, device.block_count()));
+        assert!(!running[0].cancelling);
+
+        assert!(registry.cancel(running[0].id));
+        assert!(registry.list()[0].cancelling);
+        device.resume.notify_one();
+
+        assert!(matches!(scrub.await.unwrap(), Err(Error::Cancelled)));
+        assert!(registry.is_empty());
+        assert!(!registry.cancel(running[0].id));
+        assert!(manager.get_scrub_stats().start_time.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_repair_from_mirror() {
+        let primary_file = NamedTempFile::new().unwrap();
+        let secondary_file = NamedTempFile::new().unwrap();
+        let primary: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(primary_file.path(), 1024 * 1024)
+                .await
+                .unwrap(),
+        );
+        let secondary: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(secondary_file.path(), 1024 * 1024)
+                .await
+                .unwrap(),
+        );
+        let mirror = Arc::new(MirrorBlockDevice::new(primary.clone(), secondary.clone()).unwrap());
+        let manager = ChecksumManager::with_mirror(mirror, ChecksumConfig::default());
+
+        let data = vec![42u8; 4096];
+        manager.write_block_with_checksum(7, &data).await.unwrap();
+
+        // Corrupt the copy reads are served from
+        primary.write_block(7, &[0xFF; 4096]).await.unwrap();
+
+        let mut buf = vec![0u8; 4096];
+        manager
+            .read_block_with_verification(7, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, data);
+        assert!(manager.get_bad_blocks().is_empty());
+
+        // The bad copy was rewritten from the good one
+        primary.read_block(7, &mut buf).await.unwrap();
+        assert_eq!(buf, data);
+        secondary.read_block(7, &mut buf).await.unwrap();
+        assert_eq!(buf, data);
+    }
 }
@@ -0,0 +1,299 @@
+//! Per-block choice between compressing and storing
+//!
+//! Already-compressed data such as JPEG, MP4 or ZIP gains nothing from Zstd
+//! and costs a full compression pass per block. `AdaptiveCompressor`
+//! estimates the entropy of the first [`ENTROPY_SAMPLE`] bytes of a block
+//! and only compresses blocks below a threshold. The choice made for each
+//! data block is kept in an [`AlgorithmTable`], two bits per block, so
+//! reads know how to decode it.
+
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use super::{CompressionError, Result, COMPRESSION_LEVEL};
+use crate::blockdev::{BlockDevice, BLOCK_SIZE};
+
+/// Bytes at the start of a block sampled to estimate its entropy
+pub const ENTROPY_SAMPLE: usize = 256;
+
+/// Blocks whose estimated entropy is at or above this many bits per byte
+/// are stored uncompressed
+pub const DEFAULT_ENTROPY_THRESHOLD: f64 = 7.5;
+
+/// Data blocks whose algorithm one side table block records
+pub const BLOCKS_PER_TABLE_BLOCK: u64 = BLOCK_SIZE as u64 * 4;
+
+/// How a data block is stored, as recorded in the side table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BlockAlgorithm {
+    /// Stored as is; also what never-written blocks read as
+    Stored = 0,
+    /// Compressed with Zstd
+    Zstd = 1,
+}
+
+impl BlockAlgorithm {
+    /// Decode a 2-bit side table entry; the unused values read as `None`
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(Self::Stored),
+            1 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Estimate the Shannon entropy of `sample` in bits per byte.
+///
+/// A short sample underestimates entropy, because most byte values can't
+/// appear in it at all: 256 random bytes measure about 7.2 bits per byte.
+/// The Miller-Madow correction adds back `(distinct - 1) / (2n ln 2)`,
+/// which lifts random data to about 7.6 while barely moving text.
+pub fn estimate_entropy(sample: &[u8]) -> f64 {
+    if sample.is_empty() {
+        return 0.0;
+    }
+
+    let mut histogram = [0u32; 256];
+    for &byte in sample {
+        histogram[byte as usize] += 1;
+    }
+
+    let n = sample.len() as f64;
+    let mut entropy = 0.0;
+    let mut distinct = 0;
+    for &count in histogram.iter().filter(|&&count| count > 0) {
+        let p = count as f64 / n;
+        entropy -= p * p.log2();
+        distinct += 1;
+    }
+    entropy + (distinct - 1) as f64 / (2.0 * n * std::f64::consts::LN_2)
+}
+
+/// Compresses blocks with Zstd unless they look incompressible
+#[derive(Debug, Clone)]
+pub struct AdaptiveCompressor {
+    threshold: f64,
+}
+
+impl Default for AdaptiveCompressor {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_ENTROPY_THRESHOLD,
+        }
+    }
+}
+
+impl AdaptiveCompressor {
+    /// Create a compressor with the default entropy threshold
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store blocks uncompressed from `threshold` bits per byte up
+    pub fn with_threshold(threshold: f64) -> Self {
+        Self { threshold }
+    }
+
+    /// The algorithm the block's sampled entropy calls for
+    pub fn choose(&self, block: &[u8]) -> BlockAlgorithm {
+        let sample = &block[..block.len().min(ENTROPY_SAMPLE)];
+        if estimate_entropy(sample) < self.threshold {
+            BlockAlgorithm::Zstd
+        } else {
+            BlockAlgorithm::Stored
+        }
+    }
+
+    /// Encode a block, returning the algorithm used and the bytes to store.
+    ///
+    /// A block the sample misjudged, whose compressed form is no smaller,
+    /// is stored as is.
+    pub fn compress_block(&self, block: &[u8]) -> Result<(BlockAlgorithm, Vec<u8>)> {
+        if self.choose(block) == BlockAlgorithm::Zstd {
+            let compressed = zstd::bulk::compress(block, COMPRESSION_LEVEL)?;
+            if compressed.len() < block.len() {
+                return Ok((BlockAlgorithm::Zstd, compressed));
+            }
+        }
+        Ok((BlockAlgorithm::Stored, block.to_vec()))
+    }
+
+    /// Decode a block produced by [`compress_block`](Self::compress_block)
+    pub fn decompress_block(&self, algorithm: BlockAlgorithm, data: &[u8]) -> Result<Vec<u8>> {
+        match algorithm {
+            BlockAlgorithm::Stored => Ok(data.to_vec()),
+            BlockAlgorithm::Zstd => Ok(zstd::bulk::decompress(data, BLOCK_SIZE)?),
+        }
+    }
+}
+
+/// Side table of each data block's algorithm, two bits per block, kept in
+/// one table block per [`BLOCKS_PER_TABLE_BLOCK`] data blocks
+pub struct AlgorithmTable {
+    device: Arc<dyn BlockDevice>,
+    /// First block of the table
+    start: u64,
+    entries: RwLock<Vec<u8>>,
+    /// Table blocks changed since the last `flush`
+    dirty: RwLock<Vec<bool>>,
+}
+
+impl AlgorithmTable {
+    /// Table blocks needed for `data_blocks` data blocks
+    pub fn blocks_for(data_blocks: u64) -> u64 {
+        data_blocks.div_ceil(BLOCKS_PER_TABLE_BLOCK)
+    }
+
+    /// Load the table for `data_blocks` data blocks stored from `start`
+    pub async fn open(device: Arc<dyn BlockDevice>, start: u64, data_blocks: u64) -> Result<Self> {
+        let table_blocks = Self::blocks_for(data_blocks);
+        if start + table_blocks > device.block_count() {
+            return Err(CompressionError::OutOfRange(start + table_blocks - 1));
+        }
+
+        let mut entries = vec![0u8; table_blocks as usize * BLOCK_SIZE];
+        for (i, chunk) in entries.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            device.read_block(start + i as u64, chunk).await?;
+        }
+        Ok(Self {
+            device,
+            start,
+            entries: RwLock::new(entries),
+            dirty: RwLock::new(vec![false; table_blocks as usize]),
+        })
+    }
+
+    /// Number of data blocks the table covers, rounded up to whole table
+    /// blocks
+    pub fn capacity(&self) -> u64 {
+        self.entries.read().len() as u64 * 4
+    }
+
+    /// Algorithm recorded for a data block
+    pub fn get(&self, block: u64) -> Result<BlockAlgorithm> {
+        let entries = self.entries.read();
+        let byte = *entries
+            .get((block / 4) as usize)
+            .ok_or(CompressionError::OutOfRange(block))?;
+        let bits = (byte >> ((block % 4) * 2)) & 0b11;
+        BlockAlgorithm::from_bits(bits).ok_or(CompressionError::Corrupt)
+    }
+
+    /// Record the algorithm of a data block; written out by `flush`
+    pub fn set(&self, block: u64, algorithm: BlockAlgorithm) -> Result<()> {
+        let mut entries = self.entries.write();
+        let byte = entries
+            .get_mut((block / 4) as usize)
+            .ok_or(CompressionError::OutOfRange(block))?;
+        let shift = (block % 4) * 2;
+        *byte = (*byte & !(0b11 << shift)) | ((algorithm as u8) << shift);
+        self.dirty.write()[(block / BLOCKS_PER_TABLE_BLOCK) as usize] = true;
+        Ok(())
+    }
+
+    /// Write the changed table blocks to the device
+    pub async fn flush(&self) -> Result<()> {
+        let changed: Vec<(u64, Vec<u8>)> = {
+            let entries = self.entries.read();
+            let mut dirty = self.dirty.write();
+            dirty
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, dirty)| **dirty)
+                .map(|(i, dirty)| {
+                    *dirty = false;
+                    (i as u64, entries[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE].to_vec())
+                })
+                .collect()
+        };
+        for (i, data) in changed {
+            if let Err(e) = self.device.write_block(self.start + i, &data).await {
+                self.dirty.write()[i as usize] = true;
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::FileBackedBlockDevice;
+    use tempfile::tempdir;
+
+    /// Text-like block: repetitive words with some variation
+    fn text_block(seed: usize) -> Vec<u8> {
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+        let mut i = seed;
+        while block.len() < BLOCK_SIZE {
+            block.extend_from_slice(format!("line {} of the log: status=ok user{} ", i, i % 13).as_bytes());
+            i += 1;
+        }
+        block.truncate(BLOCK_SIZE);
+        block
+    }
+
+    /// Block of xorshift output, standing in for already-compressed data
+    fn random_block(seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..BLOCK_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_entropy_separates_text_from_random_data() {
+        assert_eq!(estimate_entropy(&[]), 0.0);
+        assert_eq!(estimate_entropy(&[7; ENTROPY_SAMPLE]), 0.0);
+        assert!(estimate_entropy(&text_block(0)[..ENTROPY_SAMPLE]) < 6.0);
+        assert!(estimate_entropy(&random_block(42)[..ENTROPY_SAMPLE]) > 7.0);
+
+        let compressor = AdaptiveCompressor::new();
+        let text = text_block(5);
+        let (algorithm, compressed) = compressor.compress_block(&text).unwrap();
+        assert_eq!(algorithm, BlockAlgorithm::Zstd);
+        assert!(compressed.len() < BLOCK_SIZE / 2);
+        assert_eq!(compressor.decompress_block(algorithm, &compressed).unwrap(), text);
+
+        // Random data is stored, whichever way the sample fell
+        let random = random_block(9);
+        let (algorithm, stored) = compressor.compress_block(&random).unwrap();
+        assert_eq!(algorithm, BlockAlgorithm::Stored);
+        assert_eq!(stored, random);
+    }
+
+    #[tokio::test]
+    async fn test_algorithm_table_persists_two_bit_entries() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("table_device");
+        let device: Arc<dyn BlockDevice> =
+            Arc::new(FileBackedBlockDevice::create(&path, 8 * BLOCK_SIZE as u64).await.unwrap());
+
+        // Two table blocks, so the last entries land in the second one
+        let data_blocks = BLOCKS_PER_TABLE_BLOCK + 10;
+        assert_eq!(AlgorithmTable::blocks_for(data_blocks), 2);
+        let table = AlgorithmTable::open(device.clone(), 2, data_blocks).await.unwrap();
+        assert_eq!(table.capacity(), 2 * BLOCKS_PER_TABLE_BLOCK);
+        for block in [0, 1, 2, 3, 5, BLOCKS_PER_TABLE_BLOCK + 9] {
+            table.set(block, BlockAlgorithm::Zstd).unwrap();
+        }
+        table.set(2, BlockAlgorithm::Stored).unwrap();
+        assert!(table.get(2 * BLOCKS_PER_TABLE_BLOCK).is_err());
+        table.flush().await.unwrap();
+
+        let table = AlgorithmTable::open(device, 2, data_blocks).await.unwrap();
+        let zstd: Vec<u64> = (0..table.capacity())
+            .filter(|&block| table.get(block).unwrap() == BlockAlgorithm::Zstd)
+            .collect();
+        assert_eq!(zstd, vec![0, 1, 3, 5, BLOCKS_PER_TABLE_BLOCK + 9]);
+    }
+}
@@ -0,0 +1,455 @@
+//! Dictionary compression for small files
+//!
+//! Zstd compresses a file of a few hundred bytes poorly because there is
+//! too little input to build a model from. `CompressionDictionary` trains a
+//! shared dictionary from a sample of small files and uses it for every
+//! later small file, so the model is paid for once per filesystem instead of
+//! once per file. The dictionary is kept in a single dedicated block and is
+//! trained from the first [`TRAINING_FILES`] small files written.
+//!
+//! Compressed data starts with a tag saying whether a dictionary was used,
+//! followed by the dictionary's generation. Only one generation is kept, so
+//! the dictionary is never replaced behind the caller's back: retraining is
+//! an explicit [`CompressionDictionary::train`], after which data compressed
+//! with the earlier generation no longer decodes and has to be recompressed.
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+use parking_lot::{Mutex, RwLock};
+use thiserror::Error;
+
+use crate::blockdev::{BlockDevice, BlockDeviceError, BLOCK_SIZE};
+use crate::layout::{DiskFs, DiskFsTrait, FsError};
+
+mod adaptive;
+
+pub use adaptive::{
+    estimate_entropy, AdaptiveCompressor, AlgorithmTable, BlockAlgorithm, BLOCKS_PER_TABLE_BLOCK,
+    DEFAULT_ENTROPY_THRESHOLD, ENTROPY_SAMPLE,
+};
+
+/// Files up to this size are compressed with the dictionary
+pub const SMALL_FILE_THRESHOLD: usize = 4096;
+
+/// Small files recorded before the first dictionary is trained
+pub const TRAINING_FILES: u64 = 1000;
+
+/// Zstd level used for both plain and dictionary compression
+pub const COMPRESSION_LEVEL: i32 = 3;
+
+/// Fewest samples a dictionary is trained from
+pub const MIN_SAMPLES: usize = 16;
+
+/// Magic number at the start of the dictionary block
+const DICT_MAGIC: &[u8; 8] = b"AEGISZDC";
+
+/// Dictionary block header: magic, generation, length and CRC32
+const HEADER_SIZE: usize = 8 + 4 + 4 + 4;
+
+/// Largest dictionary that fits in the dictionary block
+pub const MAX_DICTIONARY_SIZE: usize = BLOCK_SIZE - HEADER_SIZE;
+
+/// Tag of data compressed without a dictionary
+const TAG_PLAIN: u8 = 0;
+
+/// Tag of data compressed with the dictionary; a u32 generation follows
+const TAG_DICTIONARY: u8 = 1;
+
+/// Compression error types
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    /// Reading or writing the dictionary block failed
+    #[error("Block device error: {0}")]
+    BlockDevice(#[from] BlockDeviceError),
+    /// Zstd failed to train, compress or decompress
+    #[error("Zstd error: {0}")]
+    Zstd(#[from] std::io::Error),
+    /// Too few samples were given to train a dictionary
+    #[error("Need at least {MIN_SAMPLES} samples to train a dictionary, got {0}")]
+    NotEnoughSamples(usize),
+    /// Data was compressed with a dictionary that is no longer loaded
+    #[error("Data was compressed with dictionary generation {found}, current generation is {current:?}")]
+    DictionaryMismatch {
+        /// Generation the data was compressed with
+        found: u32,
+        /// Generation of the loaded dictionary, if any
+        current: Option<u32>,
+    },
+    /// Compressed data could not be decoded
+    #[error("Compressed data is truncated or has an unknown tag")]
+    Corrupt,
+    /// The superblock points the dictionary past the end of the device
+    #[error("Dictionary block {0} lies outside the device")]
+    OutOfRange(u64),
+    /// Reading or writing the file being compressed failed
+    #[error("Filesystem error: {0}")]
+    Fs(#[from] FsError),
+}
+
+/// Result type for compression operations
+pub type Result<T> = std::result::Result<T, CompressionError>;
+
+/// A trained dictionary and its generation
+struct Dictionary {
+    /// Starts at 1 and goes up by one on every retrain
+    generation: u32,
+    bytes: Vec<u8>,
+}
+
+/// Shared Zstd dictionary for small files, stored in a dedicated block
+pub struct CompressionDictionary {
+    device: Arc<dyn BlockDevice>,
+    /// Block holding the dictionary
+    block: u64,
+    dictionary: RwLock<Option<Arc<Dictionary>>>,
+    /// Small files kept as training samples until the first dictionary
+    samples: Mutex<Vec<Vec<u8>>>,
+}
+
+impl CompressionDictionary {
+    /// Open the dictionary stored in `block`, if any.
+    ///
+    /// A block without a valid dictionary leaves small files compressed
+    /// without one until the first training.
+    pub async fn open(device: Arc<dyn BlockDevice>, block: u64) -> Result<Self> {
+        if block >= device.block_count() {
+            return Err(CompressionError::OutOfRange(block));
+        }
+
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        device.read_block(block, &mut buf).await?;
+        let dictionary = Self::parse_block(&buf, block);
+        if let Some(dict) = &dictionary {
+            log::info!(
+                "COMPRESSION: Loaded {}-byte dictionary generation {} from block {}",
+                dict.bytes.len(),
+                dict.generation,
+                block
+            );
+        }
+
+        Ok(Self {
+            device,
+            block,
+            dictionary: RwLock::new(dictionary.map(Arc::new)),
+            samples: Mutex::new(Vec::with_capacity(TRAINING_FILES as usize)),
+        })
+    }
+
+    /// Generation of the current dictionary, or `None` before training
+    pub fn generation(&self) -> Option<u32> {
+        self.dictionary.read().as_ref().map(|dict| dict.generation)
+    }
+
+    /// Size in bytes of the current dictionary
+    pub fn dictionary_size(&self) -> usize {
+        self.dictionary.read().as_ref().map_or(0, |dict| dict.bytes.len())
+    }
+
+    /// Train a new dictionary from `samples` and store it, returning its
+    /// generation. Data compressed with the previous generation can no
+    /// longer be decompressed.
+    pub async fn train<S: AsRef<[u8]>>(&self, samples: &[S]) -> Result<u32> {
+        if samples.len() < MIN_SAMPLES {
+            return Err(CompressionError::NotEnoughSamples(samples.len()));
+        }
+
+        let bytes = zstd::dict::from_samples(samples, MAX_DICTIONARY_SIZE)?;
+        self.store(bytes, samples.len()).await
+    }
+
+    /// Train a dictionary of at most `dict_size` bytes from the first
+    /// [`SMALL_FILE_THRESHOLD`] bytes of each of `sample_inos`, store it and
+    /// return it.
+    ///
+    /// Inodes that are not regular files or are empty are skipped. The
+    /// dictionary is capped at [`MAX_DICTIONARY_SIZE`].
+    pub async fn train_dictionary(
+        &self,
+        disk_fs: &DiskFs,
+        sample_inos: &[u64],
+        dict_size: usize,
+    ) -> Result<Vec<u8>> {
+        let mut continuous = Vec::new();
+        let mut sizes = Vec::new();
+        for &ino in sample_inos {
+            let inode = disk_fs.read_inode(ino).await?;
+            if inode.mode & 0o170000 != 0o100000 || inode.size == 0 {
+                continue;
+            }
+            let sample = disk_fs
+                .read_file_data(&inode, 0, SMALL_FILE_THRESHOLD as u32)
+                .await?;
+            sizes.push(sample.len());
+            continuous.extend_from_slice(&sample);
+        }
+        if sizes.len() < MIN_SAMPLES {
+            return Err(CompressionError::NotEnoughSamples(sizes.len()));
+        }
+
+        let bytes = zstd::dict::from_continuous(&continuous, &sizes, dict_size.min(MAX_DICTIONARY_SIZE))?;
+        self.store(bytes.clone(), sizes.len()).await?;
+        Ok(bytes)
+    }
+
+    /// Store a newly trained dictionary as the next generation, returning
+    /// the generation
+    async fn store(&self, bytes: Vec<u8>, sample_count: usize) -> Result<u32> {
+        let generation = self.generation().map_or(1, |generation| generation + 1);
+        let dict = Dictionary { generation, bytes };
+        self.device.write_block(self.block, &Self::to_block(&dict)).await?;
+        log::info!(
+            "COMPRESSION: Trained {}-byte dictionary generation {} from {} samples",
+            dict.bytes.len(),
+            generation,
+            sample_count
+        );
+
+        *self.dictionary.write() = Some(Arc::new(dict));
+        Ok(generation)
+    }
+
+    /// Record a newly written small file as a training sample.
+    ///
+    /// Once [`TRAINING_FILES`] small files were recorded without a
+    /// dictionary, one is trained from them; returns `true` when that
+    /// happened. After that files are no longer sampled, since replacing
+    /// the dictionary would strand data compressed with it. Files above
+    /// [`SMALL_FILE_THRESHOLD`] are ignored.
+    pub async fn record_small_file(&self, data: &[u8]) -> Result<bool> {
+        if data.is_empty() || data.len() > SMALL_FILE_THRESHOLD || self.generation().is_some() {
+            return Ok(false);
+        }
+
+        let samples: Vec<Vec<u8>> = {
+            let mut samples = self.samples.lock();
+            samples.push(data.to_vec());
+            if samples.len() < TRAINING_FILES as usize {
+                return Ok(false);
+            }
+            std::mem::take(&mut *samples)
+        };
+
+        self.train(&samples).await?;
+        Ok(true)
+    }
+
+    /// Compress `data`, using the dictionary if it is a small file and one
+    /// has been trained
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let dictionary = self.dictionary.read().clone();
+        match dictionary {
+            Some(dict) if data.len() <= SMALL_FILE_THRESHOLD => {
+                let mut out = vec![TAG_DICTIONARY];
+                out.extend_from_slice(&dict.generation.to_le_bytes());
+                let mut encoder = zstd::Encoder::with_dictionary(out, COMPRESSION_LEVEL, &dict.bytes)?;
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            _ => {
+                let mut out = vec![TAG_PLAIN];
+                out.extend(zstd::encode_all(data, COMPRESSION_LEVEL)?);
+                Ok(out)
+            }
+        }
+    }
+
+    /// Decompress data produced by [`compress`](Self::compress)
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match data.first() {
+            Some(&TAG_PLAIN) => Ok(zstd::decode_all(&data[1..])?),
+            Some(&TAG_DICTIONARY) if data.len() >= 5 => {
+                let found = u32::from_le_bytes(data[1..5].try_into().unwrap());
+                let current = self.dictionary.read().clone();
+                let dict = match current {
+                    Some(dict) if dict.generation == found => dict,
+                    other => {
+                        return Err(CompressionError::DictionaryMismatch {
+                            found,
+                            current: other.map(|dict| dict.generation),
+                        })
+                    }
+                };
+                let mut decoder = zstd::Decoder::with_dictionary(&data[5..], &dict.bytes)?;
+                let mut out = Vec::new();
+                decoder.read_to_end(&mut out)?;
+                Ok(out)
+            }
+            _ => Err(CompressionError::Corrupt),
+        }
+    }
+
+    /// Serialize a dictionary into its block
+    fn to_block(dict: &Dictionary) -> Vec<u8> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        block[..8].copy_from_slice(DICT_MAGIC);
+        block[8..12].copy_from_slice(&dict.generation.to_le_bytes());
+        block[12..16].copy_from_slice(&(dict.bytes.len() as u32).to_le_bytes());
+        block[16..20].copy_from_slice(&crc32fast::hash(&dict.bytes).to_le_bytes());
+        block[HEADER_SIZE..HEADER_SIZE + dict.bytes.len()].copy_from_slice(&dict.bytes);
+        block
+    }
+
+    /// Parse the dictionary block, or `None` if it holds no valid dictionary
+    fn parse_block(block: &[u8], block_num: u64) -> Option<Dictionary> {
+        if &block[..8] != DICT_MAGIC {
+            return None;
+        }
+        let generation = u32::from_le_bytes(block[8..12].try_into().unwrap());
+        let len = u32::from_le_bytes(block[12..16].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(block[16..20].try_into().unwrap());
+        if len > MAX_DICTIONARY_SIZE {
+            log::warn!("COMPRESSION: Ignoring dictionary in block {} with length {}", block_num, len);
+            return None;
+        }
+        let bytes = block[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+        if crc32fast::hash(&bytes) != crc {
+            log::warn!("COMPRESSION: Ignoring dictionary in block {} with a bad checksum", block_num);
+            return None;
+        }
+        Some(Dictionary { generation, bytes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::FileBackedBlockDevice;
+    use tempfile::tempdir;
+
+    /// Small JSON-like records sharing most of their structure
+    fn small_file(i: usize) -> Vec<u8> {
+        format!(
+            "{{\"id\":{},\"owner\":\"user{}\",\"mode\":\"0644\",\"tags\":[\"cache\",\"v{}\"],\"ok\":true}}",
+            i,
+            i % 17,
+            i % 5
+        )
+        .into_bytes()
+    }
+
+    async fn open_device(dir: &std::path::Path) -> Arc<dyn BlockDevice> {
+        let path = dir.join("dict_device");
+        Arc::new(FileBackedBlockDevice::create(&path, 16 * BLOCK_SIZE as u64).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_dictionary_improves_small_files() {
+        let dir = tempdir().unwrap();
+        let device = open_device(dir.path()).await;
+        let dict = CompressionDictionary::open(device.clone(), 3).await.unwrap();
+        assert_eq!(dict.generation(), None);
+
+        let files: Vec<Vec<u8>> = (0..200).map(small_file).collect();
+        let plain: usize = files.iter().map(|f| dict.compress(f).unwrap().len()).sum();
+
+        assert!(matches!(
+            dict.train(&files[..4]).await,
+            Err(CompressionError::NotEnoughSamples(4))
+        ));
+        assert_eq!(dict.train(&files).await.unwrap(), 1);
+        assert!(dict.dictionary_size() <= MAX_DICTIONARY_SIZE);
+
+        let mut with_dict = 0;
+        for file in &files {
+            let compressed = dict.compress(file).unwrap();
+            assert_eq!(&dict.decompress(&compressed).unwrap(), file);
+            with_dict += compressed.len();
+        }
+        assert!(with_dict * 4 < plain * 3, "{} vs {}", with_dict, plain);
+
+        // Large files skip the dictionary
+        let large = vec![b'x'; SMALL_FILE_THRESHOLD * 2];
+        let compressed = dict.compress(&large).unwrap();
+        assert_eq!(compressed[0], TAG_PLAIN);
+        assert_eq!(dict.decompress(&compressed).unwrap(), large);
+    }
+
+    #[tokio::test]
+    async fn test_dictionary_persists_and_retrains() {
+        let dir = tempdir().unwrap();
+        let device = open_device(dir.path()).await;
+        let dict = CompressionDictionary::open(device.clone(), 3).await.unwrap();
+
+        // The dictionary is trained once TRAINING_FILES files were seen
+        for i in 0..TRAINING_FILES as usize - 1 {
+            assert!(!dict.record_small_file(&small_file(i)).await.unwrap());
+        }
+        assert!(dict.record_small_file(&small_file(0)).await.unwrap());
+        assert_eq!(dict.generation(), Some(1));
+        let compressed = dict.compress(&small_file(7)).unwrap();
+
+        // and never replaced by later files, which would strand that data
+        for i in 0..TRAINING_FILES as usize {
+            assert!(!dict.record_small_file(&small_file(i)).await.unwrap());
+        }
+        assert_eq!(dict.generation(), Some(1));
+        assert_eq!(dict.decompress(&compressed).unwrap(), small_file(7));
+        drop(dict);
+
+        // Reopening loads the stored dictionary
+        let dict = CompressionDictionary::open(device.clone(), 3).await.unwrap();
+        assert_eq!(dict.generation(), Some(1));
+        assert_eq!(dict.decompress(&compressed).unwrap(), small_file(7));
+
+        // Data from a generation replaced by explicit retraining is refused
+        // rather than misdecoded
+        let files: Vec<Vec<u8>> = (0..100).map(small_file).collect();
+        assert_eq!(dict.train(&files).await.unwrap(), 2);
+        assert!(matches!(
+            dict.decompress(&compressed),
+            Err(CompressionError::DictionaryMismatch { found: 1, current: Some(2) })
+        ));
+        assert!(matches!(dict.decompress(&[9, 1, 2]), Err(CompressionError::Corrupt)));
+    }
+
+    #[tokio::test]
+    async fn test_train_dictionary_from_inodes() {
+        let dir = tempdir().unwrap();
+        let size = 16 * 1024 * 1024;
+        let device: Arc<dyn BlockDevice> =
+            Arc::new(FileBackedBlockDevice::create(dir.path().join("fs"), size).await.unwrap());
+        DiskFs::format(device.clone(), size, None).await.unwrap();
+        let mut disk_fs = DiskFs::open(device.clone()).await.unwrap();
+
+        let mut template = disk_fs.read_inode(disk_fs.superblock().root_inode).await.unwrap();
+        template.mode = 0o100644;
+        template.links = 1;
+        let sample_inos: Vec<u64> = (2..102).collect();
+        for &ino in &sample_inos {
+            let mut inode = template.clone();
+            inode.block = [0; 15];
+            let data = small_file(ino as usize);
+            disk_fs.write_file_data(&mut inode, 0, &data).await.unwrap();
+            inode.size = data.len() as u64;
+            disk_fs.write_inode(ino, &inode).await.unwrap();
+        }
+
+        let free_before = disk_fs.free_blocks();
+        let block = disk_fs.compression_dict_block().await.unwrap();
+        assert_eq!(disk_fs.compression_dict_block().await.unwrap(), block);
+        assert!(block >= disk_fs.layout().data_blocks, "block {} is metadata", block);
+        let dict = CompressionDictionary::open(device.clone(), block).await.unwrap();
+        assert!(matches!(
+            dict.train_dictionary(&disk_fs, &sample_inos[..4], 1024).await,
+            Err(CompressionError::NotEnoughSamples(4))
+        ));
+        let bytes = dict.train_dictionary(&disk_fs, &sample_inos, 1024).await.unwrap();
+        assert!(!bytes.is_empty() && bytes.len() <= 1024);
+        assert_eq!(dict.generation(), Some(1));
+
+        // The block stays recorded in the superblock, and reserving it took
+        // one data block without touching the filesystem's metadata
+        drop(dict);
+        let mut disk_fs = DiskFs::open(device.clone()).await.unwrap();
+        assert_eq!(disk_fs.superblock().compression_dict_block, block);
+        assert_eq!(disk_fs.free_blocks(), free_before - 1);
+        assert!(disk_fs.fsck(false).await.unwrap().is_clean());
+        let dict = CompressionDictionary::open(device, block).await.unwrap();
+        assert_eq!(dict.dictionary_size(), bytes.len());
+        let file = small_file(500);
+        let compressed = dict.compress(&file).unwrap();
+        assert_eq!(compressed[0], TAG_DICTIONARY);
+        assert_eq!(dict.decompress(&compressed).unwrap(), file);
+    }
+}
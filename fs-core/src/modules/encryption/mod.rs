@@ -0,0 +1,14 @@
+//! Encryption key management for AegisFS
+//!
+//! This module holds the pieces that protect the filesystem master key.
+//! With the `tpm` feature the key can be sealed to a TPM2 chip so it is
+//! only released when the machine boots into the same measured state.
+
+#[cfg(feature = "tpm")]
+mod tpm;
+
+#[cfg(feature = "tpm")]
+pub use tpm::{SealedBlob, TpmError, TpmKeyStorage, SEALED_PCRS};
+
+/// Size of the filesystem master key in bytes
+pub const MASTER_KEY_SIZE: usize = 32;
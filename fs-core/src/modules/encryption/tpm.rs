@@ -0,0 +1,273 @@
+//! TPM2 sealing of the filesystem master key
+//!
+//! The master key is sealed under a primary storage key of the owner
+//! hierarchy with a policy bound to PCR 0 (firmware) and PCR 7 (secure boot
+//! state). The sealed blob is useless on another machine, and on this
+//! machine it only unseals while those PCRs hold the values they had when
+//! the key was sealed.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryFrom;
+use std::io::{Cursor, Read};
+use thiserror::Error;
+use tss_esapi::attributes::{ObjectAttributesBuilder, SessionAttributesBuilder};
+use tss_esapi::constants::SessionType;
+use tss_esapi::handles::KeyHandle;
+use tss_esapi::interface_types::algorithm::{HashingAlgorithm, PublicAlgorithm};
+use tss_esapi::interface_types::key_bits::RsaKeyBits;
+use tss_esapi::interface_types::resource_handles::Hierarchy;
+use tss_esapi::interface_types::session_handles::PolicySession;
+use tss_esapi::structures::{
+    Digest, KeyedHashScheme, PcrSelectionList, PcrSelectionListBuilder, PcrSlot, Private, Public,
+    PublicBuilder, PublicKeyedHashParameters, RsaExponent, SensitiveData, SymmetricDefinition,
+    SymmetricDefinitionObject,
+};
+use tss_esapi::tcti_ldr::{DeviceConfig, TctiNameConf};
+use tss_esapi::traits::{Marshall, UnMarshall};
+use tss_esapi::utils::create_restricted_decryption_rsa_public;
+use tss_esapi::Context;
+
+use super::MASTER_KEY_SIZE;
+
+/// PCRs the master key is sealed to: firmware (0) and secure boot state (7)
+pub const SEALED_PCRS: [u32; 2] = [0, 7];
+
+/// Magic number at the start of a serialized sealed blob
+const SEALED_BLOB_MAGIC: &[u8; 8] = b"AEGISTPM";
+
+/// TPM key storage error types
+#[derive(Error, Debug)]
+pub enum TpmError {
+    #[error("TPM error: {0}")]
+    Tpm(#[from] tss_esapi::Error),
+    #[error("Invalid sealed blob: {0}")]
+    InvalidBlob(String),
+    #[error("Unsealed key has {0} bytes, expected {MASTER_KEY_SIZE}")]
+    InvalidKeyLength(usize),
+}
+
+/// Result type for TPM key storage operations
+pub type Result<T> = std::result::Result<T, TpmError>;
+
+/// A master key sealed by the TPM
+///
+/// Only the TPM that produced it can unseal it. The blob is meant to be
+/// stored in the encryption header next to the passphrase-wrapped key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedBlob {
+    /// Marshalled public area of the sealed object
+    pub public: Vec<u8>,
+    /// TPM-encrypted private area of the sealed object
+    pub private: Vec<u8>,
+}
+
+impl SealedBlob {
+    /// Serialize the blob for storage on disk
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(SEALED_BLOB_MAGIC.len() + 4 + self.public.len() + self.private.len());
+        buf.extend_from_slice(SEALED_BLOB_MAGIC);
+        buf.write_u16::<LittleEndian>(self.public.len() as u16).unwrap();
+        buf.extend_from_slice(&self.public);
+        buf.write_u16::<LittleEndian>(self.private.len() as u16).unwrap();
+        buf.extend_from_slice(&self.private);
+        buf
+    }
+
+    /// Parse a blob written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let truncated = |_| TpmError::InvalidBlob("truncated".to_string());
+        let mut cursor = Cursor::new(bytes);
+
+        let mut magic = [0u8; 8];
+        cursor.read_exact(&mut magic).map_err(truncated)?;
+        if &magic != SEALED_BLOB_MAGIC {
+            return Err(TpmError::InvalidBlob("bad magic".to_string()));
+        }
+
+        let public_len = cursor.read_u16::<LittleEndian>().map_err(truncated)? as usize;
+        let mut public = vec![0u8; public_len];
+        cursor.read_exact(&mut public).map_err(truncated)?;
+
+        let private_len = cursor.read_u16::<LittleEndian>().map_err(truncated)? as usize;
+        let mut private = vec![0u8; private_len];
+        cursor.read_exact(&mut private).map_err(truncated)?;
+
+        Ok(Self { public, private })
+    }
+}
+
+/// Seals and unseals the filesystem master key with a TPM2 chip
+pub struct TpmKeyStorage {
+    context: Context,
+    pcrs: PcrSelectionList,
+}
+
+impl TpmKeyStorage {
+    /// Connect to the TPM.
+    ///
+    /// The TCTI is taken from `TPM2TOOLS_TCTI`/`TCTI` when set, otherwise
+    /// the kernel resource manager at `/dev/tpmrm0` is used.
+    pub fn new() -> Result<Self> {
+        let tcti = TctiNameConf::from_environment_variable()
+            .unwrap_or_else(|_| TctiNameConf::Device(DeviceConfig::default()));
+        Self::with_tcti(tcti)
+    }
+
+    /// Connect to the TPM through the given TCTI
+    pub fn with_tcti(tcti: TctiNameConf) -> Result<Self> {
+        let context = Context::new(tcti)?;
+        let pcrs = PcrSelectionListBuilder::new()
+            .with_selection(HashingAlgorithm::Sha256, &[PcrSlot::Slot0, PcrSlot::Slot7])
+            .build()?;
+        Ok(Self { context, pcrs })
+    }
+
+    /// Seal the master key to the current values of PCRs 0 and 7
+    pub fn seal(&mut self, key: [u8; MASTER_KEY_SIZE]) -> Result<SealedBlob> {
+        let policy = self.pcr_policy(SessionType::Trial)?;
+        let policy_digest = self.context.policy_get_digest(policy);
+        self.flush_session(policy)?;
+        let policy_digest = policy_digest?;
+
+        let primary = self.create_primary()?;
+        let pcrs = self.pcrs.clone();
+        let created = self.context.execute_with_nullauth_session(|ctx| {
+            ctx.create(
+                primary,
+                sealed_object_public(policy_digest)?,
+                None,
+                Some(SensitiveData::try_from(key.to_vec())?),
+                None,
+                Some(pcrs),
+            )
+        });
+        self.context.flush_context(primary.into())?;
+        let created = created?;
+
+        log::info!("TPM: Sealed master key to PCRs {:?}", SEALED_PCRS);
+        Ok(SealedBlob {
+            public: created.out_public.marshall()?,
+            private: created.out_private.value().to_vec(),
+        })
+    }
+
+    /// Unseal a master key; fails if PCRs 0 and 7 have changed since sealing
+    pub fn unseal(&mut self, blob: &SealedBlob) -> Result<[u8; MASTER_KEY_SIZE]> {
+        let public = Public::unmarshall(&blob.public)?;
+        let private = Private::try_from(blob.private.clone())?;
+
+        let primary = self.create_primary()?;
+        let loaded = self
+            .context
+            .execute_with_nullauth_session(|ctx| ctx.load(primary, private, public));
+        self.context.flush_context(primary.into())?;
+        let loaded = loaded?;
+
+        let unsealed = match self.pcr_policy(SessionType::Policy) {
+            Ok(policy) => {
+                let unsealed = self
+                    .context
+                    .execute_with_session(Some(policy.into()), |ctx| ctx.unseal(loaded.into()))
+                    .map_err(TpmError::from);
+                self.flush_session(policy)?;
+                unsealed
+            }
+            Err(e) => Err(e),
+        };
+        self.context.flush_context(loaded.into())?;
+        let unsealed = unsealed.map_err(|e| {
+            log::error!("TPM: Unseal failed, boot state may have changed: {}", e);
+            e
+        })?;
+
+        let bytes = unsealed.value();
+        <[u8; MASTER_KEY_SIZE]>::try_from(bytes).map_err(|_| TpmError::InvalidKeyLength(bytes.len()))
+    }
+
+    /// Create the primary storage key the sealed object lives under.
+    ///
+    /// The key is derived from the owner hierarchy seed, so the same
+    /// template always yields the same key.
+    fn create_primary(&mut self) -> Result<KeyHandle> {
+        let public = create_restricted_decryption_rsa_public(
+            SymmetricDefinitionObject::AES_128_CFB,
+            RsaKeyBits::Rsa2048,
+            RsaExponent::default(),
+        )?;
+        let result = self.context.execute_with_nullauth_session(|ctx| {
+            ctx.create_primary(Hierarchy::Owner, public, None, None, None, None)
+        })?;
+        Ok(result.key_handle)
+    }
+
+    /// Start a session of the given type and apply the PCR policy to it
+    fn pcr_policy(&mut self, session_type: SessionType) -> Result<PolicySession> {
+        let session = self
+            .context
+            .start_auth_session(
+                None,
+                None,
+                None,
+                session_type,
+                SymmetricDefinition::AES_128_CFB,
+                HashingAlgorithm::Sha256,
+            )?
+            .ok_or_else(|| TpmError::InvalidBlob("TPM returned no session".to_string()))?;
+        let (attributes, mask) = SessionAttributesBuilder::new()
+            .with_decrypt(true)
+            .with_encrypt(true)
+            .build();
+        self.context.tr_sess_set_attributes(session, attributes, mask)?;
+
+        let policy = PolicySession::try_from(session)?;
+        // An empty digest makes the TPM use the current PCR values
+        if let Err(e) = self
+            .context
+            .policy_pcr(policy, Digest::default(), self.pcrs.clone())
+        {
+            self.flush_session(policy)?;
+            return Err(e.into());
+        }
+        Ok(policy)
+    }
+
+    fn flush_session(&mut self, policy: PolicySession) -> Result<()> {
+        let handle = tss_esapi::handles::SessionHandle::from(policy);
+        self.context.flush_context(handle.into())?;
+        Ok(())
+    }
+}
+
+/// Public template for a sealed data object bound to `policy`
+fn sealed_object_public(policy: Digest) -> tss_esapi::Result<Public> {
+    let attributes = ObjectAttributesBuilder::new()
+        .with_fixed_tpm(true)
+        .with_fixed_parent(true)
+        .with_no_da(true)
+        .build()?;
+
+    PublicBuilder::new()
+        .with_public_algorithm(PublicAlgorithm::KeyedHash)
+        .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+        .with_object_attributes(attributes)
+        .with_auth_policy(policy)
+        .with_keyed_hash_parameters(PublicKeyedHashParameters::new(KeyedHashScheme::Null))
+        .with_keyed_hash_unique_identifier(Digest::default())
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sealed_blob_roundtrip() {
+        let blob = SealedBlob {
+            public: vec![1, 2, 3],
+            private: vec![4, 5, 6, 7],
+        };
+        assert_eq!(SealedBlob::from_bytes(&blob.to_bytes()).unwrap(), blob);
+        assert!(SealedBlob::from_bytes(&blob.to_bytes()[..10]).is_err());
+        assert!(SealedBlob::from_bytes(b"NOTATPMBLOB").is_err());
+    }
+}
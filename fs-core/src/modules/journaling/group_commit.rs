@@ -0,0 +1,260 @@
+//! Group commit: one device sync for many journal commits
+//!
+//! A commit is only durable once the device has been synced, and a sync
+//! costs about the same whether it covers one transaction or a hundred.
+//! [`GroupCommitter`] lets concurrent committers share one: the first to
+//! ask waits `commit_interval` for others to join, syncs once, and wakes
+//! everyone whose writes that sync covered. ext4, XFS and PostgreSQL
+//! amortize fsync the same way.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::blockdev::BlockDevice;
+use crate::error::{Error, Result};
+
+/// Batches the device syncs of concurrent committers
+pub struct GroupCommitter {
+    /// Device synced on behalf of the committers
+    device: Arc<dyn BlockDevice>,
+    /// How long the first committer of a batch waits for others to join
+    commit_interval: Duration,
+    /// Committers waiting for a sync to cover their ticket
+    pending: Mutex<Vec<(u64, Waker)>>,
+    /// Outcome of the sync that covered each ticket, until its committer
+    /// picks it up. Only locked while holding `pending`
+    results: Mutex<HashMap<u64, std::result::Result<(), String>>>,
+    /// Next ticket handed out; tickets are taken once a committer's writes
+    /// are done
+    next_ticket: AtomicU64,
+    /// Tickets below this have been covered by a finished sync
+    covered: AtomicU64,
+    /// Whether a committer is currently leading a batch
+    leading: AtomicBool,
+    /// Device syncs issued
+    syncs: AtomicU64,
+}
+
+impl GroupCommitter {
+    /// Batch syncs of `device`, waiting up to `commit_interval` for
+    /// committers to join each one
+    pub fn new(device: Arc<dyn BlockDevice>, commit_interval: Duration) -> Self {
+        Self {
+            device,
+            commit_interval,
+            pending: Mutex::new(Vec::new()),
+            results: Mutex::new(HashMap::new()),
+            next_ticket: AtomicU64::new(0),
+            covered: AtomicU64::new(0),
+            leading: AtomicBool::new(false),
+            syncs: AtomicU64::new(0),
+        }
+    }
+
+    /// How long the first committer of a batch waits for others to join
+    pub fn commit_interval(&self) -> Duration {
+        self.commit_interval
+    }
+
+    /// Number of device syncs issued so far
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::SeqCst)
+    }
+
+    /// Number of syncs committers have asked for so far
+    pub fn requests(&self) -> u64 {
+        self.next_ticket.load(Ordering::SeqCst)
+    }
+
+    /// Make everything written to the device before this call durable.
+    ///
+    /// Returns once a device sync that started after this call has
+    /// finished, with that sync's error if it failed.
+    pub async fn sync(&self) -> Result<()> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::SeqCst);
+        loop {
+            if !self.leading.swap(true, Ordering::SeqCst) {
+                // A batch may have covered us while we were taking the lead
+                let covered = self.results.lock().remove(&ticket);
+                if let Some(result) = covered {
+                    self.hand_over();
+                    return result.map_err(Error::Other);
+                }
+                return self.lead(ticket).await;
+            }
+            if let Some(result) = (Wait { committer: self, ticket }).await {
+                return result.map_err(Error::Other);
+            }
+        }
+    }
+
+    /// Wait for others to join, sync once for everyone who asked so far and
+    /// hand the results out
+    async fn lead(&self, ticket: u64) -> Result<()> {
+        tokio::time::sleep(self.commit_interval).await;
+
+        // Every committer that asked by now had finished its writes
+        let end = self.next_ticket.load(Ordering::SeqCst);
+        let start = self.covered.load(Ordering::SeqCst);
+        let outcome = self
+            .device
+            .sync()
+            .await
+            .map_err(|e| format!("Group commit sync failed: {}", e));
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        log::trace!("GROUP_COMMIT: One sync covered {} commits", end - start);
+
+        {
+            let mut pending = self.pending.lock();
+            let mut results = self.results.lock();
+            for covered in (start..end).filter(|&t| t != ticket) {
+                results.insert(covered, outcome.clone());
+            }
+            self.covered.store(end, Ordering::SeqCst);
+            pending.retain(|(t, waker)| {
+                if *t < end {
+                    waker.wake_by_ref();
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.hand_over();
+
+        outcome.map_err(Error::Other)
+    }
+
+    /// Give up the lead, waking a committer still waiting so it can lead
+    /// the next batch
+    fn hand_over(&self) {
+        let pending = self.pending.lock();
+        self.leading.store(false, Ordering::SeqCst);
+        if let Some((_, waker)) = pending.first() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+impl std::fmt::Debug for GroupCommitter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GroupCommitter")
+            .field("commit_interval", &self.commit_interval)
+            .field("requests", &self.requests())
+            .field("syncs", &self.syncs())
+            .finish()
+    }
+}
+
+/// Waits for a sync to cover `ticket`, or for nobody to be leading
+struct Wait<'a> {
+    committer: &'a GroupCommitter,
+    ticket: u64,
+}
+
+impl Future for Wait<'_> {
+    type Output = Option<std::result::Result<(), String>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let committer = self.committer;
+        let mut pending = committer.pending.lock();
+        if let Some(result) = committer.results.lock().remove(&self.ticket) {
+            pending.retain(|(t, _)| *t != self.ticket);
+            return Poll::Ready(Some(result));
+        }
+        if !committer.leading.load(Ordering::SeqCst) {
+            pending.retain(|(t, _)| *t != self.ticket);
+            return Poll::Ready(None);
+        }
+        match pending.iter_mut().find(|(t, _)| *t == self.ticket) {
+            Some((_, waker)) => waker.clone_from(cx.waker()),
+            None => pending.push((self.ticket, cx.waker().clone())),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Wait<'_> {
+    fn drop(&mut self) {
+        // A cancelled committer may have been the one woken to lead next
+        let mut pending = self.committer.pending.lock();
+        pending.retain(|(t, _)| *t != self.ticket);
+        if !self.committer.leading.load(Ordering::SeqCst) {
+            if let Some((_, waker)) = pending.first() {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::{BlockDeviceError, FileBackedBlockDevice};
+    use async_trait::async_trait;
+    use tempfile::NamedTempFile;
+
+    /// Counts syncs on top of a file-backed device
+    struct SyncCounter {
+        inner: FileBackedBlockDevice,
+        syncs: AtomicU64,
+    }
+
+    #[async_trait]
+    impl BlockDevice for SyncCounter {
+        async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> std::result::Result<(), BlockDeviceError> {
+            self.inner.read_block(block_num, buf).await
+        }
+
+        async fn write_block(&self, block_num: u64, data: &[u8]) -> std::result::Result<(), BlockDeviceError> {
+            self.inner.write_block(block_num, data).await
+        }
+
+        fn block_count(&self) -> u64 {
+            self.inner.block_count()
+        }
+
+        async fn sync(&self) -> std::result::Result<(), BlockDeviceError> {
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+            self.inner.sync().await
+        }
+
+        async fn close(&mut self) -> std::result::Result<(), BlockDeviceError> {
+            self.inner.close().await
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_syncs_share_device_syncs() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let device = Arc::new(SyncCounter {
+            inner: FileBackedBlockDevice::create(temp_file.path(), 1024 * 1024).await.unwrap(),
+            syncs: AtomicU64::new(0),
+        });
+        let committer = Arc::new(GroupCommitter::new(device.clone(), Duration::from_millis(20)));
+
+        let tasks: Vec<_> = (0..100)
+            .map(|_| {
+                let committer = committer.clone();
+                tokio::spawn(async move { committer.sync().await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        assert_eq!(committer.requests(), 100);
+        assert_eq!(committer.syncs(), device.syncs.load(Ordering::SeqCst));
+        assert!(committer.syncs() < 10, "{} syncs for 100 commits", committer.syncs());
+
+        // A lone committer still gets its own sync
+        committer.sync().await.unwrap();
+        assert_eq!(device.syncs.load(Ordering::SeqCst), committer.syncs());
+    }
+}
@@ -7,15 +7,25 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use parking_lot::{Mutex, RwLock};
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 use tokio::sync::mpsc;
 
-use crate::blockdev::{BlockDevice, BlockDeviceError};
+use crate::blockdev::{BlockDevice, BlockDeviceError, BLOCK_SIZE};
 use crate::error::Result;
 
+mod group_commit;
+
+pub use self::group_commit::GroupCommitter;
+
+/// Journal size used when formatting with `--enable-journal` (32MB with
+/// 4KB blocks)
+pub const DEFAULT_JOURNAL_BLOCKS: u64 = 8192;
+
 /// Journal entry types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
@@ -38,6 +48,57 @@ pub enum JournalEntryType {
     BlockDealloc = 8,
     /// Checkpoint marker
     Checkpoint = 9,
+    /// Full block image to be written to its home location on checkpoint
+    BlockImage = 10,
+}
+
+impl JournalEntryType {
+    /// Every entry type, in the order of their on-disk values
+    pub const ALL: [JournalEntryType; 10] = [
+        JournalEntryType::TransactionStart,
+        JournalEntryType::TransactionEnd,
+        JournalEntryType::MetadataUpdate,
+        JournalEntryType::DataWrite,
+        JournalEntryType::InodeUpdate,
+        JournalEntryType::DirEntryUpdate,
+        JournalEntryType::BlockAlloc,
+        JournalEntryType::BlockDealloc,
+        JournalEntryType::Checkpoint,
+        JournalEntryType::BlockImage,
+    ];
+
+    /// Name the entry type is shown and filtered by
+    pub fn name(self) -> &'static str {
+        match self {
+            JournalEntryType::TransactionStart => "TransactionStart",
+            JournalEntryType::TransactionEnd => "TransactionEnd",
+            JournalEntryType::MetadataUpdate => "MetadataUpdate",
+            JournalEntryType::DataWrite => "DataWrite",
+            JournalEntryType::InodeUpdate => "InodeUpdate",
+            JournalEntryType::DirEntryUpdate => "DirEntryUpdate",
+            JournalEntryType::BlockAlloc => "BlockAlloc",
+            JournalEntryType::BlockDealloc => "BlockDealloc",
+            JournalEntryType::Checkpoint => "Checkpoint",
+            JournalEntryType::BlockImage => "BlockImage",
+        }
+    }
+}
+
+impl fmt::Display for JournalEntryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for JournalEntryType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|entry_type| entry_type.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown journal entry type {:?}", s))
+    }
 }
 
 /// Journal entry header
@@ -91,6 +152,7 @@ impl JournalEntryHeader {
             7 => JournalEntryType::BlockAlloc,
             8 => JournalEntryType::BlockDealloc,
             9 => JournalEntryType::Checkpoint,
+            10 => JournalEntryType::BlockImage,
             _ => return Err(crate::error::Error::InvalidArgument),
         };
 
@@ -141,10 +203,46 @@ impl JournalEntry {
         buf
     }
 
+    /// Deserialize an entry as written to the journal. `data` may run on
+    /// past the entry, e.g. to the end of its last block; an entry whose
+    /// data doesn't match its checksum is an error.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let header = JournalEntryHeader::from_bytes(data)?;
+        let end = JournalEntryHeader::SIZE + header.data_length as usize;
+        if data.len() < end {
+            return Err(crate::error::Error::InvalidArgument);
+        }
+        let entry = Self {
+            data: data[JournalEntryHeader::SIZE..end].to_vec(),
+            header,
+        };
+        if !entry.verify_checksum() {
+            return Err(crate::error::Error::Other(format!(
+                "Journal entry of transaction {} fails its checksum",
+                entry.header.transaction_id
+            )));
+        }
+        Ok(entry)
+    }
+
     /// Verify the checksum of the entry
     pub fn verify_checksum(&self) -> bool {
         crc32fast::hash(&self.data) == self.header.checksum
     }
+
+    /// Number of journal blocks the serialized entry occupies
+    pub fn block_count(&self) -> u64 {
+        (JournalEntryHeader::SIZE + self.data.len()).div_ceil(BLOCK_SIZE) as u64
+    }
+
+    /// Split a `BlockImage` entry into its home block number and contents
+    pub fn block_image(&self) -> Option<(u64, &[u8])> {
+        if self.header.entry_type != JournalEntryType::BlockImage || self.data.len() != 8 + BLOCK_SIZE {
+            return None;
+        }
+        let home = u64::from_le_bytes(self.data[..8].try_into().unwrap());
+        Some((home, &self.data[8..]))
+    }
 }
 
 /// Transaction state
@@ -215,21 +313,48 @@ pub enum JournalError {
 pub struct JournalConfig {
     /// Maximum number of concurrent transactions
     pub max_transactions: usize,
+    /// First device block of the journal region
+    pub journal_start: u64,
     /// Journal size in blocks
     pub journal_size: u64,
     /// Checkpoint interval in transactions
     pub checkpoint_interval: u64,
     /// Enable journal compression
     pub compress: bool,
+    /// Share device syncs between commits arriving within this interval
+    /// (group commit); `None` syncs once per commit
+    pub group_commit_interval: Option<Duration>,
+}
+
+/// Result of a journal checkpoint
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckpointStats {
+    /// Committed transactions applied to their home locations
+    pub transactions: u64,
+    /// Block images written to their home locations
+    pub blocks_applied: u64,
+    /// Journal blocks made available for reuse
+    pub blocks_reclaimed: u64,
+}
+
+/// Committed block images that have not been checkpointed yet
+#[derive(Debug, Default)]
+struct PendingCheckpoint {
+    /// Number of committed transactions the images came from
+    transactions: u64,
+    /// Home block number and contents, in commit order
+    blocks: Vec<(u64, Vec<u8>)>,
 }
 
 impl Default for JournalConfig {
     fn default() -> Self {
         Self {
             max_transactions: 256,
-            journal_size: 8192, // 32MB with 4KB blocks
+            journal_start: 0,
+            journal_size: DEFAULT_JOURNAL_BLOCKS,
             checkpoint_interval: 100,
             compress: false,
+            group_commit_interval: None,
         }
     }
 }
@@ -246,8 +371,16 @@ pub struct JournalManager {
     active_transactions: RwLock<HashMap<u64, Arc<Mutex<Transaction>>>>,
     /// Journal write position
     write_position: AtomicU64,
-    /// Journal read position (for recovery)
+    /// Journal read position: start of the entries not yet checkpointed
     read_position: AtomicU64,
+    /// Serializes journal writes with checkpoints
+    io_lock: tokio::sync::Mutex<()>,
+    /// Committed block images waiting to be applied by a checkpoint
+    pending: Mutex<PendingCheckpoint>,
+    /// Transactions committed since the last checkpoint
+    commits_since_checkpoint: AtomicU64,
+    /// Shares device syncs between concurrent commits, if enabled
+    group_commit: Option<GroupCommitter>,
     /// Background task sender
     task_sender: Option<mpsc::UnboundedSender<JournalTask>>,
 }
@@ -266,6 +399,9 @@ pub enum JournalTask {
 impl JournalManager {
     /// Create a new journal manager
     pub fn new(device: Arc<dyn BlockDevice>, config: JournalConfig) -> Self {
+        let group_commit = config
+            .group_commit_interval
+            .map(|interval| GroupCommitter::new(device.clone(), interval));
         Self {
             device,
             config,
@@ -273,10 +409,19 @@ impl JournalManager {
             active_transactions: RwLock::new(HashMap::new()),
             write_position: AtomicU64::new(0),
             read_position: AtomicU64::new(0),
+            io_lock: tokio::sync::Mutex::new(()),
+            pending: Mutex::new(PendingCheckpoint::default()),
+            commits_since_checkpoint: AtomicU64::new(0),
+            group_commit,
             task_sender: None,
         }
     }
 
+    /// The group committer, if group commit is enabled
+    pub fn group_commit(&self) -> Option<&GroupCommitter> {
+        self.group_commit.as_ref()
+    }
+
     /// Initialize the journal manager
     pub async fn init(&mut self) -> Result<()> {
         // Start background task handler
@@ -354,8 +499,55 @@ impl JournalManager {
         Ok(())
     }
 
+    /// Log a full block image that a checkpoint writes to `block_num`
+    pub fn add_block_write(&self, transaction_id: u64, block_num: u64, data: &[u8]) -> Result<()> {
+        if data.len() != BLOCK_SIZE {
+            return Err(crate::error::Error::Other(format!(
+                "Block image must be {} bytes, got {}",
+                BLOCK_SIZE,
+                data.len()
+            )));
+        }
+        if (self.config.journal_start..self.config.journal_start + self.config.journal_size)
+            .contains(&block_num)
+        {
+            return Err(crate::error::Error::Other(format!(
+                "Block {} lies inside the journal",
+                block_num
+            )));
+        }
+
+        let mut image = Vec::with_capacity(8 + BLOCK_SIZE);
+        image.extend_from_slice(&block_num.to_le_bytes());
+        image.extend_from_slice(data);
+        self.add_entry(transaction_id, JournalEntryType::BlockImage, image)
+    }
+
+    /// Size of the journal in blocks
+    pub fn journal_size(&self) -> u64 {
+        self.config.journal_size
+    }
+
+    /// First device block of the journal region
+    pub fn journal_start(&self) -> u64 {
+        self.config.journal_start
+    }
+
+    /// Journal blocks holding entries that have not been checkpointed
+    pub fn used_blocks(&self) -> u64 {
+        self.write_position.load(Ordering::SeqCst) - self.read_position.load(Ordering::SeqCst)
+    }
+
     /// Commit a transaction
+    ///
+    /// Fails without writing anything if the journal has no room for the
+    /// whole transaction; it stays active so it can be retried after a
+    /// checkpoint. With group commit the journal is unlocked once the
+    /// entries are written, so concurrent commits can join the same sync.
     pub async fn commit_transaction(&self, transaction_id: u64) -> Result<()> {
+        #[cfg(feature = "telemetry")]
+        let span = crate::modules::telemetry::OpSpan::new("journal_commit");
+
         // Get the transaction
         let transaction = {
             let active = self.active_transactions.read();
@@ -367,6 +559,8 @@ impl JournalManager {
                 .clone()
         };
 
+        let io = self.io_lock.lock().await;
+
         // Mark as committing
         {
             let mut tx = transaction.lock();
@@ -376,7 +570,15 @@ impl JournalManager {
                     transaction_id
                 )));
             }
+
+            // Start and end markers take one block each
+            let needed: u64 = 2 + tx.entries.iter().map(|e| e.block_count()).sum::<u64>();
+            if self.write_position.load(Ordering::SeqCst) + needed > self.config.journal_size {
+                return Err(crate::error::Error::Other("Journal is full".to_string()));
+            }
             tx.state = TransactionState::Committing;
+            #[cfg(feature = "telemetry")]
+            span.record_bytes(needed * BLOCK_SIZE as u64);
         }
 
         // Write transaction start marker
@@ -385,19 +587,34 @@ impl JournalManager {
         self.write_entry(&start_entry).await?;
 
         // Write all entries
-        {
-            let tx = transaction.lock();
-            for entry in &tx.entries {
-                self.write_entry(entry).await?;
-            }
+        let entries = transaction.lock().entries.clone();
+        for entry in &entries {
+            self.write_entry(entry).await?;
         }
 
         // Write transaction end marker
         let end_entry = JournalEntry::new(JournalEntryType::TransactionEnd, transaction_id, vec![]);
         self.write_entry(&end_entry).await?;
+        self.write_terminator().await?;
+
+        match &self.group_commit {
+            // Queue the images while the journal is still locked, so they
+            // are applied in the order they were written; checkpoints sync
+            // before applying anything
+            Some(group_commit) => {
+                self.queue_block_images(&entries);
+                drop(io);
+                group_commit.sync().await?;
+            }
+            None => {
+                // Flush to disk
+                self.device.sync().await?;
 
-        // Flush to disk
-        self.device.sync().await?;
+                // The transaction is durable; its block images wait for a checkpoint
+                self.queue_block_images(&entries);
+                drop(io);
+            }
+        }
 
         // Mark as committed and remove from active transactions
         {
@@ -405,13 +622,29 @@ impl JournalManager {
             tx.state = TransactionState::Committed;
         }
 
-        let mut active = self.active_transactions.write();
-        active.remove(&transaction_id);
+        self.active_transactions.write().remove(&transaction_id);
 
         log::debug!("Committed transaction {}", transaction_id);
+
+        let commits = self.commits_since_checkpoint.fetch_add(1, Ordering::SeqCst) + 1;
+        if commits >= self.config.checkpoint_interval {
+            self.checkpoint().await?;
+        }
         Ok(())
     }
 
+    /// Queue a committed transaction's block images for the next checkpoint
+    fn queue_block_images(&self, entries: &[JournalEntry]) {
+        let mut pending = self.pending.lock();
+        pending.transactions += 1;
+        pending.blocks.extend(
+            entries
+                .iter()
+                .filter_map(|e| e.block_image())
+                .map(|(home, data)| (home, data.to_vec())),
+        );
+    }
+
     /// Abort a transaction
     pub fn abort_transaction(&self, transaction_id: u64) -> Result<()> {
         let mut active = self.active_transactions.write();
@@ -428,21 +661,22 @@ impl JournalManager {
         let entry_bytes = entry.to_bytes();
         let blocks_needed = (entry_bytes.len() + 4095) / 4096; // Round up to block size
 
-        let write_pos = self
-            .write_position
-            .fetch_add(blocks_needed as u64, Ordering::SeqCst);
+        // Callers hold io_lock, so the position can't move underneath us
+        let write_pos = self.write_position.load(Ordering::SeqCst);
 
         // Check if journal is full
         if write_pos + blocks_needed as u64 > self.config.journal_size {
             return Err(crate::error::Error::Other("Journal is full".to_string()));
         }
+        self.write_position
+            .store(write_pos + blocks_needed as u64, Ordering::SeqCst);
 
         // Write the entry
         let mut block_data = vec![0u8; blocks_needed * 4096];
         block_data[..entry_bytes.len()].copy_from_slice(&entry_bytes);
 
         for i in 0..blocks_needed {
-            let block_num = write_pos + i as u64;
+            let block_num = self.config.journal_start + write_pos + i as u64;
             let block_start = i * 4096;
             let block_end = std::cmp::min(block_start + 4096, block_data.len());
 
@@ -454,96 +688,183 @@ impl JournalManager {
         Ok(())
     }
 
-    /// Recover from journal after a crash
-    async fn recover(&self) -> Result<()> {
-        log::info!("Starting journal recovery");
+    /// Write a zeroed block after the last entry so recovery stops there
+    /// instead of running into entries left over from before a checkpoint
+    async fn write_terminator(&self) -> Result<()> {
+        let pos = self.write_position.load(Ordering::SeqCst);
+        if pos < self.config.journal_size {
+            self.device
+                .write_block(self.config.journal_start + pos, &vec![0u8; BLOCK_SIZE])
+                .await?;
+        }
+        Ok(())
+    }
 
-        let mut recovered_transactions = 0;
-        let mut read_pos = self.read_position.load(Ordering::SeqCst);
-
-        while read_pos < self.write_position.load(Ordering::SeqCst) {
-            // Read journal entry header
-            let mut header_data = vec![0u8; 4096];
-            self.device.read_block(read_pos, &mut header_data).await?;
-
-            // Parse header
-            let header = match JournalEntryHeader::from_bytes(&header_data) {
-                Ok(h) => h,
-                Err(_) => {
-                    log::warn!(
-                        "Corrupt journal entry at block {}, stopping recovery",
-                        read_pos
-                    );
-                    break;
-                }
-            };
+    /// Read the entry starting at journal block `pos`, if there is a valid one
+    async fn read_entry(&self, pos: u64) -> Result<Option<JournalEntry>> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        self.device.read_block(self.config.journal_start + pos, &mut buf).await?;
 
-            // Read entry data if present
-            let mut entry_data = Vec::new();
-            if header.data_length > 0 {
-                let data_blocks = (header.data_length as usize + 4095) / 4096;
-                for i in 1..=data_blocks {
-                    let mut block_data = vec![0u8; 4096];
-                    self.device
-                        .read_block(read_pos + i as u64, &mut block_data)
-                        .await?;
-                    entry_data.extend_from_slice(&block_data);
-                }
-                entry_data.truncate(header.data_length as usize);
+        let header = match JournalEntryHeader::from_bytes(&buf) {
+            Ok(h) => h,
+            Err(_) => return Ok(None),
+        };
+
+        let total = JournalEntryHeader::SIZE + header.data_length as usize;
+        let blocks = total.div_ceil(BLOCK_SIZE) as u64;
+        if pos + blocks > self.config.journal_size {
+            log::warn!("Journal entry at block {} runs past the end of the journal", pos);
+            return Ok(None);
+        }
+
+        // Entry data continues straight after the header
+        let mut block = vec![0u8; BLOCK_SIZE];
+        for i in 1..blocks {
+            self.device
+                .read_block(self.config.journal_start + pos + i, &mut block)
+                .await?;
+            buf.extend_from_slice(&block);
+        }
+
+        match JournalEntry::from_bytes(&buf) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(_) => {
+                log::warn!("Corrupt journal entry checksum at block {}", pos);
+                Ok(None)
             }
+        }
+    }
 
-            // Create and verify entry
-            let entry = JournalEntry {
-                header,
-                data: entry_data,
+    /// Every entry in the journal with the journal block it starts at,
+    /// up to the first block that doesn't hold a valid entry. Nothing is
+    /// recovered or applied, so this works on a journal that hasn't been
+    /// opened with [`init`](Self::init).
+    pub async fn read_entries(&self) -> Result<Vec<(u64, JournalEntry)>> {
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < self.config.journal_size {
+            let Some(entry) = self.read_entry(pos).await? else {
+                break;
             };
+            let next = pos + entry.block_count();
+            entries.push((pos, entry));
+            pos = next;
+        }
+        Ok(entries)
+    }
 
-            if !entry.verify_checksum() {
-                log::warn!("Corrupt journal entry checksum at block {}", read_pos);
-                break;
-            }
+    /// Recover from journal after a crash
+    ///
+    /// Committed transactions found in the journal are queued for the next
+    /// checkpoint; transactions without an end marker are discarded.
+    async fn recover(&self) -> Result<()> {
+        log::info!("Starting journal recovery");
+
+        let mut open: HashMap<u64, Vec<(u64, Vec<u8>)>> = HashMap::new();
+        let mut recovered = PendingCheckpoint::default();
+        let mut read_pos = 0;
+
+        while read_pos < self.config.journal_size {
+            let entry = match self.read_entry(read_pos).await? {
+                Some(entry) => entry,
+                None => break,
+            };
 
             // Process the entry for recovery
+            let transaction_id = entry.header.transaction_id;
             match entry.header.entry_type {
                 JournalEntryType::TransactionStart => {
-                    log::debug!("Found transaction start: {}", entry.header.transaction_id);
+                    log::debug!("Found transaction start: {}", transaction_id);
+                    open.insert(transaction_id, Vec::new());
                 }
                 JournalEntryType::TransactionEnd => {
-                    log::debug!("Found transaction end: {}", entry.header.transaction_id);
-                    recovered_transactions += 1;
+                    log::debug!("Found transaction end: {}", transaction_id);
+                    if let Some(blocks) = open.remove(&transaction_id) {
+                        recovered.transactions += 1;
+                        recovered.blocks.extend(blocks);
+                    }
+                }
+                JournalEntryType::BlockImage => {
+                    if let (Some(blocks), Some((home, data))) =
+                        (open.get_mut(&transaction_id), entry.block_image())
+                    {
+                        blocks.push((home, data.to_vec()));
+                    }
+                }
+                JournalEntryType::Checkpoint => {
+                    // Everything before a checkpoint marker is already applied
+                    recovered = PendingCheckpoint::default();
                 }
                 _ => {
-                    // Process other entry types as needed
+                    // Logical entries have nothing to replay
                 }
             }
 
             // Move to next entry
-            let entry_blocks = (JournalEntryHeader::SIZE + entry.data.len() + 4095) / 4096;
-            read_pos += entry_blocks as u64;
+            read_pos += entry.block_count();
+        }
+
+        if !open.is_empty() {
+            log::warn!("Discarding {} incomplete journal transactions", open.len());
         }
 
-        self.read_position.store(read_pos, Ordering::SeqCst);
+        self.write_position.store(read_pos, Ordering::SeqCst);
+        self.read_position.store(0, Ordering::SeqCst);
         log::info!(
             "Journal recovery complete. Recovered {} transactions",
-            recovered_transactions
+            recovered.transactions
         );
+        *self.pending.lock() = recovered;
 
         Ok(())
     }
 
     /// Create a checkpoint
-    pub async fn checkpoint(&self) -> Result<()> {
-        let checkpoint_entry = JournalEntry::new(
-            JournalEntryType::Checkpoint,
-            0, // Checkpoint doesn't belong to a transaction
-            vec![],
-        );
+    ///
+    /// Applies every committed transaction's block images to their home
+    /// locations and syncs the device, after which the journal holds nothing
+    /// that isn't on disk. The tail then catches up with the head and the
+    /// empty journal is compacted back to its first block.
+    pub async fn checkpoint(&self) -> Result<CheckpointStats> {
+        let _io = self.io_lock.lock().await;
+
+        // Group commits queue their images before their sync, so make
+        // every entry written so far durable before applying any
+        if self.group_commit.is_some() {
+            self.device.sync().await?;
+        }
 
-        self.write_entry(&checkpoint_entry).await?;
+        let pending = std::mem::take(&mut *self.pending.lock());
+        for (home, data) in &pending.blocks {
+            self.device.write_block(*home, data).await?;
+        }
         self.device.sync().await?;
 
-        log::info!("Created journal checkpoint");
-        Ok(())
+        // Everything up to the head is now applied, so the tail can advance
+        let head = self.write_position.load(Ordering::SeqCst);
+        let reclaimed = head - self.read_position.swap(head, Ordering::SeqCst);
+
+        // With no un-applied entries left the journal can start over
+        if head > 0 {
+            self.write_position.store(0, Ordering::SeqCst);
+            self.read_position.store(0, Ordering::SeqCst);
+            self.write_terminator().await?;
+            self.device.sync().await?;
+        }
+        self.commits_since_checkpoint.store(0, Ordering::SeqCst);
+
+        let stats = CheckpointStats {
+            transactions: pending.transactions,
+            blocks_applied: pending.blocks.len() as u64,
+            blocks_reclaimed: reclaimed,
+        };
+        log::info!(
+            "Created journal checkpoint: applied {} transactions ({} blocks), reclaimed {} journal blocks",
+            stats.transactions,
+            stats.blocks_applied,
+            stats.blocks_reclaimed
+        );
+        Ok(stats)
     }
 
     /// Shutdown the journal manager
@@ -590,6 +911,49 @@ mod tests {
         assert!(entry.verify_checksum());
     }
 
+    #[tokio::test]
+    async fn test_entries_read_back_from_the_journal() {
+        let entry = JournalEntry::new(JournalEntryType::MetadataUpdate, 42, b"inode 17".to_vec());
+        let mut bytes = entry.to_bytes();
+        bytes.resize(BLOCK_SIZE, 0);
+        let parsed = JournalEntry::from_bytes(&bytes).unwrap();
+        assert_eq!((parsed.header.transaction_id, parsed.data.as_slice()), (42, &b"inode 17"[..]));
+        bytes[JournalEntryHeader::SIZE] ^= 1;
+        assert!(JournalEntry::from_bytes(&bytes).is_err());
+        assert_eq!("blockimage".parse::<JournalEntryType>(), Ok(JournalEntryType::BlockImage));
+
+        let temp_file = NamedTempFile::new().unwrap();
+        let device: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(temp_file.path(), 1024 * 1024)
+                .await
+                .unwrap(),
+        );
+        let config = JournalConfig {
+            journal_size: 16,
+            checkpoint_interval: 1000,
+            ..JournalConfig::default()
+        };
+        let mut journal = JournalManager::new(device.clone(), config.clone());
+        journal.init().await.unwrap();
+        let tx = journal.begin_transaction().unwrap();
+        journal.add_block_write(tx, 100, &[7; BLOCK_SIZE]).unwrap();
+        journal.commit_transaction(tx).await.unwrap();
+        journal.shutdown().await.unwrap();
+
+        // Reading the entries leaves the committed transaction unapplied
+        let entries = JournalManager::new(device.clone(), config).read_entries().await.unwrap();
+        let types: Vec<_> = entries.iter().map(|(_, entry)| entry.header.entry_type).collect();
+        assert_eq!(
+            types,
+            [JournalEntryType::TransactionStart, JournalEntryType::BlockImage, JournalEntryType::TransactionEnd]
+        );
+        assert_eq!(entries.iter().map(|(pos, _)| *pos).collect::<Vec<_>>(), [0, 1, 3]);
+        assert_eq!(entries[1].1.block_image().map(|(home, _)| home), Some(100));
+        let mut block = vec![0u8; BLOCK_SIZE];
+        device.read_block(100, &mut block).await.unwrap();
+        assert_eq!(block, vec![0; BLOCK_SIZE]);
+    }
+
     #[tokio::test]
     async fn test_transaction_lifecycle() {
         let temp_file = NamedTempFile::new().unwrap();
@@ -624,4 +988,104 @@ mod tests {
         // Shutdown
         journal.shutdown().await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_checkpoint_reclaims_journal_space() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let device: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(temp_file.path(), 1024 * 1024)
+                .await
+                .unwrap(),
+        );
+        let config = JournalConfig {
+            journal_size: 8,
+            checkpoint_interval: 1000,
+            ..JournalConfig::default()
+        };
+
+        let mut journal = JournalManager::new(device.clone(), config.clone());
+        journal.init().await.unwrap();
+
+        // Each transaction takes a start block, two image blocks and an end block
+        for i in 0..2u8 {
+            let tx = journal.begin_transaction().unwrap();
+            journal.add_block_write(tx, 100 + i as u64, &[i + 1; BLOCK_SIZE]).unwrap();
+            journal.commit_transaction(tx).await.unwrap();
+        }
+        assert_eq!(journal.used_blocks(), 8);
+
+        let tx = journal.begin_transaction().unwrap();
+        journal.add_block_write(tx, 102, &[3; BLOCK_SIZE]).unwrap();
+        assert!(journal.commit_transaction(tx).await.is_err());
+
+        let stats = journal.checkpoint().await.unwrap();
+        assert_eq!(
+            stats,
+            CheckpointStats {
+                transactions: 2,
+                blocks_applied: 2,
+                blocks_reclaimed: 8,
+            }
+        );
+        let mut block = vec![0u8; BLOCK_SIZE];
+        device.read_block(101, &mut block).await.unwrap();
+        assert_eq!(block, vec![2; BLOCK_SIZE]);
+
+        // The retried commit reuses the reclaimed space from the start
+        journal.commit_transaction(tx).await.unwrap();
+        assert_eq!(journal.used_blocks(), 4);
+        journal.shutdown().await.unwrap();
+
+        // A fresh manager finds the committed transaction and applies it
+        let mut journal = JournalManager::new(device.clone(), config);
+        journal.init().await.unwrap();
+        assert_eq!(journal.checkpoint().await.unwrap().transactions, 1);
+        device.read_block(102, &mut block).await.unwrap();
+        assert_eq!(block, vec![3; BLOCK_SIZE]);
+        journal.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_group_commit_batches_concurrent_commits() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let device: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(temp_file.path(), 4 * 1024 * 1024)
+                .await
+                .unwrap(),
+        );
+        let config = JournalConfig {
+            journal_size: 512,
+            checkpoint_interval: 1000,
+            group_commit_interval: Some(Duration::from_millis(10)),
+            ..JournalConfig::default()
+        };
+
+        let mut journal = JournalManager::new(device.clone(), config.clone());
+        journal.init().await.unwrap();
+        let journal = Arc::new(journal);
+        let commits: Vec<_> = (0..100u64)
+            .map(|i| {
+                let journal = journal.clone();
+                tokio::spawn(async move {
+                    let tx = journal.begin_transaction()?;
+                    journal.add_block_write(tx, 600 + i, &[i as u8 + 1; BLOCK_SIZE])?;
+                    journal.commit_transaction(tx).await
+                })
+            })
+            .collect();
+        for commit in commits {
+            commit.await.unwrap().unwrap();
+        }
+        let group_commit = journal.group_commit().unwrap();
+        assert_eq!(group_commit.requests(), 100);
+        assert!(group_commit.syncs() < 50, "{} syncs for 100 commits", group_commit.syncs());
+
+        // Every commit made it into the journal
+        let mut recovered = JournalManager::new(device.clone(), config);
+        recovered.init().await.unwrap();
+        assert_eq!(recovered.checkpoint().await.unwrap().transactions, 100);
+        let mut block = vec![0u8; BLOCK_SIZE];
+        device.read_block(699, &mut block).await.unwrap();
+        assert_eq!(block, vec![100; BLOCK_SIZE]);
+    }
 }
@@ -3,19 +3,62 @@
 //! This module contains optional filesystem features that can be enabled
 //! or disabled based on configuration and feature flags.
 
+pub mod audit;
 pub mod checksums;
+// build.rs always sets the `compression` cfg, so gate on the dependency
+#[cfg(feature = "zstd")]
+pub mod compression;
+pub mod encryption;
+pub mod fscrypt;
 pub mod journaling;
+pub mod monitoring;
+pub mod quota;
+pub mod replication;
 pub mod snapshot;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
+
+// Re-export audit types
+pub use audit::{AuditError, AuditEvent, AuditLog, AuditOp};
+
+// Re-export compression types
+#[cfg(feature = "zstd")]
+pub use compression::{
+    AdaptiveCompressor, AlgorithmTable, BlockAlgorithm, CompressionDictionary, CompressionError,
+};
+
+// Re-export fscrypt types
+pub use fscrypt::{FscryptContext, FscryptKeyring, FscryptPolicy, InodeKey};
 
 // Re-export journaling types
 pub use journaling::{
-    JournalConfig, JournalEntryType, JournalManager, Transaction, TransactionState,
+    CheckpointStats, GroupCommitter, JournalConfig, JournalEntry, JournalEntryType, JournalManager,
+    Transaction, TransactionState,
 };
 
 // Re-export checksum types
 pub use checksums::{ChecksumAlgorithm, ChecksumConfig, ChecksumManager, ScrubStats};
 
+// Re-export monitoring types
+pub use monitoring::{
+    FilesystemStats, HealthAlert, HealthCheck, HealthCheckConfig, HealthCheckHandle, MonitoredFs,
+    StatsSource,
+};
+
+// Re-export quota types
+pub use quota::{ProjectQuotaManager, QuotaEntry};
+
+// Re-export replication types
+pub use replication::{
+    BitmapDeltaFrame, ReplicationConfig, ReplicationFrame, ReplicationManager, ReplicationReceiver,
+};
+
 // Re-export snapshot types
 pub use snapshot::{
-    SnapshotConfig, SnapshotManager, SnapshotMetadata, SnapshotState, SnapshotStats,
+    ScriptHook, SnapshotChain, SnapshotConfig, SnapshotHook, SnapshotIntegrity, SnapshotManager,
+    SnapshotMetadata, SnapshotState, SnapshotStats,
 };
+
+// Re-export telemetry types
+#[cfg(feature = "telemetry")]
+pub use telemetry::{OpSpan, TelemetryBlockDevice, TelemetryConfig, TelemetryError, TelemetryGuard};
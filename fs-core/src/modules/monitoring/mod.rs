@@ -0,0 +1,592 @@
+//! Filesystem health monitoring for AegisFS
+//!
+//! `HealthCheck` runs as a background task that periodically samples
+//! [`FilesystemStats`] and [`ScrubStats`] from a [`StatsSource`] and raises
+//! alerts when free space runs low, bad blocks pile up, the journal fills or
+//! scrubs stop completing. Alerts are logged under the `aegisfs::health`
+//! target and, with the `webhook` feature, posted as JSON to a URL.
+//!
+//! Thresholds are read from the `[health_check]` section of `aegisfs.toml`:
+//!
+//! ```toml
+//! [health_check]
+//! interval = 300                  # seconds between samples
+//! low_space_threshold_percent = 10
+//! max_bad_blocks = 100
+//! journal_full_threshold_percent = 80
+//! max_scrub_age = 172800          # seconds
+//! webhook_url = "https://alerts.example.com/aegisfs"
+//! ```
+
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::modules::checksums::{ChecksumManager, ScrubStats};
+use crate::modules::journaling::JournalManager;
+use crate::AegisFS;
+
+/// Log target for health alerts
+pub const LOG_TARGET: &str = "aegisfs::health";
+
+/// Health check error types
+#[derive(Error, Debug)]
+pub enum HealthCheckError {
+    /// The configuration file could not be read
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The configuration file is not valid TOML for the health checks
+    #[error("Invalid configuration: {0}")]
+    Config(#[from] toml::de::Error),
+    #[cfg(feature = "webhook")]
+    #[error("Webhook delivery failed: {0}")]
+    Webhook(#[from] reqwest::Error),
+}
+
+/// Result type for health check operations
+pub type Result<T> = std::result::Result<T, HealthCheckError>;
+
+/// Health check configuration, the `[health_check]` section of `aegisfs.toml`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    /// Time between samples, in seconds in the config file
+    #[serde(deserialize_with = "duration_secs")]
+    pub interval: Duration,
+    /// Alert when free data blocks drop below this percentage
+    pub low_space_threshold_percent: u8,
+    /// Alert when more bad blocks than this are known
+    pub max_bad_blocks: u64,
+    /// Alert when the journal is fuller than this percentage
+    pub journal_full_threshold_percent: u8,
+    /// Alert when no scrub has completed for this long, in seconds in the
+    /// config file
+    #[serde(deserialize_with = "duration_secs")]
+    pub max_scrub_age: Duration,
+    /// Where to POST alerts as JSON; needs the `webhook` feature
+    pub webhook_url: Option<String>,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5 * 60),
+            low_space_threshold_percent: 10,
+            max_bad_blocks: 100,
+            journal_full_threshold_percent: 80,
+            max_scrub_age: Duration::from_secs(2 * 24 * 60 * 60),
+            webhook_url: None,
+        }
+    }
+}
+
+fn duration_secs<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+/// Layout of `aegisfs.toml`; sections other than `[health_check]` are ignored
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    health_check: HealthCheckConfig,
+}
+
+impl HealthCheckConfig {
+    /// Parse the `[health_check]` section of an `aegisfs.toml` document,
+    /// using the defaults if it is missing
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        Ok(toml::from_str::<ConfigFile>(contents)?.health_check)
+    }
+
+    /// Read the `[health_check]` section of an `aegisfs.toml` file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+}
+
+/// Space and journal usage of a mounted filesystem
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FilesystemStats {
+    /// Data blocks on the filesystem
+    pub total_blocks: u64,
+    /// Unallocated data blocks
+    pub free_blocks: u64,
+    /// Blocks known to be bad
+    pub bad_blocks: u64,
+    /// Size of the journal in blocks; 0 without a journal
+    pub journal_blocks: u64,
+    /// Journal blocks holding entries that have not been checkpointed
+    pub journal_used_blocks: u64,
+    /// Bytes of extended attributes held in the inode cache; 0 when the
+    /// filesystem isn't mounted
+    pub xattr_cache_bytes: u64,
+}
+
+impl FilesystemStats {
+    /// Free data blocks as a percentage of all data blocks
+    pub fn free_percent(&self) -> f64 {
+        if self.total_blocks == 0 {
+            return 100.0;
+        }
+        self.free_blocks as f64 * 100.0 / self.total_blocks as f64
+    }
+
+    /// Used journal blocks as a percentage of the journal
+    pub fn journal_used_percent(&self) -> f64 {
+        if self.journal_blocks == 0 {
+            return 0.0;
+        }
+        self.journal_used_blocks as f64 * 100.0 / self.journal_blocks as f64
+    }
+}
+
+/// Something the health check can sample
+#[async_trait]
+pub trait StatsSource: Send + Sync {
+    /// Current space, bad block and journal usage
+    async fn filesystem_stats(&self) -> FilesystemStats;
+
+    /// Statistics of the last scrub, if scrubbing is set up
+    async fn scrub_stats(&self) -> Option<ScrubStats> {
+        None
+    }
+}
+
+#[async_trait]
+impl StatsSource for AegisFS {
+    async fn filesystem_stats(&self) -> FilesystemStats {
+        AegisFS::filesystem_stats(self)
+    }
+}
+
+/// A filesystem together with the modules that track its bad blocks and
+/// journal
+pub struct MonitoredFs {
+    /// The mounted filesystem
+    pub fs: Arc<AegisFS>,
+    /// Checksum manager providing bad blocks and scrub statistics
+    pub checksums: Option<Arc<ChecksumManager>>,
+    /// Journal whose fill level is watched
+    pub journal: Option<Arc<JournalManager>>,
+}
+
+#[async_trait]
+impl StatsSource for MonitoredFs {
+    async fn filesystem_stats(&self) -> FilesystemStats {
+        let mut stats = self.fs.filesystem_stats();
+        if let Some(checksums) = &self.checksums {
+            stats.bad_blocks = checksums.get_bad_blocks().len() as u64;
+        }
+        if let Some(journal) = &self.journal {
+            stats.journal_blocks = journal.journal_size();
+            stats.journal_used_blocks = journal.used_blocks();
+        }
+        stats
+    }
+
+    async fn scrub_stats(&self) -> Option<ScrubStats> {
+        self.checksums.as_ref().map(|checksums| checksums.get_scrub_stats())
+    }
+}
+
+/// How serious an alert is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Logged at WARN
+    Warning,
+    /// Logged at ERROR
+    Critical,
+}
+
+/// What an alert is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    /// Free space is below `low_space_threshold_percent`
+    LowSpace,
+    /// More than `max_bad_blocks` bad blocks are known
+    BadBlocks,
+    /// The journal is fuller than `journal_full_threshold_percent`
+    JournalFull,
+    /// No scrub has completed within `max_scrub_age`
+    ScrubOverdue,
+}
+
+/// A threshold crossed by one sample
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthAlert {
+    /// What the alert is about
+    pub kind: AlertKind,
+    /// How serious it is
+    pub severity: Severity,
+    /// Human-readable description
+    pub message: String,
+    /// Seconds since the Unix epoch when the sample was taken
+    pub timestamp: u64,
+}
+
+/// Periodic health check over a [`StatsSource`]
+pub struct HealthCheck {
+    /// Thresholds and delivery settings
+    config: HealthCheckConfig,
+    /// What to sample
+    source: Arc<dyn StatsSource>,
+    /// When the check was created; scrub age is measured from here until a
+    /// scrub completes
+    started: SystemTime,
+    /// Client used for webhook delivery
+    #[cfg(feature = "webhook")]
+    client: reqwest::Client,
+}
+
+impl HealthCheck {
+    /// Create a health check; call [`start`](Self::start) to run it
+    pub fn new(config: HealthCheckConfig, source: Arc<dyn StatsSource>) -> Self {
+        #[cfg(not(feature = "webhook"))]
+        if config.webhook_url.is_some() {
+            log::warn!(
+                target: LOG_TARGET,
+                "webhook_url is set but AegisFS was built without the webhook feature; alerts will only be logged"
+            );
+        }
+
+        Self {
+            config,
+            source,
+            started: SystemTime::now(),
+            #[cfg(feature = "webhook")]
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Configuration in use
+    pub fn config(&self) -> &HealthCheckConfig {
+        &self.config
+    }
+
+    /// Take one sample and return the alerts it raises, without logging or
+    /// delivering them
+    pub async fn check(&self) -> Vec<HealthAlert> {
+        let stats = self.source.filesystem_stats().await;
+        let scrub = self.source.scrub_stats().await;
+        self.evaluate(&stats, scrub.as_ref(), SystemTime::now())
+    }
+
+    /// Compare a sample against the thresholds
+    fn evaluate(
+        &self,
+        stats: &FilesystemStats,
+        scrub: Option<&ScrubStats>,
+        now: SystemTime,
+    ) -> Vec<HealthAlert> {
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut alerts = Vec::new();
+        let mut alert = |kind, severity, message| {
+            alerts.push(HealthAlert {
+                kind,
+                severity,
+                message,
+                timestamp,
+            })
+        };
+
+        let free = stats.free_percent();
+        if free < self.config.low_space_threshold_percent as f64 {
+            // Running out entirely is worse than dipping below the threshold
+            let severity = if stats.free_blocks == 0 {
+                Severity::Critical
+            } else {
+                Severity::Warning
+            };
+            alert(
+                AlertKind::LowSpace,
+                severity,
+                format!(
+                    "Free space is {:.1}% ({} of {} blocks), below {}%",
+                    free, stats.free_blocks, stats.total_blocks, self.config.low_space_threshold_percent
+                ),
+            );
+        }
+
+        if stats.bad_blocks > self.config.max_bad_blocks {
+            alert(
+                AlertKind::BadBlocks,
+                Severity::Critical,
+                format!(
+                    "{} bad blocks exceed the limit of {}",
+                    stats.bad_blocks, self.config.max_bad_blocks
+                ),
+            );
+        }
+
+        let journal = stats.journal_used_percent();
+        if journal > self.config.journal_full_threshold_percent as f64 {
+            alert(
+                AlertKind::JournalFull,
+                Severity::Warning,
+                format!(
+                    "Journal is {:.1}% full ({} of {} blocks), above {}%",
+                    journal,
+                    stats.journal_used_blocks,
+                    stats.journal_blocks,
+                    self.config.journal_full_threshold_percent
+                ),
+            );
+        }
+
+        // Without scrubbing there is nothing to be overdue
+        if let Some(scrub) = scrub {
+            let last = scrub.end_time.unwrap_or(self.started);
+            let age = now.duration_since(last).unwrap_or_default();
+            if age > self.config.max_scrub_age {
+                let message = match scrub.end_time {
+                    Some(_) => format!(
+                        "Last scrub completed {}s ago, more than {}s",
+                        age.as_secs(),
+                        self.config.max_scrub_age.as_secs()
+                    ),
+                    None => format!(
+                        "No scrub has completed in {}s, more than {}s",
+                        age.as_secs(),
+                        self.config.max_scrub_age.as_secs()
+                    ),
+                };
+                alert(AlertKind::ScrubOverdue, Severity::Warning, message);
+            }
+        }
+
+        alerts
+    }
+
+    /// Log an alert and deliver it to the webhook, if one is configured
+    async fn report(&self, alert: &HealthAlert) {
+        match alert.severity {
+            Severity::Warning => log::warn!(
+                target: LOG_TARGET,
+                "kind={:?} severity=warning message=\"{}\"",
+                alert.kind,
+                alert.message
+            ),
+            Severity::Critical => log::error!(
+                target: LOG_TARGET,
+                "kind={:?} severity=critical message=\"{}\"",
+                alert.kind,
+                alert.message
+            ),
+        }
+
+        #[cfg(feature = "webhook")]
+        if let Some(url) = &self.config.webhook_url {
+            if let Err(e) = self.deliver(url, alert).await {
+                log::warn!(target: LOG_TARGET, "Failed to deliver alert to {}: {}", url, e);
+            }
+        }
+    }
+
+    /// POST an alert to the webhook as JSON
+    #[cfg(feature = "webhook")]
+    async fn deliver(&self, url: &str, alert: &HealthAlert) -> Result<()> {
+        self.client
+            .post(url)
+            .json(alert)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Start sampling in the background every `interval`
+    pub fn start(self: &Arc<Self>) -> HealthCheckHandle {
+        let (sender, mut receiver) = watch::channel(false);
+        let check = self.clone();
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(check.config.interval);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        for alert in check.check().await {
+                            check.report(&alert).await;
+                        }
+                    }
+                    _ = receiver.changed() => break,
+                }
+            }
+            log::debug!(target: LOG_TARGET, "Health check stopped");
+        });
+
+        HealthCheckHandle {
+            shutdown: sender,
+            task,
+        }
+    }
+}
+
+/// Running background health check
+pub struct HealthCheckHandle {
+    /// Tells the background task to stop
+    shutdown: watch::Sender<bool>,
+    /// Background task
+    task: JoinHandle<()>,
+}
+
+impl HealthCheckHandle {
+    /// Stop the background task and wait for it to finish
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        let _ = self.task.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Source returning fixed statistics and counting samples
+    struct FixedStats {
+        stats: FilesystemStats,
+        scrub: Option<ScrubStats>,
+        samples: AtomicU64,
+    }
+
+    #[async_trait]
+    impl StatsSource for FixedStats {
+        async fn filesystem_stats(&self) -> FilesystemStats {
+            self.samples.fetch_add(1, Ordering::SeqCst);
+            self.stats.clone()
+        }
+
+        async fn scrub_stats(&self) -> Option<ScrubStats> {
+            self.scrub.clone()
+        }
+    }
+
+    fn source(stats: FilesystemStats, scrub: Option<ScrubStats>) -> Arc<FixedStats> {
+        Arc::new(FixedStats {
+            stats,
+            scrub,
+            samples: AtomicU64::new(0),
+        })
+    }
+
+    #[test]
+    fn test_config_from_toml() {
+        let config = HealthCheckConfig::from_toml(
+            r#"
+            [other]
+            ignored = true
+
+            [health_check]
+            interval = 60
+            low_space_threshold_percent = 5
+            max_scrub_age = 3600
+            webhook_url = "http://localhost:9000/alerts"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.interval, Duration::from_secs(60));
+        assert_eq!(config.low_space_threshold_percent, 5);
+        assert_eq!(config.max_bad_blocks, 100);
+        assert_eq!(config.max_scrub_age, Duration::from_secs(3600));
+        assert_eq!(config.webhook_url.as_deref(), Some("http://localhost:9000/alerts"));
+
+        // A file without the section uses the defaults
+        assert_eq!(HealthCheckConfig::from_toml("").unwrap(), HealthCheckConfig::default());
+        assert!(HealthCheckConfig::from_toml("[health_check]\ninterval = \"soon\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_thresholds_raise_alerts() {
+        let healthy = FilesystemStats {
+            total_blocks: 1000,
+            free_blocks: 500,
+            bad_blocks: 3,
+            journal_blocks: 100,
+            journal_used_blocks: 10,
+            xattr_cache_bytes: 0,
+        };
+        let scrubbed = ScrubStats {
+            end_time: Some(SystemTime::now()),
+            ..ScrubStats::default()
+        };
+        let check = HealthCheck::new(HealthCheckConfig::default(), source(healthy, Some(scrubbed)));
+        assert!(check.check().await.is_empty());
+
+        let degraded = FilesystemStats {
+            total_blocks: 1000,
+            free_blocks: 50,
+            bad_blocks: 101,
+            journal_blocks: 100,
+            journal_used_blocks: 90,
+            xattr_cache_bytes: 0,
+        };
+        let stale = ScrubStats {
+            end_time: Some(SystemTime::now() - Duration::from_secs(3 * 24 * 60 * 60)),
+            ..ScrubStats::default()
+        };
+        let check = HealthCheck::new(HealthCheckConfig::default(), source(degraded, Some(stale)));
+        let alerts = check.check().await;
+        let kinds: Vec<AlertKind> = alerts.iter().map(|a| a.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AlertKind::LowSpace,
+                AlertKind::BadBlocks,
+                AlertKind::JournalFull,
+                AlertKind::ScrubOverdue
+            ]
+        );
+        assert_eq!(alerts[0].severity, Severity::Warning);
+        assert_eq!(alerts[1].severity, Severity::Critical);
+    }
+
+    #[tokio::test]
+    async fn test_missing_scrub_is_measured_from_start() {
+        let stats = FilesystemStats {
+            total_blocks: 1000,
+            free_blocks: 1000,
+            ..FilesystemStats::default()
+        };
+        let config = HealthCheckConfig {
+            max_scrub_age: Duration::from_secs(60),
+            ..HealthCheckConfig::default()
+        };
+        let check = HealthCheck::new(config, source(stats.clone(), Some(ScrubStats::default())));
+        let now = SystemTime::now();
+        assert!(check.evaluate(&stats, Some(&ScrubStats::default()), now).is_empty());
+
+        let later = now + Duration::from_secs(120);
+        let alerts = check.evaluate(&stats, Some(&ScrubStats::default()), later);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, AlertKind::ScrubOverdue);
+
+        // Without scrubbing configured there is nothing to be overdue
+        assert!(check.evaluate(&stats, None, later).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_background_task_samples_until_shutdown() {
+        let stats = source(FilesystemStats::default(), None);
+        let config = HealthCheckConfig {
+            interval: Duration::from_millis(10),
+            ..HealthCheckConfig::default()
+        };
+        let check = Arc::new(HealthCheck::new(config, stats.clone()));
+        let handle = check.start();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        handle.shutdown().await;
+        let samples = stats.samples.load(Ordering::SeqCst);
+        assert!(samples >= 2, "only {} samples taken", samples);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(stats.samples.load(Ordering::SeqCst), samples);
+    }
+}
@@ -0,0 +1,221 @@
+//! Project quotas for AegisFS
+//!
+//! A project is a directory subtree whose inodes all carry the same non-zero
+//! project ID, visible to users as the `aegisfs.project_id` extended
+//! attribute. Every inode and data block in the subtree is charged to the
+//! project regardless of which user owns it, which suits multi-tenant
+//! storage where per-user quotas do not.
+//!
+//! Limits are stored in the superblock. Usage is not stored anywhere; it is
+//! rebuilt from the inode table when the filesystem is mounted.
+
+use futures::TryStreamExt;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+
+use crate::error::{Error, Result};
+use crate::format::ProjectLimit;
+use crate::layout::{DiskFs, FsError};
+
+/// Extended attribute holding a directory's project ID
+pub const PROJECT_ID_XATTR: &str = "aegisfs.project_id";
+
+/// Limits and usage of one project
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaEntry {
+    /// Data blocks the project may use; 0 for no limit
+    pub block_limit: u64,
+    /// Inodes the project may use; 0 for no limit
+    pub inode_limit: u64,
+    /// Data blocks charged to the project
+    pub blocks_used: u64,
+    /// Inodes charged to the project
+    pub inodes_used: u64,
+}
+
+impl QuotaEntry {
+    /// Whether `blocks` more data blocks would go over the block limit
+    pub fn blocks_exceeded(&self, blocks: u64) -> bool {
+        self.block_limit != 0 && self.blocks_used + blocks > self.block_limit
+    }
+
+    /// Whether `inodes` more inodes would go over the inode limit
+    pub fn inodes_exceeded(&self, inodes: u64) -> bool {
+        self.inode_limit != 0 && self.inodes_used + inodes > self.inode_limit
+    }
+}
+
+/// Per-project accounting of blocks and inodes.
+///
+/// Only projects with an entry are accounted; charges against any other
+/// project ID, including 0, are ignored.
+#[derive(Debug, Default)]
+pub struct ProjectQuotaManager {
+    projects: RwLock<HashMap<u32, QuotaEntry>>,
+}
+
+impl ProjectQuotaManager {
+    /// Create a manager with no projects
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a manager for the given limits with no usage charged yet
+    pub fn with_limits(limits: &[ProjectLimit]) -> Self {
+        let projects = limits
+            .iter()
+            .map(|limit| {
+                let entry = QuotaEntry {
+                    block_limit: limit.block_limit,
+                    inode_limit: limit.inode_limit,
+                    ..QuotaEntry::default()
+                };
+                (limit.project_id, entry)
+            })
+            .collect();
+        Self {
+            projects: RwLock::new(projects),
+        }
+    }
+
+    /// Load the superblock's limits and charge every allocated inode to its
+    /// project.
+    ///
+    /// With `all_projects` set, projects without limits are accounted too,
+    /// which is what a usage report wants; otherwise the inode table is only
+    /// scanned when some project has limits.
+    pub async fn load(disk_fs: &DiskFs, all_projects: bool) -> std::result::Result<Self, FsError> {
+        let manager = Self::with_limits(&disk_fs.superblock().project_limits);
+        if !all_projects && manager.projects.read().is_empty() {
+            return Ok(manager);
+        }
+
+        let mut inodes = Box::pin(disk_fs.iter_inodes());
+        while let Some((_, inode)) = inodes.try_next().await? {
+            if inode.project_id == 0 || inode.mode == 0 {
+                continue;
+            }
+            let mut projects = manager.projects.write();
+            if !all_projects && !projects.contains_key(&inode.project_id) {
+                continue;
+            }
+            let entry = projects.entry(inode.project_id).or_default();
+            entry.inodes_used += 1;
+            entry.blocks_used += DiskFs::blocks_for_size(inode.size);
+        }
+        Ok(manager)
+    }
+
+    /// Limits and usage of a project
+    pub fn get(&self, project_id: u32) -> Option<QuotaEntry> {
+        self.projects.read().get(&project_id).copied()
+    }
+
+    /// Every accounted project, by project ID
+    pub fn report(&self) -> Vec<(u32, QuotaEntry)> {
+        let mut report: Vec<_> = self
+            .projects
+            .read()
+            .iter()
+            .map(|(&id, &entry)| (id, entry))
+            .collect();
+        report.sort_by_key(|&(id, _)| id);
+        report
+    }
+
+    /// Set a project's limits, keeping the usage already charged to it
+    pub fn set_limits(&self, project_id: u32, block_limit: u64, inode_limit: u64) -> Result<()> {
+        if project_id == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let mut projects = self.projects.write();
+        let entry = projects.entry(project_id).or_default();
+        entry.block_limit = block_limit;
+        entry.inode_limit = inode_limit;
+        Ok(())
+    }
+
+    /// The limits to store in the superblock, by project ID
+    pub fn limits(&self) -> Vec<ProjectLimit> {
+        self.report()
+            .into_iter()
+            .filter(|(_, entry)| entry.block_limit != 0 || entry.inode_limit != 0)
+            .map(|(project_id, entry)| ProjectLimit {
+                project_id,
+                block_limit: entry.block_limit,
+                inode_limit: entry.inode_limit,
+            })
+            .collect()
+    }
+
+    /// Charge a new inode to a project
+    pub fn charge_inode(&self, project_id: u32) -> Result<()> {
+        let mut projects = self.projects.write();
+        let Some(entry) = projects.get_mut(&project_id) else {
+            return Ok(());
+        };
+        if entry.inodes_exceeded(1) {
+            return Err(Error::QuotaExceeded(project_id));
+        }
+        entry.inodes_used += 1;
+        Ok(())
+    }
+
+    /// Charge data blocks to a project
+    pub fn charge_blocks(&self, project_id: u32, blocks: u64) -> Result<()> {
+        let mut projects = self.projects.write();
+        let Some(entry) = projects.get_mut(&project_id) else {
+            return Ok(());
+        };
+        if entry.blocks_exceeded(blocks) {
+            return Err(Error::QuotaExceeded(project_id));
+        }
+        entry.blocks_used += blocks;
+        Ok(())
+    }
+
+    /// Return inodes and blocks to a project, such as when a file is deleted
+    pub fn release(&self, project_id: u32, inodes: u64, blocks: u64) {
+        if let Some(entry) = self.projects.write().get_mut(&project_id) {
+            entry.inodes_used = entry.inodes_used.saturating_sub(inodes);
+            entry.blocks_used = entry.blocks_used.saturating_sub(blocks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_project_limits_are_enforced() {
+        let limit = ProjectLimit {
+            project_id: 7,
+            block_limit: 10,
+            inode_limit: 2,
+        };
+        let manager = ProjectQuotaManager::with_limits(&[limit]);
+
+        manager.charge_inode(7).unwrap();
+        manager.charge_inode(7).unwrap();
+        assert!(matches!(manager.charge_inode(7), Err(Error::QuotaExceeded(7))));
+        manager.charge_blocks(7, 10).unwrap();
+        assert!(matches!(manager.charge_blocks(7, 1), Err(Error::QuotaExceeded(7))));
+
+        // Projects without an entry are not accounted
+        manager.charge_inode(8).unwrap();
+        manager.charge_blocks(0, 1000).unwrap();
+        assert!(manager.get(8).is_none());
+
+        manager.release(7, 1, 4);
+        let entry = manager.get(7).unwrap();
+        assert_eq!((entry.inodes_used, entry.blocks_used), (1, 6));
+        manager.charge_inode(7).unwrap();
+
+        // New limits keep the usage already charged
+        manager.set_limits(7, 0, 5).unwrap();
+        assert_eq!(manager.get(7).unwrap().blocks_used, 6);
+        assert_eq!(manager.limits(), vec![ProjectLimit { project_id: 7, block_limit: 0, inode_limit: 5 }]);
+        assert!(manager.set_limits(0, 1, 1).is_err());
+    }
+}
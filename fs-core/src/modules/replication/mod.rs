@@ -0,0 +1,693 @@
+//! Synchronous block replication for AegisFS
+//!
+//! `ReplicationManager` wraps the primary block device and mirrors every
+//! block write to a remote `ReplicationReceiver` over TCP. A write only
+//! returns once the remote side has written the block and acknowledged it,
+//! so an acknowledged write exists on both sites.
+//!
+//! With [`ReplicationManager::with_bitmap_deltas`] writes to the block
+//! bitmap are held back and replicated when the device is synced, as a
+//! delta against the bitmap the replica last acknowledged. Bitmap writeback
+//! rewrites whole bitmap blocks to change a few bits, so this sends far
+//! less; in exchange the replica's bitmap may trail by one writeback, which
+//! fsck repairs after a failover.
+
+use async_trait::async_trait;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use crate::block_bitmap::{BlockBitmap, BlockBitmapError};
+use crate::blockdev::{BlockDevice, BlockDeviceError, BLOCK_SIZE};
+use crate::format::Superblock;
+use crate::layout::Layout;
+
+/// Magic number at the start of every replication frame
+const FRAME_MAGIC: &[u8; 4] = b"AGRF";
+/// Magic number at the start of every bitmap delta frame
+const DELTA_MAGIC: &[u8; 4] = b"AGBD";
+
+/// Acknowledgement status: block written on the remote device
+const ACK_OK: u8 = 0;
+/// Acknowledgement status: frame rejected or remote write failed
+const ACK_FAILED: u8 = 1;
+
+/// Replication error types
+#[derive(Error, Debug)]
+pub enum ReplicationError {
+    /// The connection to the other side failed
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// The other side did not answer in time
+    #[error("Timed out waiting for {0}")]
+    Timeout(&'static str),
+    /// A frame could not be decoded
+    #[error("Invalid replication frame: {0}")]
+    InvalidFrame(String),
+    /// The receiver could not write a block it was sent
+    #[error("Remote failed to apply block {0}")]
+    RemoteFailed(u64),
+    /// The receiver acknowledged a different block than was sent
+    #[error("Remote acknowledged block {got}, expected {expected}")]
+    UnexpectedAck {
+        /// Block that was sent
+        expected: u64,
+        /// Block that was acknowledged
+        got: u64,
+    },
+    /// A bitmap delta could not be applied
+    #[error("Block bitmap error: {0}")]
+    Bitmap(#[from] BlockBitmapError),
+}
+
+impl From<ReplicationError> for BlockDeviceError {
+    fn from(err: ReplicationError) -> Self {
+        match err {
+            ReplicationError::Io(e) => BlockDeviceError::Io(e),
+            ReplicationError::Timeout(_) => {
+                BlockDeviceError::Io(io::Error::new(io::ErrorKind::TimedOut, err))
+            }
+            other => BlockDeviceError::Io(io::Error::other(other)),
+        }
+    }
+}
+
+/// Result type for replication operations
+pub type Result<T> = std::result::Result<T, ReplicationError>;
+
+/// Replication configuration
+#[derive(Debug, Clone)]
+pub struct ReplicationConfig {
+    /// How long to wait for a connection to the remote site
+    pub connect_timeout: Duration,
+    /// How long to wait for the remote to acknowledge a block
+    pub ack_timeout: Duration,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            ack_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A single replicated block write
+#[derive(Clone)]
+pub struct ReplicationFrame {
+    /// Block number on the device
+    pub block_num: u64,
+    /// Block contents
+    pub data: [u8; BLOCK_SIZE],
+}
+
+impl ReplicationFrame {
+    /// Size of a serialized frame: magic, block number, checksum and data
+    pub const SIZE: usize = 4 + 8 + 4 + BLOCK_SIZE;
+
+    /// Create a frame from a block-sized buffer
+    pub fn new(block_num: u64, data: &[u8]) -> Result<Self> {
+        let data = data
+            .try_into()
+            .map_err(|_| ReplicationError::InvalidFrame(format!("block of {} bytes", data.len())))?;
+        Ok(Self { block_num, data })
+    }
+
+    /// Serialize the frame
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.extend_from_slice(FRAME_MAGIC);
+        buf.extend_from_slice(&self.block_num.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&self.data).to_le_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    /// Parse a frame written by `to_bytes`, verifying its checksum
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() != Self::SIZE {
+            return Err(ReplicationError::InvalidFrame(format!("{} bytes", buf.len())));
+        }
+        if &buf[..4] != FRAME_MAGIC {
+            return Err(ReplicationError::InvalidFrame("bad magic".to_string()));
+        }
+        let block_num = u64::from_le_bytes(buf[4..12].try_into().unwrap());
+        let checksum = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+        let frame = Self::new(block_num, &buf[16..])?;
+        if crc32fast::hash(&frame.data) != checksum {
+            return Err(ReplicationError::InvalidFrame(format!(
+                "checksum mismatch for block {}",
+                block_num
+            )));
+        }
+        Ok(frame)
+    }
+}
+
+/// Changes to the block bitmap since the replica's last acknowledged copy
+pub struct BitmapDeltaFrame {
+    /// First block of the bitmap on the device, checked by the replica and
+    /// echoed in its acknowledgement
+    pub bitmap_start: u64,
+    /// Delta from [`BlockBitmap::serialize_delta`]
+    pub delta: Vec<u8>,
+}
+
+impl BitmapDeltaFrame {
+    /// Size of the header: magic, bitmap start, delta length and checksum
+    const HEADER_SIZE: usize = 4 + 8 + 4 + 4;
+
+    /// Serialize the frame
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::HEADER_SIZE + self.delta.len());
+        buf.extend_from_slice(DELTA_MAGIC);
+        buf.extend_from_slice(&self.bitmap_start.to_le_bytes());
+        buf.extend_from_slice(&(self.delta.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&self.delta).to_le_bytes());
+        buf.extend_from_slice(&self.delta);
+        buf
+    }
+
+    /// Read the rest of a frame whose magic has already been read
+    async fn read_body<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let mut header = [0u8; Self::HEADER_SIZE - 4];
+        reader.read_exact(&mut header).await?;
+        let bitmap_start = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[12..].try_into().unwrap());
+        let mut delta = vec![0u8; len];
+        reader.read_exact(&mut delta).await?;
+        if crc32fast::hash(&delta) != checksum {
+            return Err(ReplicationError::InvalidFrame("checksum mismatch for bitmap delta".to_string()));
+        }
+        Ok(Self { bitmap_start, delta })
+    }
+}
+
+/// Frame read by a receiver
+enum Incoming {
+    Block(Box<ReplicationFrame>),
+    BitmapDelta(BitmapDeltaFrame),
+}
+
+impl Incoming {
+    /// Read one frame of either kind from a stream
+    async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).await?;
+        if &magic == DELTA_MAGIC {
+            return Ok(Incoming::BitmapDelta(BitmapDeltaFrame::read_body(reader).await?));
+        }
+        let mut buf = vec![0u8; ReplicationFrame::SIZE];
+        buf[..4].copy_from_slice(&magic);
+        reader.read_exact(&mut buf[4..]).await?;
+        Ok(Incoming::Block(Box::new(ReplicationFrame::from_bytes(&buf)?)))
+    }
+}
+
+/// Write an acknowledgement for `block_num`
+async fn write_ack<W: AsyncWrite + Unpin>(writer: &mut W, block_num: u64, status: u8) -> Result<()> {
+    let mut ack = [0u8; 9];
+    ack[..8].copy_from_slice(&block_num.to_le_bytes());
+    ack[8] = status;
+    writer.write_all(&ack).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read an acknowledgement and check it matches `block_num`
+async fn read_ack<R: AsyncRead + Unpin>(reader: &mut R, block_num: u64) -> Result<()> {
+    let mut ack = [0u8; 9];
+    reader.read_exact(&mut ack).await?;
+    let got = u64::from_le_bytes(ack[..8].try_into().unwrap());
+    if got != block_num {
+        return Err(ReplicationError::UnexpectedAck {
+            expected: block_num,
+            got,
+        });
+    }
+    match ack[8] {
+        ACK_OK => Ok(()),
+        _ => Err(ReplicationError::RemoteFailed(block_num)),
+    }
+}
+
+/// Block device that synchronously mirrors writes to a remote site
+///
+/// Reads are served by the primary. A write goes to the primary first and
+/// then to the remote; it fails if the remote can't be reached or doesn't
+/// acknowledge in time, and the next write reconnects.
+pub struct ReplicationManager {
+    /// Local device holding the primary copy
+    primary: Arc<dyn BlockDevice>,
+    /// Address of the `ReplicationReceiver`
+    remote_addr: SocketAddr,
+    /// Round trip of the last acknowledged write, in microseconds
+    replication_lag: AtomicU64,
+    /// Replication settings
+    config: ReplicationConfig,
+    /// Connection to the remote, opened on first use
+    connection: Mutex<Option<TcpStream>>,
+    /// Bitmap writes held back to be sent as deltas, if enabled
+    bitmap_deltas: Option<BitmapDeltas>,
+}
+
+/// State for replicating the block bitmap as deltas
+struct BitmapDeltas {
+    layout: Layout,
+    /// The bitmap as the replica last acknowledged it
+    baseline: Mutex<BlockBitmap>,
+    /// A bitmap block was written since the last delta
+    dirty: AtomicBool,
+}
+
+impl ReplicationManager {
+    /// Create a replication manager mirroring `primary` to `remote_addr`
+    pub fn new(
+        primary: Arc<dyn BlockDevice>,
+        remote_addr: SocketAddr,
+        config: ReplicationConfig,
+    ) -> Self {
+        Self {
+            primary,
+            remote_addr,
+            replication_lag: AtomicU64::new(0),
+            config,
+            connection: Mutex::new(None),
+            bitmap_deltas: None,
+        }
+    }
+
+    /// Send block bitmap changes as deltas when the device is synced
+    /// instead of mirroring each bitmap block write.
+    ///
+    /// The replica must hold the same bitmap as the primary does now, as it
+    /// does after every earlier write was replicated.
+    pub async fn with_bitmap_deltas(mut self, layout: Layout) -> Result<Self> {
+        let baseline = BlockBitmap::load_from_disk(self.primary.clone(), &layout).await?;
+        self.bitmap_deltas = Some(BitmapDeltas {
+            layout,
+            baseline: Mutex::new(baseline),
+            dirty: AtomicBool::new(false),
+        });
+        Ok(self)
+    }
+
+    /// Whether a write to `block_num` is held back for the next delta
+    fn defers_block(&self, block_num: u64) -> bool {
+        self.bitmap_deltas.as_ref().is_some_and(|deltas| {
+            let start = deltas.layout.block_bitmap;
+            (start..start + deltas.layout.block_bitmap_blocks).contains(&block_num)
+        })
+    }
+
+    /// Send the primary's block bitmap to the replica as a delta against
+    /// the copy it last acknowledged. Returns the size of the delta, 0 when
+    /// nothing changed or deltas aren't enabled.
+    pub async fn replicate_bitmap(&self) -> Result<usize> {
+        let Some(deltas) = &self.bitmap_deltas else {
+            return Ok(0);
+        };
+        let mut baseline = deltas.baseline.lock().await;
+        let current = BlockBitmap::load_from_disk(self.primary.clone(), &deltas.layout).await?;
+        if current.bitmap_data() == baseline.bitmap_data() {
+            return Ok(0);
+        }
+
+        let frame = BitmapDeltaFrame {
+            bitmap_start: deltas.layout.block_bitmap,
+            delta: current.serialize_delta(&baseline),
+        };
+        self.send(&frame.to_bytes(), frame.bitmap_start).await?;
+        log::debug!(
+            "REPLICATION: Sent a {} byte bitmap delta for {} bitmap bytes",
+            frame.delta.len(),
+            current.bitmap_data().len()
+        );
+        *baseline = current;
+        Ok(frame.delta.len())
+    }
+
+    /// Address of the remote site
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Round trip of the last write acknowledged by the remote
+    pub fn replication_lag(&self) -> Duration {
+        Duration::from_micros(self.replication_lag.load(Ordering::Relaxed))
+    }
+
+    /// Send one serialized frame to the remote and wait for it to
+    /// acknowledge `ack_id`
+    async fn send(&self, frame: &[u8], ack_id: u64) -> Result<()> {
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            let stream = timeout(self.config.connect_timeout, TcpStream::connect(self.remote_addr))
+                .await
+                .map_err(|_| ReplicationError::Timeout("connection"))??;
+            stream.set_nodelay(true)?;
+            log::info!("REPLICATION: Connected to {}", self.remote_addr);
+            *connection = Some(stream);
+        }
+        let stream = connection.as_mut().unwrap();
+
+        let started = Instant::now();
+        let result = async {
+            stream.write_all(frame).await?;
+            timeout(self.config.ack_timeout, read_ack(stream, ack_id))
+                .await
+                .map_err(|_| ReplicationError::Timeout("acknowledgement"))?
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                self.replication_lag
+                    .store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(e) => {
+                // The stream may hold a half-sent frame or a late ack; start over
+                log::error!(
+                    "REPLICATION: Block {} not acknowledged by {}: {}",
+                    ack_id,
+                    self.remote_addr,
+                    e
+                );
+                *connection = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BlockDevice for ReplicationManager {
+    async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> crate::blockdev::Result<()> {
+        self.primary.read_block(block_num, buf).await
+    }
+
+    async fn write_block(&self, block_num: u64, data: &[u8]) -> crate::blockdev::Result<()> {
+        let frame = ReplicationFrame::new(block_num, data)
+            .map_err(|_| BlockDeviceError::InvalidBlockSize(data.len()))?;
+        self.primary.write_block(block_num, data).await?;
+        if self.defers_block(block_num) {
+            if let Some(deltas) = &self.bitmap_deltas {
+                deltas.dirty.store(true, Ordering::Release);
+            }
+            return Ok(());
+        }
+        self.send(&frame.to_bytes(), frame.block_num).await?;
+        Ok(())
+    }
+
+    fn block_count(&self) -> u64 {
+        self.primary.block_count()
+    }
+
+    async fn sync(&self) -> crate::blockdev::Result<()> {
+        self.primary.sync().await?;
+        // Bitmap writeback ends with a sync, so this is where held back
+        // bitmap writes go out
+        if let Some(deltas) = &self.bitmap_deltas {
+            if deltas.dirty.swap(false, Ordering::AcqRel) {
+                if let Err(e) = self.replicate_bitmap().await {
+                    deltas.dirty.store(true, Ordering::Release);
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> crate::blockdev::Result<()> {
+        if let Some(mut stream) = self.connection.get_mut().take() {
+            stream.shutdown().await.ok();
+        }
+        self.primary.sync().await
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.primary.is_read_only()
+    }
+}
+
+/// Remote end of a replication link
+///
+/// Applies every frame it receives to its local device before
+/// acknowledging it.
+pub struct ReplicationReceiver {
+    listener: TcpListener,
+    device: Arc<dyn BlockDevice>,
+}
+
+impl ReplicationReceiver {
+    /// Listen for replication connections on `addr`
+    pub async fn bind(addr: SocketAddr, device: Arc<dyn BlockDevice>) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { listener, device })
+    }
+
+    /// Address the receiver is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept connections and apply their frames until the task is dropped
+    pub async fn run(self) -> Result<()> {
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            log::info!("REPLICATION: Accepted connection from {}", peer);
+            let device = self.device.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::serve(stream, device).await {
+                    log::warn!("REPLICATION: Connection from {} ended: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Apply frames from one primary until it disconnects
+    async fn serve(mut stream: TcpStream, device: Arc<dyn BlockDevice>) -> Result<()> {
+        stream.set_nodelay(true)?;
+        loop {
+            let frame = match Incoming::read_from(&mut stream).await {
+                Ok(Incoming::Block(frame)) => frame,
+                Ok(Incoming::BitmapDelta(frame)) => {
+                    let status = match Self::apply_bitmap_delta(&device, &frame).await {
+                        Ok(()) => ACK_OK,
+                        Err(e) => {
+                            log::error!("REPLICATION: Failed to apply bitmap delta: {}", e);
+                            ACK_FAILED
+                        }
+                    };
+                    write_ack(&mut stream, frame.bitmap_start, status).await?;
+                    continue;
+                }
+                Err(ReplicationError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(());
+                }
+                Err(ReplicationError::InvalidFrame(msg)) => {
+                    // The stream is out of step; refuse it so the primary reconnects
+                    write_ack(&mut stream, u64::MAX, ACK_FAILED).await.ok();
+                    return Err(ReplicationError::InvalidFrame(msg));
+                }
+                Err(e) => return Err(e),
+            };
+
+            let status = match device.write_block(frame.block_num, &frame.data).await {
+                Ok(()) => ACK_OK,
+                Err(e) => {
+                    log::error!("REPLICATION: Failed to apply block {}: {}", frame.block_num, e);
+                    ACK_FAILED
+                }
+            };
+            write_ack(&mut stream, frame.block_num, status).await?;
+        }
+    }
+
+    /// Patch the replica's block bitmap with a delta from the primary
+    async fn apply_bitmap_delta(device: &Arc<dyn BlockDevice>, frame: &BitmapDeltaFrame) -> Result<()> {
+        let superblock = Superblock::read_from_disk(&**device)
+            .await
+            .map_err(|e| ReplicationError::InvalidFrame(format!("replica has no filesystem: {}", e)))?;
+        let layout = Layout::for_superblock(&superblock);
+        if layout.block_bitmap != frame.bitmap_start {
+            return Err(ReplicationError::InvalidFrame(format!(
+                "bitmap delta for block {}, the replica's bitmap starts at {}",
+                frame.bitmap_start, layout.block_bitmap
+            )));
+        }
+
+        let mut bitmap = BlockBitmap::load_from_disk(device.clone(), &layout).await?;
+        bitmap.apply_delta(&frame.delta)?;
+        bitmap.save_to_disk(device.clone(), &layout).await?;
+        device.sync().await.map_err(|e| ReplicationError::Bitmap(e.into()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::FileBackedBlockDevice;
+    use std::sync::atomic::AtomicBool;
+    use tempfile::NamedTempFile;
+
+    async fn device(file: &NamedTempFile) -> Arc<dyn BlockDevice> {
+        Arc::new(
+            FileBackedBlockDevice::create(file.path(), 1024 * 1024)
+                .await
+                .unwrap(),
+        )
+    }
+
+    /// Forward traffic to `target` unless `partitioned` is set, in which
+    /// case everything sent is silently dropped
+    async fn start_proxy(target: SocketAddr, partitioned: Arc<AtomicBool>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let partitioned = partitioned.clone();
+                tokio::spawn(async move {
+                    let server = TcpStream::connect(target).await.unwrap();
+                    let (mut client_read, mut client_write) = client.into_split();
+                    let (mut server_read, mut server_write) = server.into_split();
+                    tokio::spawn(async move {
+                        tokio::io::copy(&mut server_read, &mut client_write).await.ok();
+                    });
+
+                    let mut buf = vec![0u8; 8192];
+                    loop {
+                        let n = match client_read.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => n,
+                        };
+                        if !partitioned.load(Ordering::SeqCst)
+                            && server_write.write_all(&buf[..n]).await.is_err()
+                        {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frame = ReplicationFrame::new(42, &[7u8; BLOCK_SIZE]).unwrap();
+        let bytes = frame.to_bytes();
+        let parsed = ReplicationFrame::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.block_num, 42);
+        assert_eq!(parsed.data, [7u8; BLOCK_SIZE]);
+
+        let mut corrupt = bytes.clone();
+        corrupt[100] ^= 0xFF;
+        assert!(ReplicationFrame::from_bytes(&corrupt).is_err());
+        assert!(ReplicationFrame::new(1, &[0u8; 100]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bitmap_changes_replicate_as_deltas_on_sync() {
+        use crate::{DiskFs, DiskFsTrait};
+
+        let size = 4 * 1024 * 1024;
+        let primary_file = NamedTempFile::new().unwrap();
+        let replica_file = NamedTempFile::new().unwrap();
+        let primary: Arc<dyn BlockDevice> =
+            Arc::new(FileBackedBlockDevice::create(primary_file.path(), size).await.unwrap());
+        let replica: Arc<dyn BlockDevice> =
+            Arc::new(FileBackedBlockDevice::create(replica_file.path(), size).await.unwrap());
+        DiskFs::format(primary.clone(), size, None).await.unwrap();
+        DiskFs::format(replica.clone(), size, None).await.unwrap();
+        let layout = Layout::for_superblock(&Superblock::read_from_disk(&*primary).await.unwrap());
+
+        let receiver = ReplicationReceiver::bind("127.0.0.1:0".parse().unwrap(), replica.clone())
+            .await
+            .unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        tokio::spawn(receiver.run());
+        let manager: Arc<dyn BlockDevice> = Arc::new(
+            ReplicationManager::new(primary.clone(), receiver_addr, ReplicationConfig::default())
+                .with_bitmap_deltas(layout)
+                .await
+                .unwrap(),
+        );
+
+        let mut bitmap = BlockBitmap::load_from_disk(primary.clone(), &layout).await.unwrap();
+        for block in [3, 4, 100] {
+            bitmap.set_allocated(block).unwrap();
+        }
+        bitmap.save_to_disk(manager.clone(), &layout).await.unwrap();
+
+        // Held back until the sync that ends a writeback
+        let replica_bitmap = BlockBitmap::load_from_disk(replica.clone(), &layout).await.unwrap();
+        assert!(!replica_bitmap.is_allocated(3));
+
+        manager.sync().await.unwrap();
+        let replica_bitmap = BlockBitmap::load_from_disk(replica.clone(), &layout).await.unwrap();
+        assert_eq!(replica_bitmap.bitmap_data(), bitmap.bitmap_data());
+        assert_eq!(replica_bitmap.free_blocks(), bitmap.free_blocks());
+
+        // Other blocks are still mirrored as they are written
+        manager.write_block(layout.data_blocks, &[5u8; BLOCK_SIZE]).await.unwrap();
+        let mut block = vec![0u8; BLOCK_SIZE];
+        replica.read_block(layout.data_blocks, &mut block).await.unwrap();
+        assert_eq!(block, vec![5u8; BLOCK_SIZE]);
+    }
+
+    #[tokio::test]
+    async fn test_replication_survives_network_partition() {
+        let primary_file = NamedTempFile::new().unwrap();
+        let replica_file = NamedTempFile::new().unwrap();
+        let primary = device(&primary_file).await;
+        let replica = device(&replica_file).await;
+
+        let receiver = ReplicationReceiver::bind("127.0.0.1:0".parse().unwrap(), replica.clone())
+            .await
+            .unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        tokio::spawn(receiver.run());
+
+        let partitioned = Arc::new(AtomicBool::new(false));
+        let proxy_addr = start_proxy(receiver_addr, partitioned.clone()).await;
+        let config = ReplicationConfig {
+            ack_timeout: Duration::from_millis(200),
+            ..ReplicationConfig::default()
+        };
+        let manager = ReplicationManager::new(primary.clone(), proxy_addr, config);
+
+        let mut block = vec![0u8; BLOCK_SIZE];
+
+        // An acknowledged write is on both sites
+        manager.write_block(10, &[1u8; BLOCK_SIZE]).await.unwrap();
+        replica.read_block(10, &mut block).await.unwrap();
+        assert_eq!(block, vec![1u8; BLOCK_SIZE]);
+
+        // During a partition writes fail instead of hanging or silently diverging
+        partitioned.store(true, Ordering::SeqCst);
+        assert!(manager.write_block(11, &[2u8; BLOCK_SIZE]).await.is_err());
+        replica.read_block(11, &mut block).await.unwrap();
+        assert_eq!(block, vec![0u8; BLOCK_SIZE]);
+
+        // Once the network heals the manager reconnects and replicates again
+        partitioned.store(false, Ordering::SeqCst);
+        manager.write_block(11, &[3u8; BLOCK_SIZE]).await.unwrap();
+        replica.read_block(11, &mut block).await.unwrap();
+        assert_eq!(block, vec![3u8; BLOCK_SIZE]);
+        manager.read_block(11, &mut block).await.unwrap();
+        assert_eq!(block, vec![3u8; BLOCK_SIZE]);
+    }
+}
@@ -0,0 +1,232 @@
+//! Parent-child relationships between snapshots
+//!
+//! Every snapshot records the snapshot it was taken after in
+//! `SnapshotMetadata::parent_id`, with 0 for one taken with no parent.
+//! [`SnapshotChain`] turns those links into a tree so callers can ask which
+//! snapshots a deletion or an incremental send depends on.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use super::{SnapshotMetadata, SnapshotState};
+use crate::error::{Error, Result};
+
+/// Parent ID of a snapshot at the top of the tree
+pub const NO_PARENT: u64 = 0;
+
+/// Tree of snapshots built from their parent links
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotChain {
+    /// Parent of every snapshot in the tree, `NO_PARENT` for the roots
+    parents: HashMap<u64, u64>,
+    /// Children of every snapshot, oldest first
+    children: BTreeMap<u64, Vec<u64>>,
+}
+
+impl SnapshotChain {
+    /// Build the tree from snapshot metadata, leaving out deleted snapshots.
+    ///
+    /// A snapshot whose parent isn't among them becomes a root, so a chain
+    /// with a gap still covers every live snapshot.
+    pub fn from_snapshots<'a>(snapshots: impl IntoIterator<Item = &'a SnapshotMetadata>) -> Self {
+        let live: BTreeMap<u64, u64> = snapshots
+            .into_iter()
+            .filter(|s| s.state != SnapshotState::Deleted)
+            .map(|s| (s.id, s.parent_id))
+            .collect();
+
+        let mut chain = Self::default();
+        for (&id, &parent) in &live {
+            let parent = if live.contains_key(&parent) && parent != id {
+                parent
+            } else {
+                NO_PARENT
+            };
+            chain.parents.insert(id, parent);
+            chain.children.entry(id).or_default();
+            if parent != NO_PARENT {
+                chain.children.entry(parent).or_default().push(id);
+            }
+        }
+        chain
+    }
+
+    /// Whether the snapshot is part of the tree
+    pub fn contains(&self, id: u64) -> bool {
+        self.parents.contains_key(&id)
+    }
+
+    /// Parent of a snapshot, `None` for roots and unknown snapshots
+    pub fn parent(&self, id: u64) -> Option<u64> {
+        self.parents.get(&id).copied().filter(|&parent| parent != NO_PARENT)
+    }
+
+    /// Snapshots taken directly from this one, oldest first
+    pub fn children(&self, id: u64) -> &[u64] {
+        self.children.get(&id).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Every ancestor of a snapshot, from its parent up to its root
+    pub fn ancestors(&self, id: u64) -> Vec<u64> {
+        let mut ancestors = Vec::new();
+        let mut seen = HashSet::from([id]);
+        let mut current = id;
+        while let Some(parent) = self.parent(current) {
+            // Parent links come from disk, so don't trust them to be acyclic
+            if !seen.insert(parent) {
+                log::warn!("Snapshot {} is its own ancestor, stopping at {}", id, current);
+                break;
+            }
+            ancestors.push(parent);
+            current = parent;
+        }
+        ancestors
+    }
+
+    /// Every snapshot derived from this one, nearest first
+    pub fn descendants(&self, id: u64) -> Vec<u64> {
+        let mut descendants = Vec::new();
+        let mut seen = HashSet::from([id]);
+        let mut queue: VecDeque<u64> = self.children(id).iter().copied().collect();
+        while let Some(child) = queue.pop_front() {
+            if !seen.insert(child) {
+                continue;
+            }
+            descendants.push(child);
+            queue.extend(self.children(child).iter().copied());
+        }
+        descendants
+    }
+
+    /// Lowest common ancestor of two snapshots, the newest snapshot both
+    /// were derived from.
+    ///
+    /// A snapshot counts as its own ancestor, so when one is derived from
+    /// the other the older one is returned. `NO_PARENT` means they share
+    /// no history, and an incremental send between them isn't possible.
+    pub fn lca(&self, a: u64, b: u64) -> u64 {
+        if !self.contains(a) || !self.contains(b) {
+            return NO_PARENT;
+        }
+        let lineage_of_a: HashSet<u64> = std::iter::once(a).chain(self.ancestors(a)).collect();
+        std::iter::once(b)
+            .chain(self.ancestors(b))
+            .find(|id| lineage_of_a.contains(id))
+            .unwrap_or(NO_PARENT)
+    }
+
+    /// Remove a leaf snapshot from the tree once its blocks have been
+    /// folded into its parent. Returns the parent.
+    pub fn merge_into_parent(&mut self, id: u64) -> Result<u64> {
+        if !self.contains(id) {
+            return Err(Error::Other(format!("Snapshot {} not found", id)));
+        }
+        if !self.children(id).is_empty() {
+            return Err(Error::Other(format!(
+                "Snapshot {} has children and can't be merged into its parent",
+                id
+            )));
+        }
+        let parent = self
+            .parent(id)
+            .ok_or_else(|| Error::Other(format!("Snapshot {} has no parent to merge into", id)))?;
+
+        self.parents.remove(&id);
+        self.children.remove(&id);
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|&child| child != id);
+        }
+        Ok(parent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(id: u64, parent_id: u64) -> SnapshotMetadata {
+        SnapshotMetadata {
+            id,
+            name: format!("snap-{}", id),
+            parent_id,
+            created_at: id,
+            state: SnapshotState::Active,
+            root_inode: 1,
+            block_count: 0,
+            exclusive_space: 0,
+            tags: HashMap::new(),
+        }
+    }
+
+    //        1
+    //       / \
+    //      2   4
+    //     /     \
+    //    3       5
+    //             \
+    //              6
+    fn sample_chain() -> SnapshotChain {
+        let snapshots = [
+            snapshot(1, 0),
+            snapshot(2, 1),
+            snapshot(3, 2),
+            snapshot(4, 1),
+            snapshot(5, 4),
+            snapshot(6, 5),
+        ];
+        SnapshotChain::from_snapshots(&snapshots)
+    }
+
+    #[test]
+    fn test_ancestors_and_descendants() {
+        let chain = sample_chain();
+        assert_eq!(chain.ancestors(6), vec![5, 4, 1]);
+        assert_eq!(chain.ancestors(1), Vec::<u64>::new());
+        assert_eq!(chain.descendants(1), vec![2, 4, 3, 5, 6]);
+        assert_eq!(chain.descendants(4), vec![5, 6]);
+        assert!(chain.descendants(3).is_empty());
+        assert_eq!(chain.children(1), &[2, 4]);
+    }
+
+    #[test]
+    fn test_lca() {
+        let chain = sample_chain();
+        assert_eq!(chain.lca(3, 6), 1);
+        assert_eq!(chain.lca(5, 6), 5);
+        assert_eq!(chain.lca(6, 5), 5);
+        assert_eq!(chain.lca(3, 3), 3);
+        assert_eq!(chain.lca(3, 99), NO_PARENT);
+
+        let disjoint = SnapshotChain::from_snapshots(&[snapshot(1, 0), snapshot(2, 0)]);
+        assert_eq!(disjoint.lca(1, 2), NO_PARENT);
+    }
+
+    #[test]
+    fn test_deleted_and_missing_parents_become_roots() {
+        let mut deleted = snapshot(2, 1);
+        deleted.state = SnapshotState::Deleted;
+        let chain = SnapshotChain::from_snapshots(&[snapshot(1, 0), deleted, snapshot(3, 2), snapshot(4, 9)]);
+        assert!(!chain.contains(2));
+        assert_eq!(chain.parent(3), None);
+        assert_eq!(chain.parent(4), None);
+        assert!(chain.children(1).is_empty());
+    }
+
+    #[test]
+    fn test_cyclic_links_terminate() {
+        let chain = SnapshotChain::from_snapshots(&[snapshot(1, 2), snapshot(2, 1)]);
+        assert_eq!(chain.ancestors(1), vec![2]);
+        assert_eq!(chain.descendants(1), vec![2]);
+    }
+
+    #[test]
+    fn test_merge_into_parent_only_takes_leaves() {
+        let mut chain = sample_chain();
+        assert!(chain.merge_into_parent(5).is_err());
+        assert!(chain.merge_into_parent(1).is_err());
+        assert_eq!(chain.merge_into_parent(6).unwrap(), 5);
+        assert!(!chain.contains(6));
+        assert!(chain.children(5).is_empty());
+        assert_eq!(chain.merge_into_parent(5).unwrap(), 4);
+        assert_eq!(chain.descendants(1), vec![2, 4, 3]);
+    }
+}
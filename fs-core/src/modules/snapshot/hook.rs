@@ -0,0 +1,94 @@
+//! Hooks run around snapshot creation and deletion
+//!
+//! Applications that keep state in memory (databases, VM images) need to be
+//! quiesced before a snapshot is taken to get a consistent image. Hooks
+//! registered with `SnapshotManager::register_hook` are called in the order
+//! they were registered: `pre_snapshot` before a snapshot is taken,
+//! `post_snapshot` once it has been written, and `pre_delete` before a
+//! snapshot is removed.
+
+use async_trait::async_trait;
+use tokio::process::Command;
+
+use crate::error::{Error, Result};
+
+/// Callbacks around snapshot creation and deletion
+#[async_trait]
+pub trait SnapshotHook: Send + Sync {
+    /// Called before snapshot `name` is taken. An error aborts the snapshot.
+    async fn pre_snapshot(&self, name: &str) -> Result<()>;
+
+    /// Called once snapshot `name` has been created with ID `id`. Not called
+    /// if creating the snapshot failed.
+    async fn post_snapshot(&self, name: &str, id: u64) -> Result<()>;
+
+    /// Called before snapshot `id` is deleted. An error aborts the deletion.
+    async fn pre_delete(&self, id: u64) -> Result<()>;
+}
+
+/// Hook that runs shell commands with `sh -c`.
+///
+/// The snapshot name is passed in `AEGISFS_SNAPSHOT_NAME` and its ID, once
+/// known, in `AEGISFS_SNAPSHOT_ID`. A command that exits non-zero fails the
+/// hook. Unset commands do nothing.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptHook {
+    /// Command run before a snapshot is taken
+    pub pre_snapshot: Option<String>,
+    /// Command run after a snapshot has been created
+    pub post_snapshot: Option<String>,
+    /// Command run before a snapshot is deleted
+    pub pre_delete: Option<String>,
+}
+
+impl ScriptHook {
+    /// Run `command` with the given environment and check its exit status
+    async fn run(command: &str, env: &[(&str, String)]) -> Result<()> {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(env.iter().map(|(key, value)| (*key, value.as_str())))
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(Error::Other(format!(
+                "Snapshot hook `{}` failed ({})",
+                command, status
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SnapshotHook for ScriptHook {
+    async fn pre_snapshot(&self, name: &str) -> Result<()> {
+        match &self.pre_snapshot {
+            Some(command) => Self::run(command, &[("AEGISFS_SNAPSHOT_NAME", name.to_string())]).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn post_snapshot(&self, name: &str, id: u64) -> Result<()> {
+        match &self.post_snapshot {
+            Some(command) => {
+                Self::run(
+                    command,
+                    &[
+                        ("AEGISFS_SNAPSHOT_NAME", name.to_string()),
+                        ("AEGISFS_SNAPSHOT_ID", id.to_string()),
+                    ],
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn pre_delete(&self, id: u64) -> Result<()> {
+        match &self.pre_delete {
+            Some(command) => Self::run(command, &[("AEGISFS_SNAPSHOT_ID", id.to_string())]).await,
+            None => Ok(()),
+        }
+    }
+}
@@ -11,9 +11,16 @@ use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
-use crate::blockdev::{BlockDevice, BlockDeviceError};
+use crate::blockdev::{BlockDevice, BlockDeviceError, BLOCK_SIZE};
+use crate::cache::BlockCache;
 use crate::error::Result;
-use crate::format::Inode;
+use crate::format::{FreezeInfoBlock, Inode};
+
+mod chain;
+mod hook;
+
+pub use self::chain::{SnapshotChain, NO_PARENT};
+pub use self::hook::{ScriptHook, SnapshotHook};
 
 /// Maximum number of snapshots supported
 const MAX_SNAPSHOTS: usize = 256;
@@ -57,6 +64,15 @@ pub struct SnapshotMetadata {
     pub tags: HashMap<String, String>,
 }
 
+/// Whether a snapshot was taken while the filesystem was frozen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotIntegrity {
+    /// Taken between the recorded freeze and its unfreeze
+    Frozen,
+    /// Taken outside the recorded freeze, so writes may have been in flight
+    PossiblyInconsistent,
+}
+
 /// Block reference tracking for CoW
 #[derive(Debug, Clone)]
 pub struct BlockReference {
@@ -151,6 +167,11 @@ pub struct SnapshotManager {
     total_blocks: u64,
     /// Free blocks for allocation
     free_blocks: AtomicU64,
+    /// Hooks called around snapshot creation and deletion, in order
+    hooks: RwLock<Vec<Arc<dyn SnapshotHook>>>,
+    /// Filesystem cache in front of `device`, invalidated before blocks are
+    /// read or written around it
+    cache: Option<Arc<BlockCache>>,
 }
 
 impl SnapshotManager {
@@ -169,9 +190,24 @@ impl SnapshotManager {
             pending_cow: RwLock::new(Vec::new()),
             total_blocks,
             free_blocks: AtomicU64::new(total_blocks - reserved),
+            hooks: RwLock::new(Vec::new()),
+            cache: None,
         }
     }
 
+    /// Keep `cache`, which sits in front of the same device, coherent with
+    /// the blocks this manager copies
+    pub fn with_cache(mut self, cache: Arc<BlockCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Register a hook to be called around snapshot creation and deletion.
+    /// Hooks run in the order they were registered.
+    pub fn register_hook(&self, hook: Arc<dyn SnapshotHook>) {
+        self.hooks.write().push(hook);
+    }
+
     /// Initialize the snapshot manager
     pub async fn init(&mut self) -> Result<()> {
         // Load existing snapshots from disk
@@ -187,6 +223,9 @@ impl SnapshotManager {
 
     /// Create a new snapshot
     pub async fn create_snapshot(&self, name: &str, tags: HashMap<String, String>) -> Result<u64> {
+        #[cfg(feature = "telemetry")]
+        let _span = crate::modules::telemetry::OpSpan::new("snapshot_create");
+
         // Check if we've reached the maximum
         if self.snapshots.read().len() >= MAX_SNAPSHOTS {
             return Err(crate::error::Error::Other("Too many snapshots".to_string()));
@@ -200,6 +239,13 @@ impl SnapshotManager {
             )));
         }
 
+        // Let the hooks quiesce whatever they look after; any of them can
+        // veto the snapshot
+        let hooks = self.hooks.read().clone();
+        for hook in &hooks {
+            hook.pre_snapshot(name).await?;
+        }
+
         // Generate new snapshot ID
         let snapshot_id = self.next_snapshot_id.fetch_add(1, Ordering::SeqCst);
 
@@ -242,6 +288,23 @@ impl SnapshotManager {
         self.save_snapshot_metadata(&metadata).await?;
 
         log::info!("Created snapshot '{}' with ID {}", name, snapshot_id);
+
+        // The snapshot exists now, so every post hook runs even if an
+        // earlier one fails
+        let mut hook_error = None;
+        for hook in &hooks {
+            if let Err(e) = hook.post_snapshot(name, snapshot_id).await {
+                log::warn!("Post-snapshot hook for '{}' failed: {}", name, e);
+                hook_error.get_or_insert(e);
+            }
+        }
+        if let Some(e) = hook_error {
+            return Err(crate::error::Error::Other(format!(
+                "Snapshot '{}' (ID {}) was created but a post-snapshot hook failed: {}",
+                name, snapshot_id, e
+            )));
+        }
+
         Ok(snapshot_id)
     }
 
@@ -272,18 +335,17 @@ impl SnapshotManager {
             })?;
 
         // Check if snapshot has children
-        let has_children = self
-            .snapshots
-            .read()
-            .values()
-            .any(|s| s.parent_id == snapshot_id && s.state != SnapshotState::Deleted);
-
-        if has_children {
+        if !self.chain().children(snapshot_id).is_empty() {
             return Err(crate::error::Error::Other(
                 "Cannot delete snapshot with children".to_string(),
             ));
         }
 
+        let hooks = self.hooks.read().clone();
+        for hook in &hooks {
+            hook.pre_delete(snapshot_id).await?;
+        }
+
         // Mark snapshot for deletion
         {
             let mut snapshots = self.snapshots.write();
@@ -308,6 +370,59 @@ impl SnapshotManager {
         Ok(())
     }
 
+    /// Tree of the snapshots that haven't been deleted, built from their
+    /// parent links
+    pub fn chain(&self) -> SnapshotChain {
+        SnapshotChain::from_snapshots(self.snapshots.read().values())
+    }
+
+    /// Fold a leaf snapshot into its parent, shortening the chain by one.
+    ///
+    /// Blocks only the snapshot referenced become the parent's; blocks the
+    /// parent already references just lose one reference. The snapshot is
+    /// then removed. Returns the parent's ID.
+    pub async fn merge_into_parent(&self, snapshot_id: u64) -> Result<u64> {
+        let snapshot = self.get_snapshot(snapshot_id).ok_or_else(|| {
+            crate::error::Error::Other(format!("Snapshot {} not found", snapshot_id))
+        })?;
+        let parent_id = self.chain().merge_into_parent(snapshot_id)?;
+
+        let (block_count, exclusive_blocks) = {
+            let mut block_refs = self.block_refs.write();
+            for ref_info in block_refs.values_mut() {
+                if ref_info.snapshots.remove(&snapshot_id) && !ref_info.snapshots.insert(parent_id) {
+                    ref_info.ref_count -= 1;
+                }
+            }
+            let parent_refs = block_refs.values().filter(|r| r.snapshots.contains(&parent_id));
+            parent_refs.fold((0u64, 0u64), |(total, exclusive), r| {
+                (total + 1, exclusive + (r.ref_count == 1) as u64)
+            })
+        };
+
+        let parent = {
+            let mut snapshots = self.snapshots.write();
+            snapshots.remove(&snapshot_id);
+            let parent = snapshots.get_mut(&parent_id).ok_or_else(|| {
+                crate::error::Error::Other(format!("Snapshot {} not found", parent_id))
+            })?;
+            parent.block_count = block_count;
+            parent.exclusive_space = exclusive_blocks * BLOCK_SIZE as u64;
+            parent.clone()
+        };
+        self.name_to_id.write().remove(&snapshot.name);
+        self.save_snapshot_metadata(&parent).await?;
+
+        log::info!(
+            "Merged snapshot '{}' (ID: {}) into '{}' (ID: {})",
+            snapshot.name,
+            snapshot_id,
+            parent.name,
+            parent_id
+        );
+        Ok(parent_id)
+    }
+
     /// List all active snapshots
     pub fn list_snapshots(&self) -> Vec<SnapshotMetadata> {
         self.snapshots
@@ -332,6 +447,26 @@ impl SnapshotManager {
         }
     }
 
+    /// Check a snapshot against the filesystem's freeze record.
+    ///
+    /// Only the latest freeze and unfreeze are recorded, so a snapshot
+    /// taken before the latest freeze can't be matched to one and is
+    /// flagged as well.
+    pub fn verify_snapshot_integrity(
+        &self,
+        snapshot_id: u64,
+        freeze: &FreezeInfoBlock,
+    ) -> Result<SnapshotIntegrity> {
+        let snapshot = self.get_snapshot(snapshot_id).ok_or_else(|| {
+            crate::error::Error::Other(format!("Snapshot {} not found", snapshot_id))
+        })?;
+        if freeze.covers(snapshot.created_at) {
+            Ok(SnapshotIntegrity::Frozen)
+        } else {
+            Ok(SnapshotIntegrity::PossiblyInconsistent)
+        }
+    }
+
     /// Mark a block as referenced by a snapshot (for CoW)
     pub fn reference_block(&self, block_num: u64, snapshot_id: u64) -> Result<()> {
         let mut block_refs = self.block_refs.write();
@@ -373,6 +508,13 @@ impl SnapshotManager {
         // Allocate new block
         let new_block = self.allocate_block()?;
 
+        // Write back any cached copy of the original so the device has its
+        // latest contents, and drop the new block's stale one
+        if let Some(cache) = &self.cache {
+            cache.invalidate(block_num).await?;
+            cache.invalidate(new_block).await?;
+        }
+
         // Copy data from original to new block
         let mut buffer = vec![0u8; 4096]; // Assuming 4KB blocks
         self.device.read_block(block_num, &mut buffer).await?;
@@ -572,6 +714,29 @@ mod tests {
     use crate::blockdev::FileBackedBlockDevice;
     use tempfile::NamedTempFile;
 
+    #[tokio::test]
+    async fn test_verify_snapshot_integrity_against_freeze_record() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let device = Arc::new(FileBackedBlockDevice::create(temp_file.path(), 16 * 1024 * 1024).await.unwrap());
+        let manager = SnapshotManager::new(device, SnapshotConfig::default());
+        let id = manager.create_snapshot("frozen", HashMap::new()).await.unwrap();
+        let created = manager.get_snapshot(id).unwrap().created_at;
+
+        let record = |last_freeze_time, last_unfreeze_time| FreezeInfoBlock {
+            freeze_count: 1,
+            last_freeze_time,
+            last_unfreeze_time,
+        };
+        let verify = |freeze: FreezeInfoBlock| manager.verify_snapshot_integrity(id, &freeze).unwrap();
+        assert_eq!(verify(record(created - 5, created + 5)), SnapshotIntegrity::Frozen);
+        // Still frozen when the snapshot was checked
+        assert_eq!(verify(record(created - 5, 0)), SnapshotIntegrity::Frozen);
+        assert_eq!(verify(record(created - 10, created - 5)), SnapshotIntegrity::PossiblyInconsistent);
+        assert_eq!(verify(record(created + 5, created + 10)), SnapshotIntegrity::PossiblyInconsistent);
+        assert_eq!(verify(FreezeInfoBlock::default()), SnapshotIntegrity::PossiblyInconsistent);
+        assert!(manager.verify_snapshot_integrity(id + 1, &FreezeInfoBlock::default()).is_err());
+    }
+
     #[tokio::test]
     async fn test_create_snapshot() {
         let temp_file = NamedTempFile::new().unwrap();
@@ -625,6 +790,63 @@ mod tests {
         assert_eq!(snapshots.len(), 3);
     }
 
+    /// Hook that records the calls it gets
+    #[derive(Default)]
+    struct RecordingHook {
+        calls: parking_lot::Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl SnapshotHook for RecordingHook {
+        async fn pre_snapshot(&self, name: &str) -> Result<()> {
+            self.calls.lock().push(format!("pre {}", name));
+            Ok(())
+        }
+
+        async fn post_snapshot(&self, name: &str, id: u64) -> Result<()> {
+            self.calls.lock().push(format!("post {} {}", name, id));
+            Ok(())
+        }
+
+        async fn pre_delete(&self, id: u64) -> Result<()> {
+            self.calls.lock().push(format!("delete {}", id));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_run_around_create_and_delete() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let device = Arc::new(
+            FileBackedBlockDevice::create(temp_file.path(), 100 * 1024 * 1024)
+                .await
+                .unwrap(),
+        );
+
+        let manager = SnapshotManager::new(device, SnapshotConfig::default());
+        let hook = Arc::new(RecordingHook::default());
+        manager.register_hook(hook.clone());
+
+        let id = manager.create_snapshot("hooked", HashMap::new()).await.unwrap();
+        manager.delete_snapshot(id).await.unwrap();
+        assert_eq!(
+            *hook.calls.lock(),
+            vec![
+                "pre hooked".to_string(),
+                format!("post hooked {}", id),
+                format!("delete {}", id),
+            ]
+        );
+
+        // A failing pre-snapshot command vetoes the snapshot
+        manager.register_hook(Arc::new(ScriptHook {
+            pre_snapshot: Some("exit 3".to_string()),
+            ..Default::default()
+        }));
+        assert!(manager.create_snapshot("vetoed", HashMap::new()).await.is_err());
+        assert!(manager.list_snapshots().is_empty());
+    }
+
     #[tokio::test]
     async fn test_copy_on_write() {
         let temp_file = NamedTempFile::new().unwrap();
@@ -648,6 +870,40 @@ mod tests {
         assert_ne!(new_block, 10);
     }
 
+    #[tokio::test]
+    async fn test_merge_into_parent_moves_blocks() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let device = Arc::new(
+            FileBackedBlockDevice::create(temp_file.path(), 100 * 1024 * 1024)
+                .await
+                .unwrap(),
+        );
+
+        let manager = SnapshotManager::new(device, SnapshotConfig::default());
+        let parent = manager.create_snapshot("merge-parent", HashMap::new()).await.unwrap();
+        let child = manager.create_snapshot("merge-child", HashMap::new()).await.unwrap();
+        assert_eq!(manager.chain().parent(child), Some(parent));
+
+        // Block 10 is shared, block 11 only belongs to the child
+        manager.reference_block(10, parent).unwrap();
+        manager.reference_block(10, child).unwrap();
+        manager.reference_block(11, child).unwrap();
+
+        // A snapshot with children can be neither merged nor deleted
+        assert!(manager.merge_into_parent(parent).await.is_err());
+        assert!(manager.delete_snapshot(parent).await.is_err());
+
+        assert_eq!(manager.merge_into_parent(child).await.unwrap(), parent);
+        assert!(manager.get_snapshot(child).is_none());
+        assert!(manager.get_snapshot_by_name("merge-child").is_none());
+        assert!(!manager.needs_cow(10));
+
+        let merged = manager.get_snapshot(parent).unwrap();
+        assert_eq!(merged.block_count, 2);
+        assert_eq!(merged.exclusive_space, 2 * BLOCK_SIZE as u64);
+        manager.delete_snapshot(parent).await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_delete_snapshot() {
         let temp_file = NamedTempFile::new().unwrap();
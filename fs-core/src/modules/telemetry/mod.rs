@@ -0,0 +1,382 @@
+//! OpenTelemetry traces of filesystem operations
+//!
+//! Built with the `telemetry` feature. [`init`] installs a `tracing`
+//! subscriber that exports spans over OTLP, and every FUSE operation, block
+//! device access, journal commit and snapshot creation opens an [`OpSpan`].
+//! Spans carry `fs.operation`, `fs.inode` (or `fs.block` for device I/O),
+//! `fs.bytes` and `fs.duration_ms`, so an exported or replicated deployment
+//! can follow one request across machines.
+//!
+//! Without [`init`] there is no subscriber and opening a span costs little
+//! more than reading the clock.
+
+use async_trait::async_trait;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace as sdktrace, Resource};
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use tracing::field::Empty;
+use tracing::span::EnteredSpan;
+use tracing::{Instrument, Span};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::blockdev::{self, BlockDevice};
+
+/// Environment variable naming the OTLP collector, as read by other
+/// OpenTelemetry SDKs
+pub const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// Collector used when neither the configuration nor the environment names
+/// one: the OTLP gRPC port on this machine
+pub const DEFAULT_ENDPOINT: &str = "http://localhost:4317";
+
+/// Service name spans are exported under
+pub const DEFAULT_SERVICE_NAME: &str = "aegisfs";
+
+/// Errors setting up trace export
+#[derive(Error, Debug)]
+pub enum TelemetryError {
+    #[error("Failed to start OTLP exporter: {0}")]
+    Exporter(#[from] opentelemetry::trace::TraceError),
+    #[error("A tracing subscriber is already installed: {0}")]
+    Subscriber(#[from] tracing_subscriber::util::TryInitError),
+}
+
+/// Where and as what spans are exported
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    /// OTLP collector; `None` falls back to [`ENDPOINT_ENV`], then
+    /// [`DEFAULT_ENDPOINT`]
+    pub endpoint: Option<String>,
+    /// `service.name` resource attribute of every span
+    pub service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: DEFAULT_SERVICE_NAME.to_string(),
+        }
+    }
+}
+
+impl TelemetryConfig {
+    /// Export to the given collector
+    pub fn with_endpoint(endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: Some(endpoint.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Collector spans will be sent to
+    pub fn resolved_endpoint(&self) -> String {
+        resolve_endpoint(self.endpoint.as_deref(), std::env::var(ENDPOINT_ENV).ok())
+    }
+}
+
+/// An explicit endpoint wins over the environment; empty values count as
+/// unset
+fn resolve_endpoint(configured: Option<&str>, env: Option<String>) -> String {
+    configured
+        .map(str::to_string)
+        .or(env)
+        .filter(|endpoint| !endpoint.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string())
+}
+
+/// Keeps trace export running; dropping it flushes the spans still queued
+#[must_use = "spans stop being exported when the guard is dropped"]
+pub struct TelemetryGuard {
+    endpoint: String,
+}
+
+impl TelemetryGuard {
+    /// Collector spans are sent to
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        shutdown();
+    }
+}
+
+/// Export the spans still queued and stop exporting, for processes that
+/// exit without dropping their [`TelemetryGuard`]
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Export spans to an OTLP collector over gRPC.
+///
+/// Must be called from within a Tokio runtime, which runs the batch
+/// exporter, and only once per process.
+pub fn init(config: &TelemetryConfig) -> Result<TelemetryGuard, TelemetryError> {
+    let endpoint = config.resolved_endpoint();
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint.clone()))
+        .with_trace_config(sdktrace::config().with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )])))
+        .install_batch(runtime::Tokio)?;
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+    log::info!("TELEMETRY: Exporting traces to {}", endpoint);
+    Ok(TelemetryGuard { endpoint })
+}
+
+/// Span of one filesystem operation; `fs.duration_ms` is recorded when it
+/// is dropped, so early returns and errors are timed too
+#[must_use = "the operation is traced until the span is dropped"]
+pub struct OpSpan {
+    span: Span,
+    started: Instant,
+}
+
+impl OpSpan {
+    /// Open a span for an operation, named after it in the exported trace
+    pub fn new(operation: &'static str) -> Self {
+        let span = tracing::info_span!(
+            target: "aegisfs",
+            "fs_op",
+            otel.name = operation,
+            fs.operation = operation,
+            fs.inode = Empty,
+            fs.block = Empty,
+            fs.bytes = Empty,
+            fs.duration_ms = Empty,
+        );
+        Self {
+            span,
+            started: Instant::now(),
+        }
+    }
+
+    /// Record the inode operated on
+    pub fn inode(self, ino: u64) -> Self {
+        self.span.record("fs.inode", ino);
+        self
+    }
+
+    /// Record the device block accessed
+    pub fn block(self, block_num: u64) -> Self {
+        self.span.record("fs.block", block_num);
+        self
+    }
+
+    /// Record the bytes the operation moves
+    pub fn bytes(self, bytes: u64) -> Self {
+        self.record_bytes(bytes);
+        self
+    }
+
+    /// Record the bytes the operation moves, once they are known
+    pub fn record_bytes(&self, bytes: u64) {
+        self.span.record("fs.bytes", bytes);
+    }
+
+    /// The underlying span, for instrumenting futures
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Make this the current span until the returned guard is dropped, so
+    /// spans opened meanwhile on this thread become its children.
+    ///
+    /// The guard can't cross an `.await`; instrument futures with
+    /// [`span`](Self::span) instead.
+    pub fn entered(self) -> EnteredOpSpan {
+        let entered = self.span.clone().entered();
+        EnteredOpSpan {
+            op: self,
+            _entered: entered,
+        }
+    }
+}
+
+impl Drop for OpSpan {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        self.span.record("fs.duration_ms", elapsed.as_secs_f64() * 1000.0);
+    }
+}
+
+/// An [`OpSpan`] that is the current span of its thread
+#[must_use = "the operation is traced until the span is dropped"]
+pub struct EnteredOpSpan {
+    // Dropped first, so the duration is recorded before the span is exited
+    op: OpSpan,
+    _entered: EnteredSpan,
+}
+
+impl std::ops::Deref for EnteredOpSpan {
+    type Target = OpSpan;
+
+    fn deref(&self) -> &OpSpan {
+        &self.op
+    }
+}
+
+/// A block device that opens a span for every access to another device
+pub struct TelemetryBlockDevice {
+    inner: Arc<dyn BlockDevice>,
+}
+
+impl TelemetryBlockDevice {
+    /// Trace the accesses to `inner`
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl BlockDevice for TelemetryBlockDevice {
+    async fn read_block(&self, block_num: u64, buf: &mut [u8]) -> blockdev::Result<()> {
+        let op = OpSpan::new("block_read").block(block_num).bytes(buf.len() as u64);
+        self.inner.read_block(block_num, buf).instrument(op.span().clone()).await
+    }
+
+    async fn write_block(&self, block_num: u64, data: &[u8]) -> blockdev::Result<()> {
+        let op = OpSpan::new("block_write").block(block_num).bytes(data.len() as u64);
+        self.inner.write_block(block_num, data).instrument(op.span().clone()).await
+    }
+
+    fn block_count(&self) -> u64 {
+        self.inner.block_count()
+    }
+
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    async fn sync(&self) -> blockdev::Result<()> {
+        let op = OpSpan::new("block_sync");
+        self.inner.sync().instrument(op.span().clone()).await
+    }
+
+    async fn punch_hole(&self, block_num: u64, count: u64) -> blockdev::Result<u64> {
+        let op = OpSpan::new("block_punch_hole").block(block_num);
+        let freed = self.inner.punch_hole(block_num, count).instrument(op.span().clone()).await?;
+        op.record_bytes(freed);
+        Ok(freed)
+    }
+
+    async fn discard(&self, block_num: u64, count: u64) -> blockdev::Result<()> {
+        let op = OpSpan::new("block_discard").block(block_num);
+        self.inner.discard(block_num, count).instrument(op.span().clone()).await
+    }
+
+    async fn close(&mut self) -> blockdev::Result<()> {
+        match Arc::get_mut(&mut self.inner) {
+            Some(inner) => inner.close().await,
+            None => self.inner.sync().await,
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::{FileBackedBlockDevice, BLOCK_SIZE};
+    use parking_lot::Mutex;
+    use std::fmt;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, Layer};
+
+    /// Collects every span field value as (span name, field, value)
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<(String, String, String)>>>);
+
+    impl Capture {
+        fn value(&self, operation: &str, field: &str) -> Option<String> {
+            let fields = self.0.lock();
+            let span = fields
+                .iter()
+                .find(|(_, name, value)| name == "fs.operation" && value.trim_matches('"') == operation)
+                .map(|(span, _, _)| span.clone())?;
+            fields
+                .iter()
+                .find(|(s, name, _)| *s == span && name == field)
+                .map(|(_, _, value)| value.clone())
+        }
+    }
+
+    struct Visitor<'a>(&'a Capture, String);
+
+    impl Visit for Visitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0 .0.lock().push((self.1.clone(), field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for Capture {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _ctx: Context<'_, S>) {
+            attrs.record(&mut Visitor(self, format!("{:?}", id)));
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            values.record(&mut Visitor(self, format!("{:?}", id)));
+        }
+    }
+
+    #[test]
+    fn test_endpoint_resolution() {
+        assert_eq!(resolve_endpoint(Some("http://a:4317"), Some("http://b:4317".into())), "http://a:4317");
+        assert_eq!(resolve_endpoint(None, Some("http://b:4317".into())), "http://b:4317");
+        assert_eq!(resolve_endpoint(None, Some(" ".into())), DEFAULT_ENDPOINT);
+        assert_eq!(resolve_endpoint(None, None), DEFAULT_ENDPOINT);
+    }
+
+    #[test]
+    fn test_op_span_records_attributes() {
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::registry().with(capture.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = OpSpan::new("write").inode(7).bytes(4096).entered();
+            span.record_bytes(512);
+        });
+
+        assert_eq!(capture.value("write", "fs.inode").as_deref(), Some("7"));
+        assert_eq!(capture.value("write", "fs.bytes").as_deref(), Some("512"));
+        assert!(capture.value("write", "fs.duration_ms").is_some());
+        assert!(capture.value("write", "fs.block").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_block_device_accesses_are_traced() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner: Arc<dyn BlockDevice> = Arc::new(
+            FileBackedBlockDevice::create(dir.path().join("device"), 16 * BLOCK_SIZE as u64)
+                .await
+                .unwrap(),
+        );
+        let device = TelemetryBlockDevice::new(inner);
+
+        let capture = Capture::default();
+        let _default = tracing::subscriber::set_default(tracing_subscriber::registry().with(capture.clone()));
+        device.write_block(3, &[0xAB; BLOCK_SIZE]).await.unwrap();
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        device.read_block(3, &mut buf).await.unwrap();
+        assert_eq!(buf, vec![0xAB; BLOCK_SIZE]);
+
+        assert_eq!(capture.value("block_write", "fs.block").as_deref(), Some("3"));
+        assert_eq!(capture.value("block_read", "fs.bytes").as_deref(), Some(BLOCK_SIZE.to_string().as_str()));
+        assert!(capture.value("block_read", "fs.duration_ms").is_some());
+    }
+}
@@ -0,0 +1,252 @@
+//! Registry of long-running filesystem operations
+//!
+//! Scrubs and other operations that can run for minutes register here so
+//! a management front end can show what is running, how far along it is,
+//! and cancel it. Registering returns an [`OperationHandle`]: the operation
+//! reports progress through it and checks its cancellation token, and the
+//! entry is removed when the handle is dropped, however the operation ends.
+
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_util::sync::CancellationToken;
+
+/// Kinds of long-running operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationKind {
+    /// Verifying every block's checksum
+    Scrub,
+    /// Creating or deleting a snapshot
+    Snapshot,
+    /// Writing the write cache back to disk
+    Flush,
+    /// Moving file data into contiguous extents
+    Defrag,
+    /// Packing the inode table
+    Compaction,
+}
+
+impl OperationKind {
+    /// Name the operation is reported under
+    pub fn name(self) -> &'static str {
+        match self {
+            OperationKind::Scrub => "scrub",
+            OperationKind::Snapshot => "snapshot",
+            OperationKind::Flush => "flush",
+            OperationKind::Defrag => "defrag",
+            OperationKind::Compaction => "compaction",
+        }
+    }
+}
+
+impl fmt::Display for OperationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Point-in-time view of a running operation
+#[derive(Debug, Clone, Serialize)]
+pub struct OperationInfo {
+    /// ID to cancel the operation by
+    pub id: u64,
+    /// What the operation is
+    pub kind: OperationKind,
+    /// What it is working on, e.g. the device being scrubbed
+    pub description: String,
+    /// Units of work done so far
+    pub done: u64,
+    /// Units of work in total, 0 if unknown
+    pub total: u64,
+    /// When the operation started
+    pub started: SystemTime,
+    /// Whether cancellation has been asked for but the operation hasn't
+    /// stopped yet
+    pub cancelling: bool,
+}
+
+impl OperationInfo {
+    /// Fraction of the work done, `None` if the total is unknown
+    pub fn progress(&self) -> Option<f64> {
+        if self.total == 0 {
+            None
+        } else {
+            Some((self.done as f64 / self.total as f64).min(1.0))
+        }
+    }
+}
+
+/// A registered operation
+#[derive(Debug)]
+struct Entry {
+    kind: OperationKind,
+    description: String,
+    done: Arc<AtomicU64>,
+    total: u64,
+    started: SystemTime,
+    token: CancellationToken,
+}
+
+/// Running operations, listable and cancellable by ID
+#[derive(Debug, Default)]
+pub struct OperationRegistry {
+    next_id: AtomicU64,
+    entries: RwLock<BTreeMap<u64, Entry>>,
+}
+
+impl OperationRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register an operation of `total` units of work (0 if unknown) that
+    /// stops once `token` is cancelled. It is listed until the returned
+    /// handle is dropped.
+    pub fn register(
+        self: &Arc<Self>,
+        kind: OperationKind,
+        description: impl Into<String>,
+        total: u64,
+        token: CancellationToken,
+    ) -> OperationHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let done = Arc::new(AtomicU64::new(0));
+        let description = description.into();
+        log::debug!("OPERATIONS: Started {} {} ({})", kind, id, description);
+        self.entries.write().insert(
+            id,
+            Entry {
+                kind,
+                description,
+                done: done.clone(),
+                total,
+                started: SystemTime::now(),
+                token: token.clone(),
+            },
+        );
+        OperationHandle {
+            registry: self.clone(),
+            id,
+            done,
+            token,
+        }
+    }
+
+    /// Every running operation, oldest first
+    pub fn list(&self) -> Vec<OperationInfo> {
+        self.entries
+            .read()
+            .iter()
+            .map(|(&id, entry)| OperationInfo {
+                id,
+                kind: entry.kind,
+                description: entry.description.clone(),
+                done: entry.done.load(Ordering::Relaxed),
+                total: entry.total,
+                started: entry.started,
+                cancelling: entry.token.is_cancelled(),
+            })
+            .collect()
+    }
+
+    /// Ask operation `id` to stop. Returns false if no such operation is
+    /// running. The operation stays listed until it has stopped.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.entries.read().get(&id) {
+            Some(entry) => {
+                log::info!("OPERATIONS: Cancelling {} {}", entry.kind, id);
+                entry.token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of running operations
+    pub fn len(&self) -> usize {
+        self.entries.read().len()
+    }
+
+    /// Whether nothing is running
+    pub fn is_empty(&self) -> bool {
+        self.entries.read().is_empty()
+    }
+}
+
+/// A running operation's link to its registry entry, which is removed when
+/// this is dropped
+#[derive(Debug)]
+pub struct OperationHandle {
+    registry: Arc<OperationRegistry>,
+    id: u64,
+    done: Arc<AtomicU64>,
+    token: CancellationToken,
+}
+
+impl OperationHandle {
+    /// ID the operation is listed under
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Record that `done` units of work are finished
+    pub fn set_progress(&self, done: u64) {
+        self.done.store(done, Ordering::Relaxed);
+    }
+
+    /// Token cancelled when the operation is cancelled through the registry
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Whether the operation has been asked to stop
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+impl Drop for OperationHandle {
+    fn drop(&mut self) {
+        if let Some(entry) = self.registry.entries.write().remove(&self.id) {
+            log::debug!("OPERATIONS: Finished {} {}", entry.kind, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handles_list_report_progress_and_unregister() {
+        let registry = Arc::new(OperationRegistry::new());
+        let scrub = registry.register(OperationKind::Scrub, "/dev/sdb", 200, CancellationToken::new());
+        let flush = registry.register(OperationKind::Flush, "write cache", 0, CancellationToken::new());
+        assert_ne!(scrub.id(), flush.id());
+
+        scrub.set_progress(50);
+        let listed = registry.list();
+        assert_eq!(listed.len(), 2);
+        assert_eq!((listed[0].id, listed[0].kind, listed[0].done), (scrub.id(), OperationKind::Scrub, 50));
+        assert_eq!(listed[0].progress(), Some(0.25));
+        assert_eq!(listed[1].progress(), None);
+
+        assert!(registry.cancel(flush.id()));
+        assert!(flush.is_cancelled());
+        assert!(!scrub.is_cancelled());
+        assert!(registry.list()[1].cancelling);
+
+        let flush_id = flush.id();
+        drop(flush);
+        assert!(!registry.cancel(flush_id));
+        assert_eq!(registry.len(), 1);
+        drop(scrub);
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,390 @@
+//! 9P2000.L file server over the [`Vfs`] API
+//!
+//! `NinePServer` serves a filesystem to 9P clients such as the Linux v9fs
+//! driver (`mount -t 9p -o trans=tcp,version=9p2000.L`), which gives remote
+//! access on platforms without FUSE. It handles the messages needed to walk,
+//! create, read and write files: Tversion, Tattach, Twalk, Tlopen, Tlcreate,
+//! Tread, Twrite and Tclunk. Anything else is answered with `EOPNOTSUPP`.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error::Error as FsError;
+use crate::vfs::Vfs;
+use crate::FileType;
+
+/// Protocol version spoken by the server
+pub const VERSION: &str = "9P2000.L";
+
+/// Largest message the server accepts or sends
+pub const MAX_MSIZE: u32 = 128 * 1024;
+
+/// Size of the header on every message: size, type and tag
+const HEADER_SIZE: usize = 4 + 1 + 2;
+
+/// Bytes of an Rread or Twrite message besides the data
+const IO_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4;
+
+/// Smallest message size a client may negotiate
+const MIN_MSIZE: u32 = 4096;
+
+/// Message types
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const RLOPEN: u8 = 13;
+const TLCREATE: u8 = 14;
+const RLCREATE: u8 = 15;
+const TVERSION: u8 = 100;
+const RVERSION: u8 = 101;
+const TATTACH: u8 = 104;
+const RATTACH: u8 = 105;
+const TWALK: u8 = 110;
+const RWALK: u8 = 111;
+const TREAD: u8 = 116;
+const RREAD: u8 = 117;
+const TWRITE: u8 = 118;
+const RWRITE: u8 = 119;
+const TCLUNK: u8 = 120;
+const RCLUNK: u8 = 121;
+
+/// Qid type bits
+const QTDIR: u8 = 0x80;
+const QTFILE: u8 = 0x00;
+
+/// Linux errno values carried by Rlerror; 9P2000.L uses these on every
+/// platform
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const EEXIST: u32 = 17;
+const ENOTDIR: u32 = 20;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const EFBIG: u32 = 27;
+const ENOSPC: u32 = 28;
+const ENOTEMPTY: u32 = 39;
+const EPROTO: u32 = 71;
+const EOPNOTSUPP: u32 = 95;
+
+/// Server error types
+#[derive(Error, Debug)]
+pub enum ServerError {
+    /// The connection to a client failed
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// A client sent a message that could not be decoded
+    #[error("Invalid 9P message: {0}")]
+    InvalidMessage(String),
+}
+
+/// Result type for server operations
+pub type Result<T> = std::result::Result<T, ServerError>;
+
+/// Linux errno for a failed filesystem operation
+fn lerror(err: &FsError) -> u32 {
+    match err {
+        FsError::NotFound | FsError::ParentNotFound(_) => ENOENT,
+        FsError::NotADirectory => ENOTDIR,
+        FsError::IsADirectory => EISDIR,
+        FsError::AlreadyExists => EEXIST,
+        FsError::PermissionDenied => EACCES,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::InvalidArgument | FsError::InvalidPath => EINVAL,
+        FsError::FileTooLarge => EFBIG,
+        FsError::NoSpace | FsError::InodeExhausted => ENOSPC,
+        FsError::Unsupported => EOPNOTSUPP,
+        _ => EIO,
+    }
+}
+
+/// Cursor over the body of a T-message
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> std::result::Result<&'a [u8], u32> {
+        if self.buf.len() < n {
+            return Err(EPROTO);
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> std::result::Result<u16, u32> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> std::result::Result<u32, u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> std::result::Result<u64, u32> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> std::result::Result<String, u32> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| EINVAL)
+    }
+}
+
+/// Builder for the body of an R-message
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn u16(mut self, v: u16) -> Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, v: u32) -> Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn string(self, s: &str) -> Self {
+        let mut enc = self.u16(s.len() as u16);
+        enc.buf.extend_from_slice(s.as_bytes());
+        enc
+    }
+
+    fn bytes(mut self, data: &[u8]) -> Self {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    fn qid(mut self, kind: FileType, ino: u64) -> Self {
+        let qtype = if kind == FileType::Directory { QTDIR } else { QTFILE };
+        self.buf.push(qtype);
+        self.buf.extend_from_slice(&0u32.to_le_bytes());
+        self.buf.extend_from_slice(&ino.to_le_bytes());
+        self
+    }
+}
+
+/// What a fid refers to
+#[derive(Debug, Clone, Copy)]
+struct Fid {
+    ino: u64,
+    kind: FileType,
+}
+
+/// Protocol state of one client connection
+struct Session {
+    vfs: Arc<dyn Vfs>,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Session {
+    fn new(vfs: Arc<dyn Vfs>) -> Self {
+        Self {
+            vfs,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        }
+    }
+
+    fn fid(&self, fid: u32) -> std::result::Result<Fid, u32> {
+        self.fids.get(&fid).copied().ok_or(EBADF)
+    }
+
+    /// Handle one T-message, returning the R-message type and body
+    async fn handle(&mut self, mtype: u8, body: &[u8]) -> std::result::Result<(u8, Vec<u8>), u32> {
+        let mut msg = Decoder { buf: body };
+        match mtype {
+            TVERSION => {
+                let msize = msg.u32()?.min(MAX_MSIZE);
+                if msize < MIN_MSIZE {
+                    return Err(EINVAL);
+                }
+                let version = msg.string()?;
+                // A new version starts a new session
+                self.fids.clear();
+                self.msize = msize;
+                let version = if version.starts_with(VERSION) { VERSION } else { "unknown" };
+                Ok((RVERSION, Encoder::default().u32(msize).string(version).buf))
+            }
+            TATTACH => {
+                let fid = msg.u32()?;
+                let root = self.vfs.getattr(crate::ROOT_INODE).await.map_err(|e| lerror(&e))?;
+                self.fids.insert(fid, Fid { ino: root.ino, kind: root.kind });
+                Ok((RATTACH, Encoder::default().qid(root.kind, root.ino).buf))
+            }
+            TWALK => {
+                let fid = self.fid(msg.u32()?)?;
+                let newfid = msg.u32()?;
+                let nwname = msg.u16()?;
+
+                let mut current = fid;
+                let mut reply = Encoder::default();
+                let mut walked = 0u16;
+                for i in 0..nwname {
+                    let name = msg.string()?;
+                    let found = if name == ".." && current.ino == crate::ROOT_INODE {
+                        // The root is its own parent
+                        self.vfs.getattr(current.ino).await
+                    } else {
+                        self.vfs.lookup(current.ino, &name).await
+                    };
+                    match found {
+                        Ok(attr) => {
+                            current = Fid { ino: attr.ino, kind: attr.kind };
+                            reply = reply.qid(attr.kind, attr.ino);
+                            walked += 1;
+                        }
+                        // Only a failure on the first element is an error
+                        Err(e) if i == 0 => return Err(lerror(&e)),
+                        Err(_) => break,
+                    }
+                }
+
+                if walked == nwname {
+                    self.fids.insert(newfid, current);
+                }
+                let mut out = Encoder::default().u16(walked);
+                out.buf.extend_from_slice(&reply.buf);
+                Ok((RWALK, out.buf))
+            }
+            TLOPEN => {
+                let fid = self.fid(msg.u32()?)?;
+                let attr = self.vfs.getattr(fid.ino).await.map_err(|e| lerror(&e))?;
+                let iounit = self.msize - IO_HEADER_SIZE;
+                Ok((RLOPEN, Encoder::default().qid(attr.kind, attr.ino).u32(iounit).buf))
+            }
+            TLCREATE => {
+                let fid_num = msg.u32()?;
+                let dir = self.fid(fid_num)?;
+                let name = msg.string()?;
+                let attr = self.vfs.create(dir.ino, &name).await.map_err(|e| lerror(&e))?;
+                // The fid now refers to the new, open file
+                self.fids.insert(fid_num, Fid { ino: attr.ino, kind: attr.kind });
+                let iounit = self.msize - IO_HEADER_SIZE;
+                Ok((RLCREATE, Encoder::default().qid(attr.kind, attr.ino).u32(iounit).buf))
+            }
+            TREAD => {
+                let fid = self.fid(msg.u32()?)?;
+                let offset = msg.u64()?;
+                let count = msg.u32()?.min(self.msize - IO_HEADER_SIZE);
+                if fid.kind == FileType::Directory {
+                    return Err(EISDIR);
+                }
+                let data = self.vfs.read(fid.ino, offset, count).await.map_err(|e| lerror(&e))?;
+                Ok((RREAD, Encoder::default().u32(data.len() as u32).bytes(&data).buf))
+            }
+            TWRITE => {
+                let fid = self.fid(msg.u32()?)?;
+                let offset = msg.u64()?;
+                let count = msg.u32()?;
+                let data = msg.take(count as usize)?;
+                if fid.kind == FileType::Directory {
+                    return Err(EISDIR);
+                }
+                let written = self.vfs.write(fid.ino, offset, data).await.map_err(|e| lerror(&e))?;
+                Ok((RWRITE, Encoder::default().u32(written).buf))
+            }
+            TCLUNK => {
+                let fid = self.fids.remove(&msg.u32()?).ok_or(EBADF)?;
+                // Clunk is the client's close, so persist what it wrote
+                if fid.kind == FileType::RegularFile {
+                    self.vfs.flush(fid.ino).await.map_err(|e| lerror(&e))?;
+                }
+                Ok((RCLUNK, Vec::new()))
+            }
+            _ => Err(EOPNOTSUPP),
+        }
+    }
+}
+
+/// Read one message, returning its type, tag and body
+async fn read_message(stream: &mut TcpStream) -> Result<(u8, u16, Vec<u8>)> {
+    let mut size = [0u8; 4];
+    stream.read_exact(&mut size).await?;
+    let size = u32::from_le_bytes(size);
+    if (size as usize) < HEADER_SIZE || size > MAX_MSIZE {
+        return Err(ServerError::InvalidMessage(format!("size {}", size)));
+    }
+
+    let mut buf = vec![0u8; size as usize - 4];
+    stream.read_exact(&mut buf).await?;
+    let tag = u16::from_le_bytes([buf[1], buf[2]]);
+    Ok((buf[0], tag, buf.split_off(3)))
+}
+
+/// Write one message
+async fn write_message(stream: &mut TcpStream, mtype: u8, tag: u16, body: &[u8]) -> Result<()> {
+    let size = (HEADER_SIZE + body.len()) as u32;
+    let mut buf = Vec::with_capacity(size as usize);
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.push(mtype);
+    buf.extend_from_slice(&tag.to_le_bytes());
+    buf.extend_from_slice(body);
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// 9P2000.L server for a filesystem
+pub struct NinePServer {
+    listener: TcpListener,
+    vfs: Arc<dyn Vfs>,
+}
+
+impl NinePServer {
+    /// Listen for 9P clients on `addr`
+    pub async fn bind(addr: SocketAddr, vfs: Arc<dyn Vfs>) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { listener, vfs })
+    }
+
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept clients and serve them until the task is dropped
+    pub async fn run(self) -> Result<()> {
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            log::info!("9P: Accepted connection from {}", peer);
+            let vfs = self.vfs.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::serve(stream, vfs).await {
+                    log::warn!("9P: Connection from {} ended: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Answer one client's messages until it disconnects
+    async fn serve(mut stream: TcpStream, vfs: Arc<dyn Vfs>) -> Result<()> {
+        stream.set_nodelay(true)?;
+        let mut session = Session::new(vfs);
+        loop {
+            let (mtype, tag, body) = match read_message(&mut stream).await {
+                Ok(msg) => msg,
+                Err(ServerError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            match session.handle(mtype, &body).await {
+                Ok((rtype, reply)) => write_message(&mut stream, rtype, tag, &reply).await?,
+                Err(ecode) => {
+                    log::debug!("9P: Message type {} failed with errno {}", mtype, ecode);
+                    write_message(&mut stream, RLERROR, tag, &ecode.to_le_bytes()).await?;
+                }
+            }
+        }
+    }
+}
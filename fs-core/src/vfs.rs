@@ -0,0 +1,382 @@
+//! Async filesystem API independent of FUSE
+//!
+//! [`Vfs`] exposes the filesystem operations that the FUSE callbacks are
+//! built on, so tests, the GUI and network servers can drive an [`AegisFS`]
+//! directly through a [`VfsHandle`]. The trait is object safe and can be
+//! used as `Arc<dyn Vfs>`.
+
+use crate::error::{Error, Result};
+use crate::{AegisFS, FileAttr, FileType, ROOT_INODE};
+use async_trait::async_trait;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// One entry returned by [`Vfs::readdir`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    /// Inode the entry refers to
+    pub ino: u64,
+    /// Type of the inode
+    pub kind: FileType,
+    /// Entry name
+    pub name: String,
+    /// Cursor that continues the listing after this entry
+    pub offset: u64,
+}
+
+/// Filesystem operations on inode numbers and names
+#[async_trait]
+pub trait Vfs: Send + Sync {
+    /// Find `name` in directory `parent`
+    async fn lookup(&self, parent: u64, name: &str) -> Result<FileAttr>;
+
+    /// Get the attributes of an inode
+    async fn getattr(&self, ino: u64) -> Result<FileAttr>;
+
+    /// List a directory's entries after the cursor `offset`, in the order
+    /// they were added. Start from
+    /// [`CURSOR_START`](crate::dir_children::CURSOR_START); resume from the
+    /// `offset` of the last entry seen.
+    async fn readdir(&self, ino: u64, offset: u64) -> Result<Vec<DirectoryEntry>>;
+
+    /// Create an empty regular file `name` in `parent`
+    async fn create(&self, parent: u64, name: &str) -> Result<FileAttr>;
+
+    /// Create an empty directory `name` in `parent`
+    async fn mkdir(&self, parent: u64, name: &str) -> Result<FileAttr>;
+
+    /// Read up to `size` bytes at `offset`; shorter at end of file
+    async fn read(&self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>>;
+
+    /// Write `data` at `offset`, returning the number of bytes written
+    async fn write(&self, ino: u64, offset: u64, data: &[u8]) -> Result<u32>;
+
+    /// Remove the file `name` from `parent`, returning its inode
+    async fn unlink(&self, parent: u64, name: &str) -> Result<u64>;
+
+    /// Remove the empty directory `name` from `parent`, returning its inode
+    async fn rmdir(&self, parent: u64, name: &str) -> Result<u64>;
+
+    /// Move `name` in `parent` to `newname` in `newparent`, returning its inode
+    async fn rename(&self, parent: u64, name: &str, newparent: u64, newname: &str) -> Result<u64>;
+
+    /// Persist an inode's queued writes and metadata
+    async fn flush(&self, ino: u64) -> Result<()>;
+}
+
+/// Shared handle to an [`AegisFS`] that implements [`Vfs`].
+///
+/// The filesystem's operations block on disk I/O, so each call runs on
+/// tokio's blocking pool with a clone of the handle rather than on the
+/// caller's worker thread.
+#[derive(Clone)]
+pub struct VfsHandle {
+    fs: Arc<AegisFS>,
+}
+
+impl VfsHandle {
+    /// Share `fs` with async callers
+    pub fn new(fs: AegisFS) -> Self {
+        Self { fs: Arc::new(fs) }
+    }
+
+    /// Take the filesystem back, or the handle if a clone of it is still
+    /// alive
+    pub fn into_inner(self) -> std::result::Result<AegisFS, Self> {
+        Arc::try_unwrap(self.fs).map_err(|fs| Self { fs })
+    }
+
+    /// Run `op` on the blocking pool and wait for it without blocking the
+    /// runtime
+    async fn run<T: Send + 'static>(&self, op: impl FnOnce(&AegisFS) -> Result<T> + Send + 'static) -> Result<T> {
+        let fs = self.fs.clone();
+        match tokio::task::spawn_blocking(move || op(&fs)).await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // The runtime is shutting down
+            Err(_) => Err(Error::Cancelled),
+        }
+    }
+}
+
+impl Deref for VfsHandle {
+    type Target = AegisFS;
+
+    fn deref(&self) -> &AegisFS {
+        &self.fs
+    }
+}
+
+#[async_trait]
+impl Vfs for VfsHandle {
+    async fn lookup(&self, parent: u64, name: &str) -> Result<FileAttr> {
+        let name = name.to_string();
+        self.run(move |fs| fs.vfs_lookup(parent, &name)).await
+    }
+
+    async fn getattr(&self, ino: u64) -> Result<FileAttr> {
+        self.run(move |fs| fs.vfs_getattr(ino)).await
+    }
+
+    async fn readdir(&self, ino: u64, offset: u64) -> Result<Vec<DirectoryEntry>> {
+        self.run(move |fs| {
+            let mut entries = Vec::new();
+            fs.list_directory(ino, offset, |entry| {
+                entries.push(entry);
+                false
+            })?;
+            Ok(entries)
+        })
+        .await
+    }
+
+    async fn create(&self, parent: u64, name: &str) -> Result<FileAttr> {
+        let name = name.to_string();
+        self.run(move |fs| fs.vfs_create(parent, &name)).await
+    }
+
+    async fn mkdir(&self, parent: u64, name: &str) -> Result<FileAttr> {
+        let name = name.to_string();
+        self.run(move |fs| fs.vfs_mkdir(parent, &name)).await
+    }
+
+    async fn read(&self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        self.run(move |fs| fs.read_file_data(fs.inner_ino(ino), offset, size, None)).await
+    }
+
+    async fn write(&self, ino: u64, offset: u64, data: &[u8]) -> Result<u32> {
+        let data = data.to_vec();
+        self.run(move |fs| fs.write_file_data(fs.inner_ino(ino), offset, &data, None)).await
+    }
+
+    async fn unlink(&self, parent: u64, name: &str) -> Result<u64> {
+        let name = name.to_string();
+        self.run(move |fs| fs.remove_file(fs.inner_ino(parent), &name)).await
+    }
+
+    async fn rmdir(&self, parent: u64, name: &str) -> Result<u64> {
+        let name = name.to_string();
+        self.run(move |fs| fs.remove_dir(fs.inner_ino(parent), &name)).await
+    }
+
+    async fn rename(&self, parent: u64, name: &str, newparent: u64, newname: &str) -> Result<u64> {
+        let (name, newname) = (name.to_string(), newname.to_string());
+        self.run(move |fs| fs.rename_entry(fs.inner_ino(parent), &name, fs.inner_ino(newparent), &newname))
+            .await
+    }
+
+    async fn flush(&self, ino: u64) -> Result<()> {
+        self.run(move |fs| fs.flush_inode(fs.inner_ino(ino))).await
+    }
+}
+
+/// Synchronous bodies of the [`Vfs`] operations that do more than map
+/// inode numbers, shared with the FUSE adapter
+impl AegisFS {
+    pub(crate) fn vfs_lookup(&self, parent: u64, name: &str) -> Result<FileAttr> {
+        #[cfg(feature = "metrics")]
+        let _timer = self.metrics.start(crate::metrics::Op::Lookup);
+        let parent = self.inner_ino(parent);
+        // A bound root is its own parent, like the real one
+        if name == ".." && parent == self.root_ino() {
+            return self.vfs_getattr(ROOT_INODE);
+        }
+        let parent_cached = self.get_cached_inode(parent).ok_or(Error::NotFound)?;
+        let child_ino = *parent_cached.children.get(name).ok_or(Error::NotFound)?;
+        self.get_cached_inode(child_ino)
+            .map(|child| self.outer_attr(child.attr))
+            .ok_or(Error::NotFound)
+    }
+
+    pub(crate) fn vfs_getattr(&self, ino: u64) -> Result<FileAttr> {
+        self.get_cached_inode(self.inner_ino(ino))
+            .map(|cached| self.outer_attr(cached.attr))
+            .ok_or(Error::NotFound)
+    }
+
+    pub(crate) fn vfs_create(&self, parent: u64, name: &str) -> Result<FileAttr> {
+        self.create_file(self.inner_ino(parent), name, FileType::RegularFile)
+            .map(|cached| cached.attr)
+    }
+
+    pub(crate) fn vfs_mkdir(&self, parent: u64, name: &str) -> Result<FileAttr> {
+        let parent = self.inner_ino(parent);
+        let mut cached = self.create_file(parent, name, FileType::Directory)?;
+        cached.children.insert(".".to_string(), cached.ino);
+        cached.children.insert("..".to_string(), parent);
+        self.update_cached_inode(cached.ino, cached.clone())?;
+        Ok(cached.attr)
+    }
+}
+
+impl AegisFS {
+    /// Hand the entries of a directory after `cursor` to `add`, in listing
+    /// order, until it returns `true`. The caller resumes from the `offset`
+    /// of the last entry it kept.
+    ///
+    /// Resuming from a cursor skips straight to it, so listing a directory
+    /// page by page stays linear in its size.
+    pub(crate) fn list_directory(
+        &self,
+        ino: u64,
+        cursor: u64,
+        mut add: impl FnMut(DirectoryEntry) -> bool,
+    ) -> Result<()> {
+        let ino = self.inner_ino(ino);
+        {
+            let cache = self.inode_cache.read();
+            let cached = cache.get(&ino).ok_or(Error::NotFound)?;
+            if cached.attr.kind != FileType::Directory {
+                return Err(Error::NotADirectory);
+            }
+
+            let is_root = ino == self.root_ino();
+            for (offset, name, child_ino) in cached.children.after(cursor) {
+                let child_ino = if is_root && name == ".." { ino } else { child_ino };
+                let Some(child) = cache.get(&child_ino) else {
+                    continue;
+                };
+                let entry = DirectoryEntry {
+                    ino: self.outer_ino(child_ino),
+                    kind: child.attr.kind,
+                    name: name.to_string(),
+                    offset,
+                };
+                if add(entry) {
+                    break;
+                }
+            }
+        }
+
+        self.touch_atime(ino);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dir_children::CURSOR_START;
+    use crate::blockdev::{BlockDevice, FileBackedBlockDevice};
+    use crate::{DiskFs, DiskFsTrait, MountOptions};
+    use std::sync::Arc;
+
+    const ROOT: u64 = 1;
+
+    async fn mount(path: &std::path::Path) -> Arc<dyn Vfs> {
+        let size = 4 * 1024 * 1024;
+        let device: Arc<dyn BlockDevice> =
+            Arc::new(FileBackedBlockDevice::create(path, size).await.unwrap());
+        DiskFs::format(device.clone(), size, None).await.unwrap();
+        Arc::new(VfsHandle::new(AegisFS::from_block_device(device, MountOptions::default()).await.unwrap()))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_create_write_read_readdir() {
+        let path = std::env::temp_dir().join(format!("aegisfs_vfs_{}.img", std::process::id()));
+        let fs = mount(&path).await;
+
+        let file = fs.create(ROOT, "notes.txt").await.unwrap();
+        assert_eq!(file.kind, FileType::RegularFile);
+        assert_eq!(fs.write(file.ino, 0, b"hello vfs").await.unwrap(), 9);
+        assert_eq!(fs.read(file.ino, 6, 100).await.unwrap(), b"vfs");
+        assert_eq!(fs.getattr(file.ino).await.unwrap().size, 9);
+
+        let dir = fs.mkdir(ROOT, "docs").await.unwrap();
+        assert_eq!(fs.lookup(ROOT, "docs").await.unwrap().ino, dir.ino);
+        fs.create(dir.ino, "inner.txt").await.unwrap();
+
+        let names: Vec<String> = fs
+            .readdir(ROOT, CURSOR_START)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert!(names.contains(&"docs".to_string()));
+        assert!(names.contains(&"notes.txt".to_string()));
+        let inner = fs.readdir(dir.ino, CURSOR_START).await.unwrap();
+        let names: Vec<&str> = inner.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec![".", "..", "inner.txt"]);
+
+        drop(fs);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_errors_are_typed() {
+        let path = std::env::temp_dir().join(format!("aegisfs_vfs_err_{}.img", std::process::id()));
+        let fs = mount(&path).await;
+
+        let file = fs.create(ROOT, "a.txt").await.unwrap();
+        assert!(matches!(fs.create(ROOT, "a.txt").await, Err(Error::AlreadyExists)));
+        assert!(matches!(fs.lookup(ROOT, "missing").await, Err(Error::NotFound)));
+        assert!(matches!(fs.readdir(file.ino, CURSOR_START).await, Err(Error::NotADirectory)));
+
+        let dir = fs.mkdir(ROOT, "full").await.unwrap();
+        fs.create(dir.ino, "b.txt").await.unwrap();
+        assert!(matches!(fs.unlink(ROOT, "full").await, Err(Error::IsADirectory)));
+        assert!(matches!(fs.rmdir(ROOT, "full").await, Err(Error::NotEmpty)));
+
+        assert_eq!(fs.rename(ROOT, "a.txt", dir.ino, "c.txt").await.unwrap(), file.ino);
+        assert_eq!(fs.unlink(dir.ino, "c.txt").await.unwrap(), file.ino);
+        assert!(matches!(fs.lookup(dir.ino, "c.txt").await, Err(Error::NotFound)));
+
+        drop(fs);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bind_hides_everything_outside_the_subtree() {
+        let path = std::env::temp_dir().join(format!("aegisfs_vfs_bind_{}.img", std::process::id()));
+        let size = 4 * 1024 * 1024;
+        let device: Arc<dyn BlockDevice> =
+            Arc::new(FileBackedBlockDevice::create(&path, size).await.unwrap());
+        DiskFs::format(device.clone(), size, None).await.unwrap();
+
+        let fs = VfsHandle::new(AegisFS::from_block_device(device.clone(), MountOptions::default()).await.unwrap());
+        let outside = fs.create(ROOT, "outside.txt").await.unwrap();
+        fs.write(outside.ino, 0, b"secret").await.unwrap();
+        fs.flush(outside.ino).await.unwrap();
+        let shared = fs.mkdir(ROOT, "shared").await.unwrap();
+        let deep = fs.mkdir(shared.ino, "deep").await.unwrap();
+        let inner = fs.create(deep.ino, "inner.txt").await.unwrap();
+        fs.write(inner.ino, 0, b"visible").await.unwrap();
+        fs.flush(inner.ino).await.unwrap();
+        let mut fs = fs.into_inner().ok().expect("no other handles");
+        assert!(matches!(fs.bind("/shared/deep/inner.txt").await, Err(Error::NotADirectory)));
+        drop(fs);
+
+        let options = MountOptions {
+            force: true,
+            bind: Some("/shared/deep".to_string()),
+            ..MountOptions::default()
+        };
+        let fs = VfsHandle::new(AegisFS::from_block_device(device.clone(), options).await.unwrap());
+        assert_eq!(fs.root_ino(), deep.ino);
+
+        // The bound directory is the root, and its parent is itself
+        let root = fs.getattr(ROOT).await.unwrap();
+        assert_eq!((root.ino, root.kind), (ROOT, FileType::Directory));
+        assert_eq!(fs.lookup(ROOT, "..").await.unwrap().ino, ROOT);
+        let entries = fs.readdir(ROOT, CURSOR_START).await.unwrap();
+        let listed: Vec<(&str, u64)> = entries.iter().map(|entry| (entry.name.as_str(), entry.ino)).collect();
+        assert_eq!(listed, vec![(".", ROOT), ("..", ROOT), ("inner.txt", inner.ino)]);
+        assert_eq!(fs.read(inner.ino, 0, 100).await.unwrap(), b"visible");
+
+        // Nothing above it can be named
+        assert!(matches!(fs.lookup(ROOT, "outside.txt").await, Err(Error::NotFound)));
+        assert!(matches!(fs.lookup(ROOT, "shared").await, Err(Error::NotFound)));
+        assert!(matches!(fs.unlink(ROOT, "outside.txt").await, Err(Error::NotFound)));
+
+        // New entries land in the bound directory
+        let added = fs.create(ROOT, "added.txt").await.unwrap();
+        assert_eq!(fs.cached_lookup(deep.ino, "added.txt"), Some(added.ino));
+        assert_eq!(fs.cached_lookup(1, "added.txt"), None);
+        drop(fs);
+
+        let options = MountOptions { force: true, bind: Some("/missing".to_string()), ..MountOptions::default() };
+        assert!(AegisFS::from_block_device(device, options).await.is_err());
+        std::fs::remove_file(&path).ok();
+    }
+}
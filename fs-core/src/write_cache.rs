@@ -0,0 +1,176 @@
+//! Writes queued for the deferred flush, indexed by inode
+//!
+//! Every write to a file is queued here until a flush puts it on disk, and
+//! every write and read of the file looks at what is queued for it. Each
+//! inode's writes are kept in a queue of their own, oldest first, with a
+//! running total of their size, so that work is proportional to what one
+//! file has queued.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::WriteOperation;
+
+/// Queued writes of one inode
+#[derive(Debug, Default)]
+struct InodeWrites {
+    /// Writes, oldest first
+    ops: VecDeque<WriteOperation>,
+    /// Bytes of data across `ops`
+    bytes: usize,
+}
+
+/// Writes waiting to be flushed, per inode in the order they were made
+#[derive(Debug, Default)]
+pub struct WriteCache {
+    /// Queued writes of each inode that has any
+    inodes: HashMap<u64, InodeWrites>,
+    /// Writes queued across all inodes
+    len: usize,
+}
+
+impl WriteCache {
+    /// An empty write cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of writes queued across all inodes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether nothing is queued
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queue a write after everything already queued for its inode
+    pub fn push(&mut self, op: WriteOperation) {
+        let writes = self.inodes.entry(op.ino).or_default();
+        writes.bytes += op.data.len();
+        writes.ops.push_back(op);
+        self.len += 1;
+    }
+
+    /// Every queued write; one inode's writes come oldest first, but
+    /// inodes are in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &WriteOperation> + '_ {
+        self.inodes.values().flat_map(|writes| writes.ops.iter())
+    }
+
+    /// Queued writes of `ino`, oldest first
+    pub fn inode_writes(&self, ino: u64) -> impl Iterator<Item = &WriteOperation> + '_ {
+        self.inodes
+            .get(&ino)
+            .into_iter()
+            .flat_map(|writes| writes.ops.iter())
+    }
+
+    /// Bytes of data queued for `ino`
+    pub fn queued_bytes(&self, ino: u64) -> usize {
+        self.inodes.get(&ino).map_or(0, |writes| writes.bytes)
+    }
+
+    /// Inodes with writes queued and the bytes queued for each
+    pub fn inodes(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.inodes.iter().map(|(&ino, writes)| (ino, writes.bytes))
+    }
+
+    /// Take `ino`'s oldest writes, up to about `max_bytes` of them. At
+    /// least one write is taken if any is queued.
+    pub fn take(&mut self, ino: u64, max_bytes: usize) -> Vec<WriteOperation> {
+        let Some(writes) = self.inodes.get_mut(&ino) else {
+            return Vec::new();
+        };
+        let mut taken = Vec::new();
+        let mut bytes = 0usize;
+        while taken.is_empty() || bytes < max_bytes {
+            let Some(op) = writes.ops.pop_front() else {
+                break;
+            };
+            bytes += op.data.len();
+            taken.push(op);
+        }
+        writes.bytes -= bytes;
+        if writes.ops.is_empty() {
+            self.inodes.remove(&ino);
+        }
+        self.len -= taken.len();
+        taken
+    }
+
+    /// Take every write queued for `ino`
+    pub fn remove_inode(&mut self, ino: u64) -> Vec<WriteOperation> {
+        self.take(ino, usize::MAX)
+    }
+
+    /// Take every queued write
+    pub fn take_all(&mut self) -> Vec<WriteOperation> {
+        self.len = 0;
+        std::mem::take(&mut self.inodes)
+            .into_values()
+            .flat_map(|writes| writes.ops)
+            .collect()
+    }
+
+    /// Put writes that couldn't be written back in front of their inodes'
+    /// queues, ahead of anything queued since they were taken. `writes`
+    /// are in the order they were taken.
+    pub fn requeue(&mut self, writes: Vec<WriteOperation>) {
+        self.len += writes.len();
+        for op in writes.into_iter().rev() {
+            let queued = self.inodes.entry(op.ino).or_default();
+            queued.bytes += op.data.len();
+            queued.ops.push_front(op);
+        }
+    }
+}
+
+impl Extend<WriteOperation> for WriteCache {
+    fn extend<I: IntoIterator<Item = WriteOperation>>(&mut self, ops: I) {
+        for op in ops {
+            self.push(op);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn op(ino: u64, offset: u64, len: usize) -> WriteOperation {
+        WriteOperation {
+            ino,
+            offset,
+            data: vec![ino as u8; len],
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_writes_are_taken_and_requeued_per_inode_in_order() {
+        let mut cache = WriteCache::new();
+        cache.extend([op(1, 0, 100), op(2, 0, 10), op(1, 100, 100), op(1, 200, 100)]);
+        assert_eq!((cache.len(), cache.queued_bytes(1), cache.queued_bytes(2)), (4, 300, 10));
+
+        // Up to about the budget, but always at least one write
+        let taken = cache.take(1, 150);
+        assert_eq!(taken.iter().map(|op| op.offset).collect::<Vec<_>>(), [0, 100]);
+        assert_eq!(cache.take(1, 0).len(), 1);
+        assert_eq!(cache.queued_bytes(1), 0);
+        assert_eq!(cache.inodes().collect::<Vec<_>>(), [(2, 10)]);
+
+        // A failed flush puts its writes back ahead of newer ones
+        cache.push(op(1, 300, 50));
+        cache.requeue(taken);
+        let offsets: Vec<u64> = cache.inode_writes(1).map(|op| op.offset).collect();
+        assert_eq!(offsets, [0, 100, 300]);
+        assert_eq!((cache.len(), cache.queued_bytes(1)), (4, 250));
+
+        assert_eq!(cache.remove_inode(2).len(), 1);
+        assert_eq!(cache.take_all().len(), 3);
+        assert!(cache.is_empty());
+        assert_eq!(cache.iter().count(), 0);
+    }
+}
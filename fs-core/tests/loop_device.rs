@@ -0,0 +1,86 @@
+//! Format and open AegisFS on a real block device via a loopback device.
+//!
+//! These tests need root (or CAP_SYS_ADMIN) and `losetup`, so they are
+//! ignored by default. Run them with:
+//!
+//! ```bash
+//! sudo -E cargo test --test loop_device -- --ignored --test-threads=1
+//! ```
+//!
+//! `scripts/loop-test.sh` runs the same scenario end-to-end through the CLI,
+//! including a FUSE mount.
+#![cfg(target_os = "linux")]
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use aegisfs::blockdev::{BlockDevice, FileBackedBlockDevice, BLOCK_SIZE};
+use aegisfs::format::{device_size, format_device};
+use aegisfs::layout::{DiskFs, DiskFsTrait};
+use aegisfs::AegisFS;
+use tempfile::TempDir;
+
+/// A loop device attached to an image file, detached on drop
+struct LoopDevice {
+    path: PathBuf,
+}
+
+impl LoopDevice {
+    fn attach(image: &Path) -> Self {
+        let output = Command::new("losetup")
+            .args(["--find", "--show"])
+            .arg(image)
+            .output()
+            .expect("Failed to run losetup");
+        assert!(
+            output.status.success(),
+            "losetup failed (are you root?): {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let path = String::from_utf8(output.stdout).unwrap().trim().to_string();
+        Self { path: path.into() }
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        Command::new("losetup").arg("-d").arg(&self.path).status().ok();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs root and losetup"]
+async fn test_format_and_open_loop_device() {
+    env_logger::builder().is_test(true).try_init().ok();
+
+    // Loop devices have 512-byte sectors, so this ends half way into a block
+    let temp_dir = TempDir::new().unwrap();
+    let image = temp_dir.path().join("partition.img");
+    let image_size = 64 * 1024 * 1024 + 2048;
+    std::fs::File::create(&image).unwrap().set_len(image_size).unwrap();
+    let loop_dev = LoopDevice::attach(&image);
+
+    let size = device_size(&loop_dev.path).unwrap();
+    assert_eq!(size, image_size);
+
+    // The requested size is ignored for block devices; the whole device is used
+    format_device(&loop_dev.path, 0, Some("loopvol")).await.unwrap();
+
+    let device: Arc<dyn BlockDevice> =
+        Arc::new(FileBackedBlockDevice::open(&loop_dev.path, false).await.unwrap());
+    assert_eq!(device.block_count(), size / BLOCK_SIZE as u64);
+    {
+        let disk_fs = DiskFs::open(device.clone()).await.unwrap();
+        assert_eq!(disk_fs.superblock().block_count, size / BLOCK_SIZE as u64);
+        assert_eq!(
+            disk_fs.superblock().size,
+            disk_fs.superblock().block_count * BLOCK_SIZE as u64
+        );
+    }
+    drop(device);
+
+    // The FUSE-facing filesystem mounts the partition by path
+    let fs = AegisFS::from_device(&loop_dev.path).await.unwrap();
+    drop(fs);
+}
@@ -0,0 +1,172 @@
+//! Drive an AegisFS image through the 9P server with a minimal client
+
+use aegisfs::server::NinePServer;
+use aegisfs::{AegisFS, BlockDevice, DiskFs, DiskFsTrait, FileBackedBlockDevice, MountOptions, Vfs, VfsHandle};
+use std::sync::Arc;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const RLERROR: u8 = 7;
+
+/// Minimal 9P2000.L client speaking one request at a time
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    /// Send a message and return the reply's type and body
+    async fn call(&mut self, mtype: u8, body: &[u8]) -> (u8, Vec<u8>) {
+        let mut msg = ((7 + body.len()) as u32).to_le_bytes().to_vec();
+        msg.push(mtype);
+        msg.extend_from_slice(&1u16.to_le_bytes());
+        msg.extend_from_slice(body);
+        self.stream.write_all(&msg).await.unwrap();
+
+        let mut size = [0u8; 4];
+        self.stream.read_exact(&mut size).await.unwrap();
+        let mut reply = vec![0u8; u32::from_le_bytes(size) as usize - 4];
+        self.stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(u16::from_le_bytes([reply[1], reply[2]]), 1);
+        (reply[0], reply.split_off(3))
+    }
+
+    /// Send a message that must succeed with reply type `mtype + 1`
+    async fn ok(&mut self, mtype: u8, body: &[u8]) -> Vec<u8> {
+        let (rtype, reply) = self.call(mtype, body).await;
+        assert_eq!(rtype, mtype + 1, "message {} failed: {:?}", mtype, reply);
+        reply
+    }
+}
+
+fn string(s: &str) -> Vec<u8> {
+    let mut buf = (s.len() as u16).to_le_bytes().to_vec();
+    buf.extend_from_slice(s.as_bytes());
+    buf
+}
+
+fn u32s(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_create_and_read_file_over_9p() {
+    let dir = TempDir::new().unwrap();
+    let size = 4 * 1024 * 1024;
+    let device: Arc<dyn BlockDevice> =
+        Arc::new(FileBackedBlockDevice::create(dir.path().join("served.img"), size).await.unwrap());
+    DiskFs::format(device.clone(), size, None).await.unwrap();
+    let fs = Arc::new(VfsHandle::new(AegisFS::from_block_device(device, MountOptions::default()).await.unwrap()));
+
+    let server = NinePServer::bind("127.0.0.1:0".parse().unwrap(), fs.clone()).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    let mut client = Client { stream: TcpStream::connect(addr).await.unwrap() };
+
+    // Tversion / Tattach as fid 0
+    let mut version = u32s(&[8192]);
+    version.extend(string("9P2000.L"));
+    let reply = client.ok(100, &version).await;
+    assert_eq!(&reply[4..], string("9P2000.L").as_slice());
+    let mut attach = u32s(&[0, u32::MAX]);
+    attach.extend(string("user"));
+    attach.extend(string(""));
+    attach.extend(u32s(&[1000]));
+    let qid = client.ok(104, &attach).await;
+    assert_eq!(qid[0], 0x80);
+
+    // Clone the root into fid 1 and create a file there
+    let mut walk = u32s(&[0, 1]);
+    walk.extend(0u16.to_le_bytes());
+    client.ok(110, &walk).await;
+    let mut create = u32s(&[1]);
+    create.extend(string("remote.txt"));
+    create.extend(u32s(&[0o2, 0o644, 1000]));
+    client.ok(14, &create).await;
+
+    // Twrite then Tclunk, which persists the data
+    let mut write = u32s(&[1]);
+    write.extend(0u64.to_le_bytes());
+    write.extend(u32s(&[14]));
+    write.extend_from_slice(b"hello over 9p!");
+    let reply = client.ok(118, &write).await;
+    assert_eq!(reply, u32s(&[14]));
+    client.ok(120, &u32s(&[1])).await;
+
+    // Walk to the file by name, open it and read it back
+    let mut walk = u32s(&[0, 2]);
+    walk.extend(1u16.to_le_bytes());
+    walk.extend(string("remote.txt"));
+    let reply = client.ok(110, &walk).await;
+    assert_eq!(u16::from_le_bytes([reply[0], reply[1]]), 1);
+    client.ok(12, &u32s(&[2, 0])).await;
+    let mut read = u32s(&[2]);
+    read.extend(6u64.to_le_bytes());
+    read.extend(u32s(&[100]));
+    let reply = client.ok(116, &read).await;
+    assert_eq!(&reply[..4], u32s(&[8]).as_slice());
+    assert_eq!(&reply[4..], b"over 9p!");
+
+    // Errors come back as Rlerror with a Linux errno
+    let mut walk = u32s(&[0, 3]);
+    walk.extend(1u16.to_le_bytes());
+    walk.extend(string("missing"));
+    assert_eq!(client.call(110, &walk).await, (RLERROR, u32s(&[2])));
+
+    // The file is visible through the VFS API too
+    let attr = fs.lookup(1, "remote.txt").await.unwrap();
+    assert_eq!(attr.size, 14);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_many_files_over_9p() {
+    let dir = TempDir::new().unwrap();
+    let size = 4 * 1024 * 1024;
+    let device: Arc<dyn BlockDevice> =
+        Arc::new(FileBackedBlockDevice::create(dir.path().join("many.img"), size).await.unwrap());
+    DiskFs::format(device.clone(), size, None).await.unwrap();
+    let fs = Arc::new(VfsHandle::new(AegisFS::from_block_device(device, MountOptions::default()).await.unwrap()));
+
+    let server = NinePServer::bind("127.0.0.1:0".parse().unwrap(), fs.clone()).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    let mut client = Client { stream: TcpStream::connect(addr).await.unwrap() };
+    let mut version = u32s(&[8192]);
+    version.extend(string("9P2000.L"));
+    client.ok(100, &version).await;
+    let mut attach = u32s(&[0, u32::MAX]);
+    attach.extend(string("user"));
+    attach.extend(string(""));
+    attach.extend(u32s(&[1000]));
+    client.ok(104, &attach).await;
+
+    // Each file gets a fresh clone of the root, is created there, written
+    // and clunked
+    const FILES: u32 = 40;
+    for i in 0..FILES {
+        let fid = i + 1;
+        let mut walk = u32s(&[0, fid]);
+        walk.extend(0u16.to_le_bytes());
+        client.ok(110, &walk).await;
+        let mut create = u32s(&[fid]);
+        create.extend(string(&format!("file{}.txt", i)));
+        create.extend(u32s(&[0o2, 0o644, 1000]));
+        client.ok(14, &create).await;
+
+        let data = format!("contents of file {}", i);
+        let mut write = u32s(&[fid]);
+        write.extend(0u64.to_le_bytes());
+        write.extend(u32s(&[data.len() as u32]));
+        write.extend_from_slice(data.as_bytes());
+        assert_eq!(client.ok(118, &write).await, u32s(&[data.len() as u32]));
+        client.ok(120, &u32s(&[fid])).await;
+    }
+
+    for i in 0..FILES {
+        let attr = fs.lookup(1, &format!("file{}.txt", i)).await.unwrap();
+        let expected = format!("contents of file {}", i);
+        assert_eq!(fs.read(attr.ino, 0, 100).await.unwrap(), expected.as_bytes());
+    }
+}
@@ -45,12 +45,13 @@ async fn test_data_persistence() {
     // Step 2: Verify the device was formatted (check magic number)
     println!("Verifying device format...");
     let mut device_file = File::open(&device_path).expect("Failed to open device");
-    let mut magic = [0u8; 8];
+    // The magic follows the 4-byte superblock CRC
+    let mut header = [0u8; 12];
     device_file
-        .read_exact(&mut magic)
+        .read_exact(&mut header)
         .expect("Failed to read magic");
     assert_eq!(
-        &magic, b"AEGISFS\x00",
+        &header[4..], b"AEGISFS\x00",
         "Device should be formatted with AegisFS"
     );
 
@@ -12,13 +12,15 @@ mod tests {
 
     // Helper function to create a test filesystem
     fn setup_test_fs() -> io::Result<(TempDir, PathBuf)> {
+        setup_test_fs_with(AegisFS::new())
+    }
+
+    // Mount a given filesystem instance
+    fn setup_test_fs_with(fs: AegisFS) -> io::Result<(TempDir, PathBuf)> {
         // Create a temporary directory for mounting
         let mount_dir = tempfile::tempdir()?;
         let mount_path = mount_dir.path().to_path_buf();
 
-        // Create a new AegisFS instance
-        let fs = AegisFS::new();
-
         // Mount the filesystem in a separate thread
         let mount_path_clone = mount_path.clone();
         let _mount_handle = thread::spawn(move || {
@@ -95,4 +97,37 @@ mod tests {
         // The mount_dir will be automatically cleaned up when it goes out of scope
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_large_writes_arrive_in_big_chunks() -> io::Result<()> {
+        if !std::path::Path::new("/dev/fuse").exists() {
+            println!("Skipping: /dev/fuse is not available");
+            return Ok(());
+        }
+        env_logger::builder().is_test(true).try_init().ok();
+
+        let fs = AegisFS::new();
+        let limits = fs.kernel_limits();
+        let (_mount_dir, mount_path) = setup_test_fs_with(fs)?;
+
+        // One write(2) of 1MB; with big writes the kernel passes it on in
+        // chunks larger than a page
+        let file_path = mount_path.join("large.bin");
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        {
+            let mut file = File::create(&file_path)?;
+            file.write_all(&data)?;
+            file.sync_all()?;
+        }
+
+        assert!(limits.max_write() > 4096, "max_write was {}", limits.max_write());
+        assert!(
+            limits.largest_write() > 4096,
+            "largest write was {} bytes",
+            limits.largest_write()
+        );
+        assert!(limits.largest_write() <= limits.max_write());
+        assert_eq!(fs::read(&file_path)?, data);
+        Ok(())
+    }
 }
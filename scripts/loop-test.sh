@@ -0,0 +1,101 @@
+#!/bin/bash
+
+echo "=== AegisFS Loop Device Test ==="
+echo "Formats and mounts a loopback block device end-to-end through the CLI."
+echo "Needs root (or sudo), losetup and FUSE."
+echo
+
+# Exit on first error
+set -e
+
+PROJECT_ROOT=$(pwd)
+AEGISFS="$PROJECT_ROOT/fs-app/cli/target/release/aegisfs"
+
+# --- Configuration ---
+WORK_DIR=$(mktemp -d /tmp/aegisfs_loop.XXXXXX)
+IMAGE="$WORK_DIR/partition.img"
+MOUNT_POINT="$WORK_DIR/mnt"
+# 64MiB plus half a block, so the device doesn't end on a block boundary
+IMAGE_SIZE=$((64 * 1024 * 1024 + 2048))
+LOOP_DEVICE=""
+MOUNT_PID=""
+
+# --- Helper Functions ---
+function cleanup {
+    echo "--- Cleaning up ---"
+    if mountpoint -q "$MOUNT_POINT" 2>/dev/null; then
+        sudo fusermount -u "$MOUNT_POINT" || sudo umount -f "$MOUNT_POINT"
+    fi
+    if [ -n "$MOUNT_PID" ]; then
+        wait "$MOUNT_PID" 2>/dev/null || true
+    fi
+    if [ -n "$LOOP_DEVICE" ]; then
+        sudo losetup -d "$LOOP_DEVICE" || true
+    fi
+    rm -rf "$WORK_DIR"
+    echo "Cleanup complete."
+}
+trap cleanup EXIT
+
+function fail {
+    echo "❌ Test Failed: $1"
+    exit 1
+}
+
+function mount_fs {
+    sudo "$AEGISFS" mount "$LOOP_DEVICE" "$MOUNT_POINT" > "$WORK_DIR/mount.log" 2>&1 &
+    MOUNT_PID=$!
+    for _ in $(seq 1 20); do
+        mountpoint -q "$MOUNT_POINT" && return 0
+        sleep 0.5
+    done
+    cat "$WORK_DIR/mount.log"
+    fail "Mount did not come up."
+}
+
+function unmount_fs {
+    sudo fusermount -u "$MOUNT_POINT" || sudo umount "$MOUNT_POINT"
+    wait "$MOUNT_PID" || true
+    MOUNT_PID=""
+}
+
+# --- Phase 1: Build ---
+echo "▶️ Phase 1: Building CLI..."
+(cd fs-app/cli && cargo build --release) || fail "CLI build failed."
+echo "✅ CLI built."
+echo
+
+# --- Phase 2: Loop device ---
+echo "▶️ Phase 2: Attaching loop device..."
+truncate -s "$IMAGE_SIZE" "$IMAGE"
+LOOP_DEVICE=$(sudo losetup --find --show "$IMAGE")
+mkdir -p "$MOUNT_POINT"
+DEVICE_SIZE=$(sudo blockdev --getsize64 "$LOOP_DEVICE")
+echo "✅ $LOOP_DEVICE attached ($DEVICE_SIZE bytes)."
+echo
+
+# --- Phase 3: Format ---
+echo "▶️ Phase 3: Formatting $LOOP_DEVICE..."
+sudo "$AEGISFS" format "$LOOP_DEVICE" --size 0 --force || fail "Format failed."
+echo "✅ Formatted."
+echo
+
+# --- Phase 4: Mount, write, remount, verify ---
+echo "▶️ Phase 4: Mounting and writing data..."
+mount_fs
+echo "hello from a block device" | sudo tee "$MOUNT_POINT/hello.txt" > /dev/null
+sync
+unmount_fs
+
+mount_fs
+if ! sudo grep -q "hello from a block device" "$MOUNT_POINT/hello.txt"; then
+    fail "Data did not survive a remount."
+fi
+unmount_fs
+echo "✅ Data persisted across remount."
+echo
+
+echo "=== All loop device tests passed ==="
+echo
+echo "Running the ignored loop device integration tests..."
+(cd fs-core && sudo -E "$(command -v cargo)" test --test loop_device -- --ignored --test-threads=1)
@@ -0,0 +1,30 @@
+#!/bin/bash
+
+echo "=== AegisFS Sequential Write Benchmark ==="
+echo "Writes files of increasing size to a mounted AegisFS and reports throughput."
+echo "Usage: $0 [mount-point] (default: /tmp/aegisfs)"
+echo
+
+# Exit on first error
+set -e
+
+# --- Configuration ---
+MOUNT_POINT="${1:-/tmp/aegisfs}"
+SIZES_MB=(16 64 256)
+BLOCK_SIZES=(4K 128K 1M)
+
+if ! mountpoint -q "$MOUNT_POINT"; then
+    echo "❌ $MOUNT_POINT is not a mount point"
+    exit 1
+fi
+
+for size in "${SIZES_MB[@]}"; do
+    for bs in "${BLOCK_SIZES[@]}"; do
+        file="$MOUNT_POINT/write_bench_${size}M_${bs}.bin"
+        count=$(( size * 1024 * 1024 / $(numfmt --from=iec "$bs") ))
+        # conv=fsync so the time includes getting the data to the filesystem
+        result=$(dd if=/dev/zero of="$file" bs="$bs" count="$count" conv=fsync 2>&1 | tail -n 1)
+        echo "${size}MB in ${bs} writes: ${result##*, }"
+        rm -f "$file"
+    done
+done